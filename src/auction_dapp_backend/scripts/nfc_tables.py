@@ -0,0 +1,60 @@
+#!/usr/bin/env python3
+# Writes src/nfc_tables.rs from the Unicode Character Database that ships with Python.
+# Run it from src/auction_dapp_backend when moving to a newer Unicode version:
+#   python3 scripts/nfc_tables.py > src/nfc_tables.rs
+import sys
+import unicodedata
+
+HANGUL = range(0xAC00, 0xAC00 + 11172)
+
+
+def chars():
+    for cp in range(0x110000):
+        if 0xD800 <= cp <= 0xDFFF or cp in HANGUL:
+            continue
+        yield chr(cp)
+
+
+def canonical_mapping(c):
+    mapping = unicodedata.decomposition(c)
+    if not mapping or mapping.startswith("<"):
+        return None
+    return [chr(int(cp, 16)) for cp in mapping.split()]
+
+
+def rust(c):
+    return "'\\u{%04X}'" % ord(c)
+
+
+decompositions = []
+combining = []
+compositions = []
+for c in chars():
+    if canonical_mapping(c) is not None:
+        decompositions.append((c, unicodedata.normalize("NFD", c)))
+        mapping = canonical_mapping(c)
+        # excluded, singleton and non-starter decompositions do not compose back
+        if len(mapping) == 2 and unicodedata.normalize("NFC", "".join(mapping)) == c:
+            compositions.append(((mapping[0], mapping[1]), c))
+    if unicodedata.combining(c):
+        combining.append((c, unicodedata.combining(c)))
+compositions.sort()
+
+out = sys.stdout
+out.write("//generated by scripts/nfc_tables.py from Unicode %s, do not edit\n" % unicodedata.unidata_version)
+out.write("//hangul syllables are left out, they are decomposed and composed arithmetically\n\n")
+out.write("//every character with a canonical decomposition, fully decomposed and sorted by character\n")
+out.write("#[rustfmt::skip]\npub const DECOMPOSITIONS: &[(char, &[char])] = &[\n")
+for c, d in decompositions:
+    out.write("    (%s, &[%s]),\n" % (rust(c), ", ".join(rust(x) for x in d)))
+out.write("];\n\n")
+out.write("//the canonical combining class of every character that has one, sorted by character\n")
+out.write("#[rustfmt::skip]\npub const COMBINING_CLASSES: &[(char, u8)] = &[\n")
+for c, ccc in combining:
+    out.write("    (%s, %d),\n" % (rust(c), ccc))
+out.write("];\n\n")
+out.write("//the pairs that compose into a primary composite, sorted by pair\n")
+out.write("#[rustfmt::skip]\npub const COMPOSITIONS: &[((char, char), char)] = &[\n")
+for (a, b), c in compositions:
+    out.write("    ((%s, %s), %s),\n" % (rust(a), rust(b), rust(c)))
+out.write("];\n")
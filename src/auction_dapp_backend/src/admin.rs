@@ -0,0 +1,58 @@
+use candid::Principal;
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap};
+use std::cell::RefCell;
+
+use crate::{Memory, StorablePrincipal, MEMORY_MANAGER};
+
+thread_local! {
+    static ADMIN_MAP: RefCell<StableBTreeMap<StorablePrincipal, (), Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))));
+}
+
+//install and upgrade are only callable by controllers, so whoever runs the first one becomes an admin
+pub fn init() {
+    ADMIN_MAP.with(|admins| {
+        if admins.borrow().is_empty() {
+            admins.borrow_mut().insert(caller().into(), ());
+        }
+    });
+}
+
+pub fn is_admin(p: Principal) -> bool {
+    ADMIN_MAP.with(|admins| admins.borrow().contains_key(&p.into()))
+}
+
+pub fn ensure_admin() -> Result<(), String> {
+    if !is_admin(caller()) {
+        return Err("Only admins can perform this action.".to_string());
+    }
+    Ok(())
+}
+
+#[update(name = "addAdmin")]
+fn add_admin(p: Principal) -> Result<String, String> {
+    ensure_admin()?;
+    ADMIN_MAP.with(|admins| admins.borrow_mut().insert(p.into(), ()));
+    Ok(format!("{} is now an admin", p))
+}
+
+#[update(name = "removeAdmin")]
+fn remove_admin(p: Principal) -> Result<String, String> {
+    ensure_admin()?;
+    if p == caller() {
+        return Err("You cannot remove yourself as an admin.".to_string());
+    }
+    match ADMIN_MAP.with(|admins| admins.borrow_mut().remove(&p.into())) {
+        Some(_) => Ok(format!("{} is no longer an admin", p)),
+        None => Err(format!("{} is not an admin", p)),
+    }
+}
+
+pub fn all() -> Vec<Principal> {
+    ADMIN_MAP.with(|admins| admins.borrow().iter().map(|(k, _)| k.into()).collect())
+}
+
+#[query(name = "getAdmins")]
+fn get_admins() -> Vec<Principal> {
+    all()
+}
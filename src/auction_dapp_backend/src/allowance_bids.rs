@@ -0,0 +1,423 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_cdk::{api::id, caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    balances,
+    blocks::{self, FinancialOp},
+    clock::time,
+    deposits,
+    icrc::{self, AllowanceArgs, TransferArg, TransferFromArgs},
+    ledgers, referrals,
+    settlement::{self, Settlement},
+    validation, Item, Memory, PaymentMode, MEMORY_MANAGER,
+};
+
+//bidders approve the auction on the item's ledger, only the winner's tokens are moved and the auction pays the seller.
+//a bidder whose deposited balance covers the bid has it held from there instead, and needs no approval
+#[derive(Deserialize, CandidType, Clone)]
+pub enum CollectionState {
+    Pending,
+    Collected { block_index: Nat },
+    PaidFromBalance, //the buyer's held balance paid, no tokens had to be pulled
+    Failed { reason: String },
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct Collection {
+    item_id: u64,
+    ledger: Principal,
+    state: CollectionState,
+    attempted_at: u64,
+    payout_block: Option<Nat>, //the transfer to the seller, unset when the payout went to their balance instead
+}
+
+impl Storable for Collection {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//a failed collection with the longest kept reason takes 280 bytes
+impl BoundedStorable for Collection {
+    const MAX_SIZE: u32 = 1_000;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//the deposited tokens taken off the balance of an item's highest bidder
+#[derive(Deserialize, CandidType, Clone)]
+struct BalanceHold {
+    bidder: Principal,
+    ledger: Principal,
+    amount: u128,
+}
+
+impl Storable for BalanceHold {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//a hold with two full-length principals takes 108 bytes
+impl BoundedStorable for BalanceHold {
+    const MAX_SIZE: u32 = 150;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//a bid's tokens that were checked against the balance but not taken yet
+pub struct PendingHold {
+    item_id: u64,
+    hold: BalanceHold,
+}
+
+thread_local! {
+    static COLLECTION_MAP: RefCell<StableBTreeMap<u64, Collection, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))));
+
+    //item_id -> the held balance behind its highest bid, items whose highest bid rests on an approval have none
+    static BALANCE_HOLDS: RefCell<StableBTreeMap<u64, BalanceHold, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(107)))));
+}
+
+pub fn ledger_of(item: &Item) -> Option<Principal> {
+    match item.payment_mode {
+        Some(PaymentMode::Icrc2 { ledger }) => Some(ledger),
+        _ => None,
+    }
+}
+
+/// Checks that the bidder approved the auction for at least their bid and the ledger fee, nothing is locked yet.
+pub async fn ensure_allowance(
+    ledger: Principal,
+    bidder: Principal,
+    amount: u64,
+) -> Result<(), String> {
+    //transfer_from takes its fee out of the same allowance
+    let fee = match ledgers::last_fee(ledger) {
+        Some(fee) => fee,
+        None => icrc::fee(ledger).await?,
+    };
+    let required = Nat::from(amount) + fee;
+    let args = AllowanceArgs {
+        account: bidder.into(),
+        spender: id().into(),
+    };
+    let allowance = icrc::allowance(ledger, args).await?;
+    if allowance.expires_at.is_some_and(|t| t <= time()) || allowance.allowance < required {
+        return Err(format!(
+            "Approve the auction canister to spend at least {} tokens on the ledger before bidding, the bid and the ledger fee.",
+            required
+        ));
+    }
+    Ok(())
+}
+
+//what the bidder can put towards a bid on the item, their own held bid is given back when they raise it
+fn spendable(item_id: u64, ledger: Principal, bidder: Principal) -> u128 {
+    let own_hold = BALANCE_HOLDS
+        .with(|h| h.borrow().get(&item_id))
+        .filter(|h| h.bidder == bidder)
+        .map_or(0, |h| h.amount);
+    balances::token_balance_of(bidder, ledger).saturating_add(own_hold)
+}
+
+/// Whether the bidder's deposited balance covers the bid, in which case no approval is needed.
+pub fn balance_covers(item_id: u64, ledger: Principal, bidder: Principal, amount: u64) -> bool {
+    spendable(item_id, ledger, bidder) >= amount as u128
+}
+
+/// The bid's tokens to hold from the bidder's balance, none when the balance does not cover it and the
+/// bid rests on an approval. Nothing is taken until `bid_placed`.
+pub fn balance_hold(
+    item_id: u64,
+    ledger: Principal,
+    bidder: Principal,
+    amount: u64,
+) -> Option<PendingHold> {
+    balance_covers(item_id, ledger, bidder, amount).then_some(PendingHold {
+        item_id,
+        hold: BalanceHold {
+            bidder,
+            ledger,
+            amount: amount as u128,
+        },
+    })
+}
+
+/// Gives the previous highest bidder their held tokens back and holds the new bid's, if it is paid from the balance.
+pub fn bid_placed(item_id: u64, pending: Option<PendingHold>) {
+    release_hold(item_id);
+    if let Some(PendingHold { item_id, hold }) = pending {
+        //covered, release_hold gave back the bidder's own earlier hold first
+        let _ = balances::debit_token(hold.bidder, hold.ledger, hold.amount);
+        BALANCE_HOLDS.with(|h| h.borrow_mut().insert(item_id, hold));
+    }
+}
+
+/// Returns the held tokens to the bidder's balance, e.g. when they are outbid or the item is not sold.
+pub fn release_hold(item_id: u64) {
+    if let Some(hold) = BALANCE_HOLDS.with(|h| h.borrow_mut().remove(&item_id)) {
+        balances::credit_token(hold.bidder, hold.ledger, hold.amount);
+    }
+}
+
+/// Pays for the item from the buyer's held balance, or pulls the price from their approved tokens.
+/// Called when the item is settled. The seller is credited the price less commission and withdraws
+/// it like any token balance.
+pub fn collect(item_id: u64, ledger: Principal) {
+    let collection = Collection {
+        item_id,
+        ledger,
+        state: CollectionState::Pending,
+        attempted_at: time(),
+        payout_block: None,
+    };
+    COLLECTION_MAP.with(|c| c.borrow_mut().insert(item_id, collection));
+    let held = settlement::find_settlement(item_id).ok().and_then(|s| {
+        BALANCE_HOLDS
+            .with(|h| h.borrow().get(&item_id))
+            .filter(|h| h.bidder == s.buyer && h.amount >= s.price as u128)
+    });
+    match held {
+        Some(_) => ic_cdk::spawn(pay_from_hold(item_id, ledger)),
+        //e.g. a buyer the sale was handed to after the winner defaulted
+        None => {
+            release_hold(item_id);
+            ic_cdk::spawn(pull(item_id, ledger))
+        }
+    }
+}
+
+async fn pay_from_hold(item_id: u64, ledger: Principal) {
+    let Ok(settlement) = settlement::find_settlement(item_id) else {
+        return;
+    };
+    let Some(hold) = BALANCE_HOLDS.with(|h| h.borrow_mut().remove(&item_id)) else {
+        return;
+    };
+    //anything held above the price goes back to the buyer
+    let over = hold.amount.saturating_sub(settlement.price as u128);
+    if over > 0 {
+        balances::credit_token(hold.bidder, ledger, over);
+    }
+    let payout_block = pay_seller(ledger, &settlement, item_id).await;
+    let collection = Collection {
+        item_id,
+        ledger,
+        state: CollectionState::PaidFromBalance,
+        attempted_at: time(),
+        payout_block,
+    };
+    COLLECTION_MAP.with(|c| c.borrow_mut().insert(item_id, collection));
+}
+
+async fn pull(item_id: u64, ledger: Principal) {
+    let Ok(settlement) = settlement::find_settlement(item_id) else {
+        return;
+    };
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: settlement.buyer.into(),
+        to: id().into(),
+        amount: Nat::from(settlement.price),
+        fee: None,
+        memo: Some(item_id.to_be_bytes().to_vec()),
+        created_at_time: None,
+    };
+    let (state, payout_block) = match icrc::transfer_from(ledger, args).await {
+        Ok(block_index) => {
+            deposits::mark_collected(ledger, &block_index, settlement.buyer);
+            blocks::append_token(
+                FinancialOp::Deposit,
+                ledger,
+                settlement.price as u128,
+                Some(settlement.buyer),
+                None,
+                Some(item_id),
+            );
+            let payout_block = pay_seller(ledger, &settlement, item_id).await;
+            (CollectionState::Collected { block_index }, payout_block)
+        }
+        Err(reason) => {
+            let reason = validation::reason(reason);
+            (CollectionState::Failed { reason }, None)
+        }
+    };
+    let collection = Collection {
+        item_id,
+        ledger,
+        state,
+        attempted_at: time(),
+        payout_block,
+    };
+    COLLECTION_MAP.with(|c| c.borrow_mut().insert(item_id, collection));
+}
+
+//the price is in the canister's account by now, it is split between the seller and the commission
+async fn pay_seller(ledger: Principal, settlement: &Settlement, item_id: u64) -> Option<Nat> {
+    let (payout, commission) = settlement.split(settlement.price as u128);
+    blocks::append_token(
+        FinancialOp::Payout,
+        ledger,
+        payout,
+        Some(settlement.buyer),
+        Some(settlement.seller),
+        Some(item_id),
+    );
+    if commission > 0 {
+        blocks::append_token(
+            FinancialOp::Fee,
+            ledger,
+            commission,
+            Some(settlement.seller),
+            None,
+            Some(item_id),
+        );
+    }
+    let payout_block = pay_out(ledger, settlement.seller, payout, item_id).await;
+    referrals::purchase_completed(settlement.buyer);
+    payout_block
+}
+
+//sends the seller their share less the ledger fee, or keeps it on their balance if that is not possible
+async fn pay_out(ledger: Principal, seller: Principal, payout: u128, item_id: u64) -> Option<Nat> {
+    let fee = match icrc::fee(ledger).await {
+        Ok(fee) if fee < payout => fee,
+        _ => {
+            balances::credit_token(seller, ledger, payout);
+            return None;
+        }
+    };
+    let args = TransferArg {
+        from_subaccount: None,
+        to: seller.into(),
+        amount: Nat::from(payout) - fee.clone(),
+        fee: Some(fee),
+        memo: Some(item_id.to_be_bytes().to_vec()),
+        created_at_time: Some(time()),
+    };
+    match icrc::transfer(ledger, args).await {
+        Ok(block_index) => Some(block_index),
+        Err(_) => {
+            balances::credit_token(seller, ledger, payout);
+            None
+        }
+    }
+}
+
+/// Token items need a supported ledger, and a minimum bid, if set, that covers the ledger fee.
+pub fn validate_minimum(mode: Option<PaymentMode>, minimum_bid: Option<u64>) -> Result<(), String> {
+    let Some(PaymentMode::Icrc2 { ledger }) = mode else {
+        return Ok(());
+    };
+    ledgers::ensure_supported(ledger)?;
+    match (minimum_bid, ledgers::last_fee(ledger)) {
+        (Some(minimum), Some(fee)) if fee >= minimum => Err(format!(
+            "The minimum bid has to be above the ledger fee of {}.",
+            fee
+        )),
+        _ => Ok(()),
+    }
+}
+
+//e.g. after the buyer topped up their approval following a failed collection
+#[update(name = "retryCollection")]
+fn retry_collection(item_id: u64) -> Result<String, String> {
+    let settlement = settlement::find_settlement(item_id)?;
+    if settlement.seller != caller() && settlement.buyer != caller() {
+        return Err("Only the buyer and the seller can retry the payment.".to_string());
+    }
+    let collection = COLLECTION_MAP
+        .with(|c| c.borrow().get(&item_id))
+        .ok_or_else(|| "This item is not paid through token approvals.".to_string())?;
+    if !matches!(collection.state, CollectionState::Failed { .. }) {
+        return Err("The payment for this item has not failed.".to_string());
+    }
+    collect(item_id, collection.ledger);
+    Ok("Payment collection started again".to_string())
+}
+
+#[query(name = "getCollection")]
+fn get_collection(item_id: u64) -> Option<Collection> {
+    COLLECTION_MAP.with(|c| c.borrow().get(&item_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        accept_bid, clock,
+        test_support::{self, assert_fits, principal, HOUR},
+        BidBase,
+    };
+
+    fn bid(amount: u64, bidder: Principal) {
+        let bid = BidBase {
+            bid_amount: amount,
+            bid_date: 0,
+            memo: None,
+        };
+        assert!(
+            accept_bid(1, bid, bidder).is_ok(),
+            "bid of {} refused",
+            amount
+        );
+    }
+
+    #[test]
+    fn deposited_balances_back_the_highest_bid() {
+        clock::set(1_000 * HOUR);
+        let ledger = principal(9);
+        let mut item = test_support::item(1, principal(1));
+        item.payment_mode = Some(PaymentMode::Icrc2 { ledger });
+        test_support::list(item);
+        balances::credit_token(principal(2), ledger, 100);
+        balances::credit_token(principal(3), ledger, 50);
+
+        bid(80, principal(2));
+        assert_eq!(balances::token_balance_of(principal(2), ledger), 20);
+        //the balance does not cover it, the bid rests on an approval and the outbid hold goes back
+        assert!(!balance_covers(1, ledger, principal(3), 90));
+        bid(90, principal(3));
+        assert_eq!(balances::token_balance_of(principal(2), ledger), 100);
+        assert_eq!(balances::token_balance_of(principal(3), ledger), 50);
+        //raising their own held bid counts what is already held
+        bid(95, principal(2));
+        assert!(balance_covers(1, ledger, principal(2), 100));
+        bid(100, principal(2));
+        assert_eq!(balances::token_balance_of(principal(2), ledger), 0);
+        release_hold(1);
+        assert_eq!(balances::token_balance_of(principal(2), ledger), 100);
+    }
+
+    #[test]
+    fn the_largest_failed_collection_fits() {
+        let collection = Collection {
+            item_id: u64::MAX,
+            ledger: principal(9),
+            state: CollectionState::Failed {
+                reason: validation::reason("x".repeat(10_000)),
+            },
+            attempted_at: u64::MAX,
+            payout_block: Some(Nat::from(u128::MAX)),
+        };
+        assert_fits(&collection);
+    }
+
+    #[test]
+    fn the_largest_balance_hold_fits() {
+        let hold = BalanceHold {
+            bidder: principal(2),
+            ledger: principal(9),
+            amount: u128::MAX,
+        };
+        assert_fits(&hold);
+    }
+}
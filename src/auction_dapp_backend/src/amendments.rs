@@ -0,0 +1,104 @@
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    bid_store, clock::time, guard, indexer_sync, validation, Memory, Status, ITEM_MAP,
+    MEMORY_MANAGER,
+};
+
+const MAX_AMENDMENT_LENGTH: usize = 500;
+const MAX_AMENDMENTS_PER_ITEM: usize = 20;
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct Amendment {
+    seq: u64,
+    note: String,
+    posted_at: u64,
+}
+
+impl Storable for Amendment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Amendment {
+    const MAX_SIZE: u32 = 2_500; //MAX_AMENDMENT_LENGTH chars can take up to 4 bytes each
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    //append-only notes under an item's description, keyed by (item_id, seq)
+    static AMENDMENT_MAP: RefCell<StableBTreeMap<(u64, u64), Amendment, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))));
+}
+
+fn amendments_of(item_id: u64) -> Vec<Amendment> {
+    AMENDMENT_MAP.with(|m| {
+        m.borrow()
+            .range((item_id, 0)..=(item_id, u64::MAX))
+            .map(|(_, v)| v)
+            .collect()
+    })
+}
+
+//the description is frozen once bidding starts so bidders keep seeing what they bid on,
+//anything the owner wants to add goes below it
+#[update(name = "amendItem")]
+fn amend_item(item_id: u64, note: String) -> Result<String, String> {
+    guard::check_write_headroom()?;
+    let item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+    if item.item_owner != caller() {
+        return Err("You are not authorized to amend this item.".to_string());
+    }
+    if item.status() != Status::Active {
+        return Err("Only active items can be amended.".to_string());
+    }
+    if !bid_store::has_bids(item.id) {
+        return Err("Items without bids can be edited instead.".to_string());
+    }
+    let note = validation::text("Amendment", &note, MAX_AMENDMENT_LENGTH)?;
+    let amendments = amendments_of(item_id);
+    if amendments.len() >= MAX_AMENDMENTS_PER_ITEM {
+        return Err(format!(
+            "An item can have at most {} amendments.",
+            MAX_AMENDMENTS_PER_ITEM
+        ));
+    }
+    let seq = amendments.last().map_or(0, |a| a.seq + 1);
+    let amendment = Amendment {
+        seq,
+        note,
+        posted_at: time(),
+    };
+    AMENDMENT_MAP.with(|m| m.borrow_mut().insert((item_id, seq), amendment));
+    indexer_sync::item_changed(item_id);
+    Ok(format!("Amendment {} posted", seq))
+}
+
+#[query(name = "getAmendments")]
+fn get_amendments(item_id: u64) -> Vec<Amendment> {
+    amendments_of(item_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_fits;
+
+    #[test]
+    fn the_longest_amendment_fits() {
+        assert_fits(&Amendment {
+            seq: u64::MAX,
+            note: "\u{1F600}".repeat(MAX_AMENDMENT_LENGTH),
+            posted_at: u64::MAX,
+        });
+    }
+}
@@ -0,0 +1,321 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::query;
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell, collections::BTreeSet};
+
+use crate::{
+    allowance_bids,
+    clock::time,
+    price_history::{category_key, CategoryKey, DAY_NS, UNCATEGORIZED},
+    settlement, Item, Memory, Status, StorablePrincipal, ITEM_MAP, MEMORY_MANAGER,
+};
+
+const MAX_QUERY_DAYS: u64 = 366;
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct SalesDay {
+    sales_count: u64,
+    volume: u128,
+    unique_buyers: u64,
+}
+
+impl Storable for SalesDay {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SalesDay {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//lifetime totals of one category
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct CategoryTotals {
+    finished_listings: u64, //auctions that ended or sold, withdrawn ones do not count
+    bids: u64,              //placed on the finished listings
+    sold_items: u64,
+    volume: u128,
+}
+
+impl Storable for CategoryTotals {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CategoryTotals {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Deserialize, CandidType)]
+enum AnalyticsRange {
+    LastDays(u64),                       //ending today
+    Days { from_day: u64, to_day: u64 }, //days since the unix epoch, both ends inclusive
+}
+
+#[derive(CandidType)]
+struct SalesDayPoint {
+    day: u64,
+    sales_count: u64,
+    volume: u128,
+    average_price: u64,
+    unique_buyers: u64,
+}
+
+#[derive(CandidType)]
+struct SalesAnalytics {
+    from_day: u64,
+    to_day: u64,
+    sales_count: u64,
+    volume: u128,
+    average_price: u64,
+    unique_buyers: u64, //over the whole range, a buyer of several days counts once
+    days: Vec<SalesDayPoint>, //only days with sales
+}
+
+#[derive(CandidType)]
+struct CategoryAnalytics {
+    category: String,
+    finished_listings: u64,
+    sold_items: u64,
+    volume: u128,
+    average_price: u64,
+    sale_rate_bps: u64, //sold items out of finished listings
+    average_bids_per_item: f64,
+}
+
+thread_local! {
+    //settled sales per day since the unix epoch
+    static SALES_DAYS: RefCell<StableBTreeMap<u64, SalesDay, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(96)))));
+
+    //(day, buyer) of everyone who bought that day
+    static DAY_BUYERS: RefCell<StableBTreeMap<(u64, StorablePrincipal), (), Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(97)))));
+
+    static CATEGORY_TOTALS: RefCell<StableBTreeMap<CategoryKey, CategoryTotals, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(98)))));
+}
+
+fn update_day(day: u64, f: impl FnOnce(&mut SalesDay)) {
+    SALES_DAYS.with(|d| {
+        let mut sales = d.borrow().get(&day).unwrap_or_default();
+        f(&mut sales);
+        d.borrow_mut().insert(day, sales);
+    });
+}
+
+fn update_category(category: Option<&str>, f: impl FnOnce(&mut CategoryTotals)) {
+    let key = category_key(category.unwrap_or(UNCATEGORIZED));
+    CATEGORY_TOTALS.with(|c| {
+        let mut totals = c.borrow().get(&key).unwrap_or_default();
+        f(&mut totals);
+        c.borrow_mut().insert(key, totals);
+    });
+}
+
+//volumes are in cycles, listings paid in a token are left out since their prices are in another unit
+fn counted(item: &Item) -> bool {
+    allowance_bids::ledger_of(item).is_none()
+}
+
+fn average(volume: u128, count: u64) -> u64 {
+    match count {
+        0 => 0,
+        n => (volume / n as u128) as u64,
+    }
+}
+
+/// Called for every settlement, including the ones that hand a defaulted sale to another buyer.
+pub fn sale_settled(item: &Item, buyer: Principal, price: u64, settled_at: u64) {
+    if !counted(item) {
+        return;
+    }
+    let day = settled_at / DAY_NS;
+    let new_buyer = DAY_BUYERS
+        .with(|b| b.borrow_mut().insert((day, buyer.into()), ()))
+        .is_none();
+    update_day(day, |d| {
+        d.sales_count += 1;
+        d.volume += price as u128;
+        d.unique_buyers += new_buyer as u64;
+    });
+}
+
+//the buyer stays among the day's unique buyers, they did win the item
+pub fn sale_defaulted(item: &Item, price: u64, settled_at: u64) {
+    if !counted(item) {
+        return;
+    }
+    update_day(settled_at / DAY_NS, |d| {
+        d.sales_count = d.sales_count.saturating_sub(1);
+        d.volume = d.volume.saturating_sub(price as u128);
+    });
+}
+
+/// Called once when an active listing ends, whether or not it sold.
+pub fn listing_finished(item: &Item) {
+    if !counted(item) {
+        return;
+    }
+    let bids = item.bid_count();
+    update_category(item.category.as_deref(), |c| {
+        c.finished_listings += 1;
+        c.bids += bids;
+    });
+}
+
+pub fn category_sold(item: &Item, price: u64) {
+    if !counted(item) {
+        return;
+    }
+    update_category(item.category.as_deref(), |c| {
+        c.sold_items += 1;
+        c.volume += price as u128;
+    });
+}
+
+pub fn category_sale_defaulted(item: &Item, price: u64) {
+    if !counted(item) {
+        return;
+    }
+    update_category(item.category.as_deref(), |c| {
+        c.sold_items = c.sold_items.saturating_sub(1);
+        c.volume = c.volume.saturating_sub(price as u128);
+    });
+}
+
+//counts the sales settled before the aggregates existed, runs as a schema migration
+pub fn backfill() {
+    for s in settlement::completed_sales() {
+        if let Some(item) = ITEM_MAP.with(|items| items.borrow().get(&s.item_id)) {
+            sale_settled(&item, s.buyer, s.price, s.settled_at);
+        }
+    }
+}
+
+//counts the listings that ended before the category totals existed, runs as a schema migration
+pub fn backfill_categories() {
+    let finished: Vec<Item> = ITEM_MAP.with(|items| {
+        items
+            .borrow()
+            .iter()
+            .map(|(_, item)| item)
+            .filter(|item| matches!(item.status(), Status::Ended | Status::Settled))
+            .collect()
+    });
+    for item in finished {
+        listing_finished(&item);
+    }
+    for s in settlement::completed_sales() {
+        if let Some(item) = ITEM_MAP.with(|items| items.borrow().get(&s.item_id)) {
+            category_sold(&item, s.price);
+        }
+    }
+}
+
+//cycles sales only, see `counted`
+#[query(name = "getSalesAnalytics")]
+fn get_sales_analytics(range: AnalyticsRange) -> Result<SalesAnalytics, String> {
+    let (from_day, to_day) = match range {
+        AnalyticsRange::LastDays(days) => {
+            let today = time() / DAY_NS;
+            (today.saturating_sub(days.saturating_sub(1)), today)
+        }
+        AnalyticsRange::Days { from_day, to_day } => (from_day, to_day),
+    };
+    if to_day < from_day || to_day - from_day >= MAX_QUERY_DAYS {
+        return Err(format!(
+            "Sales analytics can be queried for up to {} days at a time.",
+            MAX_QUERY_DAYS
+        ));
+    }
+
+    let days: Vec<SalesDayPoint> = SALES_DAYS.with(|d| {
+        d.borrow()
+            .range(from_day..=to_day)
+            .filter(|(_, s)| s.sales_count > 0)
+            .map(|(day, s)| SalesDayPoint {
+                day,
+                sales_count: s.sales_count,
+                volume: s.volume,
+                average_price: average(s.volume, s.sales_count),
+                unique_buyers: s.unique_buyers,
+            })
+            .collect()
+    });
+    let buyers: BTreeSet<StorablePrincipal> = DAY_BUYERS.with(|b| {
+        b.borrow()
+            .range((from_day, StorablePrincipal::default())..)
+            .take_while(|((day, _), _)| *day <= to_day)
+            .map(|((_, buyer), _)| buyer)
+            .collect()
+    });
+    let sales_count = days.iter().map(|d| d.sales_count).sum();
+    let volume = days.iter().map(|d| d.volume).sum();
+    Ok(SalesAnalytics {
+        from_day,
+        to_day,
+        sales_count,
+        volume,
+        average_price: average(volume, sales_count),
+        unique_buyers: buyers.len() as u64,
+        days,
+    })
+}
+
+//busiest categories first, over cycles listings only
+#[query(name = "getCategoryAnalytics")]
+fn get_category_analytics() -> Vec<CategoryAnalytics> {
+    let mut categories: Vec<CategoryAnalytics> = CATEGORY_TOTALS.with(|c| {
+        c.borrow()
+            .iter()
+            .map(|(key, c)| CategoryAnalytics {
+                category: String::from_utf8_lossy(key.as_slice()).into_owned(),
+                finished_listings: c.finished_listings,
+                sold_items: c.sold_items,
+                volume: c.volume,
+                average_price: average(c.volume, c.sold_items),
+                sale_rate_bps: match c.finished_listings {
+                    0 => 0,
+                    n => (c.sold_items * 10_000 / n).min(10_000),
+                },
+                average_bids_per_item: match c.finished_listings {
+                    0 => 0.0,
+                    n => c.bids as f64 / n as f64,
+                },
+            })
+            .collect()
+    });
+    categories.sort_by_key(|c| std::cmp::Reverse(c.volume));
+    categories
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_fits;
+
+    #[test]
+    fn the_largest_totals_fit() {
+        assert_fits(&SalesDay {
+            sales_count: u64::MAX,
+            volume: u128::MAX,
+            unique_buyers: u64::MAX,
+        });
+        assert_fits(&CategoryTotals {
+            finished_listings: u64::MAX,
+            bids: u64::MAX,
+            sold_items: u64::MAX,
+            volume: u128::MAX,
+        });
+    }
+}
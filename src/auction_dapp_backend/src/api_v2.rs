@@ -0,0 +1,218 @@
+use candid::{CandidType, Principal};
+use ic_cdk::{caller, query, update};
+use std::fmt;
+
+use crate::{
+    bid_limits::BidLimitError, bid_store, create_item, get_item, is_visible_to,
+    listing_quota::QuotaError, maintenance::ServiceError, names, place_bid_as, redact_bid_memos,
+    validation::ValidationError, BidBase, BidPage, BidSummary, ItemBase, ItemSummary, ItemView,
+    ItemWithBidSummary, ITEM_MAP, MAX_BID_PAGE_SIZE,
+};
+
+//the version getApiVersion reports, bump it with every set of new endpoints
+const API_VERSION: u32 = 2;
+//versions still served, the v1 methods are adapters over the v2 ones
+const SUPPORTED_VERSIONS: &[u32] = &[1, 2];
+
+const MAX_ITEMS_PAGE_SIZE: u64 = 100;
+
+/// Errors of the v2 endpoints, the v1 ones send the same errors as text.
+#[derive(CandidType, Debug, PartialEq)]
+pub enum ApiError {
+    NotFound(String),
+    NotAllowed(String),  //the caller cannot do this, e.g. bid on their own item
+    Invalid(String),     //the arguments were rejected
+    Closed(String),      //the item is not open for bidding
+    Unavailable(String), //maintenance, or the canister is low on cycles or storage
+    Limited {
+        message: String,
+        retry_at: Option<u64>,  //nanoseconds, when the call can succeed again
+        remaining: Option<u64>, //calls of the same kind left once it can, none when they are not counted
+    },
+    Rejected(String), //anything the other variants do not cover yet
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::NotFound(m)
+            | ApiError::NotAllowed(m)
+            | ApiError::Invalid(m)
+            | ApiError::Closed(m)
+            | ApiError::Unavailable(m)
+            | ApiError::Limited { message: m, .. }
+            | ApiError::Rejected(m) => write!(f, "{}", m),
+        }
+    }
+}
+
+impl From<ApiError> for String {
+    fn from(e: ApiError) -> Self {
+        e.to_string()
+    }
+}
+
+//checks that still fail with text, so `?` keeps working on them
+impl From<String> for ApiError {
+    fn from(message: String) -> Self {
+        ApiError::Rejected(message)
+    }
+}
+
+impl From<ValidationError> for ApiError {
+    fn from(e: ValidationError) -> Self {
+        ApiError::Invalid(e.to_string())
+    }
+}
+
+impl From<ServiceError> for ApiError {
+    fn from(e: ServiceError) -> Self {
+        ApiError::Unavailable(e.to_string())
+    }
+}
+
+impl From<BidLimitError> for ApiError {
+    fn from(e: BidLimitError) -> Self {
+        let (retry_at, remaining) = match e {
+            BidLimitError::TooManyBids { resets_at, .. } => (resets_at, Some(0)),
+            BidLimitError::CoolingDown {
+                retry_at,
+                remaining,
+                ..
+            } => (Some(retry_at), remaining.map(u64::from)),
+        };
+        ApiError::Limited {
+            message: e.to_string(),
+            retry_at,
+            remaining,
+        }
+    }
+}
+
+impl From<QuotaError> for ApiError {
+    fn from(e: QuotaError) -> Self {
+        let QuotaError::TooManyListings { retry_at, .. } = e;
+        ApiError::Limited {
+            message: e.to_string(),
+            retry_at,
+            remaining: Some(0),
+        }
+    }
+}
+
+#[derive(CandidType)]
+struct ApiVersion {
+    current: u32,
+    supported: Vec<u32>,
+}
+
+#[derive(CandidType)]
+pub struct BidReceipt {
+    pub item_id: u64,
+    pub amount: u64,
+    pub seq: u64,
+    pub next_minimum_bid: u64, //what the next bid on the item has to reach
+    pub ends_at: u64,
+}
+
+#[derive(CandidType)]
+struct ItemSummaryPage {
+    items: Vec<ItemSummary>,
+    next_cursor: Option<u64>, //pass it back to read the next page
+}
+
+pub fn item_with_bid_summary(key: u64) -> Result<ItemWithBidSummary, ApiError> {
+    let viewer = caller();
+    let item =
+        get_item(key).ok_or_else(|| ApiError::NotFound("Item could not be found.".to_string()))?;
+    let bids = bid_store::of(key);
+    let bid_summary = BidSummary {
+        count: item.bid_count(),
+        highest: bids.iter().map(|b| b.bid_amount).max().unwrap_or(0),
+        latest_at: bids.last().map(|b| b.placed_at.unwrap_or(b.bid_date)),
+        own_best: bids
+            .iter()
+            .filter(|b| b.bidder_principal == viewer)
+            .map(|b| b.bid_amount)
+            .max(),
+        archived: Some(item.bid_archive.is_some()),
+    };
+    Ok(ItemWithBidSummary {
+        item: item.into(),
+        bid_summary,
+    })
+}
+
+pub fn bids_page(key: u64, from_index: u64, limit: u64) -> Result<BidPage, ApiError> {
+    let limit = limit.clamp(1, MAX_BID_PAGE_SIZE) as usize;
+    let item =
+        get_item(key).ok_or_else(|| ApiError::NotFound("Item could not be found.".to_string()))?;
+    let total = bid_store::count(key);
+    let bids = redact_bid_memos(&item, bid_store::page(key, from_index, limit), caller());
+    let next = from_index + bids.len() as u64;
+    let mut bidders: Vec<Principal> = bids.iter().map(|b| b.bidder_principal).collect();
+    bidders.sort();
+    bidders.dedup();
+    let bidder_names = bidders
+        .into_iter()
+        .filter_map(|p| names::of(p).map(|name| (p, name)))
+        .collect();
+    Ok(BidPage {
+        bidder_names: Some(bidder_names),
+        next_index: (next < total).then_some(next),
+        bids,
+        archived: Some(item.bid_archive.is_some()),
+    })
+}
+
+/// The visible items from `cursor` on in id order, with the id to continue from.
+pub fn items_page(cursor: u64, limit: u64) -> (Vec<ItemSummary>, Option<u64>) {
+    let limit = limit.clamp(1, MAX_ITEMS_PAGE_SIZE) as usize;
+    let viewer = caller();
+    ITEM_MAP.with(|items| {
+        let mut page = vec![];
+        for (id, item) in items.borrow().range(cursor..) {
+            if page.len() == limit {
+                return (page, Some(id));
+            }
+            if is_visible_to(&item, viewer) {
+                page.push(ItemSummary::from(&item));
+            }
+        }
+        (page, None)
+    })
+}
+
+#[query(name = "getApiVersion")]
+fn get_api_version() -> ApiVersion {
+    ApiVersion {
+        current: API_VERSION,
+        supported: SUPPORTED_VERSIONS.to_vec(),
+    }
+}
+
+#[query(name = "getItemsV2")]
+fn get_items_v2(cursor: Option<u64>, limit: u64) -> ItemSummaryPage {
+    let (items, next_cursor) = items_page(cursor.unwrap_or(0), limit);
+    ItemSummaryPage { items, next_cursor }
+}
+
+#[query(name = "getItemV2")]
+fn get_item_v2(key: u64) -> Result<ItemWithBidSummary, ApiError> {
+    item_with_bid_summary(key)
+}
+
+#[query(name = "getBidsV2")]
+fn get_bids_v2(key: u64, from_index: u64, limit: u64) -> Result<BidPage, ApiError> {
+    bids_page(key, from_index, limit)
+}
+
+#[update(name = "listItemV2")]
+fn list_item_v2(item: ItemBase) -> Result<ItemView, ApiError> {
+    create_item(caller(), item).map(ItemView::from)
+}
+
+#[update(name = "bidForAnItemV2")]
+async fn bid_for_an_item_v2(key: u64, bid: BidBase) -> Result<BidReceipt, ApiError> {
+    place_bid_as(key, bid, caller()).await
+}
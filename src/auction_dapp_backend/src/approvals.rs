@@ -0,0 +1,188 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{
+    memory_manager::MemoryId, BoundedStorable, StableBTreeMap, StableCell, Storable,
+};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{admin, clock::time, escrow, Memory, MEMORY_MANAGER};
+
+pub const REQUIRED_APPROVALS: usize = 2;
+const MAX_APPROVERS: usize = 20;
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct ApprovalPolicy {
+    threshold: Option<u64>, //releases of at least this many cycles need approval, none when not set
+    approvers: Vec<Principal>,
+}
+
+impl Storable for ApprovalPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+struct PendingSettlement {
+    item_id: u64,
+    amount: u64,
+    queued_at: u64,
+    approvals: Vec<Principal>,
+}
+
+impl Storable for PendingSettlement {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PendingSettlement {
+    const MAX_SIZE: u32 = 300;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static APPROVAL_POLICY: RefCell<StableCell<ApprovalPolicy, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(72))),
+        ApprovalPolicy::default()).unwrap());
+
+    //item_id -> a release waiting for approvals
+    static PENDING_SETTLEMENTS: RefCell<StableBTreeMap<u64, PendingSettlement, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(73)))));
+}
+
+fn policy() -> ApprovalPolicy {
+    APPROVAL_POLICY.with(|c| c.borrow().get().clone())
+}
+
+pub fn requires_approval(amount: u64) -> bool {
+    policy().threshold.is_some_and(|t| amount >= t)
+}
+
+/// Holds a release back until enough approvers sign off on it.
+pub fn queue(item_id: u64, amount: u64) {
+    PENDING_SETTLEMENTS.with(|p| {
+        p.borrow_mut().insert(
+            item_id,
+            PendingSettlement {
+                item_id,
+                amount,
+                queued_at: time(),
+                approvals: vec![],
+            },
+        )
+    });
+}
+
+//an approver who lost their admin rights cannot approve anymore
+fn ensure_approver() -> Result<(), String> {
+    if !policy().approvers.contains(&caller()) || !admin::is_admin(caller()) {
+        return Err("Only settlement approvers can perform this action.".to_string());
+    }
+    Ok(())
+}
+
+fn find_pending(item_id: u64) -> Result<PendingSettlement, String> {
+    PENDING_SETTLEMENTS
+        .with(|p| p.borrow().get(&item_id))
+        .ok_or_else(|| "No settlement is waiting for approval for this item.".to_string())
+}
+
+#[update(name = "setApprovalPolicy")]
+fn set_approval_policy(
+    threshold: Option<u64>,
+    approvers: Vec<Principal>,
+) -> Result<String, String> {
+    admin::ensure_admin()?;
+    let mut approvers = approvers;
+    approvers.sort();
+    approvers.dedup();
+    if approvers.len() > MAX_APPROVERS {
+        return Err(format!("At most {} approvers can be set.", MAX_APPROVERS));
+    }
+    if threshold.is_some() && approvers.len() < REQUIRED_APPROVALS {
+        return Err(format!(
+            "At least {} approvers are needed to require approvals.",
+            REQUIRED_APPROVALS
+        ));
+    }
+    if let Some(p) = approvers.iter().find(|p| !admin::is_admin(**p)) {
+        return Err(format!("{} is not an admin", p));
+    }
+    APPROVAL_POLICY.with(|c| {
+        c.borrow_mut()
+            .set(ApprovalPolicy {
+                threshold,
+                approvers,
+            })
+            .unwrap()
+    });
+    Ok("Approval policy updated".to_string())
+}
+
+#[update(name = "approveSettlement")]
+fn approve_settlement(item_id: u64) -> Result<String, String> {
+    ensure_approver()?;
+    let mut pending = find_pending(item_id)?;
+    if pending.approvals.contains(&caller()) {
+        return Err("You have already approved this settlement.".to_string());
+    }
+    pending.approvals.push(caller());
+    if pending.approvals.len() < REQUIRED_APPROVALS {
+        let approvals = pending.approvals.len();
+        PENDING_SETTLEMENTS.with(|p| p.borrow_mut().insert(item_id, pending));
+        return Ok(format!(
+            "Settlement of item {} has {} of {} approvals",
+            item_id, approvals, REQUIRED_APPROVALS
+        ));
+    }
+    escrow::finish_approved(item_id, false)?;
+    PENDING_SETTLEMENTS.with(|p| p.borrow_mut().remove(&item_id));
+    Ok(format!(
+        "Payment for item {} released to the seller",
+        item_id
+    ))
+}
+
+//one approver is enough to stop a release, the payment goes back to the buyer
+#[update(name = "rejectSettlement")]
+fn reject_settlement(item_id: u64) -> Result<String, String> {
+    ensure_approver()?;
+    find_pending(item_id)?;
+    escrow::finish_approved(item_id, true)?;
+    PENDING_SETTLEMENTS.with(|p| p.borrow_mut().remove(&item_id));
+    Ok(format!(
+        "Payment for item {} refunded to the buyer",
+        item_id
+    ))
+}
+
+#[query(name = "getPendingSettlements")]
+fn get_pending_settlements() -> Result<Vec<PendingSettlement>, String> {
+    admin::ensure_admin()?;
+    Ok(PENDING_SETTLEMENTS.with(|p| p.borrow().iter().map(|(_, v)| v).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    #[test]
+    fn a_settlement_one_approval_short_fits() {
+        //the approval that completes a settlement releases it, it is never stored
+        assert_fits(&PendingSettlement {
+            item_id: u64::MAX,
+            amount: u64::MAX,
+            queued_at: u64::MAX,
+            approvals: (1..REQUIRED_APPROVALS as u8).map(principal).collect(),
+        });
+    }
+}
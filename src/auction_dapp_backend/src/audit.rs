@@ -0,0 +1,133 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{admin, clock::time, close_timers::EarlyClose, Memory, MEMORY_MANAGER};
+
+const MAX_PAGE_SIZE: u64 = 100;
+
+#[derive(Deserialize, CandidType, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Approve,
+    Remove,
+    Warn,
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub enum AuditEvent {
+    ReportResolved {
+        item_id: u64,
+        resolution: Resolution,
+        report_count: u64,
+    },
+    AuctionExtended {
+        item_id: u64,
+        previous_result_date: u64,
+        result_date: u64,
+        admin_override: bool, //bidding had already started
+    },
+    PromoCodeRedeemed {
+        code: String,
+        item_id: u64,
+    },
+    CloseTimerCancelled {
+        item_id: u64,
+        scheduled_for: u64,
+        reason: EarlyClose,
+    },
+    DisplayNameReclaimed {
+        name: String,
+        holder: Option<Principal>, //none when the name was only blocked
+        blocked: bool,
+    },
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct AuditEntry {
+    seq: u64,
+    timestamp: u64,
+    actor: Principal,
+    event: AuditEvent,
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AuditEntry {
+    const MAX_SIZE: u32 = 1_000;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    //append-only, keyed by a sequence number
+    static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditEntry, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))));
+}
+
+pub fn record(event: AuditEvent) {
+    AUDIT_LOG.with(|log| {
+        let seq = log.borrow().last_key_value().map_or(0, |(k, _)| k + 1);
+        let entry = AuditEntry {
+            seq,
+            timestamp: time(),
+            actor: caller(),
+            event,
+        };
+        log.borrow_mut().insert(seq, entry);
+    });
+}
+
+#[query(name = "getAuditLog")]
+fn get_audit_log(from_seq: u64, limit: u64) -> Result<Vec<AuditEntry>, String> {
+    admin::ensure_admin()?;
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    Ok(AUDIT_LOG.with(|log| {
+        log.borrow()
+            .range(from_seq..)
+            .take(limit)
+            .map(|(_, v)| v)
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    #[test]
+    fn the_largest_entries_fit() {
+        let events = [
+            AuditEvent::AuctionExtended {
+                item_id: u64::MAX,
+                previous_result_date: u64::MAX,
+                result_date: u64::MAX,
+                admin_override: true,
+            },
+            AuditEvent::PromoCodeRedeemed {
+                code: "x".repeat(32),
+                item_id: u64::MAX,
+            },
+            AuditEvent::DisplayNameReclaimed {
+                name: "x".repeat(24),
+                holder: Some(principal(2)),
+                blocked: true,
+            },
+        ];
+        for event in events {
+            assert_fits(&AuditEntry {
+                seq: u64::MAX,
+                timestamp: u64::MAX,
+                actor: principal(1),
+                event,
+            });
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_cdk::{api::data_certificate, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, StableCell, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    admin, balances, bid_store, budget, certification, clock::time, Memory, MEMORY_MANAGER,
+};
+
+const MANIFEST_VERSION: u64 = 1;
+
+//how each section hash was computed, so an auditor can recompute it from an export of the state
+const ITEMS_METHOD: &str =
+    "sha256 over sha256(candid(item with an empty bid_vector)) of every item in id order";
+const BIDS_METHOD: &str =
+    "sha256 over sha256(candid(bid)) of every bid, items in id order and bids in the order they were placed";
+const BALANCES_METHOD: &str = "sha256 over every cycle balance as (principal length, principal, u128 big-endian), then every token balance as (owner, ledger, u128 big-endian) with both principals prefixed the same way, in key order";
+const ROOT_METHOD: &str = "sha256 of the items, bids and balances hashes concatenated";
+
+#[derive(Deserialize, CandidType, Clone)]
+struct SectionHash {
+    hash: Vec<u8>,
+    entries: u64,
+    method: String,
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+struct AuditSnapshot {
+    version: u64,
+    taken_at: u64,
+    items: SectionHash,
+    bids: SectionHash,
+    balances: SectionHash,
+    root: Vec<u8>, //what gets certified, under the "snapshot" label of the certified tree
+    root_method: String,
+}
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct LatestSnapshot {
+    snapshot: Option<AuditSnapshot>,
+}
+
+impl Storable for LatestSnapshot {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(CandidType)]
+struct AuditSnapshotExport {
+    manifest: AuditSnapshot,
+    certificate: Option<Vec<u8>>, //only set when called as a query
+    witness: Vec<u8>,             //cbor hash tree with the path ["snapshot"]
+}
+
+thread_local! {
+    static LATEST_SNAPSHOT: RefCell<StableCell<LatestSnapshot, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(76))),
+        LatestSnapshot::default()).unwrap());
+}
+
+/// The root of the latest snapshot, certified next to the items.
+pub fn certified_root() -> Option<[u8; 32]> {
+    LATEST_SNAPSHOT
+        .with(|c| c.borrow().get().snapshot.clone())
+        .and_then(|s| s.root.try_into().ok())
+}
+
+fn section(hasher: Sha256, entries: u64, method: &str) -> SectionHash {
+    SectionHash {
+        hash: hasher.finalize().to_vec(),
+        entries,
+        method: method.to_string(),
+    }
+}
+
+//the part of a snapshot hashed so far, kept on the heap between the messages it takes
+struct SnapshotJob {
+    items_hasher: Sha256,
+    bids_hasher: Sha256,
+    item_count: u64,
+    bid_count: u64,
+    next_id: u64,
+    started_at: u64,
+}
+
+thread_local! {
+    static JOB: RefCell<Option<SnapshotJob>> = const { RefCell::new(None) };
+}
+
+//continues the running job until the items are hashed or the budget runs out
+fn continue_snapshot() {
+    let Some(mut job) = JOB.with(|j| j.borrow_mut().take()) else {
+        return;
+    };
+    let rest = budget::scan_items(job.next_id, |item| {
+        for bid in bid_store::of(item.id) {
+            job.bids_hasher
+                .update(Sha256::digest(Encode!(&bid).unwrap()));
+            job.bid_count += 1;
+        }
+        job.items_hasher
+            .update(Sha256::digest(Encode!(&item).unwrap()));
+        job.item_count += 1;
+    });
+    if let Some(next) = rest {
+        job.next_id = next;
+        JOB.with(|j| *j.borrow_mut() = Some(job));
+        budget::resume(continue_snapshot);
+        return;
+    }
+    finish_snapshot(job);
+}
+
+fn finish_snapshot(job: SnapshotJob) {
+    let mut balances_hasher = Sha256::new();
+    let balance_count = balances::hash_into(&mut balances_hasher);
+
+    let items = section(job.items_hasher, job.item_count, ITEMS_METHOD);
+    let bids = section(job.bids_hasher, job.bid_count, BIDS_METHOD);
+    let balances = section(balances_hasher, balance_count, BALANCES_METHOD);
+    let root = Sha256::new()
+        .chain_update(&items.hash)
+        .chain_update(&bids.hash)
+        .chain_update(&balances.hash)
+        .finalize()
+        .to_vec();
+    let snapshot = AuditSnapshot {
+        version: MANIFEST_VERSION,
+        taken_at: job.started_at,
+        items,
+        bids,
+        balances,
+        root,
+        root_method: ROOT_METHOD.to_string(),
+    };
+    LATEST_SNAPSHOT.with(|c| {
+        c.borrow_mut()
+            .set(LatestSnapshot {
+                snapshot: Some(snapshot),
+            })
+            .unwrap()
+    });
+    certification::certify();
+}
+
+//on a large marketplace the items are hashed over several messages, and writes between them
+//land in the snapshot or not depending on whether their item was hashed yet
+#[update(name = "takeAuditSnapshot")]
+fn take_audit_snapshot() -> Result<String, String> {
+    admin::ensure_admin()?;
+    if JOB.with(|j| j.borrow().is_some()) {
+        return Err("An audit snapshot is already being taken.".to_string());
+    }
+    JOB.with(|j| {
+        *j.borrow_mut() = Some(SnapshotJob {
+            items_hasher: Sha256::new(),
+            bids_hasher: Sha256::new(),
+            item_count: 0,
+            bid_count: 0,
+            next_id: 0,
+            started_at: time(),
+        })
+    });
+    continue_snapshot();
+    Ok(match JOB.with(|j| j.borrow().is_some()) {
+        true => {
+            "The audit snapshot is being taken, read it with getAuditSnapshot once done".to_string()
+        }
+        false => "Audit snapshot taken".to_string(),
+    })
+}
+
+#[query(name = "getAuditSnapshot")]
+fn get_audit_snapshot() -> Option<AuditSnapshotExport> {
+    let manifest = LATEST_SNAPSHOT.with(|c| c.borrow().get().snapshot.clone())?;
+    Some(AuditSnapshotExport {
+        manifest,
+        certificate: data_certificate(),
+        witness: certification::snapshot_witness(),
+    })
+}
@@ -0,0 +1,127 @@
+use candid::{CandidType, Principal};
+use ic_cdk::{caller, query};
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+use crate::{
+    cycle_bids, exposure, ledgers, withdrawals, Memory, StorablePrincipal, MEMORY_MANAGER,
+};
+
+//one per currency, ledger is not set for cycles
+#[derive(CandidType)]
+struct Position {
+    ledger: Option<Principal>,
+    symbol: String,
+    available: u128,
+    locked_in_bids: u128,    //what the caller's leading bids take up
+    claimable_refunds: u128, //debited for a withdrawal that is still pending, see retryWithdrawal
+}
+
+thread_local! {
+    //cycles owed to each principal, e.g. released escrow for sellers and refunds for buyers
+    static BALANCE_MAP: RefCell<StableBTreeMap<StorablePrincipal, u128, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))));
+
+    //token balances held in the canister's main account on each ledger, keyed by (owner, ledger)
+    static TOKEN_BALANCE_MAP: RefCell<StableBTreeMap<(StorablePrincipal, StorablePrincipal), u128, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))));
+}
+
+pub fn balance_of(p: Principal) -> u128 {
+    BALANCE_MAP.with(|b| b.borrow().get(&p.into()).unwrap_or_default())
+}
+
+pub fn total_cycles() -> u128 {
+    BALANCE_MAP.with(|b| b.borrow().iter().map(|(_, amount)| amount).sum())
+}
+
+pub fn credit(p: Principal, amount: u128) {
+    let new_balance = balance_of(p) + amount;
+    BALANCE_MAP.with(|b| b.borrow_mut().insert(p.into(), new_balance));
+}
+
+/// Takes `amount` off a cycles balance, or returns the balance when it is not enough.
+pub fn debit(p: Principal, amount: u128) -> Result<(), u128> {
+    let balance = balance_of(p);
+    if balance < amount {
+        return Err(balance);
+    }
+    BALANCE_MAP.with(|b| b.borrow_mut().insert(p.into(), balance - amount));
+    Ok(())
+}
+
+pub fn token_balance_of(p: Principal, ledger: Principal) -> u128 {
+    TOKEN_BALANCE_MAP.with(|b| {
+        b.borrow()
+            .get(&(p.into(), ledger.into()))
+            .unwrap_or_default()
+    })
+}
+
+pub fn credit_token(p: Principal, ledger: Principal, amount: u128) {
+    let new_balance = token_balance_of(p, ledger) + amount;
+    TOKEN_BALANCE_MAP.with(|b| {
+        b.borrow_mut()
+            .insert((p.into(), ledger.into()), new_balance)
+    });
+}
+
+//principals differ in length, the prefix keeps two different balance lists from hashing the same
+fn hash_principal(hasher: &mut Sha256, p: Principal) {
+    hasher.update([p.as_slice().len() as u8]);
+    hasher.update(p.as_slice());
+}
+
+/// Feeds every cycle and token balance into `hasher` in key order, returns how many there were.
+pub fn hash_into(hasher: &mut Sha256) -> u64 {
+    let cycles = BALANCE_MAP.with(|b| {
+        let b = b.borrow();
+        for (p, amount) in b.iter() {
+            hash_principal(hasher, p.into());
+            hasher.update(amount.to_be_bytes());
+        }
+        b.len()
+    });
+    let tokens = TOKEN_BALANCE_MAP.with(|b| {
+        let b = b.borrow();
+        for ((p, ledger), amount) in b.iter() {
+            hash_principal(hasher, p.into());
+            hash_principal(hasher, ledger.into());
+            hasher.update(amount.to_be_bytes());
+        }
+        b.len()
+    });
+    cycles + tokens
+}
+
+/// Takes `amount` off a token balance, or returns the balance when it is not enough.
+pub fn debit_token(p: Principal, ledger: Principal, amount: u128) -> Result<(), u128> {
+    let balance = token_balance_of(p, ledger);
+    if balance < amount {
+        return Err(balance);
+    }
+    TOKEN_BALANCE_MAP.with(|b| {
+        b.borrow_mut()
+            .insert((p.into(), ledger.into()), balance - amount)
+    });
+    Ok(())
+}
+
+#[query(name = "getBalance")]
+fn get_balance() -> Vec<Position> {
+    let p = caller();
+    let cycles = Position {
+        ledger: None,
+        symbol: "cycles".to_string(),
+        available: balance_of(p),
+        locked_in_bids: cycle_bids::held_by(p),
+        claimable_refunds: 0,
+    };
+    let tokens = ledgers::supported().into_iter().map(|info| Position {
+        ledger: Some(info.ledger),
+        symbol: info.symbol,
+        available: token_balance_of(p, info.ledger),
+        locked_in_bids: exposure::locked(p, Some(info.ledger), None),
+        claimable_refunds: withdrawals::pending_amount(p, info.ledger),
+    });
+    std::iter::once(cycles).chain(tokens).collect()
+}
@@ -0,0 +1,323 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::{
+    memory_manager::MemoryId, BoundedStorable, StableBTreeMap, StableCell, Storable,
+};
+use std::{borrow::Cow, cell::RefCell, fmt};
+
+use crate::{
+    admin, bid_store, clock::time, Item, Memory, StorablePrincipal, ITEM_MAP, MEMORY_MANAGER,
+};
+
+//a windowed limit needs the times of the bidder's last `max_bids_per_item` bids, so it is capped
+const MAX_WINDOWED_LIMIT: u32 = 50;
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct BidLimits {
+    max_bids_per_item: Option<u32>, //per bidder, no limit when not set
+    window: Option<u64>, //nanoseconds the limit counts bids over, the whole auction when not set
+    cooldown: Option<u64>, //nanoseconds a bidder has to wait between their bids on the same item
+}
+
+impl Storable for BidLimits {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//one bidder's bids on one item, so a check does not have to read the item's bid history
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct BidderActivity {
+    bids: u64,
+    recent: Vec<u64>, //placed_at of the latest bids, oldest first, at most MAX_WINDOWED_LIMIT of them
+}
+
+impl Storable for BidderActivity {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//a full `recent` takes 432 bytes
+impl BoundedStorable for BidderActivity {
+    const MAX_SIZE: u32 = 600;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BidLimitError {
+    TooManyBids {
+        limit: u32,
+        resets_at: Option<u64>,
+    },
+    CoolingDown {
+        cooldown: u64,
+        retry_at: u64,
+        remaining: Option<u32>, //bids the bidder has left on the item after it, none without a limit
+    },
+}
+
+impl fmt::Display for BidLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BidLimitError::TooManyBids {
+                limit,
+                resets_at: Some(t),
+            } => write!(
+                f,
+                "You can place {} bids on this item at a time, you can bid again at {}.",
+                limit, t
+            ),
+            BidLimitError::TooManyBids {
+                limit,
+                resets_at: None,
+            } => write!(f, "You can place at most {} bids on this item.", limit),
+            BidLimitError::CoolingDown {
+                cooldown, retry_at, ..
+            } => write!(
+                f,
+                "Bids on the same item have to be {} seconds apart, you can bid again at {}.",
+                cooldown / 1_000_000_000,
+                retry_at
+            ),
+        }
+    }
+}
+
+impl From<BidLimitError> for String {
+    fn from(e: BidLimitError) -> Self {
+        e.to_string()
+    }
+}
+
+thread_local! {
+    static BID_LIMITS: RefCell<StableCell<BidLimits, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49))),
+        BidLimits::default()).unwrap());
+
+    static ACTIVITY: RefCell<StableBTreeMap<(u64, StorablePrincipal), BidderActivity, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(102)))));
+}
+
+fn limits() -> BidLimits {
+    BID_LIMITS.with(|l| l.borrow().get().clone())
+}
+
+fn activity_of(item_id: u64, bidder: Principal) -> BidderActivity {
+    ACTIVITY
+        .with(|a| a.borrow().get(&(item_id, bidder.into())))
+        .unwrap_or_default()
+}
+
+fn count_bid(activity: &mut BidderActivity, placed_at: Option<u64>) {
+    activity.bids += 1;
+    if let Some(t) = placed_at {
+        activity.recent.push(t);
+        let extra = activity
+            .recent
+            .len()
+            .saturating_sub(MAX_WINDOWED_LIMIT as usize);
+        activity.recent.drain(..extra);
+    }
+}
+
+/// Counts a bid that was just placed.
+pub fn record(item_id: u64, bidder: Principal, placed_at: u64) {
+    let mut activity = activity_of(item_id, bidder);
+    count_bid(&mut activity, Some(placed_at));
+    ACTIVITY.with(|a| a.borrow_mut().insert((item_id, bidder.into()), activity));
+}
+
+/// Drops the counts of an item that is deleted or archived.
+pub fn forget(item_id: u64) {
+    let keys: Vec<(u64, StorablePrincipal)> = ACTIVITY.with(|a| {
+        a.borrow()
+            .range((item_id, StorablePrincipal::default())..)
+            .take_while(|((id, _), _)| *id == item_id)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    ACTIVITY.with(|a| {
+        for key in keys {
+            a.borrow_mut().remove(&key);
+        }
+    });
+}
+
+//counts the bids placed before the activity map existed, runs as a schema migration
+pub fn backfill() {
+    let ids: Vec<u64> = ITEM_MAP.with(|items| items.borrow().iter().map(|(id, _)| id).collect());
+    for id in ids {
+        for bid in bid_store::of(id) {
+            let key = (id, StorablePrincipal::from(bid.bidder_principal));
+            let mut activity = ACTIVITY.with(|a| a.borrow().get(&key)).unwrap_or_default();
+            count_bid(&mut activity, bid.placed_at);
+            ACTIVITY.with(|a| a.borrow_mut().insert(key, activity));
+        }
+    }
+}
+
+pub fn check(item: &Item, bidder: Principal) -> Result<(), BidLimitError> {
+    let limits = limits();
+    let now = time();
+    let activity = activity_of(item.id, bidder);
+    //bids are recorded as they are placed, the latest one is last
+    let last_bid = activity.recent.last().copied();
+    //bids from before placed_at was recorded only count towards an unwindowed limit
+    let counted: Vec<u64> = match limits.window {
+        Some(window) => activity
+            .recent
+            .iter()
+            .copied()
+            .filter(|t| t + window > now)
+            .collect(),
+        None => vec![],
+    };
+    let count = match limits.window {
+        Some(_) => counted.len() as u64,
+        None => activity.bids,
+    };
+    let remaining = limits
+        .max_bids_per_item
+        .map(|limit| (limit as u64).saturating_sub(count) as u32);
+    if let (Some(cooldown), Some(last)) = (limits.cooldown, last_bid) {
+        if last + cooldown > now {
+            return Err(BidLimitError::CoolingDown {
+                cooldown,
+                retry_at: last + cooldown,
+                remaining,
+            });
+        }
+    }
+    let (Some(limit), Some(0)) = (limits.max_bids_per_item, remaining) else {
+        return Ok(());
+    };
+    //the window frees a slot once the oldest of the last `limit` bids falls out of it
+    let resets_at = limits
+        .window
+        .map(|window| counted[counted.len() - limit as usize] + window);
+    Err(BidLimitError::TooManyBids { limit, resets_at })
+}
+
+#[update(name = "setBidLimits")]
+fn set_bid_limits(limits: BidLimits) -> Result<String, String> {
+    admin::ensure_admin()?;
+    if limits.max_bids_per_item == Some(0) {
+        return Err("The bid limit has to allow at least one bid.".to_string());
+    }
+    if limits.window.is_some() && limits.max_bids_per_item > Some(MAX_WINDOWED_LIMIT) {
+        return Err(format!(
+            "A bid limit with a window can allow at most {} bids.",
+            MAX_WINDOWED_LIMIT
+        ));
+    }
+    BID_LIMITS.with(|l| l.borrow_mut().set(limits).unwrap());
+    Ok("Bid limits updated".to_string())
+}
+
+#[query(name = "getBidLimits")]
+fn get_bid_limits() -> BidLimits {
+    limits()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock,
+        test_support::{self, assert_fits, principal, HOUR},
+    };
+
+    fn set_limits(max_bids_per_item: Option<u32>, window: Option<u64>, cooldown: Option<u64>) {
+        let limits = BidLimits {
+            max_bids_per_item,
+            window,
+            cooldown,
+        };
+        BID_LIMITS.with(|l| l.borrow_mut().set(limits).unwrap());
+    }
+
+    #[test]
+    fn a_full_activity_fits() {
+        let activity = BidderActivity {
+            bids: u64::MAX,
+            recent: vec![u64::MAX; MAX_WINDOWED_LIMIT as usize],
+        };
+        assert_fits(&activity);
+    }
+
+    #[test]
+    fn limits_count_the_bidders_own_bids() {
+        clock::set(1_000 * HOUR);
+        let item = test_support::item(1, principal(1));
+        set_limits(Some(2), None, None);
+        record(1, principal(2), clock::time());
+        record(1, principal(3), clock::time());
+        assert_eq!(check(&item, principal(2)), Ok(()));
+        record(1, principal(2), clock::time());
+        assert_eq!(
+            check(&item, principal(2)),
+            Err(BidLimitError::TooManyBids {
+                limit: 2,
+                resets_at: None
+            })
+        );
+        assert_eq!(check(&item, principal(3)), Ok(()));
+    }
+
+    #[test]
+    fn a_window_frees_slots_as_bids_age() {
+        clock::set(1_000 * HOUR);
+        let item = test_support::item(1, principal(1));
+        set_limits(Some(2), Some(HOUR), None);
+        let first = clock::time();
+        record(1, principal(2), first);
+        clock::advance(10);
+        record(1, principal(2), clock::time());
+        assert_eq!(
+            check(&item, principal(2)),
+            Err(BidLimitError::TooManyBids {
+                limit: 2,
+                resets_at: Some(first + HOUR)
+            })
+        );
+        clock::set(first + HOUR);
+        assert_eq!(check(&item, principal(2)), Ok(()));
+    }
+
+    #[test]
+    fn the_cooldown_runs_from_the_bidders_last_bid() {
+        clock::set(1_000 * HOUR);
+        let item = test_support::item(1, principal(1));
+        set_limits(Some(3), None, Some(60));
+        record(1, principal(2), clock::time());
+        clock::advance(30);
+        record(1, principal(3), clock::time());
+        assert_eq!(
+            check(&item, principal(2)),
+            Err(BidLimitError::CoolingDown {
+                cooldown: 60,
+                retry_at: 1_000 * HOUR + 60,
+                remaining: Some(2)
+            })
+        );
+        clock::advance(30);
+        assert_eq!(check(&item, principal(2)), Ok(()));
+    }
+
+    #[test]
+    fn forgotten_items_start_over() {
+        let item = test_support::item(1, principal(1));
+        set_limits(Some(1), None, None);
+        record(1, principal(2), 0);
+        forget(1);
+        assert_eq!(check(&item, principal(2)), Ok(()));
+    }
+}
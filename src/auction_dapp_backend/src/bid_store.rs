@@ -0,0 +1,121 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{Bid, Item, Memory, ITEM_MAP, MEMORY_MANAGER};
+
+//bids live next to the items instead of inside them, so a bid only writes itself and not the whole bid history.
+//Item::bid_vector is kept for the interface and is always empty in ITEM_MAP
+
+impl Storable for Bid {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Bid {
+    const MAX_SIZE: u32 = 500;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    //(item id, position in the item's history) -> bid
+    static BID_MAP: RefCell<StableBTreeMap<(u64, u64), Bid, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(80)))));
+
+    //item id -> number of bids stored for it, the position the next one goes to
+    static BID_COUNTS: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(81)))));
+}
+
+pub fn count(item_id: u64) -> u64 {
+    BID_COUNTS.with(|c| c.borrow().get(&item_id).unwrap_or(0))
+}
+
+pub fn has_bids(item_id: u64) -> bool {
+    count(item_id) > 0
+}
+
+/// The item's bids in the order they were placed.
+pub fn of(item_id: u64) -> Vec<Bid> {
+    page(item_id, 0, usize::MAX)
+}
+
+pub fn page(item_id: u64, from_index: u64, limit: usize) -> Vec<Bid> {
+    BID_MAP.with(|b| {
+        b.borrow()
+            .range((item_id, from_index)..)
+            .take_while(|((id, _), _)| *id == item_id)
+            .take(limit)
+            .map(|(_, bid)| bid)
+            .collect()
+    })
+}
+
+pub fn last(item_id: u64) -> Option<Bid> {
+    let count = count(item_id);
+    if count == 0 {
+        return None;
+    }
+    BID_MAP.with(|b| b.borrow().get(&(item_id, count - 1)))
+}
+
+pub fn push(bid: Bid) {
+    let item_id = bid.item_id;
+    let position = count(item_id);
+    BID_MAP.with(|b| b.borrow_mut().insert((item_id, position), bid));
+    BID_COUNTS.with(|c| c.borrow_mut().insert(item_id, position + 1));
+}
+
+/// Replaces the item's history, e.g. with the bids compaction keeps.
+pub fn replace(item_id: u64, bids: Vec<Bid>) {
+    remove_all(item_id);
+    for bid in bids {
+        push(bid);
+    }
+}
+
+pub fn remove_all(item_id: u64) {
+    let count = count(item_id);
+    BID_MAP.with(|b| {
+        let mut b = b.borrow_mut();
+        for position in 0..count {
+            b.remove(&(item_id, position));
+        }
+    });
+    BID_COUNTS.with(|c| c.borrow_mut().remove(&item_id));
+}
+
+/// Fills in the bid vector of an item read from ITEM_MAP, for the few places that hand out whole items.
+pub fn attach(mut item: Item) -> Item {
+    item.bid_vector = of(item.id);
+    item
+}
+
+//migration: moves the bids stored inside every item into BID_MAP
+pub fn move_out_of_items() {
+    let items: Vec<(u64, Item)> = ITEM_MAP.with(|items| items.borrow().iter().collect());
+    for (id, mut item) in items {
+        if item.bid_vector.is_empty() {
+            continue;
+        }
+        replace(id, std::mem::take(&mut item.bid_vector));
+        ITEM_MAP.with(|m| m.borrow_mut().insert(id, item));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{assert_fits, bid, principal};
+
+    #[test]
+    fn a_bid_with_the_longest_memo_fits() {
+        let mut bid = bid(u64::MAX, principal(1), u64::MAX, u64::MAX);
+        bid.bid_date = u64::MAX;
+        bid.placed_at = Some(u64::MAX);
+        bid.memo = Some("\u{1F600}".repeat(crate::MAX_MEMO_LENGTH));
+        assert_fits(&bid);
+    }
+}
@@ -0,0 +1,497 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Func, Int, Nat, Principal};
+use ic_cdk::{caller, query};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap};
+
+use crate::{admin, clock::time, Memory, StorablePrincipal, MEMORY_MANAGER};
+
+const MAX_BLOCKS_PER_REQUEST: u64 = 1_000;
+const HISTORY_PAGE_SIZE: usize = 50;
+
+//the ICRC-3 generic value, blocks are served in this shape
+#[derive(CandidType, Deserialize, Clone)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl Value {
+    //representation-independent hash, as specified by ICRC-3
+    fn hash(&self) -> Vec<u8> {
+        match self {
+            Value::Blob(b) => Sha256::digest(b).to_vec(),
+            Value::Text(t) => Sha256::digest(t.as_bytes()).to_vec(),
+            Value::Nat(n) => {
+                let mut buf = vec![];
+                n.encode(&mut buf).unwrap();
+                Sha256::digest(&buf).to_vec()
+            }
+            Value::Int(i) => {
+                let mut buf = vec![];
+                i.encode(&mut buf).unwrap();
+                Sha256::digest(&buf).to_vec()
+            }
+            Value::Array(values) => {
+                let mut hasher = Sha256::new();
+                for v in values {
+                    hasher.update(v.hash());
+                }
+                hasher.finalize().to_vec()
+            }
+            Value::Map(entries) => {
+                let mut pairs: Vec<Vec<u8>> = entries
+                    .iter()
+                    .map(|(k, v)| [Value::Text(k.clone()).hash(), v.hash()].concat())
+                    .collect();
+                pairs.sort();
+                let mut hasher = Sha256::new();
+                for pair in pairs {
+                    hasher.update(pair);
+                }
+                hasher.finalize().to_vec()
+            }
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum FinancialOp {
+    Deposit,            //buyer pays into escrow
+    Payout,             //escrow released to the seller
+    Refund,             //escrow returned to the buyer
+    Fee,                //marketplace fees, e.g. promotions
+    Reward,             //paid out of collected fees, e.g. to referrers
+    TreasuryWithdrawal, //collected fees sent out of the canister by admins
+    Withdrawal,         //a user's balance sent out of the canister
+}
+
+impl FinancialOp {
+    fn btype(&self) -> &'static str {
+        match self {
+            FinancialOp::Deposit => "auction_deposit",
+            FinancialOp::Payout => "auction_payout",
+            FinancialOp::Refund => "auction_refund",
+            FinancialOp::Fee => "auction_fee",
+            FinancialOp::Reward => "auction_reward",
+            FinancialOp::TreasuryWithdrawal => "auction_treasury_withdrawal",
+            FinancialOp::Withdrawal => "auction_withdrawal",
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct FinancialBlock {
+    pub op: FinancialOp,
+    pub amount: u128, //in cycles, or in the smallest unit of `ledger`'s token
+    pub from: Option<Principal>,
+    pub to: Option<Principal>,
+    pub item_id: Option<u64>,
+    pub timestamp: u64,
+    phash: Option<Vec<u8>>, //hash of the previous block, absent for the first one
+    pub ledger: Option<Principal>, //the token ledger, not set for cycles
+}
+
+impl FinancialBlock {
+    fn to_value(&self) -> Value {
+        let account = |p: &Principal| Value::Array(vec![Value::Blob(p.as_slice().to_vec())]);
+        let mut tx = vec![("amt".to_string(), Value::Nat(Nat::from(self.amount)))];
+        if let Some(from) = &self.from {
+            tx.push(("from".to_string(), account(from)));
+        }
+        if let Some(to) = &self.to {
+            tx.push(("to".to_string(), account(to)));
+        }
+        if let Some(item_id) = self.item_id {
+            tx.push(("item".to_string(), Value::Nat(Nat::from(item_id))));
+        }
+        if let Some(ledger) = &self.ledger {
+            tx.push((
+                "ledger".to_string(),
+                Value::Blob(ledger.as_slice().to_vec()),
+            ));
+        }
+
+        let mut block = vec![
+            (
+                "btype".to_string(),
+                Value::Text(self.op.btype().to_string()),
+            ),
+            ("ts".to_string(), Value::Nat(Nat::from(self.timestamp))),
+            ("tx".to_string(), Value::Map(tx)),
+        ];
+        if let Some(phash) = &self.phash {
+            block.push(("phash".to_string(), Value::Blob(phash.clone())));
+        }
+        Value::Map(block)
+    }
+}
+
+impl Storable for FinancialBlock {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for FinancialBlock {
+    const MAX_SIZE: u32 = 300;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType)]
+struct Transaction {
+    block_index: u64,
+    op: FinancialOp,
+    amount: u128,
+    from: Option<Principal>,
+    to: Option<Principal>,
+    item_id: Option<u64>,
+    timestamp: u64,
+    ledger: Option<Principal>,
+}
+
+#[derive(CandidType)]
+struct TransactionHistory {
+    transactions: Vec<Transaction>,
+    has_more: bool,
+}
+
+//also used to read blocks from the token ledgers, see icrc::get_block
+#[derive(CandidType, Deserialize)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: Func,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>, //always empty for this canister's own log
+}
+
+#[derive(CandidType)]
+struct BlockType {
+    block_type: String,
+    url: String,
+}
+
+thread_local! {
+    static BLOCK_LOG: RefCell<StableBTreeMap<u64, FinancialBlock, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))));
+
+    //(principal, block index) for every block a principal sent or received value in
+    static ACCOUNT_INDEX: RefCell<StableBTreeMap<(StorablePrincipal, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))));
+
+    //the treasury balance per ledger, kept up to date as blocks are appended. cycles are under the default principal
+    static TREASURY: RefCell<StableBTreeMap<StorablePrincipal, u128, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(105)))));
+}
+
+/// Appends a movement of cycles to the block log, chained to the previous block.
+pub fn append(
+    op: FinancialOp,
+    amount: u128,
+    from: Option<Principal>,
+    to: Option<Principal>,
+    item_id: Option<u64>,
+) -> u64 {
+    push(op, None, amount, from, to, item_id)
+}
+
+/// Appends a movement of `ledger`'s tokens to the block log.
+pub fn append_token(
+    op: FinancialOp,
+    ledger: Principal,
+    amount: u128,
+    from: Option<Principal>,
+    to: Option<Principal>,
+    item_id: Option<u64>,
+) -> u64 {
+    push(op, Some(ledger), amount, from, to, item_id)
+}
+
+fn push(
+    op: FinancialOp,
+    ledger: Option<Principal>,
+    amount: u128,
+    from: Option<Principal>,
+    to: Option<Principal>,
+    item_id: Option<u64>,
+) -> u64 {
+    BLOCK_LOG.with(|log| {
+        let last = log.borrow().last_key_value();
+        let index = last.as_ref().map_or(0, |(k, _)| k + 1);
+        let block = FinancialBlock {
+            op,
+            amount,
+            from,
+            to,
+            item_id,
+            timestamp: time(),
+            phash: last.map(|(_, b)| b.to_value().hash()),
+            ledger,
+        };
+        for party in [from, to].into_iter().flatten() {
+            ACCOUNT_INDEX.with(|idx| idx.borrow_mut().insert((party.into(), index), ()));
+        }
+        credit_treasury(&block);
+        log.borrow_mut().insert(index, block);
+        index
+    })
+}
+
+fn treasury_key(ledger: Option<Principal>) -> StorablePrincipal {
+    ledger.map(StorablePrincipal::from).unwrap_or_default()
+}
+
+//what the block moves into (or out of) the treasury
+fn treasury_change(balance: u128, block: &FinancialBlock) -> u128 {
+    match block.op {
+        FinancialOp::Fee => balance.saturating_add(block.amount),
+        FinancialOp::Reward | FinancialOp::TreasuryWithdrawal => {
+            balance.saturating_sub(block.amount)
+        }
+        _ => balance,
+    }
+}
+
+fn credit_treasury(block: &FinancialBlock) {
+    let key = treasury_key(block.ledger);
+    TREASURY.with(|t| {
+        let balance = t.borrow().get(&key).unwrap_or(0);
+        let updated = treasury_change(balance, block);
+        if updated != balance {
+            t.borrow_mut().insert(key, updated);
+        }
+    });
+}
+
+/// The treasury balance in every token ledger that has collected fees, cycles are left out.
+pub fn token_treasuries() -> Vec<(Principal, u128)> {
+    TREASURY.with(|t| {
+        t.borrow()
+            .iter()
+            .filter(|(ledger, _)| *ledger != StorablePrincipal::default())
+            .map(|(ledger, balance)| (ledger.into(), balance))
+            .collect()
+    })
+}
+
+/// The collected fees of one unit still in the canister, after rewards and treasury withdrawals.
+/// `ledger` is not set for cycles.
+pub fn treasury_balance(ledger: Option<Principal>) -> u128 {
+    TREASURY.with(|t| t.borrow().get(&treasury_key(ledger)).unwrap_or(0))
+}
+
+/// The ledgers whose running treasury balance differs from what the whole block log adds up to,
+/// with the replayed and the running balance. Only the integrity check replays the log.
+pub fn treasury_mismatches() -> Vec<(Option<Principal>, u128, u128)> {
+    let mut balances: BTreeMap<StorablePrincipal, (u128, u128)> = replay_treasury()
+        .into_iter()
+        .map(|(ledger, replayed)| (ledger, (replayed, 0)))
+        .collect();
+    TREASURY.with(|t| {
+        for (ledger, running) in t.borrow().iter() {
+            balances.entry(ledger).or_default().1 = running;
+        }
+    });
+    balances
+        .into_iter()
+        .filter(|(_, (replayed, running))| replayed != running)
+        .map(|(ledger, (replayed, running))| {
+            let ledger = (ledger != StorablePrincipal::default()).then(|| ledger.into());
+            (ledger, replayed, running)
+        })
+        .collect()
+}
+
+fn replay_treasury() -> BTreeMap<StorablePrincipal, u128> {
+    let mut balances = BTreeMap::new();
+    BLOCK_LOG.with(|log| {
+        for (_, block) in log.borrow().iter() {
+            let balance = balances.entry(treasury_key(block.ledger)).or_insert(0);
+            *balance = treasury_change(*balance, &block);
+        }
+    });
+    balances
+}
+
+//the running balances start from the log that was kept before them, runs as a schema migration
+pub fn backfill_treasury() {
+    for (ledger, balance) in replay_treasury() {
+        TREASURY.with(|t| t.borrow_mut().insert(ledger, balance));
+    }
+}
+
+fn to_u64(n: &Nat) -> u64 {
+    u64::try_from(n.0.clone()).unwrap_or(u64::MAX)
+}
+
+//newest first, users can only see their own history
+#[query(name = "getTransactionHistory")]
+fn get_transaction_history(account: Principal, page: u64) -> Result<TransactionHistory, String> {
+    if account != caller() && !admin::is_admin(caller()) {
+        return Err("You can only view your own transaction history.".to_string());
+    }
+    let account = StorablePrincipal::from(account);
+    let skip = (page as usize).saturating_mul(HISTORY_PAGE_SIZE);
+    let indexes: Vec<u64> = ACCOUNT_INDEX.with(|idx| {
+        idx.borrow()
+            .range((account, 0)..=(account, u64::MAX))
+            .map(|((_, i), _)| i)
+            .collect()
+    });
+
+    let transactions = BLOCK_LOG.with(|log| {
+        let log = log.borrow();
+        indexes
+            .iter()
+            .rev()
+            .skip(skip)
+            .take(HISTORY_PAGE_SIZE)
+            .filter_map(|&i| {
+                let block = log.get(&i)?;
+                Some(Transaction {
+                    block_index: i,
+                    op: block.op,
+                    amount: block.amount,
+                    from: block.from,
+                    to: block.to,
+                    item_id: block.item_id,
+                    timestamp: block.timestamp,
+                    ledger: block.ledger,
+                })
+            })
+            .collect()
+    });
+    Ok(TransactionHistory {
+        transactions,
+        has_more: indexes.len() > skip.saturating_add(HISTORY_PAGE_SIZE),
+    })
+}
+
+#[query]
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    let mut budget = MAX_BLOCKS_PER_REQUEST;
+    let mut blocks = vec![];
+    BLOCK_LOG.with(|log| {
+        for arg in args {
+            let start = to_u64(&arg.start);
+            let length = to_u64(&arg.length).min(budget);
+            budget -= length;
+            for (id, block) in log.borrow().range(start..start.saturating_add(length)) {
+                blocks.push(BlockWithId {
+                    id: Nat::from(id),
+                    block: block.to_value(),
+                });
+            }
+        }
+    });
+    GetBlocksResult {
+        log_length: Nat::from(BLOCK_LOG.with(|log| log.borrow().len())),
+        blocks,
+        archived_blocks: vec![],
+    }
+}
+
+#[query]
+fn icrc3_get_archives(_: Option<Principal>) -> Vec<Principal> {
+    vec![]
+}
+
+#[query]
+fn icrc3_supported_block_types() -> Vec<BlockType> {
+    [
+        FinancialOp::Deposit,
+        FinancialOp::Payout,
+        FinancialOp::Refund,
+        FinancialOp::Fee,
+        FinancialOp::Reward,
+        FinancialOp::TreasuryWithdrawal,
+        FinancialOp::Withdrawal,
+    ]
+    .iter()
+    .map(|op| BlockType {
+        block_type: op.btype().to_string(),
+        url: "https://github.com/denizcan-yilmaz/Auction_Dapp".to_string(),
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    #[test]
+    fn the_largest_block_fits() {
+        assert_fits(&FinancialBlock {
+            op: FinancialOp::TreasuryWithdrawal,
+            amount: u128::MAX,
+            from: Some(principal(1)),
+            to: Some(principal(2)),
+            item_id: Some(u64::MAX),
+            timestamp: u64::MAX,
+            phash: Some(vec![0xff; 32]),
+            ledger: Some(principal(9)),
+        });
+    }
+
+    #[test]
+    fn the_treasury_balance_follows_each_ledger() {
+        let ledger = principal(9);
+        append(FinancialOp::Fee, 100, None, None, Some(1));
+        append(FinancialOp::Deposit, 500, Some(principal(1)), None, Some(1));
+        append(FinancialOp::Reward, 30, None, Some(principal(2)), None);
+        append_token(FinancialOp::Fee, ledger, 1_000, None, None, Some(2));
+        append_token(
+            FinancialOp::TreasuryWithdrawal,
+            ledger,
+            400,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(treasury_balance(None), 70);
+        assert_eq!(treasury_balance(Some(ledger)), 600);
+        assert_eq!(treasury_balance(Some(principal(8))), 0);
+        assert!(treasury_mismatches().is_empty());
+    }
+
+    #[test]
+    fn the_backfill_replays_the_log_once() {
+        append(FinancialOp::Fee, 100, None, None, Some(1));
+        append_token(FinancialOp::Fee, principal(9), 50, None, None, Some(2));
+        //as if the blocks were appended before the running balances existed
+        for ledger in [None, Some(principal(9))] {
+            TREASURY.with(|t| t.borrow_mut().remove(&treasury_key(ledger)));
+        }
+        assert_eq!(
+            treasury_mismatches(),
+            vec![(None, 100, 0), (Some(principal(9)), 50, 0)]
+        );
+        backfill_treasury();
+        assert_eq!(treasury_balance(None), 100);
+        assert_eq!(treasury_balance(Some(principal(9))), 50);
+        assert!(treasury_mismatches().is_empty());
+    }
+}
@@ -0,0 +1,109 @@
+use candid::Principal;
+use ic_cdk::api::call::{msg_cycles_accept128, msg_cycles_available128};
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap};
+use std::cell::RefCell;
+
+use crate::{
+    balances,
+    blocks::{self, FinancialOp},
+    cycle_bids::PaymentMode,
+    Item, Memory, StorablePrincipal, MEMORY_MANAGER,
+};
+
+thread_local! {
+    //cycles each bidder put up with their first bid, keyed by (item id, bidder)
+    static BOND_MAP: RefCell<StableBTreeMap<(u64, StorablePrincipal), u128, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))));
+}
+
+pub fn held_total() -> u128 {
+    BOND_MAP.with(|b| b.borrow().iter().map(|(_, amount)| amount).sum())
+}
+
+//bonds only back escrow sales, the other payment modes collect the price with the bid
+pub fn validate(bond: Option<u64>, mode: Option<PaymentMode>) -> Result<Option<u64>, String> {
+    match (bond, mode) {
+        (Some(0), _) | (None, _) => Ok(None),
+        (Some(b), None | Some(PaymentMode::Escrow)) => Ok(Some(b)),
+        _ => Err("Bid bonds are only available for items paid through escrow.".to_string()),
+    }
+}
+
+/// The bond the bidder still has to attach, none once they put it up.
+pub fn due(item: &Item, bidder: Principal) -> u128 {
+    let Some(bond) = item.bid_bond else {
+        return 0;
+    };
+    let key = (item.id, StorablePrincipal::from(bidder));
+    match BOND_MAP.with(|b| b.borrow().contains_key(&key)) {
+        true => 0,
+        false => bond as u128,
+    }
+}
+
+/// Accepts the item's bond from the attached cycles, unless the bidder already put it up.
+pub fn take(item: &Item, bidder: Principal) -> Result<(), String> {
+    let bond = due(item, bidder);
+    if bond == 0 {
+        return Ok(());
+    }
+    let key = (item.id, StorablePrincipal::from(bidder));
+    if msg_cycles_available128() < bond {
+        return Err(format!(
+            "Attach a bond of {} cycles with your first bid on this item.",
+            bond
+        ));
+    }
+    msg_cycles_accept128(bond);
+    BOND_MAP.with(|b| b.borrow_mut().insert(key, bond));
+    blocks::append(
+        FinancialOp::Deposit,
+        bond,
+        Some(bidder),
+        None,
+        Some(item.id),
+    );
+    Ok(())
+}
+
+/// Gives a bidder's bond back to their balance.
+pub fn release(item_id: u64, bidder: Principal) {
+    if let Some(amount) = BOND_MAP.with(|b| b.borrow_mut().remove(&(item_id, bidder.into()))) {
+        balances::credit(bidder, amount);
+        blocks::append(
+            FinancialOp::Refund,
+            amount,
+            None,
+            Some(bidder),
+            Some(item_id),
+        );
+    }
+}
+
+//the buyer keeps theirs until they pay
+pub fn release_all(item_id: u64, except: Option<Principal>) {
+    let bidders: Vec<Principal> = BOND_MAP.with(|b| {
+        b.borrow()
+            .range((item_id, StorablePrincipal::default())..)
+            .take_while(|((id, _), _)| *id == item_id)
+            .map(|((_, p), _)| p.into())
+            .filter(|p| Some(*p) != except)
+            .collect()
+    });
+    for bidder in bidders {
+        release(item_id, bidder);
+    }
+}
+
+/// Pays a defaulting buyer's bond to the seller.
+pub fn forfeit(item_id: u64, buyer: Principal, seller: Principal) {
+    if let Some(amount) = BOND_MAP.with(|b| b.borrow_mut().remove(&(item_id, buyer.into()))) {
+        balances::credit(seller, amount);
+        blocks::append(
+            FinancialOp::Payout,
+            amount,
+            Some(buyer),
+            Some(seller),
+            Some(item_id),
+        );
+    }
+}
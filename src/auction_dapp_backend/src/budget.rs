@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+use crate::{clock, Item, ITEM_MAP};
+
+//a message may run 20 billion instructions, heavy scans stop at half of it and pick up in the next one
+const INSTRUCTION_BUDGET: u64 = 10_000_000_000;
+
+/// True once the current message has used up the budget for heavy work.
+pub fn exhausted() -> bool {
+    clock::instructions_used() > INSTRUCTION_BUDGET
+}
+
+/// Runs `job` again in a new message, where it continues from the checkpoint it kept.
+pub fn resume(job: fn()) {
+    clock::set_timer(Duration::ZERO, job);
+}
+
+/// Visits items in id order from `from`, until all are visited or the budget runs out.
+/// Returns the id to call it with again when the budget ran out.
+pub fn scan_items(from: u64, mut visit: impl FnMut(Item)) -> Option<u64> {
+    let mut next = from;
+    //one item at a time, so `visit` can write to the item map
+    while let Some((id, item)) = ITEM_MAP.with(|items| items.borrow().range(next..).next()) {
+        next = id + 1;
+        visit(item);
+        if exhausted() {
+            return Some(next);
+        }
+    }
+    None
+}
@@ -0,0 +1,275 @@
+use candid::{CandidType, Encode, Principal};
+use ic_cdk::{
+    api::{data_certificate, set_certified_data},
+    query,
+};
+use ic_stable_structures::{memory_manager::MemoryId, storable::Blob, StableBTreeMap};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+use crate::{
+    audit_snapshot, bid_store, is_visible_to, Item, Memory, Status, ITEM_MAP, MEMORY_MANAGER,
+};
+
+//every certified item is rehashed into the tree on each change, so only opted-in items are certified
+pub const MAX_CERTIFIED_ITEMS: u64 = 1_000;
+const ITEMS_LABEL: &[u8] = b"items";
+const SNAPSHOT_LABEL: &[u8] = b"snapshot";
+
+type Hash = [u8; 32];
+
+//the fields an agent needs to trust, the leaf of an item is the sha256 of their candid encoding
+#[derive(CandidType)]
+struct CertifiedItem {
+    id: u64,
+    item_owner: Principal,
+    highest_bid: u64,
+    leading_bidder: Option<Principal>,
+    bid_count: u64,
+    result_date: u64,
+    status: Status,
+}
+
+#[derive(CandidType)]
+struct CertifiedItemResponse {
+    data: Vec<u8>,                //candid encoded CertifiedItem, hash it to get the leaf
+    certificate: Option<Vec<u8>>, //only set when called as a query
+    witness: Vec<u8>, //cbor hash tree with the path ["items", item id as 8 big-endian bytes]
+}
+
+//which path of the tree is kept in a witness
+enum Witness {
+    Root,
+    Item(u64),
+    Snapshot,
+}
+
+thread_local! {
+    //item_id -> leaf hash of every certified item
+    static CERTIFIED_LEAVES: RefCell<StableBTreeMap<u64, Blob<32>, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64)))));
+}
+
+enum HashTree {
+    Empty,
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>),
+    Pruned(Hash),
+}
+
+fn domain_hash(domain: &str, parts: &[&[u8]]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([domain.len() as u8]);
+    hasher.update(domain.as_bytes());
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+impl HashTree {
+    fn hash(&self) -> Hash {
+        match self {
+            HashTree::Empty => domain_hash("ic-hashtree-empty", &[]),
+            HashTree::Fork(l, r) => domain_hash("ic-hashtree-fork", &[&l.hash(), &r.hash()]),
+            HashTree::Labeled(label, t) => domain_hash("ic-hashtree-labeled", &[label, &t.hash()]),
+            HashTree::Leaf(v) => domain_hash("ic-hashtree-leaf", &[v]),
+            HashTree::Pruned(h) => *h,
+        }
+    }
+
+    fn write_cbor(&self, out: &mut Vec<u8>) {
+        match self {
+            HashTree::Empty => {
+                cbor_head(out, 4, 1);
+                cbor_head(out, 0, 0);
+            }
+            HashTree::Fork(l, r) => {
+                cbor_head(out, 4, 3);
+                cbor_head(out, 0, 1);
+                l.write_cbor(out);
+                r.write_cbor(out);
+            }
+            HashTree::Labeled(label, t) => {
+                cbor_head(out, 4, 3);
+                cbor_head(out, 0, 2);
+                cbor_bytes(out, label);
+                t.write_cbor(out);
+            }
+            HashTree::Leaf(v) => {
+                cbor_head(out, 4, 2);
+                cbor_head(out, 0, 3);
+                cbor_bytes(out, v);
+            }
+            HashTree::Pruned(h) => {
+                cbor_head(out, 4, 2);
+                cbor_head(out, 0, 4);
+                cbor_bytes(out, h);
+            }
+        }
+    }
+}
+
+fn cbor_head(out: &mut Vec<u8>, major: u8, len: u64) {
+    let major = major << 5;
+    match len {
+        0..=23 => out.push(major | len as u8),
+        24..=0xff => out.extend([major | 24, len as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend((len as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend((len as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend(len.to_be_bytes());
+        }
+    }
+}
+
+fn cbor_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    cbor_head(out, 2, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+//a balanced fork tree over the leaves sorted by id, everything but the path to `target` is pruned
+fn build(leaves: &[(u64, Hash)], target: Option<u64>) -> HashTree {
+    let contains_target = target.is_some_and(|t| leaves.iter().any(|(id, _)| *id == t));
+    let tree = match leaves {
+        [] => HashTree::Empty,
+        [(id, hash)] => HashTree::Labeled(
+            id.to_be_bytes().to_vec(),
+            Box::new(HashTree::Leaf(hash.to_vec())),
+        ),
+        _ => {
+            let (left, right) = leaves.split_at(leaves.len() / 2);
+            HashTree::Fork(
+                Box::new(build(left, target)),
+                Box::new(build(right, target)),
+            )
+        }
+    };
+    match (target, contains_target) {
+        (Some(_), false) => HashTree::Pruned(tree.hash()),
+        _ => tree,
+    }
+}
+
+fn leaves() -> Vec<(u64, Hash)> {
+    CERTIFIED_LEAVES.with(|l| {
+        l.borrow()
+            .iter()
+            .map(|(id, hash)| (id, hash.as_slice().try_into().unwrap()))
+            .collect()
+    })
+}
+
+fn items_tree(target: Option<u64>) -> HashTree {
+    HashTree::Labeled(ITEMS_LABEL.to_vec(), Box::new(build(&leaves(), target)))
+}
+
+//the latest audit snapshot sits next to the items once one was taken, labels stay in order
+fn tree(witness: Witness) -> HashTree {
+    let items = match witness {
+        Witness::Root => items_tree(None),
+        Witness::Item(id) => items_tree(Some(id)),
+        Witness::Snapshot => HashTree::Pruned(items_tree(None).hash()),
+    };
+    let Some(root) = audit_snapshot::certified_root() else {
+        return items;
+    };
+    let snapshot = HashTree::Labeled(
+        SNAPSHOT_LABEL.to_vec(),
+        Box::new(HashTree::Leaf(root.to_vec())),
+    );
+    let snapshot = match witness {
+        Witness::Item(_) => HashTree::Pruned(snapshot.hash()),
+        _ => snapshot,
+    };
+    HashTree::Fork(Box::new(items), Box::new(snapshot))
+}
+
+fn witness_cbor(tree: HashTree) -> Vec<u8> {
+    let mut witness = vec![0xd9, 0xd9, 0xf7]; //the cbor self-describe tag
+    tree.write_cbor(&mut witness);
+    witness
+}
+
+pub fn snapshot_witness() -> Vec<u8> {
+    witness_cbor(tree(Witness::Snapshot))
+}
+
+fn certified_item(item: &Item) -> CertifiedItem {
+    CertifiedItem {
+        id: item.id,
+        item_owner: item.item_owner,
+        highest_bid: item.highest_bid,
+        leading_bidder: bid_store::last(item.id).map(|b| b.bidder_principal),
+        bid_count: item.bid_count(),
+        result_date: item.result_date,
+        status: item.status(),
+    }
+}
+
+pub fn has_leaf(item_id: u64) -> bool {
+    CERTIFIED_LEAVES.with(|l| l.borrow().contains_key(&item_id))
+}
+
+//leaves of items that are gone or no longer opted in
+pub fn stale_leaves() -> Vec<u64> {
+    let ids: Vec<u64> = CERTIFIED_LEAVES.with(|l| l.borrow().iter().map(|(id, _)| id).collect());
+    ids.into_iter()
+        .filter(|id| {
+            ITEM_MAP
+                .with(|items| items.borrow().get(id))
+                .is_none_or(|item| item.certified != Some(true))
+        })
+        .collect()
+}
+
+pub fn certified_count() -> u64 {
+    CERTIFIED_LEAVES.with(|l| l.borrow().len())
+}
+
+/// Rehashes a certified item after a change and updates the canister's certified data.
+pub fn item_changed(item_id: u64) {
+    let leaf = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .filter(|item| item.certified == Some(true))
+        .map(|item| Sha256::digest(Encode!(&certified_item(&item)).unwrap()));
+    let changed = CERTIFIED_LEAVES.with(|l| match leaf {
+        Some(hash) => {
+            l.borrow_mut()
+                .insert(item_id, Blob::try_from(hash.as_slice()).unwrap());
+            true
+        }
+        None => l.borrow_mut().remove(&item_id).is_some(),
+    });
+    if changed {
+        certify();
+    }
+}
+
+/// Sets the certified data to the root of the tree, it does not survive upgrades.
+pub fn certify() {
+    set_certified_data(&tree(Witness::Root).hash());
+}
+
+#[query(name = "getItemCertified")]
+fn get_item_certified(item_id: u64) -> Result<CertifiedItemResponse, String> {
+    let item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .filter(|item| is_visible_to(item, ic_cdk::caller()))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+    if item.certified != Some(true) {
+        return Err("The selected item is not certified.".to_string());
+    }
+    Ok(CertifiedItemResponse {
+        data: Encode!(&certified_item(&item)).unwrap(),
+        certificate: data_certificate(),
+        witness: witness_cbor(tree(Witness::Item(item_id))),
+    })
+}
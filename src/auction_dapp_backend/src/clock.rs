@@ -0,0 +1,155 @@
+//every module reads the time, sets one-off timers and checks its instruction budget through here instead
+//of ic_cdk, so the auction logic can run off-chain under tests or the `mock-time` feature, where the time
+//only moves when it is set or advanced and timers fire as it passes them.
+//nothing in the canister draws randomness, ties are broken by bid order, so there is no rng to inject
+use std::time::Duration;
+
+#[cfg(not(any(test, feature = "mock-time")))]
+pub use ic_cdk_timers::TimerId;
+
+#[cfg(not(any(test, feature = "mock-time")))]
+pub fn time() -> u64 {
+    #[cfg(feature = "local-dev")]
+    return ic_cdk::api::time().saturating_add(TIME_OFFSET.with(|o| o.get()));
+    #[cfg(not(feature = "local-dev"))]
+    ic_cdk::api::time()
+}
+
+#[cfg(not(any(test, feature = "mock-time")))]
+pub fn set_timer(delay: Duration, f: impl FnOnce() + 'static) -> TimerId {
+    ic_cdk_timers::set_timer(delay, f)
+}
+
+#[cfg(not(any(test, feature = "mock-time")))]
+pub fn clear_timer(timer: TimerId) {
+    ic_cdk_timers::clear_timer(timer)
+}
+
+/// The instructions the current message has run so far.
+#[cfg(not(any(test, feature = "mock-time")))]
+pub fn instructions_used() -> u64 {
+    ic_cdk::api::instruction_counter()
+}
+
+#[cfg(any(test, feature = "mock-time"))]
+pub type TimerId = u64;
+
+#[cfg(any(test, feature = "mock-time"))]
+type MockTimer = Box<dyn FnOnce()>;
+
+#[cfg(any(test, feature = "mock-time"))]
+thread_local! {
+    static MOCK_TIME: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+
+    //(fires_at, timer id) of the pending one-off timers
+    static MOCK_TIMERS: std::cell::RefCell<std::collections::BTreeMap<(u64, TimerId), MockTimer>> = std::cell::RefCell::new(std::collections::BTreeMap::new());
+
+    static NEXT_TIMER_ID: std::cell::Cell<TimerId> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(any(test, feature = "mock-time"))]
+pub fn time() -> u64 {
+    MOCK_TIME.with(|t| t.get())
+}
+
+//off-chain there is no instruction limit
+#[cfg(any(test, feature = "mock-time"))]
+pub fn instructions_used() -> u64 {
+    0
+}
+
+#[cfg(any(test, feature = "mock-time"))]
+pub fn set_timer(delay: Duration, f: impl FnOnce() + 'static) -> TimerId {
+    let id = NEXT_TIMER_ID.with(|n| n.replace(n.get() + 1));
+    let fires_at = time().saturating_add(delay.as_nanos() as u64);
+    MOCK_TIMERS.with(|t| t.borrow_mut().insert((fires_at, id), Box::new(f)));
+    id
+}
+
+#[cfg(any(test, feature = "mock-time"))]
+pub fn clear_timer(timer: TimerId) {
+    MOCK_TIMERS.with(|t| t.borrow_mut().retain(|(_, id), _| *id != timer));
+}
+
+/// Sets the mocked time, in nanoseconds since the epoch like the replica's. Timers that are due do not fire.
+#[cfg(any(test, feature = "mock-time"))]
+#[cfg_attr(not(test), allow(dead_code))] //moved by the unit tests, a mock-time build only reads it
+pub fn set(now: u64) {
+    MOCK_TIME.with(|t| t.set(now));
+}
+
+/// Moves the mocked time forward, firing the timers it passes in the order they are due.
+#[cfg(any(test, feature = "mock-time"))]
+#[cfg_attr(not(any(test, feature = "local-dev")), allow(dead_code))]
+pub fn advance(ns: u64) {
+    let until = time().saturating_add(ns);
+    loop {
+        let due = MOCK_TIMERS.with(|t| {
+            let mut timers = t.borrow_mut();
+            let key = *timers
+                .keys()
+                .next()
+                .filter(|(fires_at, _)| *fires_at <= until)?;
+            timers.remove(&key).map(|f| (key.0, f))
+        });
+        let Some((fires_at, f)) = due else {
+            break;
+        };
+        MOCK_TIME.with(|t| t.set(t.get().max(fires_at)));
+        f();
+    }
+    MOCK_TIME.with(|t| t.set(until));
+}
+
+//local-dev builds run ahead of the replica by an offset, it is kept on the heap and resets on upgrade
+#[cfg(all(feature = "local-dev", not(any(test, feature = "mock-time"))))]
+thread_local! {
+    static TIME_OFFSET: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(all(feature = "local-dev", not(any(test, feature = "mock-time"))))]
+pub fn advance(ns: u64) {
+    TIME_OFFSET.with(|o| o.set(o.get().saturating_add(ns)));
+}
+
+//never build with local-dev for mainnet, it lets controllers move every deadline.
+//timers still fire on the replica's clock, their next run acts on the moved time
+#[cfg(feature = "local-dev")]
+#[ic_cdk::update(name = "advanceTimeForTesting")]
+fn advance_time_for_testing(ns: u64) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let caller = caller.as_slice();
+    //ic-cdk 0.7 has no wrapper for this system call
+    let is_controller = unsafe { ic0::is_controller(caller.as_ptr() as i32, caller.len() as i32) };
+    if is_controller != 1 {
+        return Err("Only controllers can move the time.".to_string());
+    }
+    advance(ns);
+    Ok(format!("The canister's time is now {}", time()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn timers_fire_in_order_as_time_passes() {
+        set(1_000);
+        let fired = Rc::new(RefCell::new(vec![]));
+        for (delay, name) in [(300, "late"), (100, "early"), (200, "cleared")] {
+            let fired = fired.clone();
+            let id = set_timer(Duration::from_nanos(delay), move || {
+                fired.borrow_mut().push((name, time()))
+            });
+            if name == "cleared" {
+                clear_timer(id);
+            }
+        }
+        advance(150);
+        assert_eq!(*fired.borrow(), vec![("early", 1_100)]);
+        advance(1_000);
+        assert_eq!(*fired.borrow(), vec![("early", 1_100), ("late", 1_300)]);
+        assert_eq!(time(), 2_150);
+    }
+}
@@ -0,0 +1,85 @@
+use candid::{CandidType, Deserialize};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    time::Duration,
+};
+
+use crate::{
+    audit::{self, AuditEvent},
+    clock::{self, time, TimerId},
+    expiry,
+};
+
+const RECONCILE_BATCH: usize = 500;
+
+//why an auction closed before its timer fired
+#[derive(Deserialize, CandidType, Clone, Copy)]
+pub enum EarlyClose {
+    Stopped, //cancelled by the owner
+    Sold,    //bought before the end, e.g. through an offer
+    Removed, //taken down by moderation
+}
+
+//every active item has a timer for its result_date, set and cleared together with its end time index
+//entry. the periodic sweep stays as a fallback for items whose timer was lost
+thread_local! {
+    //on the heap, gone after an upgrade like the timers themselves until start_reconciliation recreates them
+    //item id -> (timer, the result_date it fires at)
+    static TIMERS: RefCell<HashMap<u64, (TimerId, u64)>> = RefCell::new(HashMap::new());
+
+    //the next end time index entry to recreate a timer for
+    static RECONCILE_FROM: Cell<(u64, u64)> = const { Cell::new((0, 0)) };
+}
+
+/// Sets the item's close timer, replacing the one it had.
+pub fn schedule(item_id: u64, result_date: u64) {
+    cancel(item_id);
+    let delay = Duration::from_nanos(result_date.saturating_sub(time()));
+    let timer = clock::set_timer(delay, move || {
+        TIMERS.with(|t| t.borrow_mut().remove(&item_id));
+        expiry::close_if_due(item_id);
+    });
+    TIMERS.with(|t| t.borrow_mut().insert(item_id, (timer, result_date)));
+}
+
+/// Recreates the timers of every active item after an upgrade, a batch per message.
+pub fn start_reconciliation() {
+    RECONCILE_FROM.with(|f| f.set((0, 0)));
+    clock::set_timer(Duration::ZERO, reconcile);
+}
+
+//items that ended while the canister was upgraded get a timer that fires right away
+fn reconcile() {
+    let batch = expiry::indexed_from(RECONCILE_FROM.with(|f| f.get()), RECONCILE_BATCH);
+    for (result_date, item_id) in &batch {
+        if TIMERS.with(|t| !t.borrow().contains_key(item_id)) {
+            schedule(*item_id, *result_date);
+        }
+    }
+    if let (RECONCILE_BATCH, Some(&(result_date, item_id))) = (batch.len(), batch.last()) {
+        RECONCILE_FROM.with(|f| f.set((result_date, item_id + 1)));
+        clock::set_timer(Duration::ZERO, reconcile);
+    }
+}
+
+/// Clears the item's close timer, returning when it would have fired.
+pub fn cancel(item_id: u64) -> Option<u64> {
+    let (timer, fires_at) = TIMERS.with(|t| t.borrow_mut().remove(&item_id))?;
+    clock::clear_timer(timer);
+    Some(fires_at)
+}
+
+/// Clears the timer of an auction that is finalized before its end, and records that it was.
+pub fn cancel_early(item_id: u64, reason: EarlyClose) {
+    let Some(scheduled_for) = cancel(item_id) else {
+        return;
+    };
+    if scheduled_for > time() {
+        audit::record(AuditEvent::CloseTimerCancelled {
+            item_id,
+            scheduled_for,
+            reason,
+        });
+    }
+}
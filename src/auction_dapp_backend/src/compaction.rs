@@ -0,0 +1,193 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::{memory_manager::MemoryId, StableCell, Storable};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    time::Duration,
+};
+
+use crate::{
+    admin, bid_store, budget,
+    clock::time,
+    escrow::{self, EscrowState},
+    indexer_sync,
+    winners::{self, Decision},
+    Item, Memory, Status, ITEM_MAP, MEMORY_MANAGER,
+};
+
+//the highest bids are kept in full, they are all the winner and runner-up logic looks at
+const KEPT_BIDS: usize = 5;
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+pub const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MIN_CLOSED_FOR: u64 = 30 * DAY_NS;
+
+/// What is left of a compacted bid history, next to the highest bids that are kept.
+#[derive(Deserialize, CandidType, Clone)]
+pub struct BidArchive {
+    pub bid_count: u64,
+    winner: Option<Principal>, //who the winner policy picks, only set for sold items
+    min_bid: u64,
+    max_bid: u64,
+    average_bid: u64,
+    compacted_at: u64,
+}
+
+#[cfg(test)]
+impl BidArchive {
+    /// The largest archive a compacted item keeps, for the size tests of the Item.
+    pub fn largest(winner: Principal) -> Self {
+        BidArchive {
+            bid_count: u64::MAX,
+            winner: Some(winner),
+            min_bid: u64::MAX,
+            max_bid: u64::MAX,
+            average_bid: u64::MAX,
+            compacted_at: u64::MAX,
+        }
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct CompactionPolicy {
+    closed_for: Option<u64>, //nanoseconds after the auction closed, compaction is off when not set
+}
+
+impl Storable for CompactionPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static COMPACTION_POLICY: RefCell<StableCell<CompactionPolicy, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(77))),
+        CompactionPolicy::default()).unwrap());
+
+    //where a run that ran out of instructions continues
+    static CURSOR: Cell<u64> = const { Cell::new(0) };
+}
+
+fn policy() -> CompactionPolicy {
+    COMPACTION_POLICY.with(|c| c.borrow().get().clone())
+}
+
+//a payment that is still open may need every bid, e.g. to offer the item to a runner-up
+pub fn is_closed_for_good(item: &Item) -> bool {
+    matches!(
+        item.status(),
+        Status::Ended | Status::Settled | Status::Cancelled | Status::Removed
+    ) && escrow::state_of(item.id).is_none_or(|s| {
+        matches!(
+            s,
+            EscrowState::Released | EscrowState::Refunded | EscrowState::Defaulted
+        )
+    })
+}
+
+fn is_compactable(item: &Item, closed_before: u64) -> bool {
+    item.result_date < closed_before
+        && item.bid_archive.is_none()
+        && bid_store::count(item.id) > KEPT_BIDS as u64
+        && is_closed_for_good(item)
+}
+
+/// Who the winner policy picks, for sold items only.
+pub fn winner_of(item: &Item) -> Option<Principal> {
+    match winners::decide(item) {
+        Decision::Winner(bid) if item.status() == Status::Settled => Some(bid.bidder_principal),
+        _ => None,
+    }
+}
+
+fn compact(item: &mut Item) {
+    let mut bids = bid_store::of(item.id);
+    let amounts = bids.iter().map(|b| b.bid_amount);
+    let total: u128 = amounts.clone().map(u128::from).sum();
+    let archive = BidArchive {
+        bid_count: bids.len() as u64,
+        winner: winner_of(item),
+        min_bid: amounts.clone().min().unwrap_or(0),
+        max_bid: amounts.max().unwrap_or(0),
+        average_bid: (total / bids.len().max(1) as u128) as u64,
+        compacted_at: time(),
+    };
+    //keeps the highest bids in the order they were placed
+    let mut ranked: Vec<usize> = (0..bids.len()).collect();
+    ranked.sort_by_key(|&i| {
+        let b = &bids[i];
+        std::cmp::Reverse((b.bid_amount, b.seq))
+    });
+    ranked.truncate(KEPT_BIDS);
+    ranked.sort_unstable();
+    let mut index = 0;
+    bids.retain(|_| {
+        let keep = ranked.contains(&index);
+        index += 1;
+        keep
+    });
+    bid_store::replace(item.id, bids);
+    item.bid_archive = Some(archive);
+}
+
+fn compact_closed() {
+    let Some(closed_for) = policy().closed_for else {
+        return;
+    };
+    let closed_before = time().saturating_sub(closed_for);
+    let from = CURSOR.with(|c| c.replace(0));
+    let rest = budget::scan_items(from, |mut item| {
+        if is_compactable(&item, closed_before) {
+            compact(&mut item);
+            let id = item.id;
+            ITEM_MAP.with(|m| m.borrow_mut().insert(id, item));
+            indexer_sync::item_changed(id);
+        }
+    });
+    if let Some(next) = rest {
+        CURSOR.with(|c| c.set(next));
+        budget::resume(compact_closed);
+    }
+}
+
+pub fn start_compaction_timer() {
+    ic_cdk_timers::set_timer_interval(COMPACTION_INTERVAL, compact_closed);
+}
+
+pub fn closed_for() -> Option<u64> {
+    policy().closed_for
+}
+
+/// Sets how long after closing an item keeps its full bid history, also used by retention.rs.
+pub fn set_closed_for(closed_for: Option<u64>) -> Result<(), String> {
+    if closed_for.is_some_and(|c| c < MIN_CLOSED_FOR) {
+        return Err(format!(
+            "Bid histories can only be compacted {} days after an auction closed.",
+            MIN_CLOSED_FOR / DAY_NS
+        ));
+    }
+    COMPACTION_POLICY.with(|c| c.borrow_mut().set(CompactionPolicy { closed_for }).unwrap());
+    Ok(())
+}
+
+#[update(name = "setCompactionPolicy")]
+fn set_compaction_policy(closed_for: Option<u64>) -> Result<String, String> {
+    admin::ensure_admin()?;
+    set_closed_for(closed_for)?;
+    Ok(match closed_for {
+        Some(c) => format!(
+            "Bid histories are compacted {} days after an auction closes",
+            c / DAY_NS
+        ),
+        None => "Bid history compaction is turned off".to_string(),
+    })
+}
+
+#[query(name = "getCompactionPolicy")]
+fn get_compaction_policy() -> Option<u64> {
+    closed_for()
+}
@@ -0,0 +1,186 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::api::{
+    call::{msg_cycles_accept128, msg_cycles_available128},
+    management_canister::main::{deposit_cycles, CanisterIdRecord},
+};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    balances,
+    blocks::{self, FinancialOp},
+    referrals,
+    settlement::Settlement,
+    subscriptions, Item, Memory, MEMORY_MANAGER,
+};
+
+#[derive(Deserialize, CandidType, Clone, Copy, PartialEq)]
+pub enum PaymentMode {
+    Escrow,                      //the winner pays after the auction closes
+    CyclesAtBid,                 //every bid carries its amount in cycles
+    Icrc2 { ledger: Principal }, //bids are backed by an approval on the ledger or a deposited balance, see allowance_bids
+}
+
+//the cycles attached to the current highest bid of an item
+#[derive(Deserialize, CandidType, Clone)]
+struct Hold {
+    bidder: Principal,
+    amount: u128,
+}
+
+impl Storable for Hold {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Hold {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static HOLD_MAP: RefCell<StableBTreeMap<u64, Hold, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))));
+}
+
+pub fn held_total() -> u128 {
+    HOLD_MAP.with(|h| h.borrow().iter().map(|(_, hold)| hold.amount).sum())
+}
+
+pub fn is_cycles_item(item: &Item) -> bool {
+    item.payment_mode == Some(PaymentMode::CyclesAtBid)
+}
+
+//a bid's cycles that were checked but not taken yet
+pub struct PendingHold {
+    item_id: u64,
+    hold: Hold,
+}
+
+/// Checks that the bid carries its amount in cycles, plus the bond still due, nothing is taken until `commit_hold`.
+pub fn hold_bid(
+    item_id: u64,
+    bidder: Principal,
+    amount: u64,
+    bond_due: u128,
+) -> Result<PendingHold, String> {
+    let required = (amount as u128).saturating_add(bond_due);
+    if msg_cycles_available128() < required {
+        return Err(format!(
+            "Attach {} cycles to bid on this item, it is paid for when bidding.",
+            required
+        ));
+    }
+    let hold = Hold {
+        bidder,
+        amount: amount as u128,
+    };
+    Ok(PendingHold { item_id, hold })
+}
+
+/// Takes the bid's cycles and gives the previous highest bidder theirs back, once the bid is sure to be placed.
+pub fn commit_hold(pending: PendingHold) {
+    let PendingHold { item_id, hold } = pending;
+    msg_cycles_accept128(hold.amount);
+    blocks::append(
+        FinancialOp::Deposit,
+        hold.amount,
+        Some(hold.bidder),
+        None,
+        Some(item_id),
+    );
+    release_hold(item_id);
+    HOLD_MAP.with(|h| h.borrow_mut().insert(item_id, hold));
+}
+
+/// Refunds the held cycles to the bidder's balance, e.g. when they are outbid or the item is not sold.
+pub fn release_hold(item_id: u64) {
+    if let Some(hold) = HOLD_MAP.with(|h| h.borrow_mut().remove(&item_id)) {
+        balances::credit(hold.bidder, hold.amount);
+        blocks::append(
+            FinancialOp::Refund,
+            hold.amount,
+            None,
+            Some(hold.bidder),
+            Some(item_id),
+        );
+    }
+}
+
+pub fn is_held_for(item_id: u64, bidder: Principal) -> bool {
+    HOLD_MAP
+        .with(|h| h.borrow().get(&item_id))
+        .is_some_and(|h| h.bidder == bidder)
+}
+
+pub fn held_by(bidder: Principal) -> u128 {
+    HOLD_MAP.with(|h| {
+        h.borrow()
+            .iter()
+            .filter(|(_, hold)| hold.bidder == bidder)
+            .map(|(_, hold)| hold.amount)
+            .sum()
+    })
+}
+
+/// Hands the winner's cycles to the seller, canisters get them deposited directly.
+pub fn pay_seller(settlement: &Settlement) -> Result<(), String> {
+    let (item_id, seller, buyer) = (settlement.item_id, settlement.seller, settlement.buyer);
+    let hold = HOLD_MAP
+        .with(|h| h.borrow().get(&item_id))
+        .filter(|h| h.bidder == buyer)
+        .ok_or_else(|| "The buyer has no cycles held for this item.".to_string())?;
+    HOLD_MAP.with(|h| h.borrow_mut().remove(&item_id));
+    referrals::purchase_completed(buyer);
+
+    let (payout, commission) = settlement.split(hold.amount);
+    blocks::append(
+        FinancialOp::Payout,
+        payout,
+        Some(buyer),
+        Some(seller),
+        Some(item_id),
+    );
+    if commission > 0 {
+        blocks::append(
+            FinancialOp::Fee,
+            commission,
+            Some(seller),
+            None,
+            Some(item_id),
+        );
+    }
+
+    if !subscriptions::is_canister(seller) {
+        balances::credit(seller, payout);
+        return Ok(());
+    }
+    ic_cdk::spawn(async move {
+        let target = CanisterIdRecord {
+            canister_id: seller,
+        };
+        //keep the cycles for the seller if their canister could not take them
+        if deposit_cycles(target, payout).await.is_err() {
+            balances::credit(seller, payout);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    #[test]
+    fn the_largest_hold_fits() {
+        assert_fits(&Hold {
+            bidder: principal(1),
+            amount: u128::MAX,
+        });
+    }
+}
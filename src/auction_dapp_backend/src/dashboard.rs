@@ -0,0 +1,246 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    bid_store,
+    clock::time,
+    escrow::{self, EscrowState},
+    expiry, listing_quota,
+    price_history::DAY_NS,
+    settlement, ItemSummary, Memory, StorablePrincipal, ITEM_MAP, MEMORY_MANAGER,
+};
+
+const HOUR_NS: u64 = 60 * 60 * 1_000_000_000;
+
+#[derive(Deserialize, CandidType, Clone, Copy)]
+struct HourCount {
+    hour: u64, //hours since the unix epoch
+    count: u64,
+}
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct SellerStats {
+    active_listings: u64,
+    unsold_items: u64,
+    lifetime_sales_count: u64,
+    lifetime_sales_volume: u128,
+    recent_bids: Vec<HourCount>, //one entry per hour with bids, at most the last 24
+}
+
+impl Storable for SellerStats {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SellerStats {
+    const MAX_SIZE: u32 = 1_000;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType)]
+struct BidderDashboard {
+    winning: Vec<ItemSummary>,
+    outbid: Vec<ItemSummary>,
+    awaiting_payment: Vec<ItemSummary>, //won, but not paid into escrow yet
+    total_spent: u128,
+}
+
+#[derive(CandidType)]
+struct SellerDashboard {
+    active_listings: u64,
+    bids_received_24h: u64,
+    items_ending_today: u64,
+    unsold_items: u64,
+    lifetime_sales_count: u64,
+    lifetime_sales_volume: u128,
+}
+
+//what buyers see about a seller next to their items
+#[derive(CandidType)]
+pub struct OwnerReputation {
+    lifetime_sales_count: u64,
+    lifetime_sales_volume: u128,
+    active_listings: u64,
+    unsold_items: u64,
+    verified: bool,
+}
+
+thread_local! {
+    static SELLER_STATS: RefCell<StableBTreeMap<StorablePrincipal, SellerStats, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))));
+
+    //(bidder, item_id) for every item a principal bid on or bought
+    static BIDDER_INDEX: RefCell<StableBTreeMap<(StorablePrincipal, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))));
+
+    static BIDDER_SPENT: RefCell<StableBTreeMap<StorablePrincipal, u128, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))));
+}
+
+pub fn reputation_of(seller: Principal) -> OwnerReputation {
+    let stats = SELLER_STATS
+        .with(|s| s.borrow().get(&seller.into()))
+        .unwrap_or_default();
+    OwnerReputation {
+        lifetime_sales_count: stats.lifetime_sales_count,
+        lifetime_sales_volume: stats.lifetime_sales_volume,
+        active_listings: stats.active_listings,
+        unsold_items: stats.unsold_items,
+        verified: listing_quota::is_verified(seller),
+    }
+}
+
+fn update_stats(seller: Principal, f: impl FnOnce(&mut SellerStats)) {
+    SELLER_STATS.with(|s| {
+        let mut stats = s.borrow().get(&seller.into()).unwrap_or_default();
+        f(&mut stats);
+        s.borrow_mut().insert(seller.into(), stats);
+    });
+}
+
+pub fn active_listing_counts() -> Vec<(Principal, u64)> {
+    SELLER_STATS.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, v)| v.active_listings > 0)
+            .map(|(k, v)| (k.into(), v.active_listings))
+            .collect()
+    })
+}
+
+pub fn listing_opened(seller: Principal) {
+    update_stats(seller, |s| s.active_listings += 1);
+}
+
+pub fn listing_closed(seller: Principal) {
+    update_stats(seller, |s| {
+        s.active_listings = s.active_listings.saturating_sub(1)
+    });
+}
+
+pub fn item_unsold(seller: Principal) {
+    update_stats(seller, |s| s.unsold_items += 1);
+}
+
+pub fn item_sold(seller: Principal, price: u64) {
+    update_stats(seller, |s| {
+        s.lifetime_sales_count += 1;
+        s.lifetime_sales_volume += price as u128;
+    });
+}
+
+//a sale the buyer did not pay for no longer counts, another buyer's is counted when it is settled
+pub fn sale_defaulted(seller: Principal, price: u64) {
+    update_stats(seller, |s| {
+        s.lifetime_sales_count = s.lifetime_sales_count.saturating_sub(1);
+        s.lifetime_sales_volume = s.lifetime_sales_volume.saturating_sub(price as u128);
+    });
+}
+
+pub fn bid_received(seller: Principal) {
+    let hour = time() / HOUR_NS;
+    update_stats(seller, |s| {
+        s.recent_bids.retain(|h| h.hour + 24 > hour);
+        match s.recent_bids.iter_mut().find(|h| h.hour == hour) {
+            Some(h) => h.count += 1,
+            None => s.recent_bids.push(HourCount { hour, count: 1 }),
+        }
+    });
+}
+
+pub fn bidder_joined(bidder: Principal, item_id: u64) {
+    BIDDER_INDEX.with(|idx| idx.borrow_mut().insert((bidder.into(), item_id), ()));
+}
+
+pub fn payment_made(buyer: Principal, amount: u64) {
+    BIDDER_SPENT.with(|s| {
+        let spent = s.borrow().get(&buyer.into()).unwrap_or_default();
+        s.borrow_mut().insert(buyer.into(), spent + amount as u128);
+    });
+}
+
+#[query(name = "getSellerDashboard")]
+fn get_seller_dashboard() -> SellerDashboard {
+    let now = time();
+    let hour = now / HOUR_NS;
+    let end_of_day = (now / DAY_NS + 1) * DAY_NS;
+    let stats = SELLER_STATS
+        .with(|s| s.borrow().get(&caller().into()))
+        .unwrap_or_default();
+
+    SellerDashboard {
+        active_listings: stats.active_listings,
+        bids_received_24h: stats
+            .recent_bids
+            .iter()
+            .filter(|h| h.hour + 24 > hour)
+            .map(|h| h.count)
+            .sum(),
+        items_ending_today: expiry::seller_items_ending_between(caller(), now, end_of_day),
+        unsold_items: stats.unsold_items,
+        lifetime_sales_count: stats.lifetime_sales_count,
+        lifetime_sales_volume: stats.lifetime_sales_volume,
+    }
+}
+
+#[query(name = "getBidderDashboard")]
+fn get_bidder_dashboard() -> BidderDashboard {
+    let me = StorablePrincipal::from(caller());
+    let item_ids: Vec<u64> = BIDDER_INDEX.with(|idx| {
+        idx.borrow()
+            .range((me, 0)..=(me, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+
+    let mut dashboard = BidderDashboard {
+        winning: vec![],
+        outbid: vec![],
+        awaiting_payment: vec![],
+        total_spent: BIDDER_SPENT.with(|s| s.borrow().get(&me).unwrap_or_default()),
+    };
+    for item in item_ids
+        .into_iter()
+        .filter_map(|id| ITEM_MAP.with(|items| items.borrow().get(&id)))
+    {
+        if item.is_active {
+            match bid_store::last(item.id) {
+                Some(bid) if bid.bidder_principal == caller() => {
+                    dashboard.winning.push(ItemSummary::from(&item))
+                }
+                _ => dashboard.outbid.push(ItemSummary::from(&item)),
+            }
+        } else if settlement::find_settlement(item.id).is_ok_and(|s| s.buyer == caller())
+            && escrow::state_of(item.id) == Some(EscrowState::AwaitingPayment)
+        {
+            dashboard.awaiting_payment.push(ItemSummary::from(&item));
+        }
+    }
+    dashboard
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_fits;
+
+    #[test]
+    fn a_full_day_of_bid_counts_fits() {
+        assert_fits(&SellerStats {
+            active_listings: u64::MAX,
+            unsold_items: u64::MAX,
+            lifetime_sales_count: u64::MAX,
+            lifetime_sales_volume: u128::MAX,
+            recent_bids: (0..24)
+                .map(|hour| HourCount {
+                    hour: u64::MAX - hour,
+                    count: u64::MAX,
+                })
+                .collect(),
+        });
+    }
+}
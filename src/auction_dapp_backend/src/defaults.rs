@@ -0,0 +1,70 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap, StableCell, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{admin, Memory, StorablePrincipal, MEMORY_MANAGER};
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct DefaultPolicy {
+    ban_after: Option<u32>, //missed payments after which a principal can no longer bid, never when not set
+}
+
+impl Storable for DefaultPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    //how many won auctions each principal did not pay for
+    static DEFAULT_COUNT: RefCell<StableBTreeMap<StorablePrincipal, u32, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))));
+
+    static DEFAULT_POLICY: RefCell<StableCell<DefaultPolicy, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44))),
+        DefaultPolicy::default()).unwrap());
+}
+
+fn count_of(p: Principal) -> u32 {
+    DEFAULT_COUNT.with(|d| d.borrow().get(&p.into()).unwrap_or_default())
+}
+
+pub fn record(buyer: Principal) {
+    let count = count_of(buyer) + 1;
+    DEFAULT_COUNT.with(|d| d.borrow_mut().insert(buyer.into(), count));
+}
+
+pub fn ensure_in_good_standing(p: Principal) -> Result<(), String> {
+    let ban_after = DEFAULT_POLICY.with(|c| c.borrow().get().ban_after);
+    if ban_after.is_some_and(|limit| count_of(p) >= limit) {
+        return Err("You cannot bid after repeatedly not paying for won auctions.".to_string());
+    }
+    Ok(())
+}
+
+#[update(name = "setDefaultBanThreshold")]
+fn set_default_ban_threshold(ban_after: Option<u32>) -> Result<String, String> {
+    admin::ensure_admin()?;
+    if ban_after == Some(0) {
+        return Err("The threshold has to be at least one missed payment.".to_string());
+    }
+    DEFAULT_POLICY.with(|c| c.borrow_mut().set(DefaultPolicy { ban_after }).unwrap());
+    Ok("Default ban threshold updated".to_string())
+}
+
+//lifts a ban, e.g. after the buyer settled with the seller outside the auction
+#[update(name = "clearPaymentDefaults")]
+fn clear_payment_defaults(p: Principal) -> Result<String, String> {
+    admin::ensure_admin()?;
+    DEFAULT_COUNT.with(|d| d.borrow_mut().remove(&p.into()));
+    Ok(format!("Payment defaults of {} cleared", p))
+}
+
+#[query(name = "getPaymentDefaults")]
+fn get_payment_defaults(p: Principal) -> u32 {
+    count_of(p)
+}
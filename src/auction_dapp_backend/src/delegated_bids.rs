@@ -0,0 +1,117 @@
+use candid::{CandidType, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, storable::Blob, StableBTreeMap};
+use std::cell::RefCell;
+
+use crate::{
+    bid_as, clock::time, ed25519, guard, BidBase, Memory, StorablePrincipal, MEMORY_MANAGER,
+};
+
+//signed messages are prefixed so a key used elsewhere cannot be tricked into authorizing bids
+const DOMAIN_SEPARATOR: &[u8] = b"\x19auction-bid-authorization";
+
+//what the user signs off-chain, the signed message is DOMAIN_SEPARATOR followed by its candid encoding
+#[derive(Deserialize, CandidType, Clone)]
+struct BidAuthorization {
+    canister: Principal, //this canister, so an authorization cannot be replayed elsewhere
+    item_id: u64,
+    delegate: Principal, //the bot allowed to submit it
+    max_amount: u64,     //no bid placed with it can go above this
+    expires_at: u64,
+    nonce: u64, //revokeBidAuthorizations rejects every nonce below the one passed to it
+}
+
+#[derive(CandidType)]
+struct SigningKeyInfo {
+    public_key: Option<Vec<u8>>,
+    min_nonce: u64,
+}
+
+thread_local! {
+    //user -> ed25519 public key their authorizations are signed with
+    static SIGNING_KEYS: RefCell<StableBTreeMap<StorablePrincipal, Blob<32>, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70)))));
+
+    //user -> the lowest nonce still accepted
+    static MIN_NONCES: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71)))));
+}
+
+fn min_nonce(p: Principal) -> u64 {
+    MIN_NONCES.with(|n| n.borrow().get(&p.into()).unwrap_or(0))
+}
+
+//a new key also invalidates every authorization signed with the old one
+#[update(name = "registerSigningKey")]
+fn register_signing_key(public_key: Vec<u8>) -> Result<String, String> {
+    guard::check_write_headroom()?;
+    if public_key.len() != ed25519::PUBLIC_KEY_LENGTH {
+        return Err("Signing keys have to be 32 byte ed25519 public keys.".to_string());
+    }
+    SIGNING_KEYS.with(|k| {
+        k.borrow_mut().insert(
+            caller().into(),
+            Blob::try_from(public_key.as_slice()).unwrap(),
+        )
+    });
+    Ok("Signing key registered".to_string())
+}
+
+#[update(name = "revokeBidAuthorizations")]
+fn revoke_bid_authorizations(below_nonce: u64) -> Result<String, String> {
+    guard::check_write_headroom()?;
+    if below_nonce <= min_nonce(caller()) {
+        return Err("Authorizations below this nonce are already revoked.".to_string());
+    }
+    MIN_NONCES.with(|n| n.borrow_mut().insert(caller().into(), below_nonce));
+    Ok(format!(
+        "Authorizations below nonce {} revoked",
+        below_nonce
+    ))
+}
+
+#[query(name = "getSigningKey")]
+fn get_signing_key() -> SigningKeyInfo {
+    SigningKeyInfo {
+        public_key: SIGNING_KEYS.with(|k| {
+            k.borrow()
+                .get(&caller().into())
+                .map(|b| b.as_slice().to_vec())
+        }),
+        min_nonce: min_nonce(caller()),
+    }
+}
+
+#[update(name = "placeDelegatedBid")]
+async fn place_delegated_bid(
+    signer: Principal,
+    authorization: BidAuthorization,
+    signature: Vec<u8>,
+    bid: BidBase,
+) -> Result<String, String> {
+    if authorization.canister != ic_cdk::id() {
+        return Err("The authorization is for another canister.".to_string());
+    }
+    if authorization.delegate != caller() {
+        return Err("The authorization was not given to you.".to_string());
+    }
+    if authorization.expires_at <= time() {
+        return Err("The authorization has expired.".to_string());
+    }
+    if authorization.nonce < min_nonce(signer) {
+        return Err("The authorization has been revoked.".to_string());
+    }
+    if bid.bid_amount > authorization.max_amount {
+        return Err(format!(
+            "The authorization allows bids of at most {}.",
+            authorization.max_amount
+        ));
+    }
+    let public_key = SIGNING_KEYS
+        .with(|k| k.borrow().get(&signer.into()))
+        .ok_or_else(|| "The signer has no registered signing key.".to_string())?;
+    let mut message = DOMAIN_SEPARATOR.to_vec();
+    message.extend(Encode!(&authorization).unwrap());
+    if !ed25519::verify(public_key.as_slice(), &message, &signature) {
+        return Err("The authorization signature is not valid.".to_string());
+    }
+    bid_as(authorization.item_id, bid, signer).await
+}
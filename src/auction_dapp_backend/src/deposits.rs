@@ -0,0 +1,421 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_cdk::{api::id, caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell, collections::BTreeSet};
+
+use crate::{
+    balances,
+    blocks::Value,
+    clock::time,
+    icrc::{self, Account, TransferArg, TransferError},
+    ledgers, Memory, StorablePrincipal, MEMORY_MANAGER,
+};
+
+//a sweep that was sent but not confirmed, it is resent with the same arguments so the ledger deduplicates it
+#[derive(Deserialize, CandidType, Clone)]
+struct PendingSweep {
+    amount: u128,
+    fee: u128,
+    created_at_time: u64,
+}
+
+impl Storable for PendingSweep {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PendingSweep {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    //(principal, ledger) pairs with a refresh in progress, so a deposit is never swept twice
+    static REFRESHING: RefCell<BTreeSet<(Principal, Principal)>> = const { RefCell::new(BTreeSet::new()) };
+
+    //ledger blocks already credited through notifyDeposit or pulled by the auction itself, keyed by (ledger, block index)
+    static CREDITED_BLOCKS: RefCell<StableBTreeMap<(StorablePrincipal, u64), StorablePrincipal, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))));
+
+    //keyed by (depositor, ledger), at most one sweep each is unconfirmed
+    static PENDING_SWEEPS: RefCell<StableBTreeMap<(StorablePrincipal, StorablePrincipal), PendingSweep, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(106)))));
+}
+
+pub fn deposit_subaccount(p: Principal) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"auction-deposit");
+    hasher.update(p.as_slice());
+    hasher.finalize().to_vec()
+}
+
+fn deposit_account(p: Principal) -> Account {
+    Account {
+        owner: id(),
+        subaccount: Some(deposit_subaccount(p)),
+    }
+}
+
+//transfers to this account on the ledger can be credited with refreshDeposit
+#[query(name = "getDepositAccount")]
+fn get_deposit_account(ledger: Principal) -> Result<Account, String> {
+    ledgers::ensure_supported(ledger)?;
+    Ok(deposit_account(caller()))
+}
+
+//moves whatever sits in the caller's deposit subaccount into the canister's main account and credits it
+#[update(name = "refreshDeposit")]
+async fn refresh_deposit(ledger: Principal) -> Result<Nat, String> {
+    ledgers::ensure_supported(ledger)?;
+    let depositor = caller();
+    if !REFRESHING.with(|r| r.borrow_mut().insert((depositor, ledger))) {
+        return Err("A deposit refresh is already in progress.".to_string());
+    }
+    let result = sweep_deposit(ledger, depositor).await;
+    REFRESHING.with(|r| r.borrow_mut().remove(&(depositor, ledger)));
+    result
+}
+
+fn sweep_key(depositor: Principal, ledger: Principal) -> (StorablePrincipal, StorablePrincipal) {
+    (depositor.into(), ledger.into())
+}
+
+fn sweep_args(depositor: Principal, sweep: &PendingSweep) -> TransferArg {
+    TransferArg {
+        from_subaccount: Some(deposit_subaccount(depositor)),
+        to: id().into(),
+        amount: Nat::from(sweep.amount),
+        fee: Some(Nat::from(sweep.fee)),
+        memo: Some([b"sweep".as_slice(), &sweep.created_at_time.to_be_bytes()].concat()),
+        created_at_time: Some(sweep.created_at_time),
+    }
+}
+
+//a sweep that could not be confirmed is resent first, a deposit is only looked for once none is pending
+async fn sweep_deposit(ledger: Principal, depositor: Principal) -> Result<Nat, String> {
+    let Some(sweep) = PENDING_SWEEPS.with(|s| s.borrow().get(&sweep_key(depositor, ledger))) else {
+        return new_sweep(ledger, depositor).await;
+    };
+    match icrc::transfer_raw(ledger, sweep_args(depositor, &sweep)).await? {
+        //the ledger no longer deduplicates against it. only the auction moves tokens out of the
+        //subaccount, so if less than the sweep is left there it went through
+        Err(TransferError::TooOld | TransferError::CreatedInFuture { .. }) => {
+            let balance = icrc::balance_of(ledger, deposit_account(depositor)).await?;
+            if balance < sweep.amount.saturating_add(sweep.fee) {
+                return Ok(sweep_credited(depositor, ledger, &sweep));
+            }
+            PENDING_SWEEPS.with(|s| s.borrow_mut().remove(&sweep_key(depositor, ledger)));
+            new_sweep(ledger, depositor).await
+        }
+        result => sweep_finished(depositor, ledger, &sweep, result),
+    }
+}
+
+async fn new_sweep(ledger: Principal, depositor: Principal) -> Result<Nat, String> {
+    let balance = icrc::balance_of(ledger, deposit_account(depositor)).await?;
+    let fee = icrc::fee(ledger).await?;
+    if balance <= fee {
+        return Err("No new deposit was found.".to_string());
+    }
+
+    let sweep = PendingSweep {
+        amount: nat_to_u128(&(balance - fee.clone())),
+        fee: nat_to_u128(&fee),
+        created_at_time: time(),
+    };
+    //kept until the ledger answers, a call that fails on the way back is resent by the next refresh
+    PENDING_SWEEPS.with(|s| {
+        s.borrow_mut()
+            .insert(sweep_key(depositor, ledger), sweep.clone())
+    });
+    let result = icrc::transfer_raw(ledger, sweep_args(depositor, &sweep)).await?;
+    sweep_finished(depositor, ledger, &sweep, result)
+}
+
+fn sweep_finished(
+    depositor: Principal,
+    ledger: Principal,
+    sweep: &PendingSweep,
+    result: Result<Nat, TransferError>,
+) -> Result<Nat, String> {
+    match result {
+        Ok(_) | Err(TransferError::Duplicate { .. }) => {
+            Ok(sweep_credited(depositor, ledger, sweep))
+        }
+        Err(e) => {
+            PENDING_SWEEPS.with(|s| s.borrow_mut().remove(&sweep_key(depositor, ledger)));
+            Err(format!("The token transfer failed: {:?}", e))
+        }
+    }
+}
+
+fn sweep_credited(depositor: Principal, ledger: Principal, sweep: &PendingSweep) -> Nat {
+    PENDING_SWEEPS.with(|s| s.borrow_mut().remove(&sweep_key(depositor, ledger)));
+    balances::credit_token(depositor, ledger, sweep.amount);
+    Nat::from(sweep.amount)
+}
+
+//credits a transfer the caller made straight to the canister's main account, once the ledger confirms it
+#[update(name = "notifyDeposit")]
+async fn notify_deposit(ledger: Principal, block_index: u64) -> Result<Nat, String> {
+    ledgers::ensure_supported(ledger)?;
+    let block = icrc::get_block(ledger, Nat::from(block_index))
+        .await?
+        .ok_or_else(|| "The ledger has no such block.".to_string())?;
+    credit_block(ledger, block_index, &block, caller(), id())
+}
+
+//credits `depositor` for a block that transferred tokens to `auction`'s main account, at most once
+fn credit_block(
+    ledger: Principal,
+    block_index: u64,
+    block: &Value,
+    depositor: Principal,
+    auction: Principal,
+) -> Result<Nat, String> {
+    let (from, to, amount) =
+        transfer_in(block).ok_or_else(|| "The block is not a token transfer.".to_string())?;
+    if from.owner != depositor {
+        return Err("The transfer was not sent by the caller.".to_string());
+    }
+    //deposit subaccounts are swept by refreshDeposit, crediting them here would count them twice
+    let to_main_account = to
+        .subaccount
+        .as_ref()
+        .is_none_or(|s| s.iter().all(|b| *b == 0));
+    if to.owner != auction || !to_main_account {
+        return Err("The transfer was not sent to the auction's main account.".to_string());
+    }
+
+    let key = (StorablePrincipal::from(ledger), block_index);
+    if CREDITED_BLOCKS.with(|c| c.borrow().contains_key(&key)) {
+        return Err("This transfer has already been credited.".to_string());
+    }
+    CREDITED_BLOCKS.with(|c| c.borrow_mut().insert(key, depositor.into()));
+    balances::credit_token(depositor, ledger, nat_to_u128(&amount));
+    Ok(amount)
+}
+
+/// Marks a payment the auction pulled from `from` so it can never be credited as a deposit.
+pub fn mark_collected(ledger: Principal, block_index: &Nat, from: Principal) {
+    let Ok(block_index) = u64::try_from(block_index.0.clone()) else {
+        return;
+    };
+    CREDITED_BLOCKS.with(|c| {
+        c.borrow_mut()
+            .insert((ledger.into(), block_index), from.into())
+    });
+}
+
+fn field<'a>(map: &'a Value, name: &str) -> Option<&'a Value> {
+    match map {
+        Value::Map(entries) => entries.iter().find(|(k, _)| k == name).map(|(_, v)| v),
+        _ => None,
+    }
+}
+
+fn account(value: &Value) -> Option<Account> {
+    let Value::Array(parts) = value else {
+        return None;
+    };
+    let owner = match parts.first() {
+        Some(Value::Blob(bytes)) => Principal::try_from_slice(bytes).ok()?,
+        _ => return None,
+    };
+    let subaccount = match parts.get(1) {
+        Some(Value::Blob(bytes)) => Some(bytes.clone()),
+        _ => None,
+    };
+    Some(Account { owner, subaccount })
+}
+
+//reads (from, to, amount) out of an ICRC-3 transfer block, older ledgers put the op inside tx.
+//transfer_from blocks are refused, some ledgers log them as xfer with a spender, and the spender
+//may well be the auction collecting a payment
+fn transfer_in(block: &Value) -> Option<(Account, Account, Nat)> {
+    let tx = field(block, "tx")?;
+    let is_transfer = matches!(field(block, "btype"), Some(Value::Text(t)) if t == "1xfer")
+        || matches!(field(tx, "op"), Some(Value::Text(op)) if op == "xfer");
+    let is_transfer_from = matches!(field(block, "btype"), Some(Value::Text(t)) if t == "2xfer")
+        || field(tx, "spender").is_some();
+    if !is_transfer || is_transfer_from {
+        return None;
+    }
+    let Some(Value::Nat(amount)) = field(tx, "amt") else {
+        return None;
+    };
+    Some((
+        account(field(tx, "from")?)?,
+        account(field(tx, "to")?)?,
+        amount.clone(),
+    ))
+}
+
+fn nat_to_u128(n: &Nat) -> u128 {
+    u128::try_from(n.0.clone()).unwrap_or(u128::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    fn account_value(owner: Principal) -> Value {
+        Value::Array(vec![Value::Blob(owner.as_slice().to_vec())])
+    }
+
+    fn block(btype: Option<&str>, op: Option<&str>, spender: Option<Principal>) -> Value {
+        transfer(
+            btype,
+            op,
+            spender,
+            account_value(Principal::from_slice(&[2; 29])),
+        )
+    }
+
+    fn transfer(
+        btype: Option<&str>,
+        op: Option<&str>,
+        spender: Option<Principal>,
+        to: Value,
+    ) -> Value {
+        let mut tx = vec![
+            ("amt".to_string(), Value::Nat(Nat::from(500u64))),
+            (
+                "from".to_string(),
+                account_value(Principal::from_slice(&[1; 29])),
+            ),
+            ("to".to_string(), to),
+        ];
+        if let Some(op) = op {
+            tx.push(("op".to_string(), Value::Text(op.to_string())));
+        }
+        if let Some(spender) = spender {
+            tx.push(("spender".to_string(), account_value(spender)));
+        }
+        let mut block = vec![("tx".to_string(), Value::Map(tx))];
+        if let Some(btype) = btype {
+            block.push(("btype".to_string(), Value::Text(btype.to_string())));
+        }
+        Value::Map(block)
+    }
+
+    #[test]
+    fn reads_plain_transfers() {
+        let (from, to, amount) = transfer_in(&block(Some("1xfer"), None, None)).unwrap();
+        assert_eq!(from.owner, Principal::from_slice(&[1; 29]));
+        assert_eq!(to.owner, Principal::from_slice(&[2; 29]));
+        assert_eq!(amount, Nat::from(500u64));
+        assert!(transfer_in(&block(None, Some("xfer"), None)).is_some());
+    }
+
+    #[test]
+    fn refuses_transfer_from_blocks() {
+        let spender = Some(Principal::from_slice(&[3; 29]));
+        assert!(transfer_in(&block(Some("2xfer"), None, None)).is_none());
+        assert!(transfer_in(&block(Some("1xfer"), None, spender)).is_none());
+        assert!(transfer_in(&block(None, Some("xfer"), spender)).is_none());
+    }
+
+    #[test]
+    fn refuses_other_operations() {
+        assert!(transfer_in(&block(Some("1mint"), None, None)).is_none());
+        assert!(transfer_in(&block(None, Some("approve"), None)).is_none());
+    }
+
+    #[test]
+    fn a_resent_sweep_is_credited_once() {
+        let (depositor, ledger) = (
+            Principal::from_slice(&[1; 29]),
+            Principal::from_slice(&[4; 29]),
+        );
+        let sweep = PendingSweep {
+            amount: 990,
+            fee: 10,
+            created_at_time: 1,
+        };
+        PENDING_SWEEPS.with(|s| {
+            s.borrow_mut()
+                .insert(sweep_key(depositor, ledger), sweep.clone())
+        });
+        let duplicate = Err(TransferError::Duplicate {
+            duplicate_of: Nat::from(3u64),
+        });
+        assert_eq!(
+            sweep_finished(depositor, ledger, &sweep, duplicate),
+            Ok(Nat::from(990u64))
+        );
+        assert_eq!(balances::token_balance_of(depositor, ledger), 990);
+        assert!(PENDING_SWEEPS.with(|s| s.borrow().is_empty()));
+
+        let refused = Err(TransferError::InsufficientFunds {
+            balance: Nat::from(0u64),
+        });
+        assert!(sweep_finished(depositor, ledger, &sweep, refused).is_err());
+        assert_eq!(balances::token_balance_of(depositor, ledger), 990);
+    }
+
+    #[test]
+    fn the_largest_pending_sweep_fits() {
+        let sweep = PendingSweep {
+            amount: u128::MAX,
+            fee: u128::MAX,
+            created_at_time: u64::MAX,
+        };
+        assert_fits(&sweep);
+    }
+
+    #[test]
+    fn a_transfer_to_the_main_account_is_credited_once() {
+        let (depositor, auction, ledger) = (principal(1), principal(2), principal(4));
+        let deposit = block(Some("1xfer"), None, None);
+        assert_eq!(
+            credit_block(ledger, 7, &deposit, depositor, auction),
+            Ok(Nat::from(500u64))
+        );
+        assert_eq!(balances::token_balance_of(depositor, ledger), 500);
+        assert!(credit_block(ledger, 7, &deposit, depositor, auction).is_err());
+        //the same block index on another ledger is another transfer
+        assert!(credit_block(principal(5), 7, &deposit, depositor, auction).is_ok());
+        assert_eq!(balances::token_balance_of(depositor, ledger), 500);
+    }
+
+    #[test]
+    fn only_the_sender_is_credited_for_the_main_account() {
+        let (auction, ledger) = (principal(2), principal(4));
+        let deposit = block(Some("1xfer"), None, None);
+        assert!(credit_block(ledger, 7, &deposit, principal(3), auction).is_err());
+        assert!(credit_block(ledger, 7, &deposit, principal(1), principal(3)).is_err());
+
+        let mut subaccount = vec![0; 32];
+        subaccount[31] = 1;
+        let to_subaccount = Value::Array(vec![
+            Value::Blob(auction.as_slice().to_vec()),
+            Value::Blob(subaccount),
+        ]);
+        let swept = transfer(Some("1xfer"), None, None, to_subaccount);
+        assert!(credit_block(ledger, 8, &swept, principal(1), auction).is_err());
+        assert_eq!(balances::token_balance_of(principal(1), ledger), 0);
+        assert_eq!(balances::token_balance_of(principal(3), ledger), 0);
+    }
+
+    #[test]
+    fn a_collected_payment_is_never_credited() {
+        let (depositor, auction, ledger) = (principal(1), principal(2), principal(4));
+        mark_collected(ledger, &Nat::from(7u64), depositor);
+        let deposit = block(Some("1xfer"), None, None);
+        assert!(credit_block(ledger, 7, &deposit, depositor, auction).is_err());
+        assert_eq!(balances::token_balance_of(depositor, ledger), 0);
+    }
+
+    #[test]
+    fn collected_blocks_count_as_credited() {
+        let ledger = Principal::from_slice(&[4; 29]);
+        mark_collected(ledger, &Nat::from(7u64), Principal::from_slice(&[1; 29]));
+        assert!(CREDITED_BLOCKS.with(|c| c.borrow().contains_key(&(ledger.into(), 7))));
+    }
+}
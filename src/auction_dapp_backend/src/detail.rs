@@ -0,0 +1,93 @@
+use candid::{CandidType, Principal};
+use ic_cdk::{caller, query};
+
+use crate::{
+    bid_store,
+    dashboard::{self, OwnerReputation},
+    is_visible_to,
+    profiles::{self, Profile},
+    redact_memos, settlement, watchlist, ItemView, ITEM_MAP,
+};
+
+const TOP_BIDS: usize = 10;
+
+#[derive(CandidType)]
+struct TopBid {
+    bidder: Principal,
+    amount: u64,
+    bid_date: u64,
+}
+
+#[derive(CandidType)]
+struct Winner {
+    buyer: Principal,
+    price: u64,
+    settled_at: u64,
+}
+
+#[derive(CandidType)]
+struct Relationship {
+    is_owner: bool,
+    is_leading_bidder: bool,
+    has_bid: bool,
+    is_watching: bool,
+    is_buyer: bool,
+}
+
+#[derive(CandidType)]
+struct ItemDetail {
+    item: ItemView,
+    top_bids: Vec<TopBid>, //highest first
+    winner: Option<Winner>,
+    owner_reputation: OwnerReputation,
+    owner_profile: Option<Profile>,
+    relationship: Relationship,
+}
+
+//everything an item page needs in one call
+#[query(name = "getItemDetail")]
+fn get_item_detail(item_id: u64) -> Option<ItemDetail> {
+    let viewer = caller();
+    let item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .filter(|item| is_visible_to(item, viewer))
+        .map(|item| redact_memos(bid_store::attach(item), viewer))?;
+
+    let mut top_bids: Vec<TopBid> = item
+        .bid_vector
+        .iter()
+        .map(|b| TopBid {
+            bidder: b.bidder_principal,
+            amount: b.bid_amount,
+            bid_date: b.bid_date,
+        })
+        .collect();
+    top_bids.sort_by_key(|b| std::cmp::Reverse(b.amount));
+    top_bids.truncate(TOP_BIDS);
+
+    let settled = settlement::find_settlement(item_id)
+        .ok()
+        .filter(|s| s.defaulted_at.is_none());
+    let relationship = Relationship {
+        is_owner: item.item_owner == viewer,
+        is_leading_bidder: item
+            .bid_vector
+            .last()
+            .is_some_and(|b| b.bidder_principal == viewer),
+        has_bid: item.bid_vector.iter().any(|b| b.bidder_principal == viewer),
+        is_watching: watchlist::is_watching(viewer, item_id),
+        is_buyer: settled.as_ref().is_some_and(|s| s.buyer == viewer),
+    };
+    Some(ItemDetail {
+        top_bids,
+        winner: settled.map(|s| Winner {
+            buyer: s.buyer,
+            price: s.price,
+            settled_at: s.settled_at,
+        }),
+        owner_reputation: dashboard::reputation_of(item.item_owner),
+        owner_profile: profiles::of(item.item_owner),
+        relationship,
+        item: item.into(),
+    })
+}
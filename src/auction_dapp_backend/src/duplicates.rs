@@ -0,0 +1,82 @@
+use candid::{CandidType, Principal};
+use ic_cdk::query;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::{admin, expiry, Status, ITEM_MAP};
+
+#[derive(CandidType)]
+struct DuplicateCluster {
+    owner: Principal,
+    fingerprint: String,
+    item_ids: Vec<u64>,
+}
+
+//case, punctuation and spacing are ignored so small rewordings of the same text still match
+fn fingerprint(description: &str, category: Option<&str>) -> String {
+    let normalize = |text: &str| {
+        text.to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(normalize(description).as_bytes());
+    hasher.update([0]);
+    hasher.update(normalize(category.unwrap_or_default()).as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Refuses a listing that matches one of the owner's active items, unless they confirm it is intended.
+pub fn check(
+    owner: Principal,
+    description: &str,
+    category: Option<&str>,
+    allow_duplicate: bool,
+) -> Result<(), String> {
+    if allow_duplicate {
+        return Ok(());
+    }
+    let fp = fingerprint(description, category);
+    let duplicate = expiry::seller_active_ids(owner).into_iter().find(|id| {
+        ITEM_MAP
+            .with(|items| items.borrow().get(id))
+            .is_some_and(|item| fingerprint(&item.description, item.category.as_deref()) == fp)
+    });
+    match duplicate {
+        Some(id) => Err(format!(
+            "Item {} is an active listing of yours with the same description, set allow_duplicate to list this one anyway.",
+            id
+        )),
+        None => Ok(()),
+    }
+}
+
+//active listings of one owner sharing a fingerprint
+#[query(name = "getDuplicateClusters")]
+fn get_duplicate_clusters() -> Result<Vec<DuplicateCluster>, String> {
+    admin::ensure_admin()?;
+    let mut clusters: BTreeMap<(Principal, String), Vec<u64>> = BTreeMap::new();
+    ITEM_MAP.with(|items| {
+        for (id, item) in items.borrow().iter() {
+            if item.status() == Status::Active {
+                let fp = fingerprint(&item.description, item.category.as_deref());
+                clusters.entry((item.item_owner, fp)).or_default().push(id);
+            }
+        }
+    });
+    Ok(clusters
+        .into_iter()
+        .filter(|(_, ids)| ids.len() > 1)
+        .map(|((owner, fingerprint), item_ids)| DuplicateCluster {
+            owner,
+            fingerprint,
+            item_ids,
+        })
+        .collect())
+}
@@ -0,0 +1,280 @@
+use num_bigint::BigUint;
+use sha2::{Digest, Sha512};
+
+//ed25519 signature verification (rfc 8032), written out since the canister only has sha2 for crypto.
+//it is slow next to a dedicated crate but fine for the odd signed message an update call checks
+
+pub const PUBLIC_KEY_LENGTH: usize = 32;
+pub const SIGNATURE_LENGTH: usize = 64;
+
+//extended coordinates, x = X/Z, y = Y/Z, x * y = T/Z
+struct Point {
+    x: BigUint,
+    y: BigUint,
+    z: BigUint,
+    t: BigUint,
+}
+
+impl Point {
+    fn identity() -> Self {
+        Point {
+            x: BigUint::from(0u32),
+            y: BigUint::from(1u32),
+            z: BigUint::from(1u32),
+            t: BigUint::from(0u32),
+        }
+    }
+}
+
+struct Curve {
+    p: BigUint,
+    d: BigUint,
+    sqrt_m1: BigUint,
+    order: BigUint,
+    base: Point,
+}
+
+thread_local! {
+    //the constants take modular exponentiations, they are worked out once and kept on the heap
+    static CURVE: Curve = Curve::new();
+}
+
+impl Curve {
+    fn new() -> Self {
+        let p = (BigUint::from(1u32) << 255u32) - 19u32;
+        let d = (&p - 121_665u32) * BigUint::from(121_666u32).modpow(&(&p - 2u32), &p) % &p;
+        let sqrt_m1 = BigUint::from(2u32).modpow(&((&p - 1u32) >> 2u32), &p);
+        let order = (BigUint::from(1u32) << 252u32)
+            + "27742317777372353535851937790883648493"
+                .parse::<BigUint>()
+                .unwrap();
+        let mut curve = Curve {
+            p,
+            d,
+            sqrt_m1,
+            order,
+            base: Point::identity(),
+        };
+        let y = curve.mul(&BigUint::from(4u32), &curve.inv(&BigUint::from(5u32)));
+        curve.base = curve.decode(&to_bytes(&y)).unwrap();
+        curve
+    }
+
+    fn mul(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        a * b % &self.p
+    }
+
+    fn add(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a + b) % &self.p
+    }
+
+    fn sub(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a + &self.p - b) % &self.p
+    }
+
+    fn inv(&self, a: &BigUint) -> BigUint {
+        a.modpow(&(&self.p - 2u32), &self.p)
+    }
+
+    //the unified addition formula, it doubles as well
+    fn add_points(&self, a: &Point, b: &Point) -> Point {
+        let e1 = self.mul(&self.sub(&a.y, &a.x), &self.sub(&b.y, &b.x));
+        let e2 = self.mul(&self.add(&a.y, &a.x), &self.add(&b.y, &b.x));
+        let c = self.mul(&self.mul(&a.t, &b.t), &self.add(&self.d, &self.d));
+        let d = self.mul(&self.add(&a.z, &a.z), &b.z);
+        let (e, f, g, h) = (
+            self.sub(&e2, &e1),
+            self.sub(&d, &c),
+            self.add(&d, &c),
+            self.add(&e2, &e1),
+        );
+        Point {
+            x: self.mul(&e, &f),
+            y: self.mul(&g, &h),
+            z: self.mul(&f, &g),
+            t: self.mul(&e, &h),
+        }
+    }
+
+    fn scalar_mul(&self, scalar: &BigUint, point: &Point) -> Point {
+        let mut result = Point::identity();
+        for i in (0..scalar.bits()).rev() {
+            result = self.add_points(&result, &result);
+            if (scalar >> i) & BigUint::from(1u32) == BigUint::from(1u32) {
+                result = self.add_points(&result, point);
+            }
+        }
+        result
+    }
+
+    fn same_point(&self, a: &Point, b: &Point) -> bool {
+        self.mul(&a.x, &b.z) == self.mul(&b.x, &a.z) && self.mul(&a.y, &b.z) == self.mul(&b.y, &a.z)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<Point> {
+        let mut y_bytes: [u8; 32] = bytes.try_into().ok()?;
+        let sign = y_bytes[31] >> 7;
+        y_bytes[31] &= 0x7f;
+        let y = BigUint::from_bytes_le(&y_bytes);
+        if y >= self.p {
+            return None;
+        }
+        let y2 = self.mul(&y, &y);
+        let u = self.sub(&y2, &BigUint::from(1u32));
+        let v = self.add(&self.mul(&self.d, &y2), &BigUint::from(1u32));
+        let x2 = self.mul(&u, &self.inv(&v));
+        let mut x = x2.modpow(&((&self.p + 3u32) >> 3u32), &self.p);
+        if self.mul(&x, &x) != x2 {
+            x = self.mul(&x, &self.sqrt_m1);
+        }
+        if self.mul(&x, &x) != x2 {
+            return None;
+        }
+        let x_is_zero = x == BigUint::from(0u32);
+        if x_is_zero && sign == 1 {
+            return None;
+        }
+        if !x_is_zero && to_bytes(&x)[0] & 1 != sign {
+            x = &self.p - x;
+        }
+        let t = self.mul(&x, &y);
+        Some(Point {
+            x,
+            y,
+            z: BigUint::from(1u32),
+            t,
+        })
+    }
+}
+
+fn to_bytes(n: &BigUint) -> [u8; 32] {
+    let mut bytes = n.to_bytes_le();
+    bytes.resize(32, 0);
+    bytes.try_into().unwrap()
+}
+
+/// Whether `signature` is a valid ed25519 signature of `message` by `public_key`.
+pub fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    if public_key.len() != PUBLIC_KEY_LENGTH || signature.len() != SIGNATURE_LENGTH {
+        return false;
+    }
+    CURVE.with(|curve| verify_on(curve, public_key, message, signature))
+}
+
+fn verify_on(curve: &Curve, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let (Some(a), Some(r)) = (curve.decode(public_key), curve.decode(&signature[..32])) else {
+        return false;
+    };
+    //s has to be reduced, s + order would verify as well and make signatures malleable
+    let s = BigUint::from_bytes_le(&signature[32..]);
+    if s >= curve.order {
+        return false;
+    }
+    let digest = Sha512::new()
+        .chain_update(&signature[..32])
+        .chain_update(public_key)
+        .chain_update(message)
+        .finalize();
+    let k = BigUint::from_bytes_le(&digest) % &curve.order;
+    let lhs = curve.scalar_mul(&s, &curve.base);
+    let rhs = curve.add_points(&r, &curve.scalar_mul(&k, &a));
+    curve.same_point(&lhs, &rhs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    //rfc 8032 section 7.1, tests 1 to 3 and SHA(abc)
+    const VECTORS: &[(&str, &str, &str)] = &[
+        (
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            "",
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+             5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ),
+        (
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            "72",
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+             085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ),
+        (
+            "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+            "af82",
+            "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac\
+             18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+        ),
+        (
+            "ec172b93ad5e563bf4932c70e1245034c35467ef2efd4d64ebf819683467e2bf",
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+             2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+            "dc2a4459e7369633a52b1bf277839a00201009a3efbf3ecb69bea2186c26b589\
+             09351fc9ac90b3ecfdfbc7c66431e0303dca179c138ac17ad9bef1177331a704",
+        ),
+    ];
+
+    #[test]
+    fn accepts_the_rfc_vectors() {
+        for (public_key, message, signature) in VECTORS {
+            assert!(verify(&hex(public_key), &hex(message), &hex(signature)));
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_messages_and_other_keys() {
+        let (public_key, _, signature) = VECTORS[1];
+        assert!(!verify(&hex(public_key), &hex("73"), &hex(signature)));
+        let (other_key, _, _) = VECTORS[2];
+        assert!(!verify(&hex(other_key), &hex("72"), &hex(signature)));
+        let mut flipped = hex(signature);
+        flipped[0] ^= 1;
+        assert!(!verify(&hex(public_key), &hex("72"), &flipped));
+    }
+
+    #[test]
+    fn rejects_non_canonical_s() {
+        let (public_key, message, signature) = VECTORS[0];
+        let signature = hex(signature);
+        let order = CURVE.with(|c| c.order.clone());
+        //s + order is the same scalar, a verifier that does not reduce-check would accept it
+        let s = BigUint::from_bytes_le(&signature[32..]) + order;
+        let mut malleated = signature[..32].to_vec();
+        malleated.extend_from_slice(&to_bytes(&s));
+        assert!(!verify(&hex(public_key), &hex(message), &malleated));
+
+        let mut high_bits = signature.clone();
+        high_bits[63] |= 0xe0;
+        assert!(!verify(&hex(public_key), &hex(message), &high_bits));
+    }
+
+    #[test]
+    fn rejects_non_canonical_points_and_bad_lengths() {
+        let (public_key, message, signature) = VECTORS[0];
+        //y = p encodes as y = 0 when not range checked
+        let mut y_is_p = [0xffu8; 32];
+        y_is_p[0] = 0xed;
+        y_is_p[31] = 0x7f;
+        assert!(!verify(&y_is_p, &hex(message), &hex(signature)));
+        let mut r_is_p = hex(signature);
+        r_is_p[..32].copy_from_slice(&y_is_p);
+        assert!(!verify(&hex(public_key), &hex(message), &r_is_p));
+        assert!(!verify(
+            &hex(public_key)[..31],
+            &hex(message),
+            &hex(signature)
+        ));
+        assert!(!verify(
+            &hex(public_key),
+            &hex(message),
+            &hex(signature)[..63]
+        ));
+    }
+}
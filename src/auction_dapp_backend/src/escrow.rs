@@ -0,0 +1,378 @@
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_cdk::{
+    api::call::{msg_cycles_accept128, msg_cycles_available128},
+    caller, query, update,
+};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell, time::Duration};
+
+use crate::{
+    admin, approvals, balances,
+    blocks::{self, FinancialOp},
+    bonds,
+    clock::time,
+    dashboard, referrals, settlement, validation, Memory, ITEM_MAP, MEMORY_MANAGER,
+};
+
+//how long the buyer has to pay after the sale is settled, unless the item sets its own window
+const PAYMENT_WINDOW_NS: u64 = 3 * 24 * 60 * 60 * 1_000_000_000;
+//how long the buyer has to confirm delivery or report a problem before the seller is paid anyway
+const DELIVERY_CONFIRMATION_WINDOW_NS: u64 = 14 * 24 * 60 * 60 * 1_000_000_000;
+const RELEASE_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_DISPUTE_LENGTH: usize = 400;
+
+#[derive(Deserialize, CandidType, Clone, Copy, PartialEq)]
+pub enum EscrowState {
+    AwaitingPayment,
+    Held,
+    Disputed,
+    Released,
+    Refunded,
+    Defaulted,        //the buyer did not pay in time
+    AwaitingApproval, //a large release waiting for approvers, see approvals.rs
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct Dispute {
+    reason: String,
+    opened_at: u64,
+    resolved_at: Option<u64>,
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct Escrow {
+    item_id: u64,
+    amount: u64, //in cycles, attached by the buyer to payForItem
+    state: EscrowState,
+    paid_at: Option<u64>,
+    payment_due: Option<u64>,
+    delivery_deadline: Option<u64>,
+    dispute: Option<Dispute>,
+}
+
+impl Storable for Escrow {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//an escrow with a dispute of MAX_DISPUTE_LENGTH 4-byte characters takes 1_782 bytes
+impl BoundedStorable for Escrow {
+    const MAX_SIZE: u32 = 2_000;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static ESCROW_MAP: RefCell<StableBTreeMap<u64, Escrow, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))));
+}
+
+/// Called when a settlement is created, the buyer still has to pay into it.
+pub fn open(item_id: u64, amount: u64) {
+    let window = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .and_then(|item| item.payment_window)
+        .unwrap_or(PAYMENT_WINDOW_NS);
+    let escrow = Escrow {
+        item_id,
+        amount,
+        state: EscrowState::AwaitingPayment,
+        paid_at: None,
+        payment_due: Some(time() + window),
+        delivery_deadline: None,
+        dispute: None,
+    };
+    ESCROW_MAP.with(|e| e.borrow_mut().insert(item_id, escrow));
+}
+
+pub fn state_of(item_id: u64) -> Option<EscrowState> {
+    ESCROW_MAP
+        .with(|e| e.borrow().get(&item_id))
+        .map(|e| e.state)
+}
+
+/// Cycles paid in and not yet released or refunded.
+pub fn held_total() -> u128 {
+    ESCROW_MAP.with(|e| {
+        e.borrow()
+            .iter()
+            .filter(|(_, v)| {
+                matches!(
+                    v.state,
+                    EscrowState::Held | EscrowState::Disputed | EscrowState::AwaitingApproval
+                )
+            })
+            .map(|(_, v)| v.amount as u128)
+            .sum()
+    })
+}
+
+fn is_overdue(escrow: &Escrow, now: u64) -> bool {
+    escrow.state == EscrowState::AwaitingPayment && escrow.payment_due.is_some_and(|d| d <= now)
+}
+
+/// Marks the sale defaulted if its payment window has passed, so callers need not wait for the timer.
+pub fn default_if_overdue(item_id: u64) -> bool {
+    let Some(mut escrow) = ESCROW_MAP.with(|e| e.borrow().get(&item_id)) else {
+        return false;
+    };
+    if is_overdue(&escrow, time()) {
+        escrow.state = EscrowState::Defaulted;
+        save_escrow(escrow.clone());
+        settlement::mark_defaulted(item_id);
+    }
+    escrow.state == EscrowState::Defaulted
+}
+
+pub fn start_release_timer() {
+    ic_cdk_timers::set_timer_interval(RELEASE_CHECK_INTERVAL, || {
+        release_expired();
+        default_overdue();
+    });
+}
+
+fn find_escrow(item_id: u64) -> Result<Escrow, String> {
+    ESCROW_MAP
+        .with(|e| e.borrow().get(&item_id))
+        .ok_or_else(|| "No escrow exists for this item.".to_string())
+}
+
+fn save_escrow(escrow: Escrow) {
+    ESCROW_MAP.with(|e| e.borrow_mut().insert(escrow.item_id, escrow));
+}
+
+//hands the held amount to the seller or back to the buyer and closes the escrow
+fn finish(mut escrow: Escrow, refund_buyer: bool) -> Result<Escrow, String> {
+    if !refund_buyer
+        && escrow.state != EscrowState::AwaitingApproval
+        && approvals::requires_approval(escrow.amount)
+    {
+        approvals::queue(escrow.item_id, escrow.amount);
+        escrow.state = EscrowState::AwaitingApproval;
+        save_escrow(escrow.clone());
+        return Ok(escrow);
+    }
+    let settlement = settlement::find_settlement(escrow.item_id)?;
+    if refund_buyer {
+        balances::credit(settlement.buyer, escrow.amount as u128);
+        blocks::append(
+            FinancialOp::Refund,
+            escrow.amount as u128,
+            None,
+            Some(settlement.buyer),
+            Some(escrow.item_id),
+        );
+        escrow.state = EscrowState::Refunded;
+    } else {
+        let (payout, commission) = settlement.split(escrow.amount as u128);
+        balances::credit(settlement.seller, payout);
+        blocks::append(
+            FinancialOp::Payout,
+            payout,
+            None,
+            Some(settlement.seller),
+            Some(escrow.item_id),
+        );
+        if commission > 0 {
+            blocks::append(
+                FinancialOp::Fee,
+                commission,
+                Some(settlement.seller),
+                None,
+                Some(escrow.item_id),
+            );
+        }
+        escrow.state = EscrowState::Released;
+        referrals::purchase_completed(settlement.buyer);
+    }
+    if let Some(dispute) = escrow.dispute.as_mut() {
+        dispute.resolved_at = Some(time());
+    }
+    save_escrow(escrow.clone());
+    Ok(escrow)
+}
+
+/// Closes an escrow the approvers decided on.
+pub fn finish_approved(item_id: u64, refund_buyer: bool) -> Result<Escrow, String> {
+    let escrow = find_escrow(item_id)?;
+    if escrow.state != EscrowState::AwaitingApproval {
+        return Err("This payment is not waiting for approval.".to_string());
+    }
+    finish(escrow, refund_buyer)
+}
+
+fn release_expired() {
+    let now = time();
+    let expired: Vec<Escrow> = ESCROW_MAP.with(|e| {
+        e.borrow()
+            .iter()
+            .map(|(_, v)| v)
+            .filter(|v| {
+                v.state == EscrowState::Held && v.delivery_deadline.is_some_and(|d| d <= now)
+            })
+            .collect()
+    });
+    for escrow in expired {
+        let _ = finish(escrow, false);
+    }
+}
+
+fn default_overdue() {
+    let now = time();
+    let overdue: Vec<u64> = ESCROW_MAP.with(|e| {
+        e.borrow()
+            .iter()
+            .filter(|(_, v)| is_overdue(v, now))
+            .map(|(k, _)| k)
+            .collect()
+    });
+    for item_id in overdue {
+        default_if_overdue(item_id);
+    }
+}
+
+#[update(name = "payForItem")]
+fn pay_for_item(item_id: u64) -> Result<String, String> {
+    let settlement = settlement::find_settlement(item_id)?;
+    let mut escrow = find_escrow(item_id)?;
+
+    if settlement.buyer != caller() {
+        return Err("Only the buyer can pay for this item.".to_string());
+    }
+    if default_if_overdue(item_id) {
+        return Err("The payment window for this item has passed.".to_string());
+    }
+    if escrow.state != EscrowState::AwaitingPayment {
+        return Err("This item has already been paid for.".to_string());
+    }
+    if msg_cycles_available128() < escrow.amount as u128 {
+        return Err(format!(
+            "Attach at least {} cycles to pay for this item.",
+            escrow.amount
+        ));
+    }
+
+    msg_cycles_accept128(escrow.amount as u128);
+    blocks::append(
+        FinancialOp::Deposit,
+        escrow.amount as u128,
+        Some(settlement.buyer),
+        None,
+        Some(item_id),
+    );
+    dashboard::payment_made(settlement.buyer, escrow.amount);
+    bonds::release(item_id, settlement.buyer);
+    let now = time();
+    escrow.state = EscrowState::Held;
+    escrow.paid_at = Some(now);
+    escrow.delivery_deadline = Some(now + DELIVERY_CONFIRMATION_WINDOW_NS);
+    save_escrow(escrow);
+    Ok(format!(
+        "Payment for item {} is held in escrow until delivery is confirmed",
+        item_id
+    ))
+}
+
+#[update(name = "confirmDelivery")]
+fn confirm_delivery(item_id: u64) -> Result<String, String> {
+    let settlement = settlement::find_settlement(item_id)?;
+    let escrow = find_escrow(item_id)?;
+
+    if settlement.buyer != caller() {
+        return Err("Only the buyer can confirm delivery.".to_string());
+    }
+    if escrow.state != EscrowState::Held {
+        return Err("There is no held payment to release for this item.".to_string());
+    }
+
+    let escrow = finish(escrow, false)?;
+    Ok(release_message(&escrow))
+}
+
+#[update(name = "reportProblem")]
+fn report_problem(item_id: u64, reason: String) -> Result<String, String> {
+    let settlement = settlement::find_settlement(item_id)?;
+    let mut escrow = find_escrow(item_id)?;
+
+    if settlement.buyer != caller() {
+        return Err("Only the buyer can report a problem with this item.".to_string());
+    }
+    if escrow.state != EscrowState::Held {
+        return Err("Problems can only be reported while the payment is held.".to_string());
+    }
+    if reason.trim().is_empty() {
+        return Err("Please describe the problem.".to_string());
+    }
+    let reason = validation::text("Problem", &reason, MAX_DISPUTE_LENGTH)?;
+
+    escrow.state = EscrowState::Disputed;
+    escrow.dispute = Some(Dispute {
+        reason,
+        opened_at: time(),
+        resolved_at: None,
+    });
+    save_escrow(escrow);
+    Ok("The payment is frozen until an admin resolves the dispute.".to_string())
+}
+
+#[update(name = "resolveDispute")]
+fn resolve_dispute(item_id: u64, refund_buyer: bool) -> Result<String, String> {
+    admin::ensure_admin()?;
+    let escrow = find_escrow(item_id)?;
+
+    if escrow.state != EscrowState::Disputed {
+        return Err("There is no open dispute for this item.".to_string());
+    }
+
+    let escrow = finish(escrow, refund_buyer)?;
+    Ok(release_message(&escrow))
+}
+
+fn release_message(escrow: &Escrow) -> String {
+    match escrow.state {
+        EscrowState::Refunded => {
+            format!("Payment for item {} refunded to the buyer", escrow.item_id)
+        }
+        EscrowState::AwaitingApproval => format!(
+            "Payment for item {} is waiting for approval before it is released",
+            escrow.item_id
+        ),
+        _ => format!("Payment for item {} released to the seller", escrow.item_id),
+    }
+}
+
+//only the two parties and admins can see the escrow of a sale
+#[query(name = "getEscrow")]
+fn get_escrow(item_id: u64) -> Option<Escrow> {
+    let settlement = settlement::find_settlement(item_id).ok()?;
+    if settlement.buyer != caller() && settlement.seller != caller() && !admin::is_admin(caller()) {
+        return None;
+    }
+    ESCROW_MAP.with(|e| e.borrow().get(&item_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_fits;
+
+    #[test]
+    fn the_longest_dispute_fits() {
+        assert_fits(&Escrow {
+            item_id: u64::MAX,
+            amount: u64::MAX,
+            state: EscrowState::Disputed,
+            paid_at: Some(u64::MAX),
+            payment_due: Some(u64::MAX),
+            delivery_deadline: Some(u64::MAX),
+            dispute: Some(Dispute {
+                reason: "\u{1F600}".repeat(MAX_DISPUTE_LENGTH),
+                opened_at: u64::MAX,
+                resolved_at: Some(u64::MAX),
+            }),
+        });
+    }
+}
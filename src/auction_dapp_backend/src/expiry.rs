@@ -0,0 +1,316 @@
+use candid::CandidType;
+use candid::Principal;
+use ic_cdk::{caller, update};
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap};
+use std::{cell::RefCell, time::Duration};
+
+use crate::{
+    admin, allowance_bids, analytics, bonds, budget,
+    clock::time,
+    close_timers, cycle_bids, dashboard, follows, indexer_sync, is_visible_to, relisting,
+    settlement::{self, SettlementSource},
+    stats,
+    subscriptions::{self, AuctionEvent},
+    winners::{self, Decision},
+    Item, Memory, Status, StorablePrincipal, ITEM_MAP, MEMORY_MANAGER,
+};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+//keeps a single sweep comfortably within the per-message instruction limit
+const MAX_SWEEP_LIMIT: u64 = 200;
+const TIMER_SWEEP_LIMIT: u64 = 50;
+
+#[derive(CandidType, Default)]
+struct SweepResult {
+    activated: u64, //scheduled items whose bidding opened
+    settled: u64,
+    deactivated: u64,
+    has_more: bool, //true when expired items are left for the next sweep
+}
+
+//(owner, (result_date, item_id))
+type SellerEndKey = (StorablePrincipal, (u64, u64));
+
+thread_local! {
+    //(result_date, item_id) of every active item, so expired ones can be found without a full scan
+    static END_TIME_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))));
+
+    //the same entries grouped by owner, for per-seller views
+    static SELLER_END_INDEX: RefCell<StableBTreeMap<SellerEndKey, (), Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))));
+
+    //(starts_at, item_id) of scheduled items, they become active once the sweep reaches them
+    static START_TIME_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))));
+}
+
+/// Adds an active item to the indexes, this is also what counts it as an active listing of its owner.
+pub fn index_insert(item: &Item) {
+    let key = (item.result_date, item.id);
+    close_timers::schedule(item.id, item.result_date);
+    END_TIME_INDEX.with(|idx| idx.borrow_mut().insert(key, ()));
+    let is_new = SELLER_END_INDEX
+        .with(|idx| idx.borrow_mut().insert((item.item_owner.into(), key), ()))
+        .is_none();
+    if is_new {
+        dashboard::listing_opened(item.item_owner);
+        stats::listing_opened();
+    }
+}
+
+pub fn index_remove(item: &Item) {
+    let key = (item.result_date, item.id);
+    //a timer that already fired is gone, and an early close records its own cancellation
+    close_timers::cancel(item.id);
+    END_TIME_INDEX.with(|idx| idx.borrow_mut().remove(&key));
+    let was_indexed = SELLER_END_INDEX
+        .with(|idx| idx.borrow_mut().remove(&(item.item_owner.into(), key)))
+        .is_some();
+    if was_indexed {
+        dashboard::listing_closed(item.item_owner);
+        stats::listing_closed();
+    }
+}
+
+pub fn schedule_insert(item: &Item) {
+    if let Some(starts_at) = item.starts_at {
+        START_TIME_INDEX.with(|idx| idx.borrow_mut().insert((starts_at, item.id), ()));
+    }
+}
+
+pub fn schedule_remove(item: &Item) {
+    if let Some(starts_at) = item.starts_at {
+        START_TIME_INDEX.with(|idx| idx.borrow_mut().remove(&(starts_at, item.id)));
+    }
+}
+
+/// End time index entries as (result_date, item_id), from `from` on.
+pub fn indexed_from(from: (u64, u64), limit: usize) -> Vec<(u64, u64)> {
+    END_TIME_INDEX.with(|idx| {
+        idx.borrow()
+            .range(from..)
+            .take(limit)
+            .map(|(k, _)| k)
+            .collect()
+    })
+}
+
+pub fn is_indexed(item: &Item) -> bool {
+    let key = (item.result_date, item.id);
+    END_TIME_INDEX.with(|idx| idx.borrow().contains_key(&key))
+        && SELLER_END_INDEX.with(|idx| idx.borrow().contains_key(&(item.item_owner.into(), key)))
+}
+
+//index entries pointing at missing, inactive or rescheduled items
+pub fn stale_entries() -> Vec<(u64, &'static str)> {
+    let check = |(result_date, id): (u64, u64)| match ITEM_MAP.with(|items| items.borrow().get(&id))
+    {
+        None => Some((id, "the item does not exist")),
+        Some(item) if !item.is_active => Some((id, "the item is not active")),
+        Some(item) if item.result_date != result_date => Some((id, "the end time is outdated")),
+        Some(_) => None,
+    };
+    let mut stale: Vec<(u64, &'static str)> =
+        END_TIME_INDEX.with(|idx| idx.borrow().iter().filter_map(|(k, _)| check(k)).collect());
+    stale.extend(SELLER_END_INDEX.with(|idx| {
+        idx.borrow()
+            .iter()
+            .filter_map(|((_, k), _)| check(k))
+            .collect::<Vec<_>>()
+    }));
+    stale
+}
+
+pub fn seller_items_ending_between(seller: Principal, from: u64, to: u64) -> u64 {
+    let seller = StorablePrincipal::from(seller);
+    SELLER_END_INDEX.with(|idx| {
+        idx.borrow()
+            .range((seller, (from, 0))..(seller, (to, 0)))
+            .count() as u64
+    })
+}
+
+pub fn seller_active_ids(seller: Principal) -> Vec<u64> {
+    let seller = StorablePrincipal::from(seller);
+    SELLER_END_INDEX.with(|idx| {
+        idx.borrow()
+            .range((seller, (0, 0))..=(seller, (u64::MAX, u64::MAX)))
+            .map(|((_, (_, id)), _)| id)
+            .collect()
+    })
+}
+
+/// When the seller's active listing that ends first ends.
+pub fn seller_first_end(seller: Principal) -> Option<u64> {
+    let seller = StorablePrincipal::from(seller);
+    SELLER_END_INDEX.with(|idx| {
+        idx.borrow()
+            .range((seller, (0, 0))..=(seller, (u64::MAX, u64::MAX)))
+            .next()
+            .map(|((_, (result_date, _)), _)| result_date)
+    })
+}
+
+//items listed before the indexes existed are added once, as a schema migration
+pub fn backfill_index() {
+    if SELLER_END_INDEX.with(|idx| !idx.borrow().is_empty()) {
+        return;
+    }
+    ITEM_MAP.with(|items| {
+        for (_, item) in items.borrow().iter() {
+            if item.is_active {
+                index_insert(&item);
+            }
+        }
+    });
+}
+
+/// Active items closing next, soonest first.
+pub fn ending_soon(limit: usize) -> Vec<Item> {
+    let now = time();
+    END_TIME_INDEX.with(|idx| {
+        idx.borrow()
+            .range((now, 0)..)
+            .filter_map(|((_, id), _)| ITEM_MAP.with(|items| items.borrow().get(&id)))
+            .filter(|item| item.is_active && is_visible_to(item, caller()))
+            .take(limit)
+            .collect()
+    })
+}
+
+/// (result_date, item_id) of the active items that close from now until `until`, soonest first.
+pub fn ending_before(until: u64) -> Vec<(u64, u64)> {
+    END_TIME_INDEX.with(|idx| {
+        idx.borrow()
+            .range((time(), 0)..(until, 0))
+            .map(|(key, _)| key)
+            .filter(|(_, id)| {
+                ITEM_MAP.with(|items| items.borrow().get(id).is_some_and(|item| item.is_active))
+            })
+            .collect()
+    })
+}
+
+pub fn start_sweep_timer() {
+    ic_cdk_timers::set_timer_interval(SWEEP_INTERVAL, timer_sweep);
+}
+
+//a backlog of expired items is worked off in follow-up messages instead of waiting for the next interval
+fn timer_sweep() {
+    if sweep(TIMER_SWEEP_LIMIT).has_more {
+        budget::resume(timer_sweep);
+    }
+}
+
+//closes up to `limit` items whose result_date has passed, selling to the highest bidder if there is one
+fn sweep(limit: u64) -> SweepResult {
+    let now = time();
+    let limit = limit.min(MAX_SWEEP_LIMIT) as usize;
+    let mut result = SweepResult::default();
+
+    let due: Vec<(u64, u64)> = START_TIME_INDEX.with(|idx| {
+        idx.borrow()
+            .range(..(now, u64::MAX))
+            .take(limit + 1)
+            .map(|(k, _)| k)
+            .collect()
+    });
+    result.has_more = due.len() > limit;
+    for key in due.into_iter().take(limit) {
+        if budget::exhausted() {
+            result.has_more = true;
+            break;
+        }
+        START_TIME_INDEX.with(|idx| idx.borrow_mut().remove(&key));
+        let Some(mut item) = ITEM_MAP.with(|items| items.borrow().get(&key.1)) else {
+            continue;
+        };
+        if item.set_status(Status::Active).is_ok() {
+            index_insert(&item);
+            follows::item_went_live(&item);
+            ITEM_MAP.with(|items| items.borrow_mut().insert(key.1, item));
+            indexer_sync::item_changed(key.1);
+            result.activated += 1;
+        }
+    }
+
+    let expired: Vec<(u64, u64)> = END_TIME_INDEX.with(|idx| {
+        idx.borrow()
+            .range(..(now, u64::MAX))
+            .take(limit + 1)
+            .map(|(k, _)| k)
+            .collect()
+    });
+    result.has_more |= expired.len() > limit;
+
+    for key in expired.into_iter().take(limit) {
+        if budget::exhausted() {
+            result.has_more = true;
+            break;
+        }
+        let Some(item) = ITEM_MAP.with(|items| items.borrow().get(&key.1)) else {
+            END_TIME_INDEX.with(|idx| idx.borrow_mut().remove(&key));
+            continue;
+        };
+        close(item, &mut result);
+    }
+    result
+}
+
+//ends an expired item's auction, selling to the highest bidder if there is one
+fn close(mut item: Item, result: &mut SweepResult) {
+    index_remove(&item);
+    if !item.is_active {
+        return;
+    }
+    let sold = match winners::decide(&item) {
+        Decision::Winner(bid) => {
+            let (buyer, price) = (bid.bidder_principal, bid.bid_amount);
+            let settled =
+                settlement::settle(item.id, buyer, price, SettlementSource::Auction).is_ok();
+            if settled {
+                winners::record(item.id, buyer, price, winners::policy_of(&item));
+            }
+            settled
+        }
+        Decision::ReserveNotMet | Decision::NoBids => false,
+    };
+    if !sold {
+        cycle_bids::release_hold(item.id);
+        allowance_bids::release_hold(item.id);
+        bonds::release_all(item.id, None);
+        relisting::relist_if_configured(&item);
+    }
+    match sold {
+        true => result.settled += 1,
+        false => {
+            //ended is also where an item waits when its sale could not be settled
+            let _ = item.set_status(Status::Ended);
+            dashboard::item_unsold(item.item_owner);
+            analytics::listing_finished(&item);
+            subscriptions::publish(AuctionEvent::AuctionClosed {
+                item_id: item.id,
+                buyer: None,
+                price: None,
+            });
+            let item_id = item.id;
+            ITEM_MAP.with(|items| items.borrow_mut().insert(item_id, item));
+            indexer_sync::item_changed(item_id);
+            result.deactivated += 1;
+        }
+    }
+}
+
+/// Closes the item if its auction is due, called by its close timer.
+pub fn close_if_due(item_id: u64) {
+    let Some(item) = ITEM_MAP.with(|items| items.borrow().get(&item_id)) else {
+        return;
+    };
+    if item.is_active && item.result_date <= time() {
+        close(item, &mut SweepResult::default());
+    }
+}
+
+#[update(name = "sweepExpired")]
+fn sweep_expired(limit: u64) -> Result<SweepResult, String> {
+    admin::ensure_admin()?;
+    Ok(sweep(limit))
+}
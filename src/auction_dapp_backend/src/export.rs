@@ -0,0 +1,41 @@
+use candid::{CandidType, Encode};
+use ic_cdk::query;
+
+use crate::{admin, bid_store, ItemView, ITEM_MAP};
+
+//well below the reply size limit, leaving room for the candid envelope
+const MAX_CHUNK_BYTES: usize = 1_500_000;
+const MAX_CHUNK_ITEMS: usize = 500;
+
+#[derive(CandidType)]
+struct ItemsChunk {
+    items: Vec<ItemView>,     //with their bids, in id order
+    next_cursor: Option<u64>, //pass it back to read the next chunk, none once every item was read
+}
+
+//items changed between two chunks show up as they were when their chunk was read
+#[query(name = "exportItemsChunk")]
+fn export_items_chunk(cursor: Option<u64>) -> Result<ItemsChunk, String> {
+    admin::ensure_admin()?;
+    let mut items = vec![];
+    let mut bytes = 0;
+    let mut next = cursor.unwrap_or(0);
+    while let Some((id, item)) = ITEM_MAP.with(|m| m.borrow().range(next..).next()) {
+        let item = ItemView::from(bid_store::attach(item));
+        let size = Encode!(&item).map_or(0, |b| b.len());
+        //a single item larger than a chunk still goes out on its own
+        if !items.is_empty() && (bytes + size > MAX_CHUNK_BYTES || items.len() == MAX_CHUNK_ITEMS) {
+            return Ok(ItemsChunk {
+                items,
+                next_cursor: Some(id),
+            });
+        }
+        bytes += size;
+        items.push(item);
+        next = id + 1;
+    }
+    Ok(ItemsChunk {
+        items,
+        next_cursor: None,
+    })
+}
@@ -0,0 +1,295 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    admin, allowance_bids, bid_store, Item, Memory, StorablePrincipal, ITEM_MAP, MEMORY_MANAGER,
+};
+
+//caps are per currency, the amounts of different ledgers cannot be added up
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct ExposureCaps {
+    own: Option<u128>,     //set by the user
+    imposed: Option<u128>, //set by an admin, the user cannot raise it
+}
+
+impl ExposureCaps {
+    fn effective(&self) -> Option<u128> {
+        match (self.own, self.imposed) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+impl Storable for ExposureCaps {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ExposureCaps {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//the leading bid of an active item, what it adds to its bidder's exposure
+#[derive(Deserialize, CandidType, Clone)]
+struct Lead {
+    bidder: Principal,
+    ledger: Option<Principal>,
+    amount: u128,
+}
+
+impl Storable for Lead {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Lead {
+    const MAX_SIZE: u32 = 150;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType)]
+struct Exposure {
+    locked: u128,
+    cap: Option<u128>,
+}
+
+thread_local! {
+    //keyed by (user, ledger), cycles use the default principal as their ledger
+    static CAP_MAP: RefCell<StableBTreeMap<(StorablePrincipal, StorablePrincipal), ExposureCaps, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))));
+
+    //item id -> its leading bid, only while the item is active
+    static LEADS: RefCell<StableBTreeMap<u64, Lead, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(103)))));
+
+    //the sum of a user's leads per currency, keyed like CAP_MAP
+    static LOCKED: RefCell<StableBTreeMap<(StorablePrincipal, StorablePrincipal), u128, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(104)))));
+}
+
+fn key(user: Principal, ledger: Option<Principal>) -> (StorablePrincipal, StorablePrincipal) {
+    (
+        user.into(),
+        ledger.map(StorablePrincipal::from).unwrap_or_default(),
+    )
+}
+
+fn caps_of(user: Principal, ledger: Option<Principal>) -> ExposureCaps {
+    CAP_MAP.with(|c| c.borrow().get(&key(user, ledger)).unwrap_or_default())
+}
+
+fn save(user: Principal, ledger: Option<Principal>, caps: ExposureCaps) {
+    CAP_MAP.with(|c| c.borrow_mut().insert(key(user, ledger), caps));
+}
+
+fn add_locked(lead: &Lead) {
+    let key = key(lead.bidder, lead.ledger);
+    LOCKED.with(|l| {
+        let total = l.borrow().get(&key).unwrap_or(0);
+        l.borrow_mut()
+            .insert(key, total.saturating_add(lead.amount));
+    });
+}
+
+fn remove_locked(lead: &Lead) {
+    let key = key(lead.bidder, lead.ledger);
+    LOCKED.with(|l| {
+        let total = l
+            .borrow()
+            .get(&key)
+            .unwrap_or(0)
+            .saturating_sub(lead.amount);
+        match total {
+            0 => l.borrow_mut().remove(&key),
+            _ => l.borrow_mut().insert(key, total),
+        }
+    });
+}
+
+/// Moves the item's lead to a new highest bid, the outbid bidder's exposure goes down by theirs.
+pub fn bid_placed(item: &Item, bidder: Principal, amount: u64) {
+    lead_released(item.id);
+    let lead = Lead {
+        bidder,
+        ledger: allowance_bids::ledger_of(item),
+        amount: amount as u128,
+    };
+    add_locked(&lead);
+    LEADS.with(|l| l.borrow_mut().insert(item.id, lead));
+}
+
+/// The bidder whose bid currently leads on an active item.
+pub fn leader(item_id: u64) -> Option<Principal> {
+    LEADS
+        .with(|l| l.borrow().get(&item_id))
+        .map(|lead| lead.bidder)
+}
+
+/// Called when an item stops being active, its leading bid no longer counts as stake.
+pub fn lead_released(item_id: u64) {
+    if let Some(lead) = LEADS.with(|l| l.borrow_mut().remove(&item_id)) {
+        remove_locked(&lead);
+    }
+}
+
+//the user's leading bids on open items in the currency, leaving out `except`
+pub fn locked(user: Principal, ledger: Option<Principal>, except: Option<u64>) -> u128 {
+    let total = LOCKED.with(|l| l.borrow().get(&key(user, ledger)).unwrap_or(0));
+    let excepted = except
+        .and_then(|id| LEADS.with(|l| l.borrow().get(&id)))
+        .filter(|lead| lead.bidder == user && lead.ledger == ledger)
+        .map_or(0, |lead| lead.amount);
+    total.saturating_sub(excepted)
+}
+
+//builds the leads of the items that were active when the totals were introduced, runs as a schema migration
+pub fn backfill() {
+    let active: Vec<Item> = ITEM_MAP.with(|items| {
+        items
+            .borrow()
+            .iter()
+            .map(|(_, item)| item)
+            .filter(|item| item.is_active)
+            .collect()
+    });
+    for item in active {
+        if let Some(bid) = bid_store::last(item.id) {
+            bid_placed(&item, bid.bidder_principal, bid.bid_amount);
+        }
+    }
+}
+
+/// Refuses a bid that would take the bidder's leading bids in the item's currency over their cap.
+pub fn ensure_within_cap(item: &Item, bidder: Principal, amount: u64) -> Result<(), String> {
+    let ledger = allowance_bids::ledger_of(item);
+    let Some(cap) = caps_of(bidder, ledger).effective() else {
+        return Ok(());
+    };
+    let exposure = locked(bidder, ledger, Some(item.id)) + amount as u128;
+    if exposure > cap {
+        return Err(format!(
+            "This bid would put {} at stake across your leading bids, above your cap of {}.",
+            exposure, cap
+        ));
+    }
+    Ok(())
+}
+
+//no cap lifts the user's own limit, an imposed one still applies
+#[update(name = "setExposureCap")]
+fn set_exposure_cap(ledger: Option<Principal>, cap: Option<u128>) -> Result<String, String> {
+    let mut caps = caps_of(caller(), ledger);
+    caps.own = cap;
+    save(caller(), ledger, caps);
+    Ok("Exposure cap updated".to_string())
+}
+
+#[update(name = "imposeExposureCap")]
+fn impose_exposure_cap(
+    user: Principal,
+    ledger: Option<Principal>,
+    cap: Option<u128>,
+) -> Result<String, String> {
+    admin::ensure_admin()?;
+    let mut caps = caps_of(user, ledger);
+    caps.imposed = cap;
+    save(user, ledger, caps);
+    Ok(format!("Exposure cap of {} updated", user))
+}
+
+#[query(name = "getExposure")]
+fn get_exposure(ledger: Option<Principal>) -> Exposure {
+    Exposure {
+        locked: locked(caller(), ledger, None),
+        cap: caps_of(caller(), ledger).effective(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_support::{self, assert_fits, principal},
+        PaymentMode, Status,
+    };
+
+    #[test]
+    fn the_largest_caps_and_lead_fit() {
+        assert_fits(&ExposureCaps {
+            own: Some(u128::MAX),
+            imposed: Some(u128::MAX),
+        });
+        assert_fits(&Lead {
+            bidder: principal(2),
+            ledger: Some(principal(9)),
+            amount: u128::MAX,
+        });
+    }
+
+    #[test]
+    fn only_leading_bids_are_locked() {
+        let (alice, bob) = (principal(2), principal(3));
+        let first = test_support::item(1, principal(1));
+        let second = test_support::item(2, principal(1));
+        bid_placed(&first, alice, 10);
+        bid_placed(&second, alice, 5);
+        assert_eq!(locked(alice, None, None), 15);
+        assert_eq!(locked(alice, None, Some(1)), 5);
+
+        bid_placed(&first, bob, 12);
+        assert_eq!(locked(alice, None, None), 5);
+        assert_eq!(locked(bob, None, None), 12);
+        assert_eq!(locked(bob, None, Some(2)), 12);
+    }
+
+    #[test]
+    fn currencies_are_kept_apart() {
+        let ledger = principal(9);
+        let cycles = test_support::item(1, principal(1));
+        let mut tokens = test_support::item(2, principal(1));
+        tokens.payment_mode = Some(PaymentMode::Icrc2 { ledger });
+        bid_placed(&cycles, principal(2), 10);
+        bid_placed(&tokens, principal(2), 700);
+        assert_eq!(locked(principal(2), None, None), 10);
+        assert_eq!(locked(principal(2), Some(ledger), None), 700);
+    }
+
+    #[test]
+    fn closing_an_item_releases_its_lead() {
+        let mut item = test_support::item(1, principal(1));
+        bid_placed(&item, principal(2), 10);
+        item.set_status(Status::Ended).unwrap();
+        assert_eq!(locked(principal(2), None, None), 0);
+        assert!(LOCKED.with(|l| l.borrow().is_empty()));
+    }
+
+    #[test]
+    fn caps_apply_to_the_locked_total() {
+        let first = test_support::item(1, principal(1));
+        let second = test_support::item(2, principal(1));
+        save(
+            principal(2),
+            None,
+            ExposureCaps {
+                own: Some(20),
+                imposed: None,
+            },
+        );
+        bid_placed(&first, principal(2), 15);
+        assert!(ensure_within_cap(&second, principal(2), 5).is_ok());
+        assert!(ensure_within_cap(&second, principal(2), 6).is_err());
+        //raising their own lead only counts the new amount
+        assert!(ensure_within_cap(&first, principal(2), 20).is_ok());
+    }
+}
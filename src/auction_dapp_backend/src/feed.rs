@@ -0,0 +1,45 @@
+use candid::CandidType;
+use ic_cdk::{caller, query};
+
+use crate::{expiry, is_visible_to, promotions, trending, Item, ItemSummary, ID_COUNTER, ITEM_MAP};
+
+const FEED_SECTION_SIZE: usize = 10;
+//newly listed walks ids backwards, this bounds how many deleted or hidden ids it skips
+const MAX_NEWEST_LOOKBACK: u64 = 200;
+
+#[derive(CandidType)]
+struct HomeFeed {
+    featured: Vec<ItemSummary>,
+    ending_soon: Vec<ItemSummary>,
+    newly_listed: Vec<ItemSummary>,
+    trending: Vec<ItemSummary>,
+}
+
+fn summarize(items: Vec<Item>) -> Vec<ItemSummary> {
+    items
+        .iter()
+        .take(FEED_SECTION_SIZE)
+        .map(ItemSummary::from)
+        .collect()
+}
+
+/// Latest active listings, newest first.
+pub fn newly_listed(limit: usize) -> Vec<Item> {
+    let next_id = ID_COUNTER.with(|counter| *counter.borrow().get());
+    (next_id.saturating_sub(MAX_NEWEST_LOOKBACK)..next_id)
+        .rev()
+        .filter_map(|id| ITEM_MAP.with(|items| items.borrow().get(&id)))
+        .filter(|item| item.is_active && is_visible_to(item, caller()))
+        .take(limit)
+        .collect()
+}
+
+#[query(name = "getHomeFeed")]
+fn get_home_feed() -> HomeFeed {
+    HomeFeed {
+        featured: summarize(promotions::featured_items()),
+        ending_soon: summarize(expiry::ending_soon(FEED_SECTION_SIZE)),
+        newly_listed: summarize(newly_listed(FEED_SECTION_SIZE)),
+        trending: summarize(trending::top(FEED_SECTION_SIZE)),
+    }
+}
@@ -0,0 +1,75 @@
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::query;
+
+use crate::{clock::time, ledgers, membership, promotions, settlement};
+
+const VOLUME_WINDOW_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+//sellers move up a tier once their settled volume over the last 30 days reaches from_volume
+#[derive(CandidType, Clone, Copy)]
+struct CommissionTier {
+    from_volume: u128,
+    bps: u64,
+}
+
+const COMMISSION_TIERS: [CommissionTier; 3] = [
+    CommissionTier {
+        from_volume: 0,
+        bps: 500,
+    },
+    CommissionTier {
+        from_volume: 10_000_000_000_000,
+        bps: 300,
+    },
+    CommissionTier {
+        from_volume: 100_000_000_000_000,
+        bps: 200,
+    },
+];
+
+/// The commission rate for the seller's next sale, their earlier sales decide the tier.
+pub fn commission_bps_for(seller: Principal) -> u64 {
+    let since = time().saturating_sub(VOLUME_WINDOW_NS);
+    let volume = settlement::trailing_volume(seller, since);
+    COMMISSION_TIERS
+        .iter()
+        .rev()
+        .find(|t| volume >= t.from_volume)
+        .map_or(0, |t| t.bps)
+        .saturating_sub(membership::perks_of(seller).commission_discount_bps)
+}
+
+#[derive(CandidType)]
+struct WithdrawalFee {
+    ledger: Principal,
+    symbol: String,
+    fee: Option<Nat>, //the ledger's transfer fee, not known until the ledger was first used
+}
+
+#[derive(CandidType)]
+struct FeeSchedule {
+    listing_fee: u128, //in cycles
+    commission_tiers: Vec<CommissionTier>,
+    promotion_price_per_day: u128, //in cycles
+    max_promotion_days: u64,
+    withdrawal_fees: Vec<WithdrawalFee>, //taken out of the withdrawn amount
+}
+
+//everything the auction charges, for frontends that would otherwise hardcode it
+#[query(name = "getFeeSchedule")]
+fn get_fee_schedule() -> FeeSchedule {
+    FeeSchedule {
+        listing_fee: 0,
+        commission_tiers: COMMISSION_TIERS.to_vec(),
+        promotion_price_per_day: promotions::PROMOTION_PRICE_PER_DAY,
+        max_promotion_days: promotions::MAX_PROMOTION_DAYS,
+        withdrawal_fees: ledgers::supported()
+            .into_iter()
+            .map(|info| WithdrawalFee {
+                fee: ledgers::last_fee(info.ledger),
+                ledger: info.ledger,
+                symbol: info.symbol,
+            })
+            .collect(),
+    }
+}
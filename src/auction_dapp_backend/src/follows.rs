@@ -0,0 +1,152 @@
+use candid::{CandidType, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap};
+use std::cell::RefCell;
+
+use crate::{
+    clock::time,
+    guard, is_visible_to,
+    notifications::{self, NotificationKind},
+    Item, ItemSummary, Memory, StorablePrincipal, ITEM_MAP, MEMORY_MANAGER,
+};
+
+const MAX_FOLLOWING: usize = 200;
+//the newest listings kept in each follower's feed, older ones drop out
+const MAX_FEED_ITEMS: usize = 100;
+const MAX_FEED_PAGE_SIZE: u64 = 50;
+
+#[derive(CandidType)]
+struct FollowedFeedPage {
+    items: Vec<ItemSummary>,  //newest first
+    next_cursor: Option<u64>, //pass it back to read older listings
+}
+
+thread_local! {
+    //(follower, seller) -> followed at
+    static FOLLOWING: RefCell<StableBTreeMap<(StorablePrincipal, StorablePrincipal), u64, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(89)))));
+
+    //(seller, follower), read when the seller lists an item
+    static FOLLOWERS: RefCell<StableBTreeMap<(StorablePrincipal, StorablePrincipal), (), Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(90)))));
+
+    //(follower, item id) -> when the item went live
+    static FEED: RefCell<StableBTreeMap<(StorablePrincipal, u64), u64, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(91)))));
+}
+
+fn following_of(follower: Principal) -> Vec<Principal> {
+    let follower = StorablePrincipal::from(follower);
+    FOLLOWING.with(|f| {
+        f.borrow()
+            .range((follower, StorablePrincipal::default())..)
+            .take_while(|((p, _), _)| *p == follower)
+            .map(|((_, seller), _)| seller.into())
+            .collect()
+    })
+}
+
+fn followers_of(seller: Principal) -> Vec<Principal> {
+    let seller = StorablePrincipal::from(seller);
+    FOLLOWERS.with(|f| {
+        f.borrow()
+            .range((seller, StorablePrincipal::default())..)
+            .take_while(|((p, _), _)| *p == seller)
+            .map(|((_, follower), _)| follower.into())
+            .collect()
+    })
+}
+
+fn feed_ids(follower: StorablePrincipal) -> Vec<u64> {
+    FEED.with(|feed| {
+        feed.borrow()
+            .range((follower, 0)..=(follower, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+/// Puts an item that just went live into the feeds of its owner's followers and notifies them.
+pub fn item_went_live(item: &Item) {
+    for follower in followers_of(item.item_owner) {
+        let key = StorablePrincipal::from(follower);
+        FEED.with(|feed| feed.borrow_mut().insert((key, item.id), time()));
+        let ids = feed_ids(key);
+        if ids.len() > MAX_FEED_ITEMS {
+            FEED.with(|feed| {
+                let mut feed = feed.borrow_mut();
+                for id in &ids[..ids.len() - MAX_FEED_ITEMS] {
+                    feed.remove(&(key, *id));
+                }
+            });
+        }
+        notifications::notify(
+            follower,
+            NotificationKind::FollowedSellerListed {
+                seller: item.item_owner,
+                item_id: item.id,
+            },
+        );
+    }
+}
+
+#[update(name = "followSeller")]
+fn follow_seller(seller: Principal) -> Result<String, String> {
+    guard::check_optional_write()?;
+    if seller == caller() {
+        return Err("You cannot follow yourself.".to_string());
+    }
+    let key = (caller().into(), seller.into());
+    if FOLLOWING.with(|f| f.borrow().contains_key(&key)) {
+        return Err("You already follow this seller.".to_string());
+    }
+    if following_of(caller()).len() >= MAX_FOLLOWING {
+        return Err(format!("You can follow at most {} sellers.", MAX_FOLLOWING));
+    }
+    FOLLOWING.with(|f| f.borrow_mut().insert(key, time()));
+    FOLLOWERS.with(|f| f.borrow_mut().insert((seller.into(), caller().into()), ()));
+    Ok(format!("You now follow {}", seller))
+}
+
+//listings already in the feed stay there
+#[update(name = "unfollowSeller")]
+fn unfollow_seller(seller: Principal) -> Result<String, String> {
+    let removed = FOLLOWING.with(|f| f.borrow_mut().remove(&(caller().into(), seller.into())));
+    if removed.is_none() {
+        return Err("You do not follow this seller.".to_string());
+    }
+    FOLLOWERS.with(|f| f.borrow_mut().remove(&(seller.into(), caller().into())));
+    Ok(format!("You no longer follow {}", seller))
+}
+
+#[query(name = "getFollowing")]
+fn get_following() -> Vec<Principal> {
+    following_of(caller())
+}
+
+#[query(name = "getFollowerCount")]
+fn get_follower_count(seller: Principal) -> u64 {
+    followers_of(seller).len() as u64
+}
+
+//listings that ended or were removed since they were added are skipped
+#[query(name = "getFollowedFeed")]
+fn get_followed_feed(cursor: Option<u64>, limit: u64) -> FollowedFeedPage {
+    let viewer = caller();
+    let follower = StorablePrincipal::from(viewer);
+    let limit = limit.clamp(1, MAX_FEED_PAGE_SIZE) as usize;
+    let mut ids = feed_ids(follower);
+    if let Some(cursor) = cursor {
+        ids.retain(|id| *id < cursor);
+    }
+    let mut items = vec![];
+    let mut next_cursor = None;
+    for id in ids.into_iter().rev() {
+        if items.len() == limit {
+            next_cursor = Some(id + 1);
+            break;
+        }
+        let item = ITEM_MAP.with(|m| m.borrow().get(&id));
+        if let Some(item) = item.filter(|i| i.is_active && is_visible_to(i, viewer)) {
+            items.push(ItemSummary::from(&item));
+        }
+    }
+    FollowedFeedPage { items, next_cursor }
+}
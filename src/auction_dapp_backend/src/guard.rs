@@ -0,0 +1,143 @@
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_cdk::{
+    api::{canister_balance128, stable::stable64_size},
+    query, update,
+};
+use ic_stable_structures::{memory_manager::MemoryId, StableCell, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    admin, integrity, maintenance,
+    notifications::{self, NotificationKind},
+    Memory, MEMORY_MANAGER,
+};
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+//cycles we always want to keep on top of the freezing threshold, so settlement and upgrades can still run
+const MIN_CYCLES_BALANCE: u128 = 500_000_000_000;
+
+//the canister cannot grow stable memory past this, leaving room for a few more map nodes before it traps
+const STABLE_MEMORY_LIMIT_BYTES: u64 = 64 * 1024 * 1024 * 1024;
+const MIN_STABLE_MEMORY_HEADROOM_BYTES: u64 = 256 * 1024 * 1024;
+
+const DEFAULT_HIGH_WATER_PERCENT: u8 = 80;
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct StoragePolicy {
+    high_water_percent: Option<u8>, //of STABLE_MEMORY_LIMIT_BYTES, DEFAULT_HIGH_WATER_PERCENT when not set
+    alerted: bool,                  //admins were told about the current crossing
+}
+
+impl Storable for StoragePolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static STORAGE_POLICY: RefCell<StableCell<StoragePolicy, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(83))),
+        StoragePolicy::default()).unwrap());
+}
+
+fn storage_policy() -> StoragePolicy {
+    STORAGE_POLICY.with(|c| c.borrow().get().clone())
+}
+
+fn used_bytes() -> u64 {
+    stable64_size() * WASM_PAGE_SIZE
+}
+
+fn high_water_percent(policy: &StoragePolicy) -> u8 {
+    policy
+        .high_water_percent
+        .unwrap_or(DEFAULT_HIGH_WATER_PERCENT)
+}
+
+fn high_water_bytes(policy: &StoragePolicy) -> u64 {
+    STABLE_MEMORY_LIMIT_BYTES / 100 * high_water_percent(policy) as u64
+}
+
+/// Refuses writes that grow state (new listings, bids) once cycles or stable memory run low.
+pub fn check_write_headroom() -> Result<(), String> {
+    maintenance::ensure_available()?;
+    integrity::ensure_writable()?;
+    let balance = canister_balance128();
+    if balance < MIN_CYCLES_BALANCE {
+        return Err(format!(
+            "The auction is low on cycles ({} left), new listings and bids are paused until it is topped up.",
+            balance
+        ));
+    }
+
+    let used_bytes = used_bytes();
+    if used_bytes + MIN_STABLE_MEMORY_HEADROOM_BYTES > STABLE_MEMORY_LIMIT_BYTES {
+        return Err(format!(
+            "The auction is running out of storage ({} bytes used), new listings and bids are paused.",
+            used_bytes
+        ));
+    }
+    Ok(())
+}
+
+/// Tells the admins once when storage crosses the high-water mark, and rearms when it is back below.
+pub fn check_high_water() -> bool {
+    let mut policy = storage_policy();
+    let (used, high_water) = (used_bytes(), high_water_bytes(&policy));
+    let above = used > high_water;
+    if above != policy.alerted {
+        if above {
+            for admin in admin::all() {
+                notifications::notify(
+                    admin,
+                    NotificationKind::StorageHighWater {
+                        used_bytes: used,
+                        high_water_bytes: high_water,
+                    },
+                );
+            }
+        }
+        policy.alerted = above;
+        STORAGE_POLICY.with(|c| c.borrow_mut().set(policy).unwrap());
+    }
+    above
+}
+
+/// Like check_write_headroom, but also refuses writes the auction can do without (media, messages,
+/// watchlists) above the high-water mark, so the space left goes to bids and settlements.
+pub fn check_optional_write() -> Result<(), String> {
+    check_write_headroom()?;
+    if check_high_water() {
+        return Err(format!(
+            "StorageNearlyFull: the auction is above {}% of its storage, new media, messages and watchlist entries are paused while bidding continues.",
+            high_water_percent(&storage_policy())
+        ));
+    }
+    Ok(())
+}
+
+#[update(name = "setStorageHighWaterMark")]
+fn set_storage_high_water_mark(percent: u8) -> Result<String, String> {
+    admin::ensure_admin()?;
+    if !(1..=99).contains(&percent) {
+        return Err("The high-water mark has to be between 1 and 99 percent.".to_string());
+    }
+    let mut policy = storage_policy();
+    policy.high_water_percent = Some(percent);
+    STORAGE_POLICY.with(|c| c.borrow_mut().set(policy).unwrap());
+    check_high_water();
+    Ok(format!(
+        "Non-essential writes pause above {}% of storage",
+        percent
+    ))
+}
+
+#[query(name = "getStorageHighWaterMark")]
+fn get_storage_high_water_mark() -> u8 {
+    high_water_percent(&storage_policy())
+}
@@ -0,0 +1,118 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::query;
+
+use crate::{expiry, feed, receipts, Item};
+
+const DEFAULT_FEED_SIZE: usize = 20;
+const MAX_FEED_SIZE: usize = 100;
+
+#[derive(Deserialize, CandidType)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(CandidType)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+pub fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn item_json(item: &Item) -> String {
+    format!(
+        "{{\"id\":{},\"owner\":\"{}\",\"description\":\"{}\",\"category\":{},\"highest_bid\":{},\"bid_count\":{},\"result_date\":{},\"status\":\"{:?}\"}}",
+        item.id,
+        item.item_owner,
+        escape_json(&item.description),
+        item.category
+            .as_ref()
+            .map_or("null".to_string(), |c| format!("\"{}\"", escape_json(c))),
+        item.highest_bid,
+        item.bid_count(),
+        item.result_date,
+        item.status()
+    )
+}
+
+fn feed_json(items: &[Item]) -> Vec<u8> {
+    let entries: Vec<String> = items.iter().map(item_json).collect();
+    format!("{{\"items\":[{}]}}", entries.join(",")).into_bytes()
+}
+
+fn response(status_code: u16, content_type: &str, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Cache-Control".to_string(), "max-age=30".to_string()),
+        ],
+        body,
+    }
+}
+
+//reads ?limit=N, falling back to the default for anything unparsable
+fn feed_limit(query: &str) -> usize {
+    query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("limit="))
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_FEED_SIZE)
+        .clamp(1, MAX_FEED_SIZE)
+}
+
+//responses are not certified, aggregators should poll through the raw domain
+//receipts live at /receipts/<settlement id>.json?key=<content hash>
+fn receipt_download(path: &str, query: &str) -> Option<String> {
+    let id = path
+        .strip_prefix("/receipts/")?
+        .strip_suffix(".json")?
+        .parse()
+        .ok()?;
+    let key = query.split('&').find_map(|p| p.strip_prefix("key="))?;
+    receipts::json_for_key(id, key)
+}
+
+#[query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    if req.method != "GET" {
+        return response(405, "text/plain", b"Method not allowed".to_vec());
+    }
+    let (path, query) = req.url.split_once('?').unwrap_or((&req.url, ""));
+    let limit = feed_limit(query);
+
+    match path {
+        "/feed/new-items.json" => response(
+            200,
+            "application/json",
+            feed_json(&feed::newly_listed(limit)),
+        ),
+        "/feed/ending-soon.json" => response(
+            200,
+            "application/json",
+            feed_json(&expiry::ending_soon(limit)),
+        ),
+        _ => match receipt_download(path, query) {
+            Some(json) => response(200, "application/json", json.into_bytes()),
+            None => response(404, "text/plain", b"Not found".to_vec()),
+        },
+    }
+}
@@ -0,0 +1,170 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+
+use crate::{
+    blocks::{GetBlocksArgs, GetBlocksResult, Value},
+    ledgers,
+};
+
+//the subset of the ICRC-1 and ICRC-2 ledger interfaces the auction talks to
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Account {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+#[derive(CandidType)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Allowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+fn unreachable_ledger((_, msg): (ic_cdk::api::call::RejectionCode, String)) -> String {
+    format!("The token ledger could not be reached: {}", msg)
+}
+
+pub async fn balance_of(ledger: Principal, account: Account) -> Result<Nat, String> {
+    let (balance,): (Nat,) = ic_cdk::call(ledger, "icrc1_balance_of", (account,))
+        .await
+        .map_err(unreachable_ledger)?;
+    Ok(balance)
+}
+
+pub async fn decimals(ledger: Principal) -> Result<u8, String> {
+    let (decimals,): (u8,) = ic_cdk::call(ledger, "icrc1_decimals", ())
+        .await
+        .map_err(unreachable_ledger)?;
+    Ok(decimals)
+}
+
+pub async fn fee(ledger: Principal) -> Result<Nat, String> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(unreachable_ledger)?;
+    ledgers::remember_fee(ledger, &fee);
+    Ok(fee)
+}
+
+/// Returns the ledger block index of the transfer.
+pub async fn transfer(ledger: Principal, args: TransferArg) -> Result<Nat, String> {
+    transfer_raw(ledger, args)
+        .await?
+        .map_err(|e| format!("The token transfer failed: {:?}", e))
+}
+
+//keeps the ledger's own rejection apart from not reaching the ledger at all
+pub async fn transfer_raw(
+    ledger: Principal,
+    args: TransferArg,
+) -> Result<Result<Nat, TransferError>, String> {
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (args,))
+        .await
+        .map_err(unreachable_ledger)?;
+    Ok(result)
+}
+
+pub async fn allowance(ledger: Principal, args: AllowanceArgs) -> Result<Allowance, String> {
+    let (allowance,): (Allowance,) = ic_cdk::call(ledger, "icrc2_allowance", (args,))
+        .await
+        .map_err(unreachable_ledger)?;
+    Ok(allowance)
+}
+
+/// Returns the ledger block index of the transfer.
+pub async fn transfer_from(ledger: Principal, args: TransferFromArgs) -> Result<Nat, String> {
+    let (result,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(ledger, "icrc2_transfer_from", (args,))
+            .await
+            .map_err(unreachable_ledger)?;
+    result.map_err(|e| format!("The token transfer failed: {:?}", e))
+}
+
+/// Looks a block up with ICRC-3, following the ledger to its archive when it is no longer held there.
+pub async fn get_block(ledger: Principal, index: Nat) -> Result<Option<Value>, String> {
+    let args = vec![GetBlocksArgs {
+        start: index.clone(),
+        length: Nat::from(1u8),
+    }];
+    let (result,): (GetBlocksResult,) = ic_cdk::call(ledger, "icrc3_get_blocks", (args,))
+        .await
+        .map_err(unreachable_ledger)?;
+    if let Some(found) = result.blocks.into_iter().find(|b| b.id == index) {
+        return Ok(Some(found.block));
+    }
+    let Some(archived) = result.archived_blocks.into_iter().next() else {
+        return Ok(None);
+    };
+    let (result,): (GetBlocksResult,) = ic_cdk::call(
+        archived.callback.principal,
+        &archived.callback.method,
+        (archived.args,),
+    )
+    .await
+    .map_err(unreachable_ledger)?;
+    Ok(result
+        .blocks
+        .into_iter()
+        .find(|b| b.id == index)
+        .map(|b| b.block))
+}
@@ -0,0 +1,125 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::{caller, query};
+
+use crate::{
+    bid_store, is_visible_to,
+    winners::{self, WinnerPolicy},
+    Item, ITEM_MAP,
+};
+
+const MAX_BANDS: usize = 20;
+
+//from this price on, every bid has to beat the current one by `increment`
+#[derive(Deserialize, CandidType, Clone, Copy, PartialEq)]
+pub struct IncrementBand {
+    from: u64,
+    increment: u64,
+}
+
+//used by items without their own bands, any higher bid is enough
+const DEFAULT_BANDS: &[IncrementBand] = &[IncrementBand {
+    from: 0,
+    increment: 1,
+}];
+
+/// The most increment bands an item can have, for the size tests of the Item.
+#[cfg(test)]
+pub fn largest_bands() -> Vec<IncrementBand> {
+    (0..MAX_BANDS as u64)
+        .map(|i| IncrementBand {
+            from: u64::MAX - MAX_BANDS as u64 + i,
+            increment: u64::MAX,
+        })
+        .collect()
+}
+
+pub fn validate(bands: Option<Vec<IncrementBand>>) -> Result<Option<Vec<IncrementBand>>, String> {
+    let Some(bands) = bands else {
+        return Ok(None);
+    };
+    if bands.is_empty() || bands.len() > MAX_BANDS {
+        return Err(format!(
+            "An item can have 1 to {} increment bands.",
+            MAX_BANDS
+        ));
+    }
+    if bands[0].from != 0 {
+        return Err("The first increment band has to start at 0.".to_string());
+    }
+    if bands.windows(2).any(|w| w[0].from >= w[1].from) {
+        return Err("Increment bands have to be sorted by their starting price.".to_string());
+    }
+    if bands.iter().any(|b| b.increment == 0) {
+        return Err("Increments have to be greater than 0.".to_string());
+    }
+    Ok(Some(bands))
+}
+
+/// The lowest amount the next bid on the item can be, the first one also has to reach the item's minimum.
+/// Items that give ties to the earliest bid take a bid matching the top one, it ranks behind it.
+pub fn next_minimum_bid(item: &Item) -> u64 {
+    if winners::policy_of(item) == WinnerPolicy::EarliestAtTopPrice && bid_store::has_bids(item.id)
+    {
+        return item.highest_bid;
+    }
+    let bands = item.increment_bands.as_deref().unwrap_or(DEFAULT_BANDS);
+    let increment = bands
+        .iter()
+        .rev()
+        .find(|b| b.from <= item.highest_bid)
+        .map_or(1, |b| b.increment);
+    let next = item.highest_bid.saturating_add(increment);
+    match item.minimum_bid {
+        Some(minimum) if !bid_store::has_bids(item.id) => next.max(minimum),
+        _ => next,
+    }
+}
+
+#[query(name = "getNextMinimumBid")]
+fn get_next_minimum_bid(item_id: u64) -> Result<u64, String> {
+    let item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .filter(|item| is_visible_to(item, caller()))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+    Ok(next_minimum_bid(&item))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, principal};
+
+    fn band(from: u64, increment: u64) -> IncrementBand {
+        IncrementBand { from, increment }
+    }
+
+    #[test]
+    fn the_band_of_the_current_price_applies() {
+        let mut item = test_support::item(1, principal(1));
+        item.increment_bands = validate(Some(vec![band(0, 5), band(100, 25)])).unwrap();
+        assert_eq!(next_minimum_bid(&item), 5);
+        item.highest_bid = 99;
+        assert_eq!(next_minimum_bid(&item), 104);
+        item.highest_bid = 100;
+        assert_eq!(next_minimum_bid(&item), 125);
+    }
+
+    #[test]
+    fn the_minimum_only_holds_for_the_first_bid() {
+        let mut item = test_support::item(1, principal(1));
+        item.minimum_bid = Some(40);
+        assert_eq!(next_minimum_bid(&item), 40);
+        bid_store::push(test_support::bid(1, principal(2), 40, 0));
+        item.highest_bid = 40;
+        assert_eq!(next_minimum_bid(&item), 41);
+    }
+
+    #[test]
+    fn bands_are_validated() {
+        assert!(validate(Some(vec![])).is_err());
+        assert!(validate(Some(vec![band(1, 5)])).is_err());
+        assert!(validate(Some(vec![band(0, 5), band(0, 10)])).is_err());
+        assert!(validate(Some(vec![band(0, 0)])).is_err());
+        assert!(validate(None).unwrap().is_none());
+    }
+}
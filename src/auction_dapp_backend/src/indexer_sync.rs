@@ -0,0 +1,252 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::{
+    memory_manager::MemoryId, BoundedStorable, StableBTreeMap, StableCell, Storable,
+};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    time::Duration,
+};
+
+use crate::{
+    admin, bid_store, certification, clock::time, redact_memos, sync, ItemView, Memory, ITEM_MAP,
+    MEMORY_MANAGER,
+};
+
+const INGEST_METHOD: &str = "ingestDeltas";
+const BATCH_SIZE: usize = 50;
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, CandidType, Clone)]
+enum Change {
+    ItemChanged {
+        item_id: u64,
+    },
+    ItemRemoved {
+        item_id: u64,
+    },
+    BidPlaced {
+        item_id: u64,
+        bidder: Principal,
+        amount: u64,
+        bid_date: u64,
+    },
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+struct ChangeEntry {
+    change: Change,
+    recorded_at: u64,
+}
+
+//what the indexer receives, items are sent as they are at push time
+#[derive(CandidType)]
+struct IndexerDelta {
+    seq: u64,
+    change: Change,
+    item: Option<ItemView>,
+    recorded_at: u64,
+}
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct IndexerConfig {
+    indexer: Option<Principal>,
+    next_seq: u64, //first sequence number the indexer has not acknowledged yet
+}
+
+#[derive(CandidType)]
+struct IndexerStatus {
+    indexer: Option<Principal>,
+    next_seq: u64,
+    pending: u64,
+}
+
+impl Storable for ChangeEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ChangeEntry {
+    const MAX_SIZE: u32 = 200;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for IndexerConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static CHANGE_LOG: RefCell<StableBTreeMap<u64, ChangeEntry, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))));
+
+    static INDEXER_CONFIG: RefCell<StableCell<IndexerConfig, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))),
+        IndexerConfig::default()).unwrap());
+
+    static PUSH_IN_PROGRESS: Cell<bool> = const { Cell::new(false) };
+}
+
+fn config() -> IndexerConfig {
+    INDEXER_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+//changes are only kept while an indexer is configured, it catches up from next_seq
+fn record(change: Change) {
+    let Some(_) = config().indexer else {
+        return;
+    };
+    CHANGE_LOG.with(|log| {
+        let seq = log
+            .borrow()
+            .last_key_value()
+            .map_or(config().next_seq, |(k, _)| k + 1);
+        log.borrow_mut().insert(
+            seq,
+            ChangeEntry {
+                change,
+                recorded_at: time(),
+            },
+        );
+    });
+    ic_cdk::spawn(push_batch());
+}
+
+pub fn item_changed(item_id: u64) {
+    sync::touch(item_id);
+    certification::item_changed(item_id);
+    record(Change::ItemChanged { item_id });
+}
+
+pub fn item_removed(item_id: u64) {
+    sync::touch(item_id);
+    certification::item_changed(item_id);
+    record(Change::ItemRemoved { item_id });
+}
+
+pub fn bid_placed(item_id: u64, bidder: Principal, amount: u64, bid_date: u64) {
+    sync::touch(item_id);
+    certification::item_changed(item_id);
+    record(Change::BidPlaced {
+        item_id,
+        bidder,
+        amount,
+        bid_date,
+    });
+}
+
+pub fn start_retry_timer() {
+    ic_cdk_timers::set_timer_interval(RETRY_INTERVAL, || ic_cdk::spawn(push_batch()));
+}
+
+//sends the oldest unacknowledged changes, acknowledged ones are dropped from the log
+async fn push_batch() {
+    let cfg = config();
+    let Some(indexer) = cfg.indexer else {
+        return;
+    };
+    if PUSH_IN_PROGRESS.with(|p| p.replace(true)) {
+        return;
+    }
+
+    let batch: Vec<IndexerDelta> = CHANGE_LOG.with(|log| {
+        log.borrow()
+            .range(cfg.next_seq..)
+            .take(BATCH_SIZE)
+            .map(|(seq, entry)| {
+                let item = match entry.change {
+                    Change::ItemChanged { item_id } | Change::BidPlaced { item_id, .. } => ITEM_MAP
+                        .with(|items| items.borrow().get(&item_id))
+                        .map(|item| redact_memos(bid_store::attach(item), Principal::anonymous()))
+                        .map(ItemView::from),
+                    Change::ItemRemoved { .. } => None,
+                };
+                IndexerDelta {
+                    seq,
+                    change: entry.change,
+                    item,
+                    recorded_at: entry.recorded_at,
+                }
+            })
+            .collect()
+    });
+
+    if let Some(last_seq) = batch.last().map(|d| d.seq) {
+        let result: Result<(), _> = ic_cdk::call(indexer, INGEST_METHOD, (batch,)).await;
+        //the indexer may have been replaced while the call was in flight
+        if result.is_ok() && config().indexer == Some(indexer) {
+            INDEXER_CONFIG.with(|c| {
+                let mut cfg = c.borrow().get().clone();
+                cfg.next_seq = last_seq + 1;
+                c.borrow_mut().set(cfg).unwrap();
+            });
+            CHANGE_LOG.with(|log| {
+                let acked: Vec<u64> = log.borrow().range(..=last_seq).map(|(k, _)| k).collect();
+                for seq in acked {
+                    log.borrow_mut().remove(&seq);
+                }
+            });
+        }
+    }
+    PUSH_IN_PROGRESS.with(|p| p.set(false));
+}
+
+//setting no indexer stops recording and drops pending changes
+#[update(name = "setIndexer")]
+fn set_indexer(indexer: Option<Principal>) -> Result<String, String> {
+    admin::ensure_admin()?;
+    INDEXER_CONFIG.with(|c| {
+        let mut cfg = c.borrow().get().clone();
+        cfg.indexer = indexer;
+        c.borrow_mut().set(cfg).unwrap();
+    });
+    if indexer.is_none() {
+        CHANGE_LOG.with(|log| {
+            let all: Vec<u64> = log.borrow().iter().map(|(k, _)| k).collect();
+            for seq in all {
+                log.borrow_mut().remove(&seq);
+            }
+        });
+    }
+    Ok("Indexer updated".to_string())
+}
+
+#[query(name = "getIndexerStatus")]
+fn get_indexer_status() -> Result<IndexerStatus, String> {
+    admin::ensure_admin()?;
+    let cfg = config();
+    Ok(IndexerStatus {
+        indexer: cfg.indexer,
+        next_seq: cfg.next_seq,
+        pending: CHANGE_LOG.with(|log| log.borrow().len()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    #[test]
+    fn the_largest_change_fits() {
+        assert_fits(&ChangeEntry {
+            change: Change::BidPlaced {
+                item_id: u64::MAX,
+                bidder: principal(1),
+                amount: u64::MAX,
+                bid_date: u64::MAX,
+            },
+            recorded_at: u64::MAX,
+        });
+    }
+}
@@ -0,0 +1,290 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{api::canister_balance128, pre_upgrade, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, StableCell, Storable};
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap};
+
+use crate::{
+    admin, balances, bid_store, blocks, bonds, certification, clock::time, cycle_bids, dashboard,
+    escrow, expiry, Memory, Status, ID_COUNTER, ITEM_MAP, MEMORY_MANAGER,
+};
+
+//a handful of violations is enough to tell what went wrong, without the report growing unbounded
+const MAX_REPORTED_VIOLATIONS: usize = 50;
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+pub struct IntegrityReport {
+    checked_at: u64,
+    violations: Vec<String>,
+    violation_count: u64,
+    acknowledged: bool, //set by an admin to resume writes after a failed check
+}
+
+impl Storable for IntegrityReport {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    //the result of the check run before the last upgrade
+    static LAST_REPORT: RefCell<StableCell<IntegrityReport, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))),
+        IntegrityReport::default()).unwrap());
+}
+
+#[derive(CandidType, Clone, Copy, PartialEq, Debug)]
+enum Invariant {
+    HighestBid,      //highest_bid equals the largest bid
+    ItemKey,         //items are stored under their own id
+    IdCounter,       //every id is below the id counter
+    ActiveStatus,    //is_active agrees with the status
+    EndTimeIndex,    //the end time indexes hold exactly the active items
+    ActiveCount,     //the dashboard's active listing counts match the items
+    CertifiedLeaf,   //the certified tree holds exactly the certified items
+    CyclesCoverage,  //the canister holds at least the cycles it owes
+    TreasuryBalance, //the running treasury balances match the block log
+}
+
+#[derive(CandidType, Clone)]
+struct Violation {
+    invariant: Invariant,
+    item_id: Option<u64>,
+    principal: Option<Principal>,
+    detail: String,
+}
+
+impl Violation {
+    fn item(invariant: Invariant, item_id: u64, detail: String) -> Self {
+        Violation {
+            invariant,
+            item_id: Some(item_id),
+            principal: None,
+            detail,
+        }
+    }
+
+    fn principal(invariant: Invariant, principal: Principal, detail: String) -> Self {
+        Violation {
+            invariant,
+            item_id: None,
+            principal: Some(principal),
+            detail,
+        }
+    }
+}
+
+#[derive(CandidType)]
+struct InvariantReport {
+    checked_at: u64,
+    items_checked: u64,
+    violation_count: u64,
+    violations: Vec<Violation>, //at most MAX_REPORTED_VIOLATIONS of them
+}
+
+fn check() -> Vec<Violation> {
+    let mut violations = vec![];
+    let next_id = ID_COUNTER.with(|c| *c.borrow().get());
+    let mut active_per_seller = BTreeMap::new();
+
+    ITEM_MAP.with(|items| {
+        for (id, item) in items.borrow().iter() {
+            let max_bid = bid_store::of(id)
+                .iter()
+                .map(|b| b.bid_amount)
+                .max()
+                .unwrap_or(0);
+            if item.highest_bid != max_bid {
+                violations.push(Violation::item(
+                    Invariant::HighestBid,
+                    id,
+                    format!(
+                        "item {}: highest_bid is {} but the highest bid is {}",
+                        id, item.highest_bid, max_bid
+                    ),
+                ));
+            }
+            if id != item.id {
+                violations.push(Violation::item(
+                    Invariant::ItemKey,
+                    id,
+                    format!("item {}: stored under key {}", item.id, id),
+                ));
+            }
+            if id >= next_id {
+                violations.push(Violation::item(
+                    Invariant::IdCounter,
+                    id,
+                    format!("item {}: id is not below the id counter ({})", id, next_id),
+                ));
+            }
+            if item.is_active != (item.status() == Status::Active) {
+                violations.push(Violation::item(
+                    Invariant::ActiveStatus,
+                    id,
+                    format!(
+                        "item {}: is_active does not match status {:?}",
+                        id,
+                        item.status()
+                    ),
+                ));
+            }
+            if item.is_active {
+                *active_per_seller.entry(item.item_owner).or_insert(0u64) += 1;
+                if !expiry::is_indexed(&item) {
+                    violations.push(Violation::item(
+                        Invariant::EndTimeIndex,
+                        id,
+                        format!("item {}: active but not in the end time index", id),
+                    ));
+                }
+            }
+            if item.certified == Some(true) && !certification::has_leaf(id) {
+                violations.push(Violation::item(
+                    Invariant::CertifiedLeaf,
+                    id,
+                    format!("item {}: certified but not in the certified tree", id),
+                ));
+            }
+        }
+    });
+
+    for (item_id, reason) in expiry::stale_entries() {
+        violations.push(Violation::item(
+            Invariant::EndTimeIndex,
+            item_id,
+            format!("end time index entry for item {}: {}", item_id, reason),
+        ));
+    }
+    for item_id in certification::stale_leaves() {
+        violations.push(Violation::item(
+            Invariant::CertifiedLeaf,
+            item_id,
+            format!(
+                "certified tree leaf for item {}: the item is gone or not certified",
+                item_id
+            ),
+        ));
+    }
+    for (seller, counted) in dashboard::active_listing_counts() {
+        let actual = active_per_seller.remove(&seller).unwrap_or(0);
+        if counted != actual {
+            violations.push(Violation::principal(
+                Invariant::ActiveCount,
+                seller,
+                format!(
+                    "seller {}: dashboard counts {} active listings but {} are active",
+                    seller, counted, actual
+                ),
+            ));
+        }
+    }
+    for (seller, actual) in active_per_seller {
+        violations.push(Violation::principal(
+            Invariant::ActiveCount,
+            seller,
+            format!(
+                "seller {}: {} active listings are not counted by the dashboard",
+                seller, actual
+            ),
+        ));
+    }
+
+    //collected fees are the canister's own, so only what users are owed or have put up has to be covered
+    let owed = balances::total_cycles()
+        + escrow::held_total()
+        + cycle_bids::held_total()
+        + bonds::held_total();
+    let held = canister_balance128();
+    if held < owed {
+        violations.push(Violation {
+            invariant: Invariant::CyclesCoverage,
+            item_id: None,
+            principal: None,
+            detail: format!(
+                "the canister holds {} cycles but owes balances, escrow, holds and bonds of {}",
+                held, owed
+            ),
+        });
+    }
+    for (ledger, replayed, running) in blocks::treasury_mismatches() {
+        let unit = ledger.map_or("cycles".to_string(), |l| format!("ledger {}", l));
+        violations.push(Violation {
+            invariant: Invariant::TreasuryBalance,
+            item_id: None,
+            principal: ledger,
+            detail: format!(
+                "the treasury balance in {} is {} but the block log adds up to {}",
+                unit, running, replayed
+            ),
+        });
+    }
+    violations
+}
+
+//a failed check must never trap here, or the canister could not be upgraded to fix it
+#[pre_upgrade]
+fn pre_upgrade() {
+    let violations = check();
+    let report = IntegrityReport {
+        checked_at: time(),
+        violation_count: violations.len() as u64,
+        acknowledged: violations.is_empty(),
+        violations: violations
+            .into_iter()
+            .take(MAX_REPORTED_VIOLATIONS)
+            .map(|v| v.detail)
+            .collect(),
+    };
+    LAST_REPORT.with(|r| {
+        let _ = r.borrow_mut().set(report);
+    });
+}
+
+/// Writes stay paused after an upgrade that found corrupted state, until an admin acknowledges it.
+pub fn ensure_writable() -> Result<(), String> {
+    let report = LAST_REPORT.with(|r| r.borrow().get().clone());
+    if report.violation_count > 0 && !report.acknowledged {
+        return Err(format!(
+            "The auction found {} inconsistencies in its data during the last upgrade, changes are paused until they are reviewed.",
+            report.violation_count
+        ));
+    }
+    Ok(())
+}
+
+#[query(name = "getIntegrityReport")]
+fn get_integrity_report() -> Result<IntegrityReport, String> {
+    admin::ensure_admin()?;
+    Ok(LAST_REPORT.with(|r| r.borrow().get().clone()))
+}
+
+#[update(name = "acknowledgeIntegrityReport")]
+fn acknowledge_integrity_report() -> Result<String, String> {
+    admin::ensure_admin()?;
+    LAST_REPORT.with(|r| {
+        let mut report = r.borrow().get().clone();
+        report.acknowledged = true;
+        r.borrow_mut().set(report).unwrap();
+    });
+    Ok("Integrity report acknowledged, writes are resumed".to_string())
+}
+
+//runs the same checks as an upgrade does, without pausing writes
+#[query(name = "verifyInvariants")]
+fn verify_invariants() -> Result<InvariantReport, String> {
+    admin::ensure_admin()?;
+    let violations = check();
+    Ok(InvariantReport {
+        checked_at: time(),
+        items_checked: ITEM_MAP.with(|items| items.borrow().len()),
+        violation_count: violations.len() as u64,
+        violations: violations
+            .into_iter()
+            .take(MAX_REPORTED_VIOLATIONS)
+            .collect(),
+    })
+}
@@ -0,0 +1,128 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap};
+
+use crate::{admin, icrc, validation, Memory, StorablePrincipal, MEMORY_MANAGER};
+
+const MAX_SYMBOL_LENGTH: usize = 16;
+//what the symbol takes as UTF-8, all of it fits in a LedgerInfo
+const MAX_SYMBOL_BYTES: usize = 32;
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct LedgerInfo {
+    pub ledger: Principal,
+    pub symbol: String,
+    pub decimals: Option<u8>, //amounts on the ledger are in units of 10^-decimals, e.g. 6 for ckUSDC
+}
+
+impl Storable for LedgerInfo {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//a ledger with a MAX_SYMBOL_BYTES symbol takes 95 bytes
+impl BoundedStorable for LedgerInfo {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    //only these ledgers are trusted to report deposits and balances
+    static LEDGER_MAP: RefCell<StableBTreeMap<StorablePrincipal, LedgerInfo, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))));
+
+    //the transfer fee each ledger last reported, so queries can show it without calling the ledger
+    static LAST_FEE: RefCell<BTreeMap<Principal, Nat>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+pub fn remember_fee(ledger: Principal, fee: &Nat) {
+    LAST_FEE.with(|f| f.borrow_mut().insert(ledger, fee.clone()));
+}
+
+pub fn last_fee(ledger: Principal) -> Option<Nat> {
+    LAST_FEE.with(|f| f.borrow().get(&ledger).cloned())
+}
+
+pub fn ensure_supported(ledger: Principal) -> Result<(), String> {
+    if !LEDGER_MAP.with(|l| l.borrow().contains_key(&ledger.into())) {
+        return Err(format!("{} is not a supported token ledger.", ledger));
+    }
+    Ok(())
+}
+
+pub fn supported() -> Vec<LedgerInfo> {
+    LEDGER_MAP.with(|l| l.borrow().iter().map(|(_, v)| v).collect())
+}
+
+pub fn decimals(ledger: Principal) -> Option<u8> {
+    LEDGER_MAP
+        .with(|l| l.borrow().get(&ledger.into()))
+        .and_then(|info| info.decimals)
+}
+
+//asks the ledger for its decimals and fee, so amounts can be shown and checked without it
+#[update(name = "addLedger")]
+async fn add_ledger(ledger: Principal, symbol: String) -> Result<String, String> {
+    admin::ensure_admin()?;
+    let symbol = validation::bounded_text("Symbol", &symbol, MAX_SYMBOL_LENGTH, MAX_SYMBOL_BYTES)?;
+    let decimals = icrc::decimals(ledger).await?;
+    icrc::fee(ledger).await?;
+    let info = LedgerInfo {
+        ledger,
+        symbol,
+        decimals: Some(decimals),
+    };
+    LEDGER_MAP.with(|l| l.borrow_mut().insert(ledger.into(), info));
+    Ok(format!("{} is now a supported ledger", ledger))
+}
+
+//balances already held on the ledger stay withdrawable through the ledger itself
+#[update(name = "removeLedger")]
+fn remove_ledger(ledger: Principal) -> Result<String, String> {
+    admin::ensure_admin()?;
+    match LEDGER_MAP.with(|l| l.borrow_mut().remove(&ledger.into())) {
+        Some(_) => Ok(format!("{} is no longer a supported ledger", ledger)),
+        None => Err(format!("{} is not a supported token ledger.", ledger)),
+    }
+}
+
+#[query(name = "getSupportedLedgers")]
+fn get_supported_ledgers() -> Vec<LedgerInfo> {
+    supported()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    #[test]
+    fn the_largest_symbol_fits() {
+        let symbol = "\u{1F600}".repeat(MAX_SYMBOL_BYTES / 4);
+        let symbol =
+            validation::bounded_text("Symbol", &symbol, MAX_SYMBOL_LENGTH, MAX_SYMBOL_BYTES)
+                .unwrap();
+        assert_fits(&LedgerInfo {
+            ledger: principal(1),
+            symbol,
+            decimals: Some(u8::MAX),
+        });
+    }
+
+    #[test]
+    fn symbols_over_the_byte_bound_are_refused() {
+        let symbol = "\u{1F600}".repeat(MAX_SYMBOL_LENGTH);
+        assert_eq!(
+            validation::bounded_text("Symbol", &symbol, MAX_SYMBOL_LENGTH, MAX_SYMBOL_BYTES),
+            Err(validation::ValidationError::TooLarge {
+                field: "Symbol",
+                max_bytes: MAX_SYMBOL_BYTES
+            })
+        );
+    }
+}
@@ -1,25 +1,142 @@
 use candid::{CandidType, Decode, Deserialize, Encode, Principal};
-use ic_cdk::{caller, query, update};
+use ic_cdk::{caller, init, post_upgrade, query, update};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    storable::Blob,
     BoundedStorable, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
 };
 use std::{borrow::Cow, cell::RefCell, collections::HashMap};
 
+mod admin;
+mod allowance_bids;
+mod amendments;
+mod analytics;
+mod api_v2;
+mod approvals;
+mod audit;
+mod audit_snapshot;
+mod balances;
+mod bid_limits;
+mod bid_store;
+mod blocks;
+mod bonds;
+mod budget;
+mod certification;
+mod clock;
+mod close_timers;
+mod compaction;
+mod cycle_bids;
+mod dashboard;
+mod defaults;
+mod delegated_bids;
+mod deposits;
+mod detail;
+mod duplicates;
+mod ed25519;
+mod escrow;
+mod expiry;
+mod export;
+mod exposure;
+mod feed;
+mod fees;
+mod follows;
+mod guard;
+mod http;
+mod icrc;
+mod increments;
+mod indexer_sync;
+mod integrity;
+mod ledgers;
+mod listing_quota;
+mod maintenance;
+mod media;
+mod membership;
+mod memory_usage;
+mod messages;
+mod migrations;
+mod names;
+mod nfc;
+mod nfc_tables;
+mod notification_prefs;
+mod notification_relay;
+mod notifications;
+mod offers;
+mod partners;
+mod price_history;
+mod profiles;
+mod promo_codes;
+mod promotions;
+mod receipts;
+mod referrals;
+mod relay;
+mod relisting;
+mod reports;
+mod retention;
+mod runner_up;
+mod settlement;
+mod stats;
+mod subscriptions;
+mod sync;
+mod tax;
+mod terms;
+#[cfg(test)]
+mod test_support;
+mod treasury;
+mod trending;
+mod validation;
+mod watchlist;
+mod winners;
+mod wire;
+mod withdrawals;
+mod xrc;
+
+use api_v2::{ApiError, BidReceipt};
+use audit::AuditEvent;
+use clock::time;
+use close_timers::EarlyClose;
+use compaction::BidArchive;
+use cycle_bids::PaymentMode;
+use increments::IncrementBand;
+use media::{Media, MediaBase};
+use notifications::NotificationKind;
+use relisting::RelistPolicy;
+use subscriptions::AuctionEvent;
+use tax::TaxInfo;
+use winners::WinnerPolicy;
+use wire::ItemView;
+
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 #[derive(Deserialize, CandidType)]
 struct ItemBase {
     description: String,
-    result_date: u64, //specifies when the auction will be closed automatically for the given item
-    is_active: bool,
+    result_date: u64, //specifies when the auction will be closed automatically for the given item, in nanoseconds like ic time
+    is_active: bool,  //false keeps the item as a draft
     latest_update: u64,
+    accepts_offers: Option<bool>, //lets buyers negotiate privately through offers
+    auto_accept_price: Option<u64>, //offers at or above this are accepted without the seller
+    terms: Option<String>,        //terms of sale bidders have to accept before their first bid
+    category: Option<String>,
+    starts_at: Option<u64>, //bidding opens at this time, the item is scheduled until then
+    winner_policy: Option<WinnerPolicy>, //highest bid when not set
+    relist_policy: Option<RelistPolicy>,
+    increment_bands: Option<Vec<IncrementBand>>, //the default increments apply when not set
+    payment_mode: Option<PaymentMode>,           //escrow when not set
+    bid_bond: Option<u64>, //cycles every bidder puts up with their first bid, lost if they win and do not pay
+    payment_window: Option<u64>, //nanoseconds the winner has to pay, three days when not set
+    tax: Option<TaxInfo>,
+    minimum_bid: Option<u64>, //in the smallest unit of the item's currency, e.g. 1_000_000 for 1 ckUSDC
+    media: Option<Vec<MediaBase>>, //off-chain images with their hashes, checked by the canister
+    allow_duplicate: Option<bool>, //lists it even if the owner has an active item with the same description
+    promo_code: Option<String>,    //redeemed for this item, see applyPromoCode
+    certified: Option<bool>, //serves the item through getItemCertified, meant for high-value auctions
 }
 
 #[derive(Deserialize, CandidType)]
 struct BidBase {
     bid_amount: u64,
     bid_date: u64, //kept as a unix timestamp
+    memo: Option<String>,
 }
 
 #[derive(Deserialize, CandidType)]
@@ -28,8 +145,39 @@ struct Bid {
     bidder_principal: Principal,
     bid_date: u64, // will be kept as a unix timestamp
     bid_amount: u64,
+    memo: Option<String>, //e.g. a quote or purchase order reference, only shown to the owner and the bidder
+    seq: Option<u64>,     //canister-wide bid order, unset for bids placed before it was recorded
+    placed_at: Option<u64>, //consensus time the bid was accepted, unlike the client supplied bid_date
 }
 
+#[derive(Deserialize, CandidType, Clone, Copy, PartialEq, Debug)]
+enum Status {
+    Draft,
+    Scheduled,
+    Active,
+    Ended, //closed without a sale, or waiting for one
+    Settled,
+    Cancelled,
+    Removed, //taken down by moderation
+}
+
+impl Status {
+    fn can_become(self, next: Status) -> bool {
+        use Status::*;
+        let allowed = matches!(
+            (self, next),
+            (Draft, Scheduled | Active | Cancelled)
+                | (Scheduled, Draft | Active | Cancelled)
+                | (Active, Ended | Settled | Cancelled)
+                | (Ended, Settled)
+                | (Settled, Ended) //the buyer defaulted
+        );
+        allowed || (next == Removed && self != Removed)
+    }
+}
+
+//as stored, endpoints return it as an ItemView. new fields have to be Option so the items stored
+//before them still decode, and go into ItemView as well
 #[derive(Deserialize, CandidType)]
 struct Item {
     item_owner: Principal,
@@ -38,12 +186,127 @@ struct Item {
     highest_bid: u64,
     latest_update: u64,
     result_date: u64,
-    bid_vector: Vec<Bid>,
+    bid_vector: Vec<Bid>, //always empty in ITEM_MAP, the bids are kept in bid_store.rs
+    is_active: bool,
+    accepts_offers: Option<bool>,
+    auto_accept_price: Option<u64>,
+    is_hidden: Option<bool>, //set when enough users flag the item, cleared by moderation
+    terms: Option<String>,
+    category: Option<String>, //lowercased and trimmed, see normalize_category
+    status: Option<Status>,   //always set once the status migration ran, is_active mirrors it
+    starts_at: Option<u64>,
+    winner_policy: Option<WinnerPolicy>,
+    relist_policy: Option<RelistPolicy>,
+    relisted_from: Option<u64>, //the item that closed below its reserve before this one
+    increment_bands: Option<Vec<IncrementBand>>,
+    payment_mode: Option<PaymentMode>,
+    bid_bond: Option<u64>,
+    payment_window: Option<u64>,
+    tax: Option<TaxInfo>,
+    minimum_bid: Option<u64>,
+    media: Option<Vec<Media>>,
+    certified: Option<bool>,
+    bid_archive: Option<BidArchive>, //set once the bid history was compacted, only the highest bids are kept then
+}
+
+impl Item {
+    fn status(&self) -> Status {
+        self.status.unwrap_or(if self.is_active {
+            Status::Active
+        } else {
+            Status::Draft
+        })
+    }
+
+    /// Moves the item to `next` if the lifecycle allows it.
+    fn set_status(&mut self, next: Status) -> Result<(), String> {
+        let current = self.status();
+        if current != next && !current.can_become(next) {
+            return Err(format!(
+                "Item {} cannot go from {:?} to {:?}.",
+                self.id, current, next
+            ));
+        }
+        if current == Status::Active && next != Status::Active {
+            exposure::lead_released(self.id);
+        }
+        self.status = Some(next);
+        self.is_active = next == Status::Active;
+        Ok(())
+    }
+
+    /// How many bids were placed, including those dropped by compaction.
+    fn bid_count(&self) -> u64 {
+        self.bid_archive
+            .as_ref()
+            .map_or_else(|| bid_store::count(self.id), |a| a.bid_count)
+    }
+}
+
+//what the owner asked for when listing or editing an item
+fn requested_status(publish: bool, starts_at: Option<u64>) -> Status {
+    match starts_at {
+        _ if !publish => Status::Draft,
+        Some(t) if t > time() => Status::Scheduled,
+        _ => Status::Active,
+    }
+}
+
+const MAX_CATEGORY_LENGTH: usize = 32;
+
+fn normalize_category(category: &str) -> String {
+    validation::sanitize(category).to_lowercase()
+}
+
+fn validate_category(category: Option<String>) -> Result<Option<String>, String> {
+    match category.map(|c| normalize_category(&c)) {
+        Some(c) if c.is_empty() => Ok(None),
+        Some(c) if c.len() > MAX_CATEGORY_LENGTH => Err(format!(
+            "Category cannot be longer than {} bytes.",
+            MAX_CATEGORY_LENGTH
+        )),
+        category => Ok(category),
+    }
+}
+
+//what list views need to render an item, without the full description and bid history
+#[derive(CandidType)]
+struct ItemSummary {
+    id: u64,
+    item_owner: Principal,
+    short_description: String,
+    highest_bid: u64,
+    result_date: u64,
+    bid_count: u64,
     is_active: bool,
+    status: Status,
+    owner_name: Option<String>, //the owner's display name
+}
+
+const SHORT_DESCRIPTION_LENGTH: usize = 140;
+
+impl From<&Item> for ItemSummary {
+    fn from(item: &Item) -> Self {
+        ItemSummary {
+            id: item.id,
+            item_owner: item.item_owner,
+            short_description: item
+                .description
+                .chars()
+                .take(SHORT_DESCRIPTION_LENGTH)
+                .collect(),
+            highest_bid: item.highest_bid,
+            result_date: item.result_date,
+            bid_count: item.bid_count(),
+            is_active: item.is_active,
+            status: item.status(),
+            owner_name: names::of(item.item_owner),
+        }
+    }
 }
 
 impl Storable for Item {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -52,11 +315,43 @@ impl Storable for Item {
     }
 }
 
+//a listing with every field at its bound takes 9_320 bytes
 impl BoundedStorable for Item {
     const MAX_SIZE: u32 = 10_000;
     const IS_FIXED_SIZE: bool = false;
 }
 
+//principals are at most 29 bytes, which lets them be used as stable map keys
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+struct StorablePrincipal(Blob<29>);
+
+impl From<Principal> for StorablePrincipal {
+    fn from(p: Principal) -> Self {
+        StorablePrincipal(Blob::try_from(p.as_slice()).unwrap())
+    }
+}
+
+impl From<StorablePrincipal> for Principal {
+    fn from(p: StorablePrincipal) -> Self {
+        Principal::from_slice(p.0.as_slice())
+    }
+}
+
+impl Storable for StorablePrincipal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        self.0.to_bytes()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StorablePrincipal(Blob::from_bytes(bytes))
+    }
+}
+
+impl BoundedStorable for StorablePrincipal {
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 thread_local! {
     static MEMORY_MANAGER : RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
 
@@ -66,6 +361,47 @@ thread_local! {
     static ID_COUNTER: RefCell<StableCell<u64, Memory>> = RefCell::new(StableCell::init(
         MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
         u64::default()).unwrap());
+
+    //next bid sequence number, shared by all items so any two bids can be ordered
+    static BID_SEQ: RefCell<StableCell<u64, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))),
+        u64::default()).unwrap());
+}
+
+//timers don't survive upgrades, so they are started again from post_upgrade
+fn start_timers() {
+    escrow::start_release_timer();
+    expiry::start_sweep_timer();
+    promotions::start_expiry_timer();
+    trending::start_cleanup_timer();
+    subscriptions::start_delivery_timer();
+    indexer_sync::start_retry_timer();
+    xrc::start_refresh_timer();
+    media::start_check_timer();
+    compaction::start_compaction_timer();
+    retention::start_sweep_timer();
+    memory_usage::start_sample_timer();
+    notifications::start_expiry_timer();
+    notifications::start_ending_soon_timer();
+    notification_relay::start_delivery_timer();
+}
+
+#[init]
+fn init() {
+    admin::init();
+    migrations::init();
+    start_timers();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    admin::init();
+    migrations::run();
+    subscriptions::reset_in_flight();
+    notification_relay::reset_in_flight();
+    certification::certify();
+    start_timers();
+    close_timers::start_reconciliation();
 }
 
 fn get_and_inc_current_id() -> u64 {
@@ -74,59 +410,245 @@ fn get_and_inc_current_id() -> u64 {
         id_tmp = *(counter.borrow()).get();
         counter.borrow_mut().set(id_tmp + 1).unwrap();
     });
-    return id_tmp;
+    id_tmp
+}
+
+fn next_bid_seq() -> u64 {
+    BID_SEQ.with(|seq| {
+        let next = *seq.borrow().get();
+        seq.borrow_mut().set(next + 1).unwrap();
+        next
+    })
 }
 
+//every page of getItemsV2 at once, kept for frontends that have not moved to it
 #[query(name = "getAllItems")]
-fn get_all_items() -> Option<HashMap<u64, Item>> {
-    let mut map: HashMap<u64, Item> = HashMap::new();
+fn get_all_items() -> Option<HashMap<u64, ItemSummary>> {
+    let mut map: HashMap<u64, ItemSummary> = HashMap::new();
+    let mut cursor = Some(0);
+    while let Some(from) = cursor {
+        let (items, next) = api_v2::items_page(from, u64::MAX);
+        map.extend(items.into_iter().map(|s| (s.id, s)));
+        cursor = next;
+    }
+    Some(map)
+}
 
-    ITEM_MAP.with(|p| {
-        for (k, v) in p.borrow().iter() {
-            map.insert(k, v);
+//hidden items stay visible to their owner and to admins reviewing them
+fn is_visible_to(item: &Item, p: Principal) -> bool {
+    item.is_hidden != Some(true) || item.item_owner == p || admin::is_admin(p)
+}
+
+const MAX_MEMO_LENGTH: usize = 64;
+
+//bid memos are private to the owner, the bidder who wrote them and admins
+fn redact_memos(mut item: Item, viewer: Principal) -> Item {
+    let bids = std::mem::take(&mut item.bid_vector);
+    item.bid_vector = redact_bid_memos(&item, bids, viewer);
+    item
+}
+
+fn redact_bid_memos(item: &Item, mut bids: Vec<Bid>, viewer: Principal) -> Vec<Bid> {
+    if item.item_owner == viewer || admin::is_admin(viewer) {
+        return bids;
+    }
+    for bid in bids.iter_mut() {
+        if bid.bidder_principal != viewer {
+            bid.memo = None;
         }
-    });
-    return Some(map);
+    }
+    bids
+}
+
+const MAX_BID_PAGE_SIZE: u64 = 100;
+
+#[derive(CandidType)]
+struct BidSummary {
+    count: u64,
+    highest: u64,
+    latest_at: Option<u64>, //when the last bid was placed
+    own_best: Option<u64>,  //the caller's highest bid on the item
+    archived: Option<bool>, //only the highest bids are kept in detail, see compaction.rs
+}
+
+//getItem leaves the bid vector empty, bids are read page by page with getBids
+#[derive(CandidType)]
+struct ItemWithBidSummary {
+    item: ItemView,
+    bid_summary: BidSummary,
+}
+
+#[derive(CandidType)]
+struct BidPage {
+    bids: Vec<Bid>,
+    next_index: Option<u64>, //pass it back as `from_index` to read the next page
+    archived: Option<bool>,  //the history was compacted, the pages only hold the highest bids
+    bidder_names: Option<Vec<(Principal, String)>>, //display names of the bidders on the page
 }
 
-#[query(name = "getItem")]
 fn get_item(key: u64) -> Option<Item> {
-    ITEM_MAP.with(|p| p.borrow().get(&key))
+    ITEM_MAP
+        .with(|p| p.borrow().get(&key))
+        .filter(|item| is_visible_to(item, caller()))
+}
+
+#[query(name = "getItem")]
+fn get_item_with_bid_summary(key: u64) -> Option<ItemWithBidSummary> {
+    api_v2::item_with_bid_summary(key).ok()
+}
+
+//in the order they were placed
+#[query(name = "getBids")]
+fn get_bids(key: u64, from_index: u64, limit: u64) -> Option<BidPage> {
+    api_v2::bids_page(key, from_index, limit).ok()
 }
 
 #[update(name = "listItem")]
-fn list_item(item: ItemBase) -> Option<Item> {
+fn list_item(item: ItemBase) -> Result<ItemView, String> {
+    create_item(caller(), item)
+        .map(ItemView::from)
+        .map_err(String::from)
+}
+
+/// Lists an item for `owner`, shared by listItem and partners listing on behalf of a seller.
+fn create_item(owner: Principal, item: ItemBase) -> Result<Item, ApiError> {
+    guard::check_write_headroom().map_err(ApiError::Unavailable)?;
+    let category = validate_category(item.category)?;
+    let description = validation::bounded_text(
+        "Description",
+        &item.description,
+        validation::MAX_DESCRIPTION_LENGTH,
+        validation::MAX_DESCRIPTION_BYTES,
+    )?;
+    duplicates::check(
+        owner,
+        &description,
+        category.as_deref(),
+        item.allow_duplicate.unwrap_or(false),
+    )?;
+    let terms = validation::optional_bounded_text(
+        "Terms",
+        item.terms,
+        validation::MAX_TERMS_LENGTH,
+        validation::MAX_TERMS_BYTES,
+    )?;
+    let relist_policy = relisting::validate(item.relist_policy)?;
+    let increment_bands = increments::validate(item.increment_bands)?;
+    let bid_bond = bonds::validate(item.bid_bond, item.payment_mode)?;
+    let tax = tax::validate(item.tax)?;
+    allowance_bids::validate_minimum(item.payment_mode, item.minimum_bid)?;
+    if item.media.as_ref().is_some_and(|m| !m.is_empty()) {
+        guard::check_optional_write().map_err(ApiError::Unavailable)?;
+    }
+    let media = media::validate(item.media, None)?;
+    let status = requested_status(item.is_active, item.starts_at);
+    if status != Status::Draft {
+        listing_quota::ensure_below_quota(owner)?;
+    }
+    let promo_code = item
+        .promo_code
+        .as_deref()
+        .map(promo_codes::check)
+        .transpose()?;
+    if item.certified == Some(true) {
+        ensure_certification_capacity()?;
+    }
     let id_tmp = get_and_inc_current_id();
 
     let new_item: Item = Item {
-        item_owner: caller(),
+        item_owner: owner,
         id: id_tmp,
-        description: item.description,
+        description,
         highest_bid: 0,
         latest_update: item.latest_update,
         result_date: item.result_date,
         bid_vector: vec![],
-        is_active: item.is_active,
+        is_active: status == Status::Active,
+        accepts_offers: item.accepts_offers,
+        auto_accept_price: item.auto_accept_price,
+        is_hidden: None,
+        terms,
+        category,
+        status: Some(status),
+        starts_at: item.starts_at,
+        winner_policy: item.winner_policy,
+        relist_policy,
+        relisted_from: None,
+        increment_bands,
+        payment_mode: item.payment_mode,
+        bid_bond,
+        payment_window: item.payment_window,
+        tax,
+        minimum_bid: item.minimum_bid,
+        media,
+        certified: item.certified,
+        bid_archive: None,
     };
 
-    return ITEM_MAP.with(|item| item.borrow_mut().insert(id_tmp, new_item));
+    //redeemed before the item is stored, so a code that cannot be used leaves nothing listed
+    if let Some(code) = promo_code {
+        promo_codes::redeem(id_tmp, &code)?;
+    }
+    store_new_item(new_item);
+    get_item(id_tmp).ok_or_else(|| ApiError::Rejected("Item could not be listed.".to_string()))
+}
+
+fn ensure_certification_capacity() -> Result<(), String> {
+    if certification::certified_count() >= certification::MAX_CERTIFIED_ITEMS {
+        return Err(format!(
+            "At most {} items can be certified at a time.",
+            certification::MAX_CERTIFIED_ITEMS
+        ));
+    }
+    Ok(())
+}
+
+fn store_new_item(new_item: Item) {
+    let (id, owner) = (new_item.id, new_item.item_owner);
+    stats::item_listed(owner);
+    match new_item.status() {
+        Status::Active => {
+            expiry::index_insert(&new_item);
+            follows::item_went_live(&new_item);
+        }
+        Status::Scheduled => expiry::schedule_insert(&new_item),
+        _ => {}
+    }
+    ITEM_MAP.with(|item| item.borrow_mut().insert(id, new_item));
+    subscriptions::publish(AuctionEvent::ItemListed { item_id: id, owner });
+    indexer_sync::item_changed(id);
 }
 
 #[update(name = "editItem")]
 fn edit_item(key: u64, new_item: ItemBase) -> Result<String, String> {
+    let category = validate_category(new_item.category)?;
+    let description = validation::bounded_text(
+        "Description",
+        &new_item.description,
+        validation::MAX_DESCRIPTION_LENGTH,
+        validation::MAX_DESCRIPTION_BYTES,
+    )?;
+    let terms = validation::optional_bounded_text(
+        "Terms",
+        new_item.terms,
+        validation::MAX_TERMS_LENGTH,
+        validation::MAX_TERMS_BYTES,
+    )?;
+    let relist_policy = relisting::validate(new_item.relist_policy)?;
+    let increment_bands = increments::validate(new_item.increment_bands)?;
+    let bid_bond = bonds::validate(new_item.bid_bond, new_item.payment_mode)?;
+    let tax = tax::validate(new_item.tax)?;
+    allowance_bids::validate_minimum(new_item.payment_mode, new_item.minimum_bid)?;
     let mut ret_item: Option<Item> = None;
     let mut is_authorized: bool = true;
 
     ITEM_MAP.with(|items| {
-        for (k, mut v) in items.borrow_mut().iter() {
+        for (k, v) in items.borrow_mut().iter() {
             if k == key {
                 if v.item_owner != caller() {
                     is_authorized = false;
+                    break;
                 }
-                v.description = new_item.description;
-                v.result_date = new_item.result_date;
-                v.is_active = new_item.is_active;
-                v.latest_update = new_item.latest_update;
                 ret_item = Some(v);
                 break;
             }
@@ -137,26 +659,142 @@ fn edit_item(key: u64, new_item: ItemBase) -> Result<String, String> {
         return Err("Item could not be edited. Most probably, could not be found".to_string());
     }
     match ret_item {
-        Some(_) => {
-            ITEM_MAP.with(|item| item.borrow_mut().insert(key, ret_item.unwrap()));
+        Some(mut v) => {
+            if !matches!(
+                v.status(),
+                Status::Draft | Status::Scheduled | Status::Active
+            ) {
+                return Err("Only draft, scheduled or active items can be edited.".to_string());
+            }
+            let has_bids = bid_store::has_bids(key);
+            if has_bids && v.winner_policy != new_item.winner_policy {
+                return Err(
+                    "The winner policy cannot be changed once bidding has started.".to_string(),
+                );
+            }
+            if has_bids && v.payment_mode != new_item.payment_mode {
+                return Err(
+                    "The payment mode cannot be changed once bidding has started.".to_string(),
+                );
+            }
+            if has_bids && v.bid_bond != bid_bond {
+                return Err("The bid bond cannot be changed once bidding has started.".to_string());
+            }
+            if has_bids && v.description != description {
+                return Err(
+                    "The description cannot be changed once bidding has started, post an amendment instead."
+                        .to_string(),
+                );
+            }
+            let media = media::validate(new_item.media, v.media.as_ref())?;
+            //removing media is always fine, it only frees space
+            if media.as_ref().is_some_and(|m| !m.is_empty())
+                && !media::same_files(v.media.as_ref(), media.as_ref())
+            {
+                guard::check_optional_write()?;
+            }
+            if has_bids && !media::same_files(v.media.as_ref(), media.as_ref()) {
+                return Err("The media cannot be changed once bidding has started.".to_string());
+            }
+            if has_bids && v.result_date != new_item.result_date {
+                return Err(
+                    "The end time can only be extended by an admin once bidding has started."
+                        .to_string(),
+                );
+            }
+            let previous_result_date = v.result_date;
+            let status = requested_status(new_item.is_active, new_item.starts_at);
+            let goes_live = status == Status::Active && v.status() != Status::Active;
+            if goes_live {
+                listing_quota::ensure_below_quota(v.item_owner)?;
+            }
+            if new_item.certified == Some(true) && v.certified != Some(true) {
+                ensure_certification_capacity()?;
+            }
+            v.set_status(status)?;
+            expiry::index_remove(&v);
+            expiry::schedule_remove(&v);
+            v.description = description;
+            v.result_date = new_item.result_date;
+            v.latest_update = new_item.latest_update;
+            v.accepts_offers = new_item.accepts_offers;
+            v.auto_accept_price = new_item.auto_accept_price;
+            v.terms = terms;
+            v.category = category;
+            v.starts_at = new_item.starts_at;
+            v.winner_policy = new_item.winner_policy;
+            v.relist_policy = relist_policy;
+            v.increment_bands = increment_bands;
+            v.payment_mode = new_item.payment_mode;
+            v.bid_bond = bid_bond;
+            v.payment_window = new_item.payment_window;
+            v.tax = tax;
+            v.minimum_bid = new_item.minimum_bid;
+            v.media = media;
+            v.certified = new_item.certified;
+            match status {
+                Status::Active => expiry::index_insert(&v),
+                Status::Scheduled => expiry::schedule_insert(&v),
+                _ => {}
+            }
+            if goes_live {
+                follows::item_went_live(&v);
+            }
+            ITEM_MAP.with(|item| item.borrow_mut().insert(key, v));
+            indexer_sync::item_changed(key);
+            if new_item.result_date > previous_result_date {
+                audit::record(AuditEvent::AuctionExtended {
+                    item_id: key,
+                    previous_result_date,
+                    result_date: new_item.result_date,
+                    admin_override: false,
+                });
+            }
             Ok("Item edited successfully".to_string())
         }
         None => Err("Item could not be edited. Most probably, could not be found".to_string()),
     }
 }
 
+//owners can move the end time freely through editItem until the first bid, after that only admins can
+#[update(name = "extendAuction")]
+fn extend_auction(key: u64, result_date: u64) -> Result<String, String> {
+    admin::ensure_admin()?;
+    let mut item = ITEM_MAP
+        .with(|items| items.borrow().get(&key))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+    if item.status() != Status::Active {
+        return Err("Only active items can be extended.".to_string());
+    }
+    if result_date <= item.result_date {
+        return Err("The new end time must be after the current one.".to_string());
+    }
+    audit::record(AuditEvent::AuctionExtended {
+        item_id: key,
+        previous_result_date: item.result_date,
+        result_date,
+        admin_override: bid_store::has_bids(key),
+    });
+    expiry::index_remove(&item);
+    item.result_date = result_date;
+    expiry::index_insert(&item);
+    ITEM_MAP.with(|items| items.borrow_mut().insert(key, item));
+    indexer_sync::item_changed(key);
+    Ok("Auction extended successfully".to_string())
+}
+
 #[update(name = "stopListing")]
 fn stop_listing(key: u64) -> Result<String, String> {
     let mut ret_item: Option<Item> = None;
     let mut is_authorized: bool = true;
 
     ITEM_MAP.with(|items| {
-        for (k, mut v) in items.borrow_mut().iter() {
+        for (k, v) in items.borrow_mut().iter() {
             if k == key {
                 if v.item_owner != caller() {
                     is_authorized = false;
+                    break;
                 }
-                v.is_active = false;
                 ret_item = Some(v);
                 break;
             }
@@ -167,8 +805,19 @@ fn stop_listing(key: u64) -> Result<String, String> {
         return Err("You are not authorized to edit this item.".to_string());
     }
     match ret_item {
-        Some(_) => {
-            ITEM_MAP.with(|item| item.borrow_mut().insert(key, ret_item.unwrap()));
+        Some(mut v) => {
+            v.set_status(Status::Cancelled)?;
+            cycle_bids::release_hold(key);
+            allowance_bids::release_hold(key);
+            bonds::release_all(key, None);
+            close_timers::cancel_early(key, EarlyClose::Stopped);
+            if !bid_store::has_bids(key) {
+                promotions::refund_on_cancel(key);
+            }
+            expiry::index_remove(&v);
+            expiry::schedule_remove(&v);
+            ITEM_MAP.with(|item| item.borrow_mut().insert(key, v));
+            indexer_sync::item_changed(key);
             Ok("Selected item  is no longer actively listed on the auction list.".to_string())
         }
         None => Err("Item could not be edited. Most probably, could not be found.".to_string()),
@@ -191,17 +840,62 @@ fn delete_item(key: u64) -> Result<String, String> {
                     fi.item_owner
                 ));
             }
+            //sold and running auctions are kept for the buyer and bidders
+            if matches!(fi.status(), Status::Active | Status::Settled) {
+                return Err(
+                    "Active and sold items cannot be deleted, stop the listing first.".to_string(),
+                );
+            }
+            expiry::index_remove(&fi);
+            expiry::schedule_remove(&fi);
             ITEM_MAP.with(|items| {
                 items.borrow_mut().remove(&key);
             });
+            bid_store::remove_all(key);
+            bid_limits::forget(key);
+            stats::item_deleted();
+            indexer_sync::item_removed(key);
             Ok(format!("Item with id {} removed successfully", fi.id))
         }
         None => Err("Item could not be found.".to_string()),
     }
 }
 
+//items paid through token approvals need an async allowance check before the bid is placed
 #[update(name = "bidForAnItem")]
-fn bid_for_an_item(key: u64, bid: BidBase) -> Result<String, String> {
+async fn bid_for_an_item(key: u64, bid: BidBase) -> Result<String, String> {
+    bid_as(key, bid, caller()).await
+}
+
+/// Places a bid attributed to `bidder`, with the v1 confirmation text.
+async fn bid_as(key: u64, bid: BidBase, bidder: Principal) -> Result<String, String> {
+    let receipt = place_bid_as(key, bid, bidder).await?;
+    Ok(format!("Successfully bidded for item {}", receipt.item_id))
+}
+
+/// Places a bid attributed to `bidder`, who may not be the caller when the bid is relayed.
+async fn place_bid_as(key: u64, bid: BidBase, bidder: Principal) -> Result<BidReceipt, ApiError> {
+    let ledger = ITEM_MAP
+        .with(|items| items.borrow().get(&key))
+        .and_then(|item| allowance_bids::ledger_of(&item));
+    //a bid the deposited balance covers is held from it and needs no approval
+    let needs_allowance = ledger
+        .filter(|ledger| !allowance_bids::balance_covers(key, *ledger, bidder, bid.bid_amount));
+    if let Some(ledger) = needs_allowance {
+        allowance_bids::ensure_allowance(ledger, bidder, bid.bid_amount)
+            .await
+            .map_err(ApiError::NotAllowed)?;
+    }
+    place_bid(key, bid, bidder)
+}
+
+fn place_bid(key: u64, bid: BidBase, bidder: Principal) -> Result<BidReceipt, ApiError> {
+    guard::check_write_headroom().map_err(ApiError::Unavailable)?;
+    accept_bid(key, bid, bidder)
+}
+
+//everything after the canister-wide headroom check, which reads the replica's cycles and memory
+fn accept_bid(key: u64, bid: BidBase, bidder: Principal) -> Result<BidReceipt, ApiError> {
     let mut found_item: Option<Item> = None;
 
     ITEM_MAP.with(|items| {
@@ -210,30 +904,280 @@ fn bid_for_an_item(key: u64, bid: BidBase) -> Result<String, String> {
 
     match found_item {
         Some(fi) => {
-            if fi.item_owner == caller() {
-                return Err(format!("You cannot bid for you own item",));
+            if fi.item_owner == bidder {
+                return Err(ApiError::NotAllowed(
+                    "You cannot bid for you own item".to_string(),
+                ));
             }
-            if !fi.is_active {
-                return Err(format!("The selected item is not actively listed.",));
+            if !fi.is_active || fi.is_hidden == Some(true) {
+                return Err(ApiError::Closed(
+                    "The selected item is not actively listed.".to_string(),
+                ));
             }
-            if bid.bid_amount <= fi.highest_bid {
-                return Err(format!(
-                    "Your bid cannot be lower than the current highest bid.",
+            if fi.result_date <= time() {
+                return Err(ApiError::Closed(
+                    "The auction for this item has ended.".to_string(),
                 ));
             }
-            let fi_id = fi.id;
+            terms::ensure_accepted(&fi, bidder).map_err(ApiError::NotAllowed)?;
+            defaults::ensure_in_good_standing(bidder).map_err(ApiError::NotAllowed)?;
+            bid_limits::check(&fi, bidder)?;
+            let memo = validation::optional_text("Memo", bid.memo, MAX_MEMO_LENGTH)?;
+            let minimum = increments::next_minimum_bid(&fi);
+            if bid.bid_amount < minimum {
+                return Err(ApiError::Invalid(format!(
+                    "Your bid has to be at least {}.",
+                    minimum
+                )));
+            }
+            //a token price at or below the ledger fee would leave the seller nothing
+            let ledger_fee = allowance_bids::ledger_of(&fi).and_then(ledgers::last_fee);
+            if ledger_fee.is_some_and(|fee| fee >= bid.bid_amount) {
+                return Err(ApiError::Invalid(
+                    "Your bid has to be above the token ledger's fee.".to_string(),
+                ));
+            }
+            //a bid matching the top one, where the item allows it, ranks behind the earlier one and takes nothing
+            let takes_lead = bid.bid_amount > fi.highest_bid;
+            let leader = exposure::leader(key);
+            if !takes_lead && leader == Some(bidder) {
+                return Err(ApiError::Invalid(
+                    "Your bid already leads at this price.".to_string(),
+                ));
+            }
+            exposure::ensure_within_cap(&fi, bidder, bid.bid_amount)
+                .map_err(ApiError::NotAllowed)?;
+            //both the bid's cycles and the bond are checked before either is taken, an error still commits
+            let hold = match cycle_bids::is_cycles_item(&fi) && takes_lead {
+                true => Some(cycle_bids::hold_bid(
+                    key,
+                    bidder,
+                    bid.bid_amount,
+                    bonds::due(&fi, bidder),
+                )?),
+                false => None,
+            };
+            let ledger = allowance_bids::ledger_of(&fi).filter(|_| takes_lead);
+            let balance_hold = ledger.and_then(|ledger| {
+                allowance_bids::balance_hold(key, ledger, bidder, bid.bid_amount)
+            });
+            bonds::take(&fi, bidder)?;
+            if let Some(hold) = hold {
+                cycle_bids::commit_hold(hold);
+            }
+            if ledger.is_some() {
+                allowance_bids::bid_placed(key, balance_hold);
+            }
+            dashboard::bid_received(fi.item_owner);
+            dashboard::bidder_joined(bidder, key);
+            stats::bid_placed(bidder);
+            let seq = next_bid_seq();
             let new_bid = Bid {
                 item_id: key,
-                bidder_principal: caller(),
+                bidder_principal: bidder,
                 bid_amount: bid.bid_amount,
                 bid_date: bid.bid_date,
+                memo,
+                seq: Some(seq),
+                placed_at: Some(time()),
             };
+            let outbid = leader.filter(|p| *p != bidder && takes_lead);
             let mut new_item = fi;
-            new_item.highest_bid = new_bid.bid_amount;
-            new_item.bid_vector.push(new_bid);
+            new_item.highest_bid = new_item.highest_bid.max(new_bid.bid_amount);
+            bid_store::push(new_bid);
+            bid_limits::record(key, bidder, time());
+            if takes_lead {
+                exposure::bid_placed(&new_item, bidder, bid.bid_amount);
+            }
+            if let Some(previous) = outbid {
+                notifications::notify(
+                    previous,
+                    NotificationKind::Outbid {
+                        item_id: key,
+                        leading_bid: bid.bid_amount,
+                        minimum_counter_bid: increments::next_minimum_bid(&new_item),
+                        ends_at: new_item.result_date,
+                        time_remaining: new_item.result_date.saturating_sub(time()),
+                    },
+                );
+            }
+            let receipt = BidReceipt {
+                item_id: key,
+                amount: bid.bid_amount,
+                seq,
+                next_minimum_bid: increments::next_minimum_bid(&new_item),
+                ends_at: new_item.result_date,
+            };
             ITEM_MAP.with(|items| items.borrow_mut().insert(key, new_item));
-            Ok(format!("Successfully bidded for item {}", fi_id))
+            trending::record(key, trending::TrendEvent::Bid);
+            subscriptions::publish(AuctionEvent::BidPlaced {
+                item_id: key,
+                bidder,
+                amount: bid.bid_amount,
+            });
+            indexer_sync::bid_placed(key, bidder, bid.bid_amount, bid.bid_date);
+            Ok(receipt)
         }
-        None => Err("Item could not be found.".to_string()),
+        None => Err(ApiError::NotFound("Item could not be found.".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, principal, HOUR};
+
+    fn offer(amount: u64) -> BidBase {
+        BidBase {
+            bid_amount: amount,
+            bid_date: 0,
+            memo: None,
+        }
+    }
+
+    fn accepted(key: u64, amount: u64, bidder: Principal) -> u64 {
+        match accept_bid(key, offer(amount), bidder) {
+            Ok(receipt) => receipt.next_minimum_bid,
+            Err(e) => panic!("bid of {} was refused: {:?}", amount, e),
+        }
+    }
+
+    #[test]
+    fn bids_have_to_beat_the_highest_one() {
+        clock::set(1_000 * HOUR);
+        test_support::list(test_support::item(1, principal(1)));
+
+        assert_eq!(accepted(1, 50, principal(2)), 51);
+        assert!(matches!(
+            accept_bid(1, offer(50), principal(3)),
+            Err(ApiError::Invalid(_))
+        ));
+        assert_eq!(accepted(1, 51, principal(3)), 52);
+        assert_eq!(test_support::stored(1).highest_bid, 51);
+        assert_eq!(bid_store::count(1), 2);
+    }
+
+    #[test]
+    fn owners_cannot_bid_on_their_items() {
+        clock::set(1_000 * HOUR);
+        test_support::list(test_support::item(1, principal(1)));
+        assert!(matches!(
+            accept_bid(1, offer(10), principal(1)),
+            Err(ApiError::NotAllowed(_))
+        ));
+        assert!(!bid_store::has_bids(1));
+    }
+
+    #[test]
+    fn bids_on_hidden_or_missing_items_are_refused() {
+        clock::set(1_000 * HOUR);
+        let mut item = test_support::item(1, principal(1));
+        item.is_hidden = Some(true);
+        test_support::list(item);
+        assert!(matches!(
+            accept_bid(1, offer(10), principal(2)),
+            Err(ApiError::Closed(_))
+        ));
+        assert!(matches!(
+            accept_bid(2, offer(10), principal(2)),
+            Err(ApiError::NotFound(_))
+        ));
+        assert!(!bid_store::has_bids(1));
+    }
+
+    #[test]
+    fn a_higher_bid_takes_the_lead() {
+        clock::set(1_000 * HOUR);
+        test_support::list(test_support::item(1, principal(1)));
+        accepted(1, 10, principal(2));
+        assert_eq!(exposure::leader(1), Some(principal(2)));
+        accepted(1, 11, principal(3));
+        assert_eq!(exposure::leader(1), Some(principal(3)));
+        //the previous leader can bid again, the leader cannot match themselves
+        accepted(1, 12, principal(2));
+        assert!(matches!(
+            accept_bid(1, offer(12), principal(2)),
+            Err(ApiError::Invalid(_))
+        ));
+        assert_eq!(exposure::leader(1), Some(principal(2)));
+    }
+
+    #[test]
+    fn the_largest_item_fits() {
+        let item = Item {
+            item_owner: principal(1),
+            id: u64::MAX,
+            description: "\u{1F600}".repeat(validation::MAX_DESCRIPTION_BYTES / 4),
+            highest_bid: u64::MAX,
+            latest_update: u64::MAX,
+            result_date: u64::MAX,
+            bid_vector: vec![],
+            is_active: true,
+            accepts_offers: Some(true),
+            auto_accept_price: Some(u64::MAX),
+            is_hidden: Some(true),
+            terms: Some("\u{1F600}".repeat(validation::MAX_TERMS_BYTES / 4)),
+            category: Some("x".repeat(MAX_CATEGORY_LENGTH)),
+            status: Some(Status::Cancelled),
+            starts_at: Some(u64::MAX),
+            winner_policy: Some(WinnerPolicy::HighestBidAboveReserve { reserve: u64::MAX }),
+            relist_policy: Some(RelistPolicy::largest()),
+            relisted_from: Some(u64::MAX),
+            increment_bands: Some(increments::largest_bands()),
+            payment_mode: Some(PaymentMode::Icrc2 {
+                ledger: principal(9),
+            }),
+            bid_bond: Some(u64::MAX),
+            payment_window: Some(u64::MAX),
+            tax: Some(TaxInfo::largest()),
+            minimum_bid: Some(u64::MAX),
+            media: Some(vec![Media::largest(); 5]),
+            certified: Some(true),
+            bid_archive: Some(BidArchive::largest(principal(2))),
+        };
+        test_support::assert_fits(&item);
+    }
+
+    #[test]
+    fn principals_of_any_length_fit_a_key() {
+        test_support::assert_fits(&StorablePrincipal::from(principal(1)));
+        test_support::assert_fits(&StorablePrincipal::from(Principal::anonymous()));
+    }
+
+    #[test]
+    fn bids_are_refused_once_the_end_passes() {
+        clock::set(1_000 * HOUR);
+        test_support::list(test_support::item(1, principal(1)));
+        accepted(1, 10, principal(2));
+        //set does not fire the close timer, the end time alone has to stop the bid
+        clock::set(clock::time() + 24 * HOUR);
+        assert!(matches!(
+            accept_bid(1, offer(20), principal(3)),
+            Err(ApiError::Closed(_))
+        ));
+    }
+
+    #[test]
+    fn the_close_timer_sells_to_the_highest_bidder() {
+        clock::set(1_000 * HOUR);
+        test_support::list(test_support::item(1, principal(1)));
+        accepted(1, 10, principal(2));
+        accepted(1, 12, principal(3));
+
+        clock::advance(24 * HOUR - 1);
+        assert_eq!(test_support::stored(1).status(), Status::Active);
+        clock::advance(1);
+        assert_eq!(test_support::stored(1).status(), Status::Settled);
+        let sale = settlement::find_settlement(1).unwrap();
+        assert_eq!((sale.buyer, sale.price), (principal(3), 12));
+    }
+
+    #[test]
+    fn an_unbid_auction_ends_unsold() {
+        clock::set(1_000 * HOUR);
+        test_support::list(test_support::item(1, principal(1)));
+        clock::advance(24 * HOUR);
+        assert_eq!(test_support::stored(1).status(), Status::Ended);
+        assert!(settlement::find_settlement(1).is_err());
     }
 }
@@ -0,0 +1,132 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap, StableCell, Storable};
+use std::{borrow::Cow, cell::RefCell, fmt};
+
+use crate::{admin, clock::time, expiry, membership, Memory, StorablePrincipal, MEMORY_MANAGER};
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct ListingQuota {
+    max_active: Option<u64>, //concurrently active listings per principal, no limit when not set
+    max_active_verified: Option<u64>, //the same for verified sellers, max_active applies when not set
+}
+
+impl Storable for ListingQuota {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QuotaError {
+    TooManyListings { limit: u64, retry_at: Option<u64> }, //when the owner's first active listing ends
+}
+
+impl fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaError::TooManyListings { limit, .. } => write!(
+                f,
+                "You can have at most {} active listings at a time.",
+                limit
+            ),
+        }
+    }
+}
+
+impl From<QuotaError> for String {
+    fn from(e: QuotaError) -> Self {
+        e.to_string()
+    }
+}
+
+#[derive(CandidType)]
+struct QuotaUsage {
+    active: u64,
+    limit: Option<u64>,
+    verified: bool,
+}
+
+thread_local! {
+    static LISTING_QUOTA: RefCell<StableCell<ListingQuota, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51))),
+        ListingQuota::default()).unwrap());
+
+    //sellers an admin has verified, with when they were verified
+    static VERIFIED_SELLERS: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52)))));
+}
+
+pub fn is_verified(p: Principal) -> bool {
+    VERIFIED_SELLERS.with(|v| v.borrow().contains_key(&p.into()))
+}
+
+fn limit_for(p: Principal) -> Option<u64> {
+    let quota = LISTING_QUOTA.with(|c| c.borrow().get().clone());
+    let limit = if is_verified(p) {
+        quota.max_active_verified.or(quota.max_active)
+    } else {
+        quota.max_active
+    };
+    limit.map(|l| l + membership::perks_of(p).extra_listings)
+}
+
+/// Refuses another active listing once the owner is at their quota.
+pub fn ensure_below_quota(owner: Principal) -> Result<(), QuotaError> {
+    let Some(limit) = limit_for(owner) else {
+        return Ok(());
+    };
+    if expiry::seller_active_ids(owner).len() as u64 >= limit {
+        return Err(QuotaError::TooManyListings {
+            limit,
+            retry_at: expiry::seller_first_end(owner),
+        });
+    }
+    Ok(())
+}
+
+#[update(name = "setListingQuota")]
+fn set_listing_quota(
+    max_active: Option<u64>,
+    max_active_verified: Option<u64>,
+) -> Result<String, String> {
+    admin::ensure_admin()?;
+    if max_active == Some(0) || max_active_verified == Some(0) {
+        return Err("The quota has to allow at least one listing.".to_string());
+    }
+    LISTING_QUOTA.with(|c| {
+        c.borrow_mut()
+            .set(ListingQuota {
+                max_active,
+                max_active_verified,
+            })
+            .unwrap()
+    });
+    Ok("Listing quota updated".to_string())
+}
+
+#[update(name = "setSellerVerified")]
+fn set_seller_verified(p: Principal, verified: bool) -> Result<String, String> {
+    admin::ensure_admin()?;
+    VERIFIED_SELLERS.with(|v| {
+        if verified {
+            v.borrow_mut().insert(p.into(), time());
+        } else {
+            v.borrow_mut().remove(&p.into());
+        }
+    });
+    Ok(format!("{} verified: {}", p, verified))
+}
+
+#[query(name = "getListingQuota")]
+fn get_listing_quota() -> QuotaUsage {
+    let p = caller();
+    QuotaUsage {
+        active: expiry::seller_active_ids(p).len() as u64,
+        limit: limit_for(p),
+        verified: is_verified(p),
+    }
+}
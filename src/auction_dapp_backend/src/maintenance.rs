@@ -0,0 +1,106 @@
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_cdk::{api::call::accept_message, caller, inspect_message, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, StableCell, Storable};
+use std::{borrow::Cow, cell::RefCell, fmt};
+
+use crate::{admin, clock::time, validation, Memory, MEMORY_MANAGER};
+
+const MAX_MESSAGE_LENGTH: usize = 300;
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct ServiceStatus {
+    maintenance: bool,
+    message: Option<String>, //shown to users while the auction is under maintenance
+    since: Option<u64>,
+}
+
+impl Storable for ServiceStatus {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ServiceError {
+    Maintenance { message: Option<String> },
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::Maintenance { message: Some(m) } => {
+                write!(
+                    f,
+                    "Maintenance: the auction is temporarily unavailable. {}",
+                    m
+                )
+            }
+            ServiceError::Maintenance { message: None } => {
+                write!(f, "Maintenance: the auction is temporarily unavailable.")
+            }
+        }
+    }
+}
+
+impl From<ServiceError> for String {
+    fn from(e: ServiceError) -> Self {
+        e.to_string()
+    }
+}
+
+thread_local! {
+    static SERVICE_STATUS: RefCell<StableCell<ServiceStatus, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60))),
+        ServiceStatus::default()).unwrap());
+}
+
+fn status() -> ServiceStatus {
+    SERVICE_STATUS.with(|s| s.borrow().get().clone())
+}
+
+/// Fails while the auction is under maintenance, admins still get through to finish it.
+pub fn ensure_available() -> Result<(), ServiceError> {
+    let status = status();
+    if status.maintenance && !admin::is_admin(caller()) {
+        return Err(ServiceError::Maintenance {
+            message: status.message,
+        });
+    }
+    Ok(())
+}
+
+//rejects update calls from users before they go through consensus,
+//calls from other canisters skip this and are stopped by ensure_available
+#[inspect_message]
+fn inspect_message() {
+    if let Err(e) = ensure_available() {
+        ic_cdk::trap(&e.to_string());
+    }
+    accept_message();
+}
+
+//admins are whoever installed or upgraded the canister, see admin::init
+#[update(name = "setMaintenanceMode")]
+fn set_maintenance_mode(maintenance: bool, message: Option<String>) -> Result<String, String> {
+    admin::ensure_admin()?;
+    let message = validation::optional_text("Message", message, MAX_MESSAGE_LENGTH)?;
+    let status = ServiceStatus {
+        maintenance,
+        since: maintenance.then(time),
+        message: message.filter(|_| maintenance),
+    };
+    SERVICE_STATUS.with(|s| s.borrow_mut().set(status).unwrap());
+    Ok(format!(
+        "Maintenance mode {}",
+        if maintenance { "on" } else { "off" }
+    ))
+}
+
+#[query(name = "getServiceStatus")]
+fn get_service_status() -> ServiceStatus {
+    status()
+}
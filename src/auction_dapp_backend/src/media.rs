@@ -0,0 +1,234 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext, TransformFunc,
+};
+use ic_cdk::query;
+use sha2::{Digest, Sha256};
+use std::{cell::RefCell, time::Duration};
+
+use crate::{clock::time, indexer_sync, validation, ITEM_MAP};
+
+const MAX_MEDIA_PER_ITEM: usize = 5;
+//urls are ascii once percent-encoded, so they are bounded in bytes as well, five of them fit in an Item
+const MAX_URL_LENGTH: usize = 300;
+//the most an https outcall can return, bigger files cannot be checked
+const MAX_RESPONSE_BYTES: u64 = 2_000_000;
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const CHECKS_PER_RUN: usize = 5;
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct MediaBase {
+    url: String,
+    sha256: String, //hex digest of the file the url serves
+}
+
+#[derive(Deserialize, CandidType, Clone, PartialEq)]
+pub enum MediaCheck {
+    Pending,
+    Verified { checked_at: u64 },
+    Unverified { checked_at: u64, reason: String },
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct Media {
+    url: String,
+    sha256: String,
+    check: MediaCheck,
+}
+
+#[cfg(test)]
+impl Media {
+    /// The largest media entry a listing can carry, for the size tests of the Item.
+    pub fn largest() -> Self {
+        Media {
+            url: "x".repeat(MAX_URL_LENGTH),
+            sha256: "f".repeat(64),
+            check: MediaCheck::Unverified {
+                checked_at: u64::MAX,
+                reason: "\u{1F600}".repeat(validation::MAX_REASON_BYTES / 4),
+            },
+        }
+    }
+}
+
+thread_local! {
+    static CHECK_IN_PROGRESS: RefCell<bool> = const { RefCell::new(false) };
+}
+
+/// Checks the listed media, carrying over the result of earlier checks for files that did not change.
+pub fn validate(
+    media: Option<Vec<MediaBase>>,
+    previous: Option<&Vec<Media>>,
+) -> Result<Option<Vec<Media>>, String> {
+    let Some(media) = media else {
+        return Ok(None);
+    };
+    if media.len() > MAX_MEDIA_PER_ITEM {
+        return Err(format!(
+            "An item can have at most {} media files.",
+            MAX_MEDIA_PER_ITEM
+        ));
+    }
+    media
+        .into_iter()
+        .map(|m| {
+            let url =
+                validation::bounded_text("Media url", &m.url, MAX_URL_LENGTH, MAX_URL_LENGTH)?;
+            if !url.starts_with("https://") {
+                return Err("Media urls have to start with https://.".to_string());
+            }
+            let sha256 = m.sha256.trim().to_lowercase();
+            if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err("Media hashes have to be 64 hex characters.".to_string());
+            }
+            let check = previous
+                .and_then(|p| p.iter().find(|o| o.url == url && o.sha256 == sha256))
+                .map_or(MediaCheck::Pending, |o| o.check.clone());
+            Ok(Media { url, sha256, check })
+        })
+        .collect::<Result<Vec<_>, String>>()
+        .map(Some)
+}
+
+/// Whether two media lists point at the same files, ignoring how far they were checked.
+pub fn same_files(a: Option<&Vec<Media>>, b: Option<&Vec<Media>>) -> bool {
+    let files = |m: Option<&Vec<Media>>| {
+        m.map(|m| {
+            m.iter()
+                .map(|f| (f.url.clone(), f.sha256.clone()))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default()
+    };
+    files(a) == files(b)
+}
+
+pub fn start_check_timer() {
+    ic_cdk_timers::set_timer_interval(CHECK_INTERVAL, || ic_cdk::spawn(check_pending()));
+}
+
+//fetches a few pending files per run, each outcall costs cycles
+async fn check_pending() {
+    if CHECK_IN_PROGRESS.with(|c| c.replace(true)) {
+        return;
+    }
+    let pending: Vec<(u64, String, String)> = ITEM_MAP.with(|items| {
+        items
+            .borrow()
+            .iter()
+            .flat_map(|(id, item)| {
+                item.media
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|m| m.check == MediaCheck::Pending)
+                    .map(move |m| (id, m.url, m.sha256))
+            })
+            .take(CHECKS_PER_RUN)
+            .collect()
+    });
+    for (item_id, url, sha256) in pending {
+        let check = match fetch(&url).await {
+            Ok(body) if hex(&Sha256::digest(&body)) == sha256 => {
+                MediaCheck::Verified { checked_at: time() }
+            }
+            Ok(_) => MediaCheck::Unverified {
+                checked_at: time(),
+                reason: "The file does not match its hash.".to_string(),
+            },
+            Err(reason) => MediaCheck::Unverified {
+                checked_at: time(),
+                reason: validation::reason(reason),
+            },
+        };
+        record(item_id, &url, &sha256, check);
+    }
+    CHECK_IN_PROGRESS.with(|c| c.replace(false));
+}
+
+async fn fetch(url: &str) -> Result<Vec<u8>, String> {
+    let request = CanisterHttpRequestArgument {
+        url: url.to_string(),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        headers: vec![],
+        body: None,
+        transform: Some(TransformContext {
+            function: TransformFunc(candid::Func {
+                principal: ic_cdk::id(),
+                method: "transformMedia".to_string(),
+            }),
+            context: vec![],
+        }),
+    };
+    let (response,) = http_request(request)
+        .await
+        .map_err(|(_, msg)| format!("The file could not be fetched: {}", msg))?;
+    if response.status != 200u64 {
+        return Err(format!(
+            "The file could not be fetched: status {}",
+            response.status
+        ));
+    }
+    Ok(response.body)
+}
+
+//the item may have been edited while the file was fetched, only the same file gets the result
+fn record(item_id: u64, url: &str, sha256: &str, check: MediaCheck) {
+    let Some(mut item) = ITEM_MAP.with(|items| items.borrow().get(&item_id)) else {
+        return;
+    };
+    let Some(media) = item.media.as_mut() else {
+        return;
+    };
+    let mut changed = false;
+    for m in media
+        .iter_mut()
+        .filter(|m| m.url == url && m.sha256 == sha256 && m.check == MediaCheck::Pending)
+    {
+        m.check = check.clone();
+        changed = true;
+    }
+    if changed {
+        ITEM_MAP.with(|items| items.borrow_mut().insert(item_id, item));
+        indexer_sync::item_changed(item_id);
+    }
+}
+
+//replicas have to agree on the response, headers like Date differ between them
+#[query(name = "transformMedia")]
+fn transform_media(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: args.response.body,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base(url: &str) -> MediaBase {
+        MediaBase {
+            url: url.to_string(),
+            sha256: "F".repeat(64),
+        }
+    }
+
+    #[test]
+    fn urls_are_bounded_in_bytes() {
+        let longest = format!("https://{}", "x".repeat(MAX_URL_LENGTH - 8));
+        let media = validate(Some(vec![base(&longest)]), None).unwrap().unwrap();
+        assert_eq!(media[0].sha256, "f".repeat(64));
+        assert!(media[0].check == MediaCheck::Pending);
+
+        let multibyte = format!("https://{}", "\u{e9}".repeat(MAX_URL_LENGTH - 8));
+        assert!(multibyte.chars().count() <= MAX_URL_LENGTH);
+        assert!(validate(Some(vec![base(&multibyte)]), None).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{
+    memory_manager::MemoryId, BoundedStorable, StableBTreeMap, StableCell, Storable,
+};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{admin, balances, clock::time, ledgers, Memory, StorablePrincipal, MEMORY_MANAGER};
+
+const MEMBERSHIP_PERIOD_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(Deserialize, CandidType, Clone, Copy, PartialEq, Debug)]
+pub enum Tier {
+    Basic,
+    Pro,
+}
+
+#[derive(CandidType, Clone, Copy, Default)]
+pub struct Perks {
+    pub extra_listings: u64,          //on top of the listing quota
+    pub commission_discount_bps: u64, //taken off the seller's commission tier
+    pub promotion_discount_percent: u64,
+}
+
+impl Tier {
+    fn perks(self) -> Perks {
+        match self {
+            Tier::Basic => Perks {
+                extra_listings: 5,
+                commission_discount_bps: 50,
+                promotion_discount_percent: 10,
+            },
+            Tier::Pro => Perks {
+                extra_listings: 20,
+                commission_discount_bps: 150,
+                promotion_discount_percent: 25,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct Membership {
+    tier: Tier,
+    since: u64,
+    expires_at: u64,
+}
+
+impl Storable for Membership {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Membership {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+struct MembershipPrice {
+    tier: Tier,
+    ledger: Principal,
+    price: u128, //per 30 days, in the ledger's smallest unit
+}
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct MembershipPrices {
+    prices: Vec<MembershipPrice>,
+}
+
+impl Storable for MembershipPrices {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(CandidType)]
+struct MembershipPlan {
+    tier: Tier,
+    perks: Perks,
+    prices: Vec<MembershipPrice>,
+}
+
+thread_local! {
+    static MEMBERSHIP_MAP: RefCell<StableBTreeMap<StorablePrincipal, Membership, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))));
+
+    static MEMBERSHIP_PRICES: RefCell<StableCell<MembershipPrices, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54))),
+        MembershipPrices::default()).unwrap());
+}
+
+fn active_membership(p: Principal) -> Option<Membership> {
+    MEMBERSHIP_MAP
+        .with(|m| m.borrow().get(&p.into()))
+        .filter(|m| m.expires_at > time())
+}
+
+/// What the principal's current membership unlocks, nothing once it expired.
+pub fn perks_of(p: Principal) -> Perks {
+    active_membership(p).map_or(Perks::default(), |m| m.tier.perks())
+}
+
+//paid from the caller's token balance, buying the same tier again extends it,
+//switching tiers starts a new period right away
+#[update(name = "buyMembership")]
+fn buy_membership(tier: Tier, ledger: Principal) -> Result<Membership, String> {
+    let buyer = caller();
+    let price = MEMBERSHIP_PRICES
+        .with(|c| {
+            c.borrow()
+                .get()
+                .prices
+                .iter()
+                .find(|p| p.tier == tier && p.ledger == ledger)
+                .map(|p| p.price)
+        })
+        .ok_or_else(|| {
+            format!(
+                "The {:?} membership cannot be bought with this token.",
+                tier
+            )
+        })?;
+    balances::debit_token(buyer, ledger, price).map_err(|balance| {
+        format!(
+            "The membership costs {}, your balance is {}. Deposit more tokens first.",
+            price, balance
+        )
+    })?;
+    //the tokens stay in the canister's account, like the commission on token sales
+
+    let now = time();
+    let membership = match active_membership(buyer) {
+        Some(current) if current.tier == tier => Membership {
+            expires_at: current.expires_at + MEMBERSHIP_PERIOD_NS,
+            ..current
+        },
+        _ => Membership {
+            tier,
+            since: now,
+            expires_at: now + MEMBERSHIP_PERIOD_NS,
+        },
+    };
+    MEMBERSHIP_MAP.with(|m| m.borrow_mut().insert(buyer.into(), membership.clone()));
+    Ok(membership)
+}
+
+#[update(name = "setMembershipPrices")]
+fn set_membership_prices(prices: Vec<MembershipPrice>) -> Result<String, String> {
+    admin::ensure_admin()?;
+    let supported = ledgers::supported();
+    if let Some(p) = prices
+        .iter()
+        .find(|p| !supported.iter().any(|l| l.ledger == p.ledger))
+    {
+        return Err(format!("{} is not a supported ledger.", p.ledger));
+    }
+    if prices.iter().any(|p| p.price == 0) {
+        return Err("Memberships cannot be free.".to_string());
+    }
+    MEMBERSHIP_PRICES.with(|c| c.borrow_mut().set(MembershipPrices { prices }).unwrap());
+    Ok("Membership prices updated".to_string())
+}
+
+#[query(name = "getMembershipPlans")]
+fn get_membership_plans() -> Vec<MembershipPlan> {
+    let prices = MEMBERSHIP_PRICES.with(|c| c.borrow().get().prices.clone());
+    [Tier::Basic, Tier::Pro]
+        .into_iter()
+        .map(|tier| MembershipPlan {
+            tier,
+            perks: tier.perks(),
+            prices: prices.iter().filter(|p| p.tier == tier).cloned().collect(),
+        })
+        .collect()
+}
+
+#[query(name = "getMembership")]
+fn get_membership() -> Option<Membership> {
+    active_membership(caller())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_fits;
+
+    #[test]
+    fn a_membership_fits() {
+        assert_fits(&Membership {
+            tier: Tier::Pro,
+            since: u64::MAX,
+            expires_at: u64::MAX,
+        });
+    }
+}
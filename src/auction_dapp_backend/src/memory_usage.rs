@@ -0,0 +1,233 @@
+use candid::CandidType;
+use ic_cdk::{api::stable::stable64_size, query};
+use ic_stable_structures::{memory_manager::MemoryId, Memory as _, StableBTreeMap};
+use std::{cell::RefCell, time::Duration};
+
+use crate::{admin, clock::time, guard, Memory, MEMORY_MANAGER};
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+//a month of daily samples
+const KEPT_SAMPLES: usize = 30;
+
+//what every virtual memory holds, add new ones here when they are allocated
+const MEMORIES: &[(u8, &str)] = &[
+    (0, "items"),
+    (1, "item id counter"),
+    (2, "offers"),
+    (3, "offer id counter"),
+    (4, "settlements"),
+    (5, "admins"),
+    (6, "cycle balances"),
+    (7, "escrows"),
+    (8, "messages"),
+    (9, "reports"),
+    (10, "audit log"),
+    (11, "terms acceptances"),
+    (12, "end time index"),
+    (13, "promotions"),
+    (14, "watchlists"),
+    (15, "trending scores"),
+    (16, "price history"),
+    (17, "seller stats"),
+    (18, "seller end time index"),
+    (19, "bidder index"),
+    (20, "bidder spending"),
+    (21, "event subscriptions"),
+    (22, "subscription outbox"),
+    (23, "indexer change log"),
+    (24, "indexer config"),
+    (25, "block log"),
+    (26, "block account index"),
+    (27, "schema version"),
+    (28, "integrity report"),
+    (29, "bid sequence"),
+    (30, "stats counters"),
+    (31, "stats participants"),
+    (32, "start time index"),
+    (33, "winners"),
+    (34, "notifications"),
+    (35, "runner-up offers"),
+    (36, "cycle bid holds"),
+    (37, "allowance collections"),
+    (38, "ledgers"),
+    (39, "token balances"),
+    (40, "withdrawals"),
+    (41, "credited deposits"),
+    (42, "bid bonds"),
+    (43, "default counts"),
+    (44, "default policy"),
+    (45, "promotion refund policy"),
+    (46, "receipts"),
+    (47, "reference currencies"),
+    (48, "exposure caps"),
+    (49, "bid limits"),
+    (50, "amendments"),
+    (51, "listing quota"),
+    (52, "verified sellers"),
+    (53, "memberships"),
+    (54, "membership prices"),
+    (55, "referral policy"),
+    (56, "referrals"),
+    (57, "referrer index"),
+    (58, "promo codes"),
+    (59, "promo code redemptions"),
+    (60, "service status"),
+    (61, "modification log"),
+    (62, "last modified index"),
+    (63, "auction event log"),
+    (64, "certified leaves"),
+    (65, "partners"),
+    (66, "partner listings"),
+    (67, "partner authorizations"),
+    (68, "relay intermediaries"),
+    (69, "relay consents"),
+    (70, "bid signing keys"),
+    (71, "bid authorization nonces"),
+    (72, "approval policy"),
+    (73, "pending settlements"),
+    (74, "treasury config"),
+    (75, "treasury withdrawals"),
+    (76, "audit snapshot"),
+    (77, "compaction policy"),
+    (78, "archive policy"),
+    (79, "archived items"),
+    (80, "bids"),
+    (81, "bid counts"),
+    (82, "memory usage samples"),
+    (83, "storage policy"),
+    (84, "item subscribers"),
+    (85, "display names"),
+    (86, "display name owners"),
+    (87, "blocked display names"),
+    (88, "profiles"),
+    (89, "following"),
+    (90, "followers"),
+    (91, "followed feeds"),
+    (92, "notification preferences"),
+    (93, "relay endpoints"),
+    (94, "relay deliveries"),
+    (95, "sales by seller"),
+    (96, "daily sales"),
+    (97, "daily buyers"),
+    (98, "category analytics"),
+    (99, "purchases by buyer"),
+    (100, "watchers by item"),
+    (101, "ending soon notices"),
+    (102, "bids per bidder"),
+    (103, "leading bids"),
+    (104, "locked per user"),
+    (105, "treasury balances"),
+    (106, "pending deposit sweeps"),
+    (107, "token bid holds"),
+    (108, "relisted items"),
+];
+
+#[derive(CandidType)]
+struct MemoryUsage {
+    id: u8,
+    name: String,
+    pages: u64,
+    growth_pages: u64, //since the oldest kept sample
+}
+
+#[derive(CandidType)]
+struct MemoryReport {
+    stable_pages: u64, //all of stable memory, including the memory manager's own bookkeeping
+    page_size: u64,
+    memories: Vec<MemoryUsage>, //only the allocated ones, largest first
+    growth_since: Option<u64>,  //when the oldest kept sample was taken
+}
+
+thread_local! {
+    //(taken at, memory id) -> pages
+    static SAMPLES: RefCell<StableBTreeMap<(u64, u8), u64, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(82)))));
+}
+
+//a memory has no pages until its structure first writes to it
+fn pages_of(id: u8) -> u64 {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)).size())
+}
+
+fn sample_times() -> Vec<u64> {
+    let mut times: Vec<u64> = SAMPLES.with(|s| s.borrow().iter().map(|((t, _), _)| t).collect());
+    times.dedup();
+    times
+}
+
+fn take_sample() {
+    guard::check_high_water();
+    let now = time();
+    SAMPLES.with(|s| {
+        let mut s = s.borrow_mut();
+        for (id, _) in MEMORIES {
+            let pages = pages_of(*id);
+            if pages > 0 {
+                s.insert((now, *id), pages);
+            }
+        }
+    });
+    let times = sample_times();
+    let Some(&cutoff) = times.iter().rev().nth(KEPT_SAMPLES - 1) else {
+        return;
+    };
+    let expired: Vec<(u64, u8)> = SAMPLES.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(key, _)| key)
+            .take_while(|(t, _)| *t < cutoff)
+            .collect()
+    });
+    SAMPLES.with(|s| {
+        let mut s = s.borrow_mut();
+        for key in expired {
+            s.remove(&key);
+        }
+    });
+}
+
+pub fn start_sample_timer() {
+    ic_cdk_timers::set_timer_interval(SAMPLE_INTERVAL, take_sample);
+}
+
+#[query(name = "getMemoryUsage")]
+fn get_memory_usage() -> Result<MemoryReport, String> {
+    admin::ensure_admin()?;
+    let oldest = sample_times().first().copied();
+    let mut memories: Vec<MemoryUsage> = MEMORIES
+        .iter()
+        .map(|(id, name)| {
+            let pages = pages_of(*id);
+            let sampled = oldest
+                .and_then(|t| SAMPLES.with(|s| s.borrow().get(&(t, *id))))
+                .unwrap_or(0);
+            MemoryUsage {
+                id: *id,
+                name: name.to_string(),
+                pages,
+                growth_pages: pages.saturating_sub(sampled),
+            }
+        })
+        .filter(|m| m.pages > 0)
+        .collect();
+    memories.sort_by_key(|m| std::cmp::Reverse(m.pages));
+    Ok(MemoryReport {
+        stable_pages: stable64_size(),
+        page_size: WASM_PAGE_SIZE,
+        memories,
+        growth_since: oldest,
+    })
+}
+
+//the daily samples of one memory, oldest first
+#[query(name = "getMemoryHistory")]
+fn get_memory_history(id: u8) -> Result<Vec<(u64, u64)>, String> {
+    admin::ensure_admin()?;
+    Ok(SAMPLES.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|((_, m), _)| *m == id)
+            .map(|((t, _), pages)| (t, pages))
+            .collect()
+    }))
+}
@@ -0,0 +1,149 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    admin, bid_store,
+    clock::time,
+    guard,
+    notifications::{self, NotificationKind},
+    settlement, validation, Item, Memory, ITEM_MAP, MEMORY_MANAGER,
+};
+
+const MAX_MESSAGE_LENGTH: usize = 1_000;
+const MAX_PAGE_SIZE: u64 = 100;
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct Message {
+    item_id: u64,
+    seq: u64,
+    sender: Principal,
+    body: String,
+    sent_at: u64,
+}
+
+impl Storable for Message {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Message {
+    const MAX_SIZE: u32 = 5_000; //MAX_MESSAGE_LENGTH chars can take up to 4 bytes each
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType)]
+struct MessagePage {
+    messages: Vec<Message>,
+    next_seq: Option<u64>, //pass it back as `from_seq` to read the next page
+}
+
+thread_local! {
+    //one thread per item, keyed by (item_id, seq)
+    static MESSAGE_MAP: RefCell<StableBTreeMap<(u64, u64), Message, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))));
+}
+
+//the owner, everyone who bid and whoever bought the item can take part in its thread
+fn is_participant(item: &Item, p: Principal) -> bool {
+    item.item_owner == p
+        || bid_store::of(item.id)
+            .iter()
+            .any(|b| b.bidder_principal == p)
+        || settlement::find_settlement(item.id).is_ok_and(|s| s.buyer == p)
+}
+
+fn next_seq(item_id: u64) -> u64 {
+    MESSAGE_MAP.with(|m| {
+        m.borrow()
+            .range((item_id, 0)..=(item_id, u64::MAX))
+            .last()
+            .map_or(0, |((_, seq), _)| seq + 1)
+    })
+}
+
+#[update(name = "sendMessage")]
+fn send_message(item_id: u64, body: String) -> Result<String, String> {
+    guard::check_optional_write()?;
+    let item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+
+    if !is_participant(&item, caller()) {
+        return Err(
+            "Only the owner, bidders and the buyer can message about this item.".to_string(),
+        );
+    }
+    let body = validation::text("Message", &body, MAX_MESSAGE_LENGTH)?;
+
+    let seq = next_seq(item_id);
+    let message = Message {
+        item_id,
+        seq,
+        sender: caller(),
+        body,
+        sent_at: time(),
+    };
+    MESSAGE_MAP.with(|m| m.borrow_mut().insert((item_id, seq), message));
+    //the owner hears from everyone, the owner's messages go to the buyer or else the leading bidder
+    let recipient = if caller() != item.item_owner {
+        Some(item.item_owner)
+    } else {
+        settlement::find_settlement(item_id)
+            .map(|s| s.buyer)
+            .ok()
+            .or_else(|| bid_store::last(item_id).map(|b| b.bidder_principal))
+    };
+    if let Some(recipient) = recipient {
+        notifications::notify(recipient, NotificationKind::NewMessage { item_id, seq });
+    }
+    Ok(format!("Message {} sent", seq))
+}
+
+#[query(name = "getMessages")]
+fn get_messages(item_id: u64, from_seq: u64, limit: u64) -> Result<MessagePage, String> {
+    let item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+
+    if !is_participant(&item, caller()) && !admin::is_admin(caller()) {
+        return Err("You are not authorized to read messages about this item.".to_string());
+    }
+
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let mut messages: Vec<Message> = MESSAGE_MAP.with(|m| {
+        m.borrow()
+            .range((item_id, from_seq)..=(item_id, u64::MAX))
+            .take(limit + 1)
+            .map(|(_, v)| v)
+            .collect()
+    });
+    let next_seq = if messages.len() > limit {
+        messages.pop().map(|m| m.seq)
+    } else {
+        None
+    };
+    Ok(MessagePage { messages, next_seq })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    #[test]
+    fn the_longest_message_fits() {
+        assert_fits(&Message {
+            item_id: u64::MAX,
+            seq: u64::MAX,
+            sender: principal(1),
+            body: "\u{1F600}".repeat(MAX_MESSAGE_LENGTH),
+            sent_at: u64::MAX,
+        });
+    }
+}
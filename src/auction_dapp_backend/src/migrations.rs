@@ -0,0 +1,83 @@
+use ic_stable_structures::{memory_manager::MemoryId, StableCell};
+use std::cell::RefCell;
+
+use crate::{
+    analytics, bid_limits, bid_store, blocks, clock::time, expiry, exposure, relisting, settlement,
+    stats, sync, watchlist, Item, Memory, Status, ITEM_MAP, MEMORY_MANAGER,
+};
+
+//applied in order, a migration's schema version is its position in this list plus one.
+//never reorder or remove entries, only append new ones
+const MIGRATIONS: &[fn()] = &[
+    expiry::backfill_index,
+    reencode_items,
+    stats::backfill,
+    assign_statuses,
+    sync::backfill,
+    bid_store::move_out_of_items,
+    settlement::backfill_seller_index,
+    analytics::backfill,
+    analytics::backfill_categories,
+    settlement::backfill_buyer_index,
+    watchlist::backfill_item_index,
+    bid_limits::backfill,
+    exposure::backfill,
+    blocks::backfill_treasury,
+    relisting::backfill,
+];
+
+thread_local! {
+    //the number of migrations already applied to the stable state
+    static SCHEMA_VERSION: RefCell<StableCell<u64, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))),
+        0).unwrap());
+}
+
+/// A fresh install is already at the latest schema and needs no migrations.
+pub fn init() {
+    set_version(MIGRATIONS.len() as u64);
+}
+
+/// Applies every migration newer than the stored schema version.
+pub fn run() {
+    let current = SCHEMA_VERSION.with(|v| *v.borrow().get());
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        migration();
+        set_version(i as u64 + 1);
+    }
+}
+
+fn set_version(version: u64) {
+    SCHEMA_VERSION.with(|v| v.borrow_mut().set(version).unwrap());
+}
+
+//items written before the optional fields existed are decoded with them unset,
+//storing them again writes every field so older encodings don't linger
+fn reencode_items() {
+    let items: Vec<(u64, Item)> = ITEM_MAP.with(|items| items.borrow().iter().collect());
+    ITEM_MAP.with(|map| {
+        for (id, item) in items {
+            map.borrow_mut().insert(id, item);
+        }
+    });
+}
+
+//before statuses existed an inactive item could be a draft, a stopped listing or a closed auction,
+//drafts are the safest guess for the ones that were neither sold, removed nor past their end
+fn assign_statuses() {
+    let now = time();
+    let items: Vec<(u64, Item)> = ITEM_MAP.with(|items| items.borrow().iter().collect());
+    ITEM_MAP.with(|map| {
+        for (id, mut item) in items {
+            let status = match item {
+                _ if item.is_active => Status::Active,
+                _ if item.is_hidden == Some(true) => Status::Removed,
+                _ if settlement::find_settlement(id).is_ok() => Status::Settled,
+                _ if item.result_date <= now => Status::Ended,
+                _ => Status::Draft,
+            };
+            item.status = Some(status);
+            map.borrow_mut().insert(id, item);
+        }
+    });
+}
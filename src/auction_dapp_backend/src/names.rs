@@ -0,0 +1,202 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{
+    memory_manager::MemoryId, storable::Blob, BoundedStorable, StableBTreeMap, Storable,
+};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    admin,
+    audit::{self, AuditEvent},
+    clock::time,
+    guard, Memory, StorablePrincipal, MEMORY_MANAGER,
+};
+
+const MIN_NAME_LENGTH: usize = 3;
+const MAX_NAME_LENGTH: usize = 24;
+const MAX_RESOLVE: usize = 100;
+
+//lowercased, so names that only differ in case cannot both be taken
+type NameKey = Blob<MAX_NAME_LENGTH>;
+
+#[derive(Deserialize, CandidType, Clone)]
+struct DisplayName {
+    name: String, //as the owner typed it
+    set_at: u64,
+}
+
+impl Storable for DisplayName {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//names are checked by their length in bytes, a full-length one takes 54
+impl BoundedStorable for DisplayName {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static NAMES: RefCell<StableBTreeMap<StorablePrincipal, DisplayName, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(85)))));
+
+    static NAME_OWNERS: RefCell<StableBTreeMap<NameKey, StorablePrincipal, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(86)))));
+
+    //names an admin reclaimed and blocked, with when, nobody can take them again
+    static BLOCKED_NAMES: RefCell<StableBTreeMap<NameKey, u64, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(87)))));
+}
+
+//only ascii is allowed, but the length is still counted in bytes, which is what has to fit the stored name
+fn validate(name: &str) -> Result<String, String> {
+    let name = name.trim().to_string();
+    if name.len() < MIN_NAME_LENGTH
+        || name.len() > MAX_NAME_LENGTH
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err(format!(
+            "Display names are {} to {} letters, digits, dots, dashes or underscores.",
+            MIN_NAME_LENGTH, MAX_NAME_LENGTH
+        ));
+    }
+    Ok(name)
+}
+
+fn name_key(name: &str) -> NameKey {
+    Blob::try_from(name.to_lowercase().as_bytes()).unwrap()
+}
+
+/// The principal's display name, if they set one.
+pub fn of(p: Principal) -> Option<String> {
+    NAMES.with(|n| n.borrow().get(&p.into())).map(|d| d.name)
+}
+
+fn release(p: Principal) -> Option<String> {
+    let previous = NAMES.with(|n| n.borrow_mut().remove(&p.into()))?;
+    NAME_OWNERS.with(|o| o.borrow_mut().remove(&name_key(&previous.name)));
+    Some(previous.name)
+}
+
+#[update(name = "setDisplayName")]
+fn set_display_name(name: String) -> Result<String, String> {
+    guard::check_optional_write()?;
+    let name = validate(&name)?;
+    let key = name_key(&name);
+    if BLOCKED_NAMES.with(|b| b.borrow().contains_key(&key)) {
+        return Err("This display name is not available.".to_string());
+    }
+    let owner = NAME_OWNERS
+        .with(|o| o.borrow().get(&key))
+        .map(Principal::from);
+    if owner.is_some_and(|o| o != caller()) {
+        return Err("This display name is already taken.".to_string());
+    }
+    release(caller());
+    NAME_OWNERS.with(|o| o.borrow_mut().insert(key, caller().into()));
+    NAMES.with(|n| {
+        n.borrow_mut().insert(
+            caller().into(),
+            DisplayName {
+                name: name.clone(),
+                set_at: time(),
+            },
+        )
+    });
+    Ok(format!("Your display name is now {}", name))
+}
+
+#[update(name = "clearDisplayName")]
+fn clear_display_name() -> Result<String, String> {
+    match release(caller()) {
+        Some(name) => Ok(format!("{} is no longer your display name", name)),
+        None => Err("You have not set a display name.".to_string()),
+    }
+}
+
+//takes an abusive name away from whoever holds it, `block` keeps anyone from taking it again
+#[update(name = "reclaimDisplayName")]
+fn reclaim_display_name(name: String, block: bool) -> Result<String, String> {
+    admin::ensure_admin()?;
+    let name = validate(&name)?;
+    let key = name_key(&name);
+    let owner = NAME_OWNERS
+        .with(|o| o.borrow().get(&key))
+        .map(Principal::from);
+    if let Some(owner) = owner {
+        release(owner);
+    }
+    if block {
+        BLOCKED_NAMES.with(|b| b.borrow_mut().insert(key, time()));
+    } else if owner.is_none() {
+        return Err("Nobody holds this display name.".to_string());
+    }
+    audit::record(AuditEvent::DisplayNameReclaimed {
+        name: name.clone(),
+        holder: owner,
+        blocked: block,
+    });
+    Ok(format!("{} was reclaimed", name))
+}
+
+#[update(name = "unblockDisplayName")]
+fn unblock_display_name(name: String) -> Result<String, String> {
+    admin::ensure_admin()?;
+    let name = validate(&name)?;
+    match BLOCKED_NAMES.with(|b| b.borrow_mut().remove(&name_key(&name))) {
+        Some(_) => Ok(format!("{} can be taken again", name)),
+        None => Err("This display name is not blocked.".to_string()),
+    }
+}
+
+#[query(name = "getDisplayName")]
+fn get_display_name(p: Principal) -> Option<String> {
+    of(p)
+}
+
+//for lists that show many principals at once, principals without a name are left out
+#[query(name = "resolveDisplayNames")]
+fn resolve_display_names(principals: Vec<Principal>) -> Vec<(Principal, String)> {
+    principals
+        .into_iter()
+        .take(MAX_RESOLVE)
+        .filter_map(|p| of(p).map(|name| (p, name)))
+        .collect()
+}
+
+#[query(name = "getPrincipalByDisplayName")]
+fn get_principal_by_display_name(name: String) -> Option<Principal> {
+    let name = validate(&name).ok()?;
+    NAME_OWNERS
+        .with(|o| o.borrow().get(&name_key(&name)))
+        .map(Principal::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_fits;
+
+    #[test]
+    fn the_longest_name_fits() {
+        let name = validate(&"x".repeat(MAX_NAME_LENGTH)).unwrap();
+        let stored = DisplayName {
+            name,
+            set_at: u64::MAX,
+        };
+        assert_fits(&stored);
+    }
+
+    #[test]
+    fn names_are_bounded_in_bytes() {
+        assert!(validate("abc").is_ok());
+        assert!(validate(&"x".repeat(MAX_NAME_LENGTH + 1)).is_err());
+        //an 8 character name of 4-byte characters is 32 bytes
+        assert!(validate(&"\u{1F600}".repeat(8)).is_err());
+        assert!(validate("caf\u{e9}").is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//unicode canonical composition (NFC), so text that renders the same is stored and compared the same.
+//the character data is generated, see scripts/nfc_tables.py
+use crate::nfc_tables::{COMBINING_CLASSES, COMPOSITIONS, DECOMPOSITIONS};
+
+//hangul syllables are composed of leading consonants, vowels and optional trailing consonants
+const S_BASE: u32 = 0xAC00;
+const L_BASE: u32 = 0x1100;
+const V_BASE: u32 = 0x1161;
+const T_BASE: u32 = 0x11A7;
+const L_COUNT: u32 = 19;
+const V_COUNT: u32 = 21;
+const T_COUNT: u32 = 28;
+const N_COUNT: u32 = V_COUNT * T_COUNT;
+const S_COUNT: u32 = L_COUNT * N_COUNT;
+
+fn combining_class(c: char) -> u8 {
+    COMBINING_CLASSES
+        .binary_search_by_key(&c, |(c, _)| *c)
+        .map_or(0, |i| COMBINING_CLASSES[i].1)
+}
+
+//every value below is a valid scalar in the hangul blocks
+fn hangul(cp: u32) -> char {
+    char::from_u32(cp).unwrap()
+}
+
+fn decompose(c: char, out: &mut Vec<char>) {
+    let s = c as u32;
+    if (S_BASE..S_BASE + S_COUNT).contains(&s) {
+        let index = s - S_BASE;
+        out.push(hangul(L_BASE + index / N_COUNT));
+        out.push(hangul(V_BASE + (index % N_COUNT) / T_COUNT));
+        if !index.is_multiple_of(T_COUNT) {
+            out.push(hangul(T_BASE + index % T_COUNT));
+        }
+        return;
+    }
+    match DECOMPOSITIONS.binary_search_by_key(&c, |(c, _)| *c) {
+        Ok(i) => out.extend_from_slice(DECOMPOSITIONS[i].1),
+        Err(_) => out.push(c),
+    }
+}
+
+fn compose(a: char, b: char) -> Option<char> {
+    let (a32, b32) = (a as u32, b as u32);
+    if (L_BASE..L_BASE + L_COUNT).contains(&a32) && (V_BASE..V_BASE + V_COUNT).contains(&b32) {
+        let index = (a32 - L_BASE) * N_COUNT + (b32 - V_BASE) * T_COUNT;
+        return Some(hangul(S_BASE + index));
+    }
+    if (S_BASE..S_BASE + S_COUNT).contains(&a32)
+        && (a32 - S_BASE).is_multiple_of(T_COUNT)
+        && (T_BASE + 1..T_BASE + T_COUNT).contains(&b32)
+    {
+        return Some(hangul(a32 + (b32 - T_BASE)));
+    }
+    COMPOSITIONS
+        .binary_search_by_key(&(a, b), |(pair, _)| *pair)
+        .ok()
+        .map(|i| COMPOSITIONS[i].1)
+}
+
+/// Decomposes the text, puts combining marks in canonical order and composes it again.
+pub fn normalize(value: &str) -> String {
+    //ascii is always normalized, which is most of what the auction stores
+    if value.is_ascii() {
+        return value.to_string();
+    }
+    let mut chars = Vec::with_capacity(value.len());
+    for c in value.chars() {
+        decompose(c, &mut chars);
+    }
+
+    //each run of combining marks is sorted by class, marks of the same class keep their order
+    let mut i = 0;
+    while i < chars.len() {
+        if combining_class(chars[i]) == 0 {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < chars.len() && combining_class(chars[i]) != 0 {
+            i += 1;
+        }
+        chars[start..i].sort_by_key(|c| combining_class(*c));
+    }
+
+    //a mark composes with the last starter unless a mark of the same or a higher class comes between them
+    let mut out: Vec<char> = Vec::with_capacity(chars.len());
+    let mut starter: Option<usize> = None;
+    let mut last_class = 0;
+    for c in chars {
+        let class = combining_class(c);
+        if let Some(at) = starter {
+            let adjacent = out.len() == at + 1;
+            if adjacent || (last_class != 0 && last_class < class) {
+                if let Some(composed) = compose(out[at], c) {
+                    out[at] = composed;
+                    continue;
+                }
+            }
+        }
+        if class == 0 {
+            starter = Some(out.len());
+        }
+        last_class = class;
+        out.push(c);
+    }
+    out.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combining_marks_are_composed() {
+        assert_eq!(normalize("caf\u{65}\u{301}"), "caf\u{e9}");
+        assert_eq!(normalize("A\u{30A}"), "\u{c5}");
+        //the angstrom sign is a singleton, it always becomes the letter
+        assert_eq!(normalize("\u{212B}"), "\u{c5}");
+        assert_eq!(normalize("plain ascii"), "plain ascii");
+    }
+
+    #[test]
+    fn marks_are_put_in_canonical_order() {
+        assert_eq!(normalize("a\u{323}\u{302}"), "\u{1EAD}");
+        assert_eq!(normalize("a\u{302}\u{323}"), "\u{1EAD}");
+        assert_eq!(normalize("\u{1E0B}\u{323}"), "\u{1E0D}\u{307}");
+    }
+
+    #[test]
+    fn hangul_syllables_compose_arithmetically() {
+        assert_eq!(normalize("\u{1100}\u{1161}\u{11A8}"), "\u{AC01}");
+        assert_eq!(normalize("\u{AC00}\u{11A8}"), "\u{AC01}");
+        assert_eq!(normalize("\u{AC01}"), "\u{AC01}");
+    }
+
+    #[test]
+    fn excluded_and_blocked_compositions_stay_apart() {
+        //devanagari qa is excluded from composition
+        assert_eq!(normalize("\u{958}"), "\u{915}\u{93C}");
+        //a second acute cannot compose once the first one has
+        assert_eq!(normalize("e\u{301}\u{301}"), "\u{e9}\u{301}");
+    }
+}
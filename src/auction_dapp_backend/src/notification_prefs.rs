@@ -0,0 +1,151 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{clock::time, Memory, StorablePrincipal, MEMORY_MANAGER};
+
+const MINUTE: u64 = 60 * 1_000_000_000;
+const MINUTES_PER_DAY: u64 = 24 * 60;
+const MAX_UTC_OFFSET_MINUTES: i16 = 14 * 60;
+
+#[derive(Deserialize, CandidType, Clone, Copy, PartialEq, Debug)]
+pub enum NotificationType {
+    AuctionWon,
+    RunnerUpOffer,
+    Outbid,
+    StorageHighWater,
+    FollowedSellerListed,
+    NewMessage,
+    ItemSold,
+    EndingSoon,
+}
+
+//a daily window in the user's local time, it wraps past midnight when end is before start
+#[derive(Deserialize, CandidType, Clone, Copy)]
+struct QuietHours {
+    start_minute: u16, //minutes after local midnight
+    end_minute: u16,
+    utc_offset_minutes: i16,
+}
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct NotificationPrefs {
+    muted: Vec<NotificationType>,    //never written to the inbox
+    quiet_hours: Option<QuietHours>, //notifications arriving in it are held back until it ends
+}
+
+impl Storable for NotificationPrefs {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for NotificationPrefs {
+    const MAX_SIZE: u32 = 300;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static PREFS: RefCell<StableBTreeMap<StorablePrincipal, NotificationPrefs, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(92)))));
+}
+
+fn prefs_of(p: Principal) -> NotificationPrefs {
+    PREFS
+        .with(|s| s.borrow().get(&p.into()))
+        .unwrap_or_default()
+}
+
+//when the quiet hours the time falls in end, none outside of them
+fn quiet_until(quiet: QuietHours, now: u64) -> Option<u64> {
+    let offset = quiet.utc_offset_minutes as i64 * MINUTE as i64;
+    let local = (now as i64 + offset) as u64;
+    let minute = local / MINUTE % MINUTES_PER_DAY;
+    let (start, end) = (quiet.start_minute as u64, quiet.end_minute as u64);
+    let inside = if start <= end {
+        (start..end).contains(&minute)
+    } else {
+        minute >= start || minute < end
+    };
+    if !inside {
+        return None;
+    }
+    let minutes_left = (end + MINUTES_PER_DAY - minute) % MINUTES_PER_DAY;
+    Some(now - local % MINUTE + minutes_left * MINUTE)
+}
+
+/// Whether the recipient wants notifications of this type at all.
+pub fn wants(recipient: Principal, kind: NotificationType) -> bool {
+    !prefs_of(recipient).muted.contains(&kind)
+}
+
+/// When a notification written now should show up, none to show it right away.
+pub fn held_until(recipient: Principal) -> Option<u64> {
+    prefs_of(recipient)
+        .quiet_hours
+        .and_then(|q| quiet_until(q, time()))
+}
+
+#[update(name = "setNotificationPrefs")]
+fn set_notification_prefs(prefs: NotificationPrefs) -> Result<String, String> {
+    if let Some(q) = prefs.quiet_hours {
+        if q.start_minute as u64 >= MINUTES_PER_DAY || q.end_minute as u64 >= MINUTES_PER_DAY {
+            return Err("Quiet hours are given in minutes after midnight, below 1440.".to_string());
+        }
+        if q.start_minute == q.end_minute {
+            return Err("Quiet hours have to start and end at different times.".to_string());
+        }
+        if q.utc_offset_minutes.abs() > MAX_UTC_OFFSET_MINUTES {
+            return Err("The utc offset can be at most 14 hours.".to_string());
+        }
+    }
+    let mut muted = vec![];
+    for kind in prefs.muted {
+        if !muted.contains(&kind) {
+            muted.push(kind);
+        }
+    }
+    let prefs = NotificationPrefs {
+        muted,
+        quiet_hours: prefs.quiet_hours,
+    };
+    PREFS.with(|s| s.borrow_mut().insert(caller().into(), prefs));
+    Ok("Notification preferences updated".to_string())
+}
+
+#[query(name = "getNotificationPrefs")]
+fn get_notification_prefs() -> NotificationPrefs {
+    prefs_of(caller())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_fits;
+
+    #[test]
+    fn muting_every_notification_fits() {
+        use NotificationType::*;
+        assert_fits(&NotificationPrefs {
+            muted: vec![
+                AuctionWon,
+                RunnerUpOffer,
+                Outbid,
+                StorageHighWater,
+                FollowedSellerListed,
+                NewMessage,
+                ItemSold,
+                EndingSoon,
+            ],
+            quiet_hours: Some(QuietHours {
+                start_minute: u16::MAX,
+                end_minute: u16::MAX,
+                utc_offset_minutes: -MAX_UTC_OFFSET_MINUTES,
+            }),
+        });
+    }
+}
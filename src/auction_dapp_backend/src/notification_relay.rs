@@ -0,0 +1,445 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext, TransformFunc,
+};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell, time::Duration};
+
+use crate::{
+    clock::time, guard, notifications::NotificationKind, validation, Memory, StorablePrincipal,
+    MEMORY_MANAGER,
+};
+
+const MAX_URL_LENGTH: usize = 300;
+const MAX_TOKEN_LENGTH: usize = 200;
+const MAX_REASON_LENGTH: usize = 200;
+//only the status is read, the relay's answer is not needed
+const MAX_RESPONSE_BYTES: u64 = 1_024;
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+const RETRY_BASE_DELAY_NS: u64 = 30 * 1_000_000_000;
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(10);
+const DELIVERIES_PER_TICK: usize = 10;
+//finished deliveries kept per user so they can see what reached their relay
+const MAX_FINISHED_PER_USER: usize = 20;
+
+#[derive(Deserialize, CandidType)]
+struct RelayEndpointBase {
+    url: String,
+    token: Option<String>, //sent as a bearer token, for relay services that need one
+    min_outbid_amount: Option<u64>, //outbids are only relayed on items at least this high, none relays no outbids
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+struct RelayEndpoint {
+    url: String,
+    token: Option<String>,
+    min_outbid_amount: Option<u64>,
+    registered_at: u64,
+}
+
+#[derive(CandidType)]
+struct RelayEndpointInfo {
+    url: String,
+    has_token: bool, //the token itself is never sent back
+    min_outbid_amount: Option<u64>,
+    registered_at: u64,
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+enum RelayStatus {
+    Pending,
+    Delivered { at: u64 },
+    Failed { at: u64, reason: String }, //gave up after the last attempt
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+struct RelayDelivery {
+    seq: u64,
+    kind: NotificationKind,
+    created_at: u64,
+    attempts: u32,
+    next_attempt_at: u64,
+    in_flight: bool,
+    last_error: Option<String>,
+    status: RelayStatus,
+}
+
+impl Storable for RelayEndpoint {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RelayEndpoint {
+    const MAX_SIZE: u32 = 2_048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for RelayDelivery {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RelayDelivery {
+    const MAX_SIZE: u32 = 2_048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static ENDPOINTS: RefCell<StableBTreeMap<StorablePrincipal, RelayEndpoint, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(93)))));
+
+    //(recipient, seq) -> delivery
+    static DELIVERIES: RefCell<StableBTreeMap<(StorablePrincipal, u64), RelayDelivery, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(94)))));
+}
+
+fn deliveries_of(recipient: StorablePrincipal) -> Vec<RelayDelivery> {
+    DELIVERIES.with(|d| {
+        d.borrow()
+            .range((recipient, 0)..=(recipient, u64::MAX))
+            .map(|(_, delivery)| delivery)
+            .collect()
+    })
+}
+
+//wins and runner-up offers always matter, outbids only on items worth the user's threshold
+fn is_critical(endpoint: &RelayEndpoint, kind: &NotificationKind) -> bool {
+    match kind {
+        NotificationKind::AuctionWon { .. } | NotificationKind::RunnerUpOffer { .. } => true,
+        NotificationKind::Outbid { leading_bid, .. } => endpoint
+            .min_outbid_amount
+            .is_some_and(|min| *leading_bid >= min),
+        _ => false,
+    }
+}
+
+/// Queues a notification for the recipient's relay, if they registered one and it is critical.
+pub fn enqueue(recipient: Principal, kind: &NotificationKind, held_until: Option<u64>) {
+    let recipient = StorablePrincipal::from(recipient);
+    let Some(endpoint) = ENDPOINTS.with(|e| e.borrow().get(&recipient)) else {
+        return;
+    };
+    if !is_critical(&endpoint, kind) {
+        return;
+    }
+    let now = time();
+    DELIVERIES.with(|d| {
+        let seq = d
+            .borrow()
+            .range((recipient, 0)..=(recipient, u64::MAX))
+            .last()
+            .map_or(0, |((_, seq), _)| seq + 1);
+        let delivery = RelayDelivery {
+            seq,
+            kind: kind.clone(),
+            created_at: now,
+            attempts: 0,
+            next_attempt_at: held_until.unwrap_or(now),
+            in_flight: false,
+            last_error: None,
+            status: RelayStatus::Pending,
+        };
+        d.borrow_mut().insert((recipient, seq), delivery);
+    });
+}
+
+fn payload(recipient: Principal, delivery: &RelayDelivery) -> String {
+    let fields = match delivery.kind {
+        NotificationKind::AuctionWon { item_id, amount } => {
+            format!("\"item_id\":{},\"amount\":{}", item_id, amount)
+        }
+        NotificationKind::RunnerUpOffer {
+            item_id,
+            amount,
+            expires_at,
+        } => format!(
+            "\"item_id\":{},\"amount\":{},\"expires_at\":{}",
+            item_id, amount, expires_at
+        ),
+        NotificationKind::Outbid {
+            item_id,
+            leading_bid,
+            minimum_counter_bid,
+            ends_at,
+            ..
+        } => format!(
+            "\"item_id\":{},\"leading_bid\":{},\"minimum_counter_bid\":{},\"ends_at\":{}",
+            item_id, leading_bid, minimum_counter_bid, ends_at
+        ),
+        _ => String::new(),
+    };
+    let separator = if fields.is_empty() { "" } else { "," };
+    format!(
+        "{{\"recipient\":\"{}\",\"seq\":{},\"kind\":\"{}\",\"created_at\":{}{}{}}}",
+        recipient,
+        delivery.seq,
+        delivery.kind.name(),
+        delivery.created_at,
+        separator,
+        fields
+    )
+}
+
+pub fn start_delivery_timer() {
+    ic_cdk_timers::set_timer_interval(DELIVERY_INTERVAL, deliver_due);
+}
+
+fn deliver_due() {
+    let now = time();
+    let due: Vec<((StorablePrincipal, u64), RelayDelivery)> = DELIVERIES.with(|d| {
+        d.borrow()
+            .iter()
+            .filter(|(_, d)| {
+                matches!(d.status, RelayStatus::Pending) && !d.in_flight && d.next_attempt_at <= now
+            })
+            .take(DELIVERIES_PER_TICK)
+            .collect()
+    });
+
+    for (key, mut delivery) in due {
+        //the relay may have been removed since the delivery was queued
+        let Some(endpoint) = ENDPOINTS.with(|e| e.borrow().get(&key.0)) else {
+            DELIVERIES.with(|d| d.borrow_mut().remove(&key));
+            continue;
+        };
+        delivery.in_flight = true;
+        DELIVERIES.with(|d| d.borrow_mut().insert(key, delivery.clone()));
+        ic_cdk::spawn(async move {
+            let result = post(key.0.into(), &endpoint, &delivery).await;
+            finish_delivery(key, delivery, result);
+        });
+    }
+}
+
+async fn post(
+    recipient: Principal,
+    endpoint: &RelayEndpoint,
+    delivery: &RelayDelivery,
+) -> Result<(), String> {
+    //every replica sends the request, the key lets the relay drop the copies
+    let mut headers = vec![
+        HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        },
+        HttpHeader {
+            name: "Idempotency-Key".to_string(),
+            value: format!("{}-{}", recipient, delivery.seq),
+        },
+    ];
+    if let Some(token) = &endpoint.token {
+        headers.push(HttpHeader {
+            name: "Authorization".to_string(),
+            value: format!("Bearer {}", token),
+        });
+    }
+    let request = CanisterHttpRequestArgument {
+        url: endpoint.url.clone(),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers,
+        body: Some(payload(recipient, delivery).into_bytes()),
+        transform: Some(TransformContext {
+            function: TransformFunc(candid::Func {
+                principal: ic_cdk::id(),
+                method: "transformRelay".to_string(),
+            }),
+            context: vec![],
+        }),
+    };
+    let (response,) = http_request(request)
+        .await
+        .map_err(|(_, msg)| format!("The relay could not be reached: {}", msg))?;
+    if !(200u64..300u64).contains(&response.status) {
+        return Err(format!(
+            "The relay answered with status {}",
+            response.status
+        ));
+    }
+    Ok(())
+}
+
+//failed attempts back off exponentially, only the newest finished deliveries are kept
+fn finish_delivery(
+    key: (StorablePrincipal, u64),
+    mut delivery: RelayDelivery,
+    result: Result<(), String>,
+) {
+    delivery.attempts += 1;
+    delivery.in_flight = false;
+    match result {
+        Ok(()) => delivery.status = RelayStatus::Delivered { at: time() },
+        Err(reason) => {
+            let reason: String = reason.chars().take(MAX_REASON_LENGTH).collect();
+            if delivery.attempts >= MAX_DELIVERY_ATTEMPTS {
+                delivery.status = RelayStatus::Failed {
+                    at: time(),
+                    reason: reason.clone(),
+                };
+            } else {
+                delivery.next_attempt_at =
+                    time() + RETRY_BASE_DELAY_NS * 2u64.pow(delivery.attempts);
+            }
+            delivery.last_error = Some(reason);
+        }
+    }
+    DELIVERIES.with(|d| d.borrow_mut().insert(key, delivery));
+    prune_finished(key.0);
+}
+
+fn prune_finished(recipient: StorablePrincipal) {
+    let finished: Vec<u64> = deliveries_of(recipient)
+        .into_iter()
+        .filter(|d| !matches!(d.status, RelayStatus::Pending))
+        .map(|d| d.seq)
+        .collect();
+    if finished.len() > MAX_FINISHED_PER_USER {
+        DELIVERIES.with(|d| {
+            let mut d = d.borrow_mut();
+            for seq in &finished[..finished.len() - MAX_FINISHED_PER_USER] {
+                d.remove(&(recipient, *seq));
+            }
+        });
+    }
+}
+
+//in-flight flags don't survive an upgrade, outcalls interrupted by one are retried
+pub fn reset_in_flight() {
+    let stuck: Vec<((StorablePrincipal, u64), RelayDelivery)> =
+        DELIVERIES.with(|d| d.borrow().iter().filter(|(_, d)| d.in_flight).collect());
+    for (key, mut delivery) in stuck {
+        delivery.in_flight = false;
+        DELIVERIES.with(|d| d.borrow_mut().insert(key, delivery));
+    }
+}
+
+//replicas have to agree on the response, only the status is kept
+#[query(name = "transformRelay")]
+fn transform_relay(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: vec![],
+    }
+}
+
+//the token is kept in canister memory, use one that only allows posting to the relay
+#[update(name = "setRelayEndpoint")]
+fn set_relay_endpoint(endpoint: RelayEndpointBase) -> Result<String, String> {
+    guard::check_optional_write()?;
+    let url = validation::text("Relay url", &endpoint.url, MAX_URL_LENGTH)?;
+    if !url.starts_with("https://") {
+        return Err("Relay urls have to start with https://.".to_string());
+    }
+    let token = match endpoint.token.map(|t| t.trim().to_string()) {
+        Some(t) if t.len() > MAX_TOKEN_LENGTH => {
+            return Err(format!(
+                "Relay tokens can be at most {} characters.",
+                MAX_TOKEN_LENGTH
+            ));
+        }
+        Some(t) if t.chars().any(|c| !c.is_ascii_graphic()) => {
+            return Err("Relay tokens can only contain printable ascii characters.".to_string());
+        }
+        Some(t) if !t.is_empty() => Some(t),
+        _ => None,
+    };
+    let endpoint = RelayEndpoint {
+        url,
+        token,
+        min_outbid_amount: endpoint.min_outbid_amount,
+        registered_at: time(),
+    };
+    ENDPOINTS.with(|e| e.borrow_mut().insert(caller().into(), endpoint));
+    Ok("Relay endpoint registered".to_string())
+}
+
+//pending deliveries are dropped, the history of finished ones is kept
+#[update(name = "removeRelayEndpoint")]
+fn remove_relay_endpoint() -> Result<String, String> {
+    let recipient = StorablePrincipal::from(caller());
+    if ENDPOINTS
+        .with(|e| e.borrow_mut().remove(&recipient))
+        .is_none()
+    {
+        return Err("You have not registered a relay endpoint.".to_string());
+    }
+    let pending: Vec<u64> = deliveries_of(recipient)
+        .into_iter()
+        .filter(|d| matches!(d.status, RelayStatus::Pending) && !d.in_flight)
+        .map(|d| d.seq)
+        .collect();
+    DELIVERIES.with(|d| {
+        let mut d = d.borrow_mut();
+        for seq in pending {
+            d.remove(&(recipient, seq));
+        }
+    });
+    Ok("Relay endpoint removed".to_string())
+}
+
+#[query(name = "getRelayEndpoint")]
+fn get_relay_endpoint() -> Option<RelayEndpointInfo> {
+    ENDPOINTS
+        .with(|e| e.borrow().get(&caller().into()))
+        .map(|e| RelayEndpointInfo {
+            url: e.url,
+            has_token: e.token.is_some(),
+            min_outbid_amount: e.min_outbid_amount,
+            registered_at: e.registered_at,
+        })
+}
+
+//newest first
+#[query(name = "getRelayDeliveries")]
+fn get_relay_deliveries() -> Vec<RelayDelivery> {
+    let mut deliveries = deliveries_of(caller().into());
+    deliveries.reverse();
+    deliveries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_fits;
+
+    #[test]
+    fn the_largest_endpoint_and_delivery_fit() {
+        assert_fits(&RelayEndpoint {
+            url: "\u{1F600}".repeat(MAX_URL_LENGTH),
+            token: Some("x".repeat(MAX_TOKEN_LENGTH)),
+            min_outbid_amount: Some(u64::MAX),
+            registered_at: u64::MAX,
+        });
+        let reason = "\u{1F600}".repeat(MAX_REASON_LENGTH);
+        assert_fits(&RelayDelivery {
+            seq: u64::MAX,
+            kind: NotificationKind::Outbid {
+                item_id: u64::MAX,
+                leading_bid: u64::MAX,
+                minimum_counter_bid: u64::MAX,
+                ends_at: u64::MAX,
+                time_remaining: u64::MAX,
+            },
+            created_at: u64::MAX,
+            attempts: MAX_DELIVERY_ATTEMPTS,
+            next_attempt_at: u64::MAX,
+            in_flight: true,
+            last_error: Some(reason.clone()),
+            status: RelayStatus::Failed {
+                at: u64::MAX,
+                reason,
+            },
+        });
+    }
+}
@@ -0,0 +1,437 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::BTreeSet,
+    time::Duration,
+};
+
+use crate::{
+    bid_store, budget,
+    clock::time,
+    expiry, increments,
+    notification_prefs::{self, NotificationType},
+    notification_relay, watchlist, Memory, StorablePrincipal, ITEM_MAP, MEMORY_MANAGER,
+};
+
+const MAX_PAGE_SIZE: u64 = 100;
+//the digest only looks at this many of the newest notifications
+const MAX_DIGEST_SCAN: usize = 1_000;
+//outbid auctions ending within this are listed as urgent in the digest
+const URGENT_WITHIN: u64 = 60 * 60 * 1_000_000_000;
+const MAX_URGENT: usize = 5;
+const MAX_MARKED: usize = 100;
+//read notifications are deleted this long after they were read
+const READ_RETENTION: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const ENDING_SOON_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Deserialize, CandidType, Clone)]
+pub enum NotificationKind {
+    AuctionWon {
+        item_id: u64,
+        amount: u64,
+    },
+    RunnerUpOffer {
+        item_id: u64,
+        amount: u64,
+        expires_at: u64,
+    },
+    Outbid {
+        item_id: u64,
+        leading_bid: u64,
+        minimum_counter_bid: u64, //the lowest bid that would lead again
+        ends_at: u64,
+        time_remaining: u64, //when the notification was created
+    },
+    StorageHighWater {
+        used_bytes: u64,
+        high_water_bytes: u64, //non-essential writes are paused above this
+    },
+    FollowedSellerListed {
+        seller: Principal,
+        item_id: u64,
+    },
+    NewMessage {
+        item_id: u64,
+        seq: u64,
+    },
+    ItemSold {
+        item_id: u64,
+        amount: u64,
+    },
+    EndingSoon {
+        item_id: u64,
+        ends_at: u64,
+    },
+}
+
+impl NotificationKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NotificationKind::AuctionWon { .. } => "AuctionWon",
+            NotificationKind::RunnerUpOffer { .. } => "RunnerUpOffer",
+            NotificationKind::Outbid { .. } => "Outbid",
+            NotificationKind::StorageHighWater { .. } => "StorageHighWater",
+            NotificationKind::FollowedSellerListed { .. } => "FollowedSellerListed",
+            NotificationKind::NewMessage { .. } => "NewMessage",
+            NotificationKind::ItemSold { .. } => "ItemSold",
+            NotificationKind::EndingSoon { .. } => "EndingSoon",
+        }
+    }
+
+    fn notification_type(&self) -> NotificationType {
+        match self {
+            NotificationKind::AuctionWon { .. } => NotificationType::AuctionWon,
+            NotificationKind::RunnerUpOffer { .. } => NotificationType::RunnerUpOffer,
+            NotificationKind::Outbid { .. } => NotificationType::Outbid,
+            NotificationKind::StorageHighWater { .. } => NotificationType::StorageHighWater,
+            NotificationKind::FollowedSellerListed { .. } => NotificationType::FollowedSellerListed,
+            NotificationKind::NewMessage { .. } => NotificationType::NewMessage,
+            NotificationKind::ItemSold { .. } => NotificationType::ItemSold,
+            NotificationKind::EndingSoon { .. } => NotificationType::EndingSoon,
+        }
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct Notification {
+    seq: u64,
+    kind: NotificationKind,
+    created_at: u64,
+    read_at: Option<u64>,    //unread when not set
+    held_until: Option<u64>, //arrived in the recipient's quiet hours, shown once they end
+}
+
+impl Notification {
+    fn is_held(&self, now: u64) -> bool {
+        self.held_until.is_some_and(|t| t > now)
+    }
+}
+
+impl Storable for Notification {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//the candid type table grows with every kind, the largest notification now takes 285 bytes
+impl BoundedStorable for Notification {
+    const MAX_SIZE: u32 = 300;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType)]
+struct NotificationPage {
+    notifications: Vec<Notification>,
+    next_seq: Option<u64>, //pass it back as `from_seq` to read the next page
+}
+
+#[derive(CandidType)]
+struct KindCount {
+    kind: String,
+    count: u64,
+}
+
+#[derive(CandidType)]
+struct UrgentItem {
+    item_id: u64,
+    leading_bid: u64,
+    minimum_counter_bid: u64,
+    ends_at: u64,
+}
+
+#[derive(CandidType)]
+struct NotificationDigest {
+    total: u64, //unread notifications, like the counts below
+    by_kind: Vec<KindCount>,
+    urgent: Vec<UrgentItem>, //auctions the caller was outbid on that end soon, soonest first
+    latest_seq: Option<u64>,
+}
+
+thread_local! {
+    //one inbox per principal, keyed by (recipient, seq)
+    static INBOX: RefCell<StableBTreeMap<(StorablePrincipal, u64), Notification, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))));
+
+    //where the expiry sweep stopped when it ran out of budget
+    static EXPIRY_CURSOR: Cell<Option<(StorablePrincipal, u64)>> = const { Cell::new(None) };
+
+    //items whose bidders and watchers were told the auction ends soon, with the end they were told of
+    static ENDING_SOON_SENT: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(101)))));
+}
+
+/// Writes to the recipient's inbox, unless they muted notifications of this kind.
+pub fn notify(recipient: Principal, kind: NotificationKind) {
+    if !notification_prefs::wants(recipient, kind.notification_type()) {
+        return;
+    }
+    let held_until = notification_prefs::held_until(recipient);
+    notification_relay::enqueue(recipient, &kind, held_until);
+    let recipient = StorablePrincipal::from(recipient);
+    INBOX.with(|inbox| {
+        let seq = inbox
+            .borrow()
+            .range((recipient, 0)..=(recipient, u64::MAX))
+            .last()
+            .map_or(0, |((_, seq), _)| seq + 1);
+        let notification = Notification {
+            seq,
+            kind,
+            created_at: time(),
+            read_at: None,
+            held_until,
+        };
+        inbox.borrow_mut().insert((recipient, seq), notification);
+    });
+}
+
+pub fn start_expiry_timer() {
+    ic_cdk_timers::set_timer_interval(EXPIRY_INTERVAL, expire_read);
+}
+
+pub fn start_ending_soon_timer() {
+    ic_cdk_timers::set_timer_interval(ENDING_SOON_INTERVAL, notify_ending_soon);
+}
+
+//once per item, extending its end does not notify again
+fn notify_ending_soon() {
+    let now = time();
+    let past: Vec<u64> = ENDING_SOON_SENT.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, ends_at)| *ends_at < now)
+            .map(|(item_id, _)| item_id)
+            .collect()
+    });
+    for item_id in past {
+        //the item is closed by now, or extended and still remembered through its new end
+        let still_active = ITEM_MAP
+            .with(|items| items.borrow().get(&item_id))
+            .is_some_and(|item| item.is_active);
+        if !still_active {
+            ENDING_SOON_SENT.with(|s| s.borrow_mut().remove(&item_id));
+        }
+    }
+
+    for (ends_at, item_id) in expiry::ending_before(now + URGENT_WITHIN) {
+        if ENDING_SOON_SENT.with(|s| s.borrow().contains_key(&item_id)) {
+            continue;
+        }
+        let mut recipients: BTreeSet<Principal> = bid_store::of(item_id)
+            .into_iter()
+            .map(|bid| bid.bidder_principal)
+            .collect();
+        recipients.extend(watchlist::watchers_of(item_id));
+        for recipient in recipients {
+            notify(recipient, NotificationKind::EndingSoon { item_id, ends_at });
+        }
+        ENDING_SOON_SENT.with(|s| s.borrow_mut().insert(item_id, ends_at));
+    }
+}
+
+//unread notifications are kept however old they are
+fn expire_read() {
+    let cutoff = time().saturating_sub(READ_RETENTION);
+    let mut next = EXPIRY_CURSOR.with(|c| c.take());
+    loop {
+        let entry = INBOX.with(|inbox| {
+            let inbox = inbox.borrow();
+            match next {
+                Some(from) => inbox.range(from..).next(),
+                None => inbox.iter().next(),
+            }
+        });
+        let Some(((recipient, seq), n)) = entry else {
+            return;
+        };
+        if n.read_at.is_some_and(|t| t < cutoff) {
+            INBOX.with(|inbox| inbox.borrow_mut().remove(&(recipient, seq)));
+        }
+        next = Some((recipient, seq + 1));
+        if budget::exhausted() {
+            EXPIRY_CURSOR.with(|c| c.set(next));
+            budget::resume(expire_read);
+            return;
+        }
+    }
+}
+
+fn set_read(keys: impl Iterator<Item = (StorablePrincipal, u64)>) -> u64 {
+    let now = time();
+    INBOX.with(|inbox| {
+        let mut inbox = inbox.borrow_mut();
+        let mut marked = 0;
+        for key in keys {
+            if let Some(mut n) = inbox.get(&key).filter(|n| n.read_at.is_none()) {
+                n.read_at = Some(now);
+                inbox.insert(key, n);
+                marked += 1;
+            }
+        }
+        marked
+    })
+}
+
+#[update(name = "markRead")]
+fn mark_read(seqs: Vec<u64>) -> Result<String, String> {
+    if seqs.len() > MAX_MARKED {
+        return Err(format!(
+            "At most {} notifications can be marked at once.",
+            MAX_MARKED
+        ));
+    }
+    let recipient = StorablePrincipal::from(caller());
+    let marked = set_read(seqs.into_iter().map(|seq| (recipient, seq)));
+    Ok(format!("{} notifications marked as read", marked))
+}
+
+#[update(name = "markAllRead")]
+fn mark_all_read() -> Result<String, String> {
+    let recipient = StorablePrincipal::from(caller());
+    let keys: Vec<(StorablePrincipal, u64)> = INBOX.with(|inbox| {
+        inbox
+            .borrow()
+            .range((recipient, 0)..=(recipient, u64::MAX))
+            .filter(|(_, n)| n.read_at.is_none())
+            .map(|(k, _)| k)
+            .collect()
+    });
+    let marked = set_read(keys.into_iter());
+    Ok(format!("{} notifications marked as read", marked))
+}
+
+//dismissed notifications are deleted right away, read or not
+#[update(name = "dismissNotifications")]
+fn dismiss_notifications(seqs: Vec<u64>) -> Result<String, String> {
+    if seqs.len() > MAX_MARKED {
+        return Err(format!(
+            "At most {} notifications can be dismissed at once.",
+            MAX_MARKED
+        ));
+    }
+    let recipient = StorablePrincipal::from(caller());
+    let dismissed = INBOX.with(|inbox| {
+        let mut inbox = inbox.borrow_mut();
+        let mut dismissed = 0;
+        for seq in seqs {
+            if inbox.remove(&(recipient, seq)).is_some() {
+                dismissed += 1;
+            }
+        }
+        dismissed
+    });
+    Ok(format!("{} notifications dismissed", dismissed))
+}
+
+//everything a badge or dropdown needs in one call, urgent items are read from the items as they are now
+#[query(name = "getNotificationDigest")]
+fn get_notification_digest() -> NotificationDigest {
+    let viewer = caller();
+    let recipient = StorablePrincipal::from(viewer);
+    let mut notifications: Vec<Notification> = INBOX.with(|inbox| {
+        let inbox = inbox.borrow();
+        let Some(((_, last), _)) = inbox.range((recipient, 0)..=(recipient, u64::MAX)).last()
+        else {
+            return vec![];
+        };
+        let from = (last + 1).saturating_sub(MAX_DIGEST_SCAN as u64);
+        inbox
+            .range((recipient, from)..=(recipient, u64::MAX))
+            .map(|(_, v)| v)
+            .collect()
+    });
+    notifications.reverse();
+    let mut by_kind: Vec<KindCount> = vec![];
+    let mut outbid_on: Vec<u64> = vec![];
+    let now = time();
+    notifications.retain(|n| n.read_at.is_none() && !n.is_held(now));
+    for n in &notifications {
+        match by_kind.iter_mut().find(|k| k.kind == n.kind.name()) {
+            Some(k) => k.count += 1,
+            None => by_kind.push(KindCount {
+                kind: n.kind.name().to_string(),
+                count: 1,
+            }),
+        }
+        if let NotificationKind::Outbid { item_id, .. } = n.kind {
+            if !outbid_on.contains(&item_id) {
+                outbid_on.push(item_id);
+            }
+        }
+    }
+    let mut urgent: Vec<UrgentItem> = outbid_on
+        .into_iter()
+        .filter_map(|id| ITEM_MAP.with(|items| items.borrow().get(&id)))
+        .filter(|item| item.is_active && item.result_date > now)
+        .filter(|item| item.result_date - now <= URGENT_WITHIN)
+        .filter(|item| bid_store::last(item.id).is_some_and(|b| b.bidder_principal != viewer))
+        .map(|item| UrgentItem {
+            item_id: item.id,
+            leading_bid: item.highest_bid,
+            minimum_counter_bid: increments::next_minimum_bid(&item),
+            ends_at: item.result_date,
+        })
+        .collect();
+    urgent.sort_by_key(|u| u.ends_at);
+    urgent.truncate(MAX_URGENT);
+    NotificationDigest {
+        total: notifications.len() as u64,
+        by_kind,
+        urgent,
+        latest_seq: notifications.first().map(|n| n.seq),
+    }
+}
+
+#[query(name = "getNotifications")]
+fn get_notifications(from_seq: u64, limit: u64) -> NotificationPage {
+    let recipient = StorablePrincipal::from(caller());
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let now = time();
+    let mut notifications: Vec<Notification> = INBOX.with(|inbox| {
+        inbox
+            .borrow()
+            .range((recipient, from_seq)..=(recipient, u64::MAX))
+            .filter(|(_, n)| !n.is_held(now))
+            .take(limit + 1)
+            .map(|(_, v)| v)
+            .collect()
+    });
+    let next_seq = if notifications.len() > limit {
+        notifications.pop().map(|n| n.seq)
+    } else {
+        None
+    };
+    NotificationPage {
+        notifications,
+        next_seq,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_fits;
+
+    #[test]
+    fn the_largest_notification_fits() {
+        assert_fits(&Notification {
+            seq: u64::MAX,
+            kind: NotificationKind::Outbid {
+                item_id: u64::MAX,
+                leading_bid: u64::MAX,
+                minimum_counter_bid: u64::MAX,
+                ends_at: u64::MAX,
+                time_remaining: u64::MAX,
+            },
+            created_at: u64::MAX,
+            read_at: Some(u64::MAX),
+            held_until: Some(u64::MAX),
+        });
+    }
+}
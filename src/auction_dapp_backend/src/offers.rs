@@ -0,0 +1,269 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{
+    memory_manager::MemoryId, BoundedStorable, StableBTreeMap, StableCell, Storable,
+};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    clock::time,
+    cycle_bids, guard,
+    settlement::{self, SettlementSource},
+    terms, Item, Memory, ITEM_MAP, MEMORY_MANAGER,
+};
+
+#[derive(Deserialize, CandidType, Clone, Copy, PartialEq)]
+pub enum OfferStatus {
+    Pending,
+    Countered,
+    Accepted,
+    Declined,
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct Offer {
+    id: u64,
+    item_id: u64,
+    buyer: Principal,
+    amount: u64,
+    counter_amount: Option<u64>, //set by the seller when the offer is countered
+    status: OfferStatus,
+    created_at: u64,
+    updated_at: u64,
+}
+
+impl Storable for Offer {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Offer {
+    const MAX_SIZE: u32 = 500;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Offer {
+    fn is_open(&self) -> bool {
+        matches!(self.status, OfferStatus::Pending | OfferStatus::Countered)
+    }
+}
+
+thread_local! {
+    //keyed by (item_id, offer_id) so all offers of an item can be read with a single range
+    static OFFER_MAP: RefCell<StableBTreeMap<(u64, u64), Offer, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))));
+
+    static OFFER_ID_COUNTER: RefCell<StableCell<u64, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+        u64::default()).unwrap());
+}
+
+fn get_and_inc_offer_id() -> u64 {
+    let mut id_tmp = 0;
+    OFFER_ID_COUNTER.with(|counter| {
+        id_tmp = *(counter.borrow()).get();
+        counter.borrow_mut().set(id_tmp + 1).unwrap();
+    });
+    id_tmp
+}
+
+fn item_offers(item_id: u64) -> Vec<Offer> {
+    OFFER_MAP.with(|offers| {
+        offers
+            .borrow()
+            .range((item_id, 0)..=(item_id, u64::MAX))
+            .map(|(_, v)| v)
+            .collect()
+    })
+}
+
+fn find_negotiable_item(item_id: u64) -> Result<Item, String> {
+    let item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+    if !item.is_active {
+        return Err("The selected item is not actively listed.".to_string());
+    }
+    if item.accepts_offers != Some(true) || cycle_bids::is_cycles_item(&item) {
+        return Err("The selected item does not accept offers.".to_string());
+    }
+    Ok(item)
+}
+
+fn find_offer(item_id: u64, offer_id: u64) -> Result<Offer, String> {
+    OFFER_MAP
+        .with(|offers| offers.borrow().get(&(item_id, offer_id)))
+        .ok_or_else(|| "Offer could not be found.".to_string())
+}
+
+fn save_offer(offer: Offer) {
+    OFFER_MAP.with(|offers| offers.borrow_mut().insert((offer.item_id, offer.id), offer));
+}
+
+//accepting one offer closes the item, so every other open offer on it is declined
+fn decline_other_offers(item_id: u64, accepted_id: u64) {
+    let now = time();
+    for mut offer in item_offers(item_id) {
+        if offer.id != accepted_id && offer.is_open() {
+            offer.status = OfferStatus::Declined;
+            offer.updated_at = now;
+            save_offer(offer);
+        }
+    }
+}
+
+//settles the item at the agreed price and closes the rest of the negotiation
+fn complete_offer(mut offer: Offer, price: u64) -> Result<Offer, String> {
+    settlement::settle(
+        offer.item_id,
+        offer.buyer,
+        price,
+        SettlementSource::Offer { offer_id: offer.id },
+    )?;
+    offer.status = OfferStatus::Accepted;
+    offer.updated_at = time();
+    save_offer(offer.clone());
+    decline_other_offers(offer.item_id, offer.id);
+    Ok(offer)
+}
+
+#[update(name = "makeOffer")]
+fn make_offer(item_id: u64, amount: u64) -> Result<Offer, String> {
+    guard::check_write_headroom()?;
+    let item = find_negotiable_item(item_id)?;
+
+    if item.item_owner == caller() {
+        return Err("You cannot make an offer for your own item".to_string());
+    }
+    if amount == 0 {
+        return Err("Your offer must be greater than zero.".to_string());
+    }
+    terms::ensure_accepted(&item, caller())?;
+    if item_offers(item_id)
+        .iter()
+        .any(|o| o.buyer == caller() && o.is_open())
+    {
+        return Err("You already have an open offer for this item.".to_string());
+    }
+
+    let now = time();
+    let offer = Offer {
+        id: get_and_inc_offer_id(),
+        item_id,
+        buyer: caller(),
+        amount,
+        counter_amount: None,
+        status: OfferStatus::Pending,
+        created_at: now,
+        updated_at: now,
+    };
+
+    if item.auto_accept_price.is_some_and(|price| amount >= price) {
+        return complete_offer(offer, amount);
+    }
+    save_offer(offer.clone());
+    Ok(offer)
+}
+
+#[update(name = "counterOffer")]
+fn counter_offer(item_id: u64, offer_id: u64, amount: u64) -> Result<Offer, String> {
+    let item = find_negotiable_item(item_id)?;
+    let mut offer = find_offer(item_id, offer_id)?;
+
+    if item.item_owner != caller() {
+        return Err("You are not authorized to counter offers on this item.".to_string());
+    }
+    if offer.status != OfferStatus::Pending {
+        return Err("Only pending offers can be countered.".to_string());
+    }
+    if amount <= offer.amount {
+        return Err("A counter-offer must be higher than the original offer.".to_string());
+    }
+
+    offer.counter_amount = Some(amount);
+    offer.status = OfferStatus::Countered;
+    offer.updated_at = time();
+    save_offer(offer.clone());
+    Ok(offer)
+}
+
+//the seller accepts pending offers, the buyer accepts the seller's counter
+#[update(name = "acceptOffer")]
+fn accept_offer(item_id: u64, offer_id: u64) -> Result<String, String> {
+    let item = find_negotiable_item(item_id)?;
+    let offer = find_offer(item_id, offer_id)?;
+
+    let price = match offer.status {
+        OfferStatus::Pending if item.item_owner == caller() => offer.amount,
+        OfferStatus::Countered if offer.buyer == caller() => offer.counter_amount.unwrap(),
+        OfferStatus::Pending | OfferStatus::Countered => {
+            return Err("You are not authorized to accept this offer.".to_string())
+        }
+        _ => return Err("The selected offer is no longer open.".to_string()),
+    };
+
+    complete_offer(offer, price)?;
+    Ok(format!(
+        "Offer accepted, item {} sold for {}",
+        item_id, price
+    ))
+}
+
+#[update(name = "declineOffer")]
+fn decline_offer(item_id: u64, offer_id: u64) -> Result<String, String> {
+    let item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+    let mut offer = find_offer(item_id, offer_id)?;
+
+    let is_authorized = match offer.status {
+        OfferStatus::Pending => item.item_owner == caller(),
+        OfferStatus::Countered => offer.buyer == caller(),
+        _ => return Err("The selected offer is no longer open.".to_string()),
+    };
+    if !is_authorized {
+        return Err("You are not authorized to decline this offer.".to_string());
+    }
+
+    offer.status = OfferStatus::Declined;
+    offer.updated_at = time();
+    save_offer(offer);
+    Ok("Offer declined.".to_string())
+}
+
+//offers are private: the seller sees all of them, buyers only their own
+#[query(name = "getOffers")]
+fn get_offers(item_id: u64) -> Vec<Offer> {
+    let is_owner = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .is_some_and(|item| item.item_owner == caller());
+
+    item_offers(item_id)
+        .into_iter()
+        .filter(|o| is_owner || o.buyer == caller())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    #[test]
+    fn a_countered_offer_fits() {
+        assert_fits(&Offer {
+            id: u64::MAX,
+            item_id: u64::MAX,
+            buyer: principal(1),
+            amount: u64::MAX,
+            counter_amount: Some(u64::MAX),
+            status: OfferStatus::Countered,
+            created_at: u64::MAX,
+            updated_at: u64::MAX,
+        });
+    }
+}
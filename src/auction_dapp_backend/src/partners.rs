@@ -0,0 +1,197 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    admin, clock::time, create_item, guard, validation, Item, ItemBase, ItemSummary, ItemView,
+    Memory, Status, StorablePrincipal, ITEM_MAP, MEMORY_MANAGER,
+};
+
+const MAX_NAME_LENGTH: usize = 100;
+//what the name takes as UTF-8, all of it fits in a Partner
+const MAX_NAME_BYTES: usize = 150;
+
+#[derive(Deserialize, CandidType, Clone)]
+struct Partner {
+    name: String,
+    max_active: u64, //listings of the partner that can be open at a time
+    registered_at: u64,
+}
+
+impl Storable for Partner {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//a partner with a MAX_NAME_BYTES name takes 195 bytes
+impl BoundedStorable for Partner {
+    const MAX_SIZE: u32 = 200;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    //api principal of the partner dapp (usually its canister) -> partner
+    static PARTNERS: RefCell<StableBTreeMap<StorablePrincipal, Partner, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65)))));
+
+    //(partner, item_id) of every item a partner listed
+    static PARTNER_LISTINGS: RefCell<StableBTreeMap<(StorablePrincipal, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66)))));
+
+    //(seller, partner) pairs, a partner can only list for sellers that authorized it
+    static AUTHORIZATIONS: RefCell<StableBTreeMap<(StorablePrincipal, StorablePrincipal), u64, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67)))));
+}
+
+fn partner_of(p: Principal) -> Result<Partner, String> {
+    PARTNERS
+        .with(|partners| partners.borrow().get(&p.into()))
+        .ok_or_else(|| "Only registered partners can use the partner api.".to_string())
+}
+
+fn partner_items(partner: Principal) -> Vec<Item> {
+    let key = StorablePrincipal::from(partner);
+    let ids: Vec<u64> = PARTNER_LISTINGS.with(|l| {
+        l.borrow()
+            .range((key, 0)..=(key, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    ITEM_MAP.with(|items| {
+        let items = items.borrow();
+        ids.iter().filter_map(|id| items.get(id)).collect()
+    })
+}
+
+fn open_count(partner: Principal) -> u64 {
+    partner_items(partner)
+        .iter()
+        .filter(|item| {
+            matches!(
+                item.status(),
+                Status::Draft | Status::Scheduled | Status::Active
+            )
+        })
+        .count() as u64
+}
+
+#[update(name = "registerPartner")]
+fn register_partner(
+    api_principal: Principal,
+    name: String,
+    max_active: u64,
+) -> Result<String, String> {
+    admin::ensure_admin()?;
+    let name = validation::bounded_text("Partner name", &name, MAX_NAME_LENGTH, MAX_NAME_BYTES)?;
+    if max_active == 0 {
+        return Err("The quota has to allow at least one listing.".to_string());
+    }
+    let registered_at = PARTNERS
+        .with(|partners| partners.borrow().get(&api_principal.into()))
+        .map_or(time(), |p| p.registered_at);
+    PARTNERS.with(|partners| {
+        partners.borrow_mut().insert(
+            api_principal.into(),
+            Partner {
+                name,
+                max_active,
+                registered_at,
+            },
+        )
+    });
+    Ok(format!("Partner {} registered", api_principal))
+}
+
+//listings the partner already made stay up, it just cannot list more
+#[update(name = "removePartner")]
+fn remove_partner(api_principal: Principal) -> Result<String, String> {
+    admin::ensure_admin()?;
+    PARTNERS
+        .with(|partners| partners.borrow_mut().remove(&api_principal.into()))
+        .ok_or_else(|| "Partner could not be found.".to_string())?;
+    Ok(format!("Partner {} removed", api_principal))
+}
+
+#[update(name = "authorizePartner")]
+fn authorize_partner(partner: Principal, authorized: bool) -> Result<String, String> {
+    guard::check_write_headroom()?;
+    partner_of(partner)?;
+    let key = (
+        StorablePrincipal::from(caller()),
+        StorablePrincipal::from(partner),
+    );
+    AUTHORIZATIONS.with(|a| {
+        if authorized {
+            a.borrow_mut().insert(key, time());
+        } else {
+            a.borrow_mut().remove(&key);
+        }
+    });
+    Ok(format!("{} authorized: {}", partner, authorized))
+}
+
+#[update(name = "listItemOnBehalf")]
+fn list_item_on_behalf(seller: Principal, item: ItemBase) -> Result<ItemView, String> {
+    let partner = partner_of(caller())?;
+    let key = (
+        StorablePrincipal::from(seller),
+        StorablePrincipal::from(caller()),
+    );
+    if !AUTHORIZATIONS.with(|a| a.borrow().contains_key(&key)) {
+        return Err("The seller has not authorized this partner.".to_string());
+    }
+    if open_count(caller()) >= partner.max_active {
+        return Err(format!(
+            "A partner can have at most {} open listings at a time.",
+            partner.max_active
+        ));
+    }
+    let new_item = create_item(seller, item)?;
+    PARTNER_LISTINGS.with(|l| l.borrow_mut().insert((caller().into(), new_item.id), ()));
+    Ok(new_item.into())
+}
+
+#[query(name = "getListingsForPartner")]
+fn get_listings_for_partner() -> Result<Vec<ItemSummary>, String> {
+    partner_of(caller())?;
+    Ok(partner_items(caller())
+        .iter()
+        .map(ItemSummary::from)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_fits;
+
+    #[test]
+    fn the_largest_partner_name_fits() {
+        //37 characters of 4 bytes and 2 of one make up the whole byte bound
+        let name = "\u{1F600}".repeat(37) + "ab";
+        let name = validation::bounded_text("Partner name", &name, MAX_NAME_LENGTH, MAX_NAME_BYTES)
+            .unwrap();
+        let partner = Partner {
+            name,
+            max_active: u64::MAX,
+            registered_at: u64::MAX,
+        };
+        assert_fits(&partner);
+    }
+
+    #[test]
+    fn names_over_the_byte_bound_are_refused() {
+        let name = "\u{1F600}".repeat(38);
+        assert!(name.chars().count() <= MAX_NAME_LENGTH);
+        assert_eq!(
+            validation::bounded_text("Partner name", &name, MAX_NAME_LENGTH, MAX_NAME_BYTES),
+            Err(validation::ValidationError::TooLarge {
+                field: "Partner name",
+                max_bytes: MAX_NAME_BYTES
+            })
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_cdk::query;
+use ic_stable_structures::{
+    memory_manager::MemoryId, storable::Blob, BoundedStorable, StableBTreeMap, Storable,
+};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{Memory, MEMORY_MANAGER};
+
+pub const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+pub const UNCATEGORIZED: &str = "uncategorized";
+//the median is taken over this many prices per day, later sales only move count/sum/min/max
+const MAX_SAMPLED_PRICES: usize = 200;
+const MAX_QUERY_DAYS: u64 = 366;
+
+pub type CategoryKey = Blob<{ crate::MAX_CATEGORY_LENGTH }>;
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct PriceBucket {
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+    sampled_prices: Vec<u64>,
+}
+
+impl Storable for PriceBucket {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PriceBucket {
+    const MAX_SIZE: u32 = 2_500;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType)]
+struct PricePoint {
+    day: u64, //days since the unix epoch
+    count: u64,
+    average: u64,
+    median: u64,
+    min: u64,
+    max: u64,
+}
+
+thread_local! {
+    //settled prices bucketed per (category, day)
+    static PRICE_MAP: RefCell<StableBTreeMap<(CategoryKey, u64), PriceBucket, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))));
+}
+
+pub fn category_key(category: &str) -> CategoryKey {
+    Blob::try_from(category.as_bytes()).unwrap()
+}
+
+/// Called for every settlement.
+pub fn record(category: Option<&str>, price: u64, settled_at: u64) {
+    let key = (
+        category_key(category.unwrap_or(UNCATEGORIZED)),
+        settled_at / DAY_NS,
+    );
+    PRICE_MAP.with(|p| {
+        let mut bucket = p.borrow().get(&key).unwrap_or_default();
+        bucket.min = if bucket.count == 0 {
+            price
+        } else {
+            bucket.min.min(price)
+        };
+        bucket.max = bucket.max.max(price);
+        bucket.count += 1;
+        bucket.sum += price as u128;
+        if bucket.sampled_prices.len() < MAX_SAMPLED_PRICES {
+            bucket.sampled_prices.push(price);
+        }
+        p.borrow_mut().insert(key, bucket);
+    });
+}
+
+fn median(prices: &[u64]) -> u64 {
+    let mut sorted = prices.to_vec();
+    sorted.sort_unstable();
+    match sorted.len() {
+        0 => 0,
+        n if n % 2 == 0 => (sorted[n / 2 - 1] + sorted[n / 2]) / 2,
+        n => sorted[n / 2],
+    }
+}
+
+//days are counted since the unix epoch, both ends inclusive
+#[query(name = "getPriceHistory")]
+fn get_price_history(
+    category: String,
+    from_day: u64,
+    to_day: u64,
+) -> Result<Vec<PricePoint>, String> {
+    if category.len() > crate::MAX_CATEGORY_LENGTH {
+        return Err("Category could not be found.".to_string());
+    }
+    if to_day < from_day || to_day - from_day >= MAX_QUERY_DAYS {
+        return Err(format!(
+            "Price history can be queried for up to {} days at a time.",
+            MAX_QUERY_DAYS
+        ));
+    }
+
+    let key = category_key(&crate::normalize_category(&category));
+    Ok(PRICE_MAP.with(|p| {
+        p.borrow()
+            .range((key, from_day)..=(key, to_day))
+            .map(|((_, day), b)| PricePoint {
+                day,
+                count: b.count,
+                average: (b.sum / b.count as u128) as u64,
+                median: median(&b.sampled_prices),
+                min: b.min,
+                max: b.max,
+            })
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_fits;
+
+    #[test]
+    fn a_fully_sampled_bucket_fits() {
+        assert_fits(&PriceBucket {
+            count: u64::MAX,
+            sum: u128::MAX,
+            min: u64::MAX,
+            max: u64::MAX,
+            sampled_prices: vec![u64::MAX; MAX_SAMPLED_PRICES],
+        });
+    }
+}
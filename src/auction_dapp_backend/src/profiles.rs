@@ -0,0 +1,144 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{clock::time, guard, names, validation, Memory, StorablePrincipal, MEMORY_MANAGER};
+
+const MAX_BIO_LENGTH: usize = 500;
+const MAX_URL_LENGTH: usize = 300;
+const MAX_LINK_LABEL_LENGTH: usize = 32;
+const MAX_LINKS: usize = 5;
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct ProfileLink {
+    label: String, //e.g. "website" or "email"
+    url: String,   //https:// or mailto:
+}
+
+#[derive(Deserialize, CandidType)]
+struct ProfileBase {
+    avatar: Option<String>, //https url of the image, it is not fetched or checked
+    bio: Option<String>,
+    links: Vec<ProfileLink>,
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct Profile {
+    display_name: Option<String>, //filled in when read, see names.rs
+    avatar: Option<String>,
+    bio: Option<String>,
+    links: Vec<ProfileLink>,
+    updated_at: u64,
+}
+
+impl Storable for Profile {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Profile {
+    const MAX_SIZE: u32 = 4_096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static PROFILES: RefCell<StableBTreeMap<StorablePrincipal, Profile, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(88)))));
+}
+
+fn validate_url(field: &'static str, url: &str, schemes: &[&str]) -> Result<String, String> {
+    let url = validation::text(field, url, MAX_URL_LENGTH)?;
+    if !schemes.iter().any(|s| url.starts_with(s)) {
+        return Err(format!(
+            "{} has to start with {}.",
+            field,
+            schemes.join(" or ")
+        ));
+    }
+    Ok(url)
+}
+
+/// The principal's profile with their display name, if they set one.
+pub fn of(p: Principal) -> Option<Profile> {
+    PROFILES
+        .with(|s| s.borrow().get(&p.into()))
+        .map(|mut profile| {
+            profile.display_name = names::of(p);
+            profile
+        })
+}
+
+#[update(name = "setProfile")]
+fn set_profile(profile: ProfileBase) -> Result<String, String> {
+    guard::check_optional_write()?;
+    if profile.links.len() > MAX_LINKS {
+        return Err(format!("A profile can have at most {} links.", MAX_LINKS));
+    }
+    let avatar = match profile.avatar.map(|a| validation::sanitize(&a)) {
+        Some(a) if !a.is_empty() => Some(validate_url("Avatar url", &a, &["https://"])?),
+        _ => None,
+    };
+    let links = profile
+        .links
+        .into_iter()
+        .map(|l| {
+            Ok(ProfileLink {
+                label: validation::text("Link label", &l.label, MAX_LINK_LABEL_LENGTH)?,
+                url: validate_url("Link url", &l.url, &["https://", "mailto:"])?,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let profile = Profile {
+        display_name: None,
+        avatar,
+        bio: validation::optional_text("Bio", profile.bio, MAX_BIO_LENGTH)?,
+        links,
+        updated_at: time(),
+    };
+    //the limits are in characters, multi-byte text can still go over what a profile may take up
+    if profile.to_bytes().len() > Profile::MAX_SIZE as usize {
+        return Err("The profile is too large, shorten the bio or the links.".to_string());
+    }
+    PROFILES.with(|s| s.borrow_mut().insert(caller().into(), profile));
+    Ok("Profile updated".to_string())
+}
+
+#[update(name = "clearProfile")]
+fn clear_profile() -> Result<String, String> {
+    match PROFILES.with(|s| s.borrow_mut().remove(&caller().into())) {
+        Some(_) => Ok("Profile removed".to_string()),
+        None => Err("You have not set a profile.".to_string()),
+    }
+}
+
+#[query(name = "getProfile")]
+fn get_profile(p: Principal) -> Option<Profile> {
+    of(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_fits;
+
+    #[test]
+    fn an_ascii_profile_at_every_limit_fits() {
+        assert_fits(&Profile {
+            display_name: None,
+            avatar: Some("x".repeat(MAX_URL_LENGTH)),
+            bio: Some("x".repeat(MAX_BIO_LENGTH)),
+            links: (0..MAX_LINKS)
+                .map(|_| ProfileLink {
+                    label: "x".repeat(MAX_LINK_LABEL_LENGTH),
+                    url: "x".repeat(MAX_URL_LENGTH),
+                })
+                .collect(),
+            updated_at: u64::MAX,
+        });
+    }
+}
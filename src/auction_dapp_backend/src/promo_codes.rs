@@ -0,0 +1,226 @@
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{
+    memory_manager::MemoryId, storable::Blob, BoundedStorable, StableBTreeMap, Storable,
+};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    admin,
+    audit::{self, AuditEvent},
+    clock::time,
+    settlement, Memory, ITEM_MAP, MEMORY_MANAGER,
+};
+
+const MAX_CODE_LENGTH: usize = 32;
+
+type CodeKey = Blob<MAX_CODE_LENGTH>;
+
+#[derive(Deserialize, CandidType, Clone, Copy, PartialEq, Debug)]
+pub enum Discount {
+    Percent(u64), //off the commission
+    Flat(u128),   //off the commission, in the smallest unit of the item's currency
+}
+
+impl Discount {
+    fn apply(self, commission: u128) -> u128 {
+        match self {
+            Discount::Percent(p) => commission * p as u128 / 100,
+            Discount::Flat(amount) => amount.min(commission),
+        }
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+struct PromoCode {
+    code: String,
+    discount: Discount,
+    max_redemptions: Option<u64>, //no limit when not set
+    redemptions: u64,
+    expires_at: Option<u64>,
+    created_at: u64,
+}
+
+impl Storable for PromoCode {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PromoCode {
+    const MAX_SIZE: u32 = 200;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+struct Redemption {
+    code: String,
+    discount: Discount,
+    redeemed_at: u64,
+}
+
+impl Storable for Redemption {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Redemption {
+    const MAX_SIZE: u32 = 150;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static PROMO_CODES: RefCell<StableBTreeMap<CodeKey, PromoCode, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58)))));
+
+    //at most one code per item, keyed by the item id
+    static REDEMPTIONS: RefCell<StableBTreeMap<u64, Redemption, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59)))));
+}
+
+//codes are matched case-insensitively
+fn normalize(code: &str) -> Result<String, String> {
+    let code = code.trim().to_uppercase();
+    if code.is_empty()
+        || code.len() > MAX_CODE_LENGTH
+        || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err(format!(
+            "Promo codes are 1 to {} letters, digits or dashes.",
+            MAX_CODE_LENGTH
+        ));
+    }
+    Ok(code)
+}
+
+fn code_key(code: &str) -> CodeKey {
+    Blob::try_from(code.as_bytes()).unwrap()
+}
+
+/// Checks that a code can still be redeemed, returning it normalized.
+pub fn check(code: &str) -> Result<String, String> {
+    let code = normalize(code)?;
+    let promo = PROMO_CODES
+        .with(|p| p.borrow().get(&code_key(&code)))
+        .ok_or_else(|| "The promo code does not exist.".to_string())?;
+    if promo.expires_at.is_some_and(|t| t <= time()) {
+        return Err("The promo code has expired.".to_string());
+    }
+    if promo
+        .max_redemptions
+        .is_some_and(|max| promo.redemptions >= max)
+    {
+        return Err("The promo code has been used up.".to_string());
+    }
+    Ok(code)
+}
+
+/// Attaches a code to the item, its discount is taken off the commission when the sale is paid out.
+pub fn redeem(item_id: u64, code: &str) -> Result<(), String> {
+    if REDEMPTIONS.with(|r| r.borrow().contains_key(&item_id)) {
+        return Err("A promo code was already redeemed for this item.".to_string());
+    }
+    let code = check(code)?;
+    let mut promo = PROMO_CODES
+        .with(|p| p.borrow().get(&code_key(&code)))
+        .ok_or_else(|| "The promo code does not exist.".to_string())?;
+    promo.redemptions += 1;
+    let redemption = Redemption {
+        code: code.clone(),
+        discount: promo.discount,
+        redeemed_at: time(),
+    };
+    PROMO_CODES.with(|p| p.borrow_mut().insert(code_key(&code), promo));
+    REDEMPTIONS.with(|r| r.borrow_mut().insert(item_id, redemption));
+    audit::record(AuditEvent::PromoCodeRedeemed { code, item_id });
+    Ok(())
+}
+
+/// What the item's promo code takes off its commission.
+pub fn discount_for(item_id: u64, commission: u128) -> u128 {
+    REDEMPTIONS
+        .with(|r| r.borrow().get(&item_id))
+        .map_or(0, |r| r.discount.apply(commission))
+}
+
+//for items listed without a code, up until they sell
+#[update(name = "applyPromoCode")]
+fn apply_promo_code(item_id: u64, code: String) -> Result<String, String> {
+    let item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+    if item.item_owner != caller() {
+        return Err("Only the owner can apply a promo code to this item.".to_string());
+    }
+    if settlement::find_settlement(item_id).is_ok() {
+        return Err("Promo codes cannot be applied after the item sold.".to_string());
+    }
+    redeem(item_id, &code)?;
+    Ok("Promo code applied".to_string())
+}
+
+#[update(name = "createPromoCode")]
+fn create_promo_code(
+    code: String,
+    discount: Discount,
+    max_redemptions: Option<u64>,
+    expires_at: Option<u64>,
+) -> Result<String, String> {
+    admin::ensure_admin()?;
+    let code = normalize(&code)?;
+    if matches!(discount, Discount::Percent(p) if p == 0 || p > 100)
+        || discount == Discount::Flat(0)
+    {
+        return Err("A percentage discount has to be 1 to 100, a flat one above zero.".to_string());
+    }
+    if PROMO_CODES.with(|p| p.borrow().contains_key(&code_key(&code))) {
+        return Err("The promo code already exists.".to_string());
+    }
+    let promo = PromoCode {
+        code: code.clone(),
+        discount,
+        max_redemptions,
+        redemptions: 0,
+        expires_at,
+        created_at: time(),
+    };
+    PROMO_CODES.with(|p| p.borrow_mut().insert(code_key(&code), promo));
+    Ok(format!("Promo code {} created", code))
+}
+
+#[query(name = "getPromoCodes")]
+fn get_promo_codes() -> Result<Vec<PromoCode>, String> {
+    admin::ensure_admin()?;
+    Ok(PROMO_CODES.with(|p| p.borrow().iter().map(|(_, v)| v).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_fits;
+
+    #[test]
+    fn the_longest_code_fits() {
+        let code = "x".repeat(MAX_CODE_LENGTH);
+        assert_fits(&PromoCode {
+            code: code.clone(),
+            discount: Discount::Flat(u128::MAX),
+            max_redemptions: Some(u64::MAX),
+            redemptions: u64::MAX,
+            expires_at: Some(u64::MAX),
+            created_at: u64::MAX,
+        });
+        assert_fits(&Redemption {
+            code,
+            discount: Discount::Flat(u128::MAX),
+            redeemed_at: u64::MAX,
+        });
+    }
+}
@@ -0,0 +1,233 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{
+    api::call::{msg_cycles_accept128, msg_cycles_available128},
+    caller, query, update,
+};
+use ic_stable_structures::{
+    memory_manager::MemoryId, BoundedStorable, StableBTreeMap, StableCell, Storable,
+};
+use std::{borrow::Cow, cell::RefCell, time::Duration};
+
+use crate::{
+    admin, balances,
+    blocks::{self, FinancialOp},
+    clock::time,
+    is_visible_to, membership, Item, ItemSummary, Memory, ITEM_MAP, MEMORY_MANAGER,
+};
+
+pub const PROMOTION_PRICE_PER_DAY: u128 = 100_000_000_000; //in cycles
+pub const MAX_PROMOTION_DAYS: u64 = 30;
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct Promotion {
+    item_id: u64,
+    owner: Principal,
+    starts_at: u64,
+    expires_at: u64,
+    fee_paid: u128, //total cycles paid, including extensions
+}
+
+impl Storable for Promotion {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Promotion {
+    const MAX_SIZE: u32 = 200;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//how much of the unused promotion time is paid back when an item is cancelled before any bids
+#[derive(Deserialize, CandidType, Clone)]
+struct FeeRefundPolicy {
+    unused_time_percent: u64,
+}
+
+impl Default for FeeRefundPolicy {
+    fn default() -> Self {
+        FeeRefundPolicy {
+            unused_time_percent: 100,
+        }
+    }
+}
+
+impl Storable for FeeRefundPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static PROMOTION_MAP: RefCell<StableBTreeMap<u64, Promotion, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))));
+
+    static REFUND_POLICY: RefCell<StableCell<FeeRefundPolicy, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45))),
+        FeeRefundPolicy::default()).unwrap());
+}
+
+pub fn start_expiry_timer() {
+    ic_cdk_timers::set_timer_interval(EXPIRY_CHECK_INTERVAL, purge_expired);
+}
+
+fn purge_expired() {
+    let now = time();
+    let expired: Vec<u64> = PROMOTION_MAP.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_, v)| v.expires_at <= now)
+            .map(|(k, _)| k)
+            .collect()
+    });
+    PROMOTION_MAP.with(|p| {
+        for item_id in expired {
+            p.borrow_mut().remove(&item_id);
+        }
+    });
+}
+
+/// Currently promoted items, most recently promoted first.
+pub fn featured_items() -> Vec<Item> {
+    let now = time();
+    let mut promotions: Vec<Promotion> = PROMOTION_MAP.with(|p| {
+        p.borrow()
+            .iter()
+            .map(|(_, v)| v)
+            .filter(|v| v.starts_at <= now && now < v.expires_at)
+            .collect()
+    });
+    promotions.sort_by_key(|p| std::cmp::Reverse(p.starts_at));
+
+    promotions
+        .into_iter()
+        .filter_map(|p| ITEM_MAP.with(|items| items.borrow().get(&p.item_id)))
+        .filter(|item| item.is_active && is_visible_to(item, caller()))
+        .collect()
+}
+
+//the fee is paid with cycles attached to the call, promoting an already featured item extends it
+#[update(name = "promoteItem")]
+fn promote_item(item_id: u64, days: u64) -> Result<Promotion, String> {
+    let item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+
+    if item.item_owner != caller() {
+        return Err("Only the owner can promote this item.".to_string());
+    }
+    if !item.is_active || item.is_hidden == Some(true) {
+        return Err("The selected item is not actively listed.".to_string());
+    }
+    if days == 0 || days > MAX_PROMOTION_DAYS {
+        return Err(format!(
+            "Items can be promoted for 1 to {} days at a time.",
+            MAX_PROMOTION_DAYS
+        ));
+    }
+    let discount = membership::perks_of(caller()).promotion_discount_percent as u128;
+    let fee = PROMOTION_PRICE_PER_DAY * days as u128 * (100 - discount) / 100;
+    if msg_cycles_available128() < fee {
+        return Err(format!(
+            "Attach {} cycles to promote this item for {} days.",
+            fee, days
+        ));
+    }
+
+    msg_cycles_accept128(fee);
+    blocks::append(FinancialOp::Fee, fee, Some(caller()), None, Some(item_id));
+    let now = time();
+    let promotion = match PROMOTION_MAP.with(|p| p.borrow().get(&item_id)) {
+        Some(mut current) if current.expires_at > now => {
+            current.expires_at += days * DAY_NS;
+            current.fee_paid += fee;
+            current
+        }
+        _ => Promotion {
+            item_id,
+            owner: caller(),
+            starts_at: now,
+            expires_at: now + days * DAY_NS,
+            fee_paid: fee,
+        },
+    };
+    PROMOTION_MAP.with(|p| p.borrow_mut().insert(item_id, promotion.clone()));
+    Ok(promotion)
+}
+
+/// Ends the item's promotion and credits the owner the policy's share of the time left on it.
+pub fn refund_on_cancel(item_id: u64) {
+    let Some(promotion) = PROMOTION_MAP.with(|p| p.borrow_mut().remove(&item_id)) else {
+        return;
+    };
+    let now = time();
+    if promotion.expires_at <= now {
+        return;
+    }
+    let percent = REFUND_POLICY.with(|c| c.borrow().get().unused_time_percent) as u128;
+    let total = (promotion.expires_at - promotion.starts_at) as u128;
+    let unused = (promotion.expires_at - now.max(promotion.starts_at)) as u128;
+    let refund = promotion.fee_paid * unused * percent / (total * 100);
+    if refund == 0 {
+        return;
+    }
+    balances::credit(promotion.owner, refund);
+    blocks::append(
+        FinancialOp::Refund,
+        refund,
+        None,
+        Some(promotion.owner),
+        Some(item_id),
+    );
+}
+
+#[update(name = "setFeeRefundPolicy")]
+fn set_fee_refund_policy(policy: FeeRefundPolicy) -> Result<String, String> {
+    admin::ensure_admin()?;
+    if policy.unused_time_percent > 100 {
+        return Err("At most 100 percent of a fee can be refunded.".to_string());
+    }
+    REFUND_POLICY.with(|c| c.borrow_mut().set(policy).unwrap());
+    Ok("Fee refund policy updated".to_string())
+}
+
+#[query(name = "getFeeRefundPolicy")]
+fn get_fee_refund_policy() -> FeeRefundPolicy {
+    REFUND_POLICY.with(|c| c.borrow().get().clone())
+}
+
+#[query(name = "getFeaturedItems")]
+fn get_featured_items() -> Vec<ItemSummary> {
+    featured_items().iter().map(ItemSummary::from).collect()
+}
+
+#[query(name = "getPromotion")]
+fn get_promotion(item_id: u64) -> Option<Promotion> {
+    PROMOTION_MAP.with(|p| p.borrow().get(&item_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    #[test]
+    fn a_promotion_fits() {
+        assert_fits(&Promotion {
+            item_id: u64::MAX,
+            owner: principal(1),
+            starts_at: u64::MAX,
+            expires_at: u64::MAX,
+            fee_paid: u128::MAX,
+        });
+    }
+}
@@ -0,0 +1,158 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    clock::time,
+    http::escape_json,
+    settlement::{Settlement, SettlementSource},
+    tax::TaxBreakdown,
+    Memory, MEMORY_MANAGER,
+};
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct Receipt {
+    id: u64,
+    item_id: u64,
+    seller: Principal,
+    buyer: Principal,
+    price: u64,
+    commission: u128,
+    seller_proceeds: u128,
+    tax: Option<TaxBreakdown>,
+    source: String,
+    settled_at: u64,
+    issued_at: u64,
+    content_hash: Vec<u8>, //sha256 of content_json(), lets either party prove the receipt was not altered
+}
+
+impl Storable for Receipt {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Receipt {
+    const MAX_SIZE: u32 = 1_000;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Receipt {
+    //the exact bytes the content hash is taken over
+    fn content_json(&self) -> String {
+        format!(
+            "{{\"id\":{},\"item_id\":{},\"seller\":\"{}\",\"buyer\":\"{}\",\"price\":{},\"commission\":{},\"seller_proceeds\":{},\"tax\":{},\"source\":\"{}\",\"settled_at\":{},\"issued_at\":{}}}",
+            self.id,
+            self.item_id,
+            self.seller,
+            self.buyer,
+            self.price,
+            self.commission,
+            self.seller_proceeds,
+            self.tax.as_ref().map_or("null".to_string(), |t| t.json()),
+            escape_json(&self.source),
+            self.settled_at,
+            self.issued_at
+        )
+    }
+
+    pub fn json(&self) -> String {
+        format!(
+            "{{\"receipt\":{},\"sha256\":\"{}\"}}",
+            self.content_json(),
+            hex(&self.content_hash)
+        )
+    }
+}
+
+thread_local! {
+    //written once per settlement and never changed, keyed by the settlement id
+    static RECEIPT_MAP: RefCell<StableBTreeMap<u64, Receipt, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))));
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn next_id() -> u64 {
+    RECEIPT_MAP.with(|r| r.borrow().last_key_value().map_or(0, |(k, _)| k + 1))
+}
+
+/// Issues the receipt for a new settlement under the settlement's id.
+pub fn issue(id: u64, settlement: &Settlement) {
+    let (seller_proceeds, commission) = settlement.split(settlement.price as u128);
+    let source = match settlement.source {
+        SettlementSource::Auction => "auction".to_string(),
+        SettlementSource::Offer { offer_id } => format!("offer {}", offer_id),
+        SettlementSource::RunnerUp { previous_buyer } => {
+            format!("second chance after {}", previous_buyer)
+        }
+    };
+    let mut receipt = Receipt {
+        id,
+        item_id: settlement.item_id,
+        seller: settlement.seller,
+        buyer: settlement.buyer,
+        price: settlement.price,
+        commission,
+        seller_proceeds,
+        tax: settlement.tax.clone(),
+        source,
+        settled_at: settlement.settled_at,
+        issued_at: time(),
+        content_hash: vec![],
+    };
+    receipt.content_hash = Sha256::digest(receipt.content_json().as_bytes()).to_vec();
+    RECEIPT_MAP.with(|r| r.borrow_mut().insert(id, receipt));
+}
+
+/// The receipt as served over http, the content hash doubles as the key to download it.
+pub fn json_for_key(id: u64, key: &str) -> Option<String> {
+    RECEIPT_MAP
+        .with(|r| r.borrow().get(&id))
+        .filter(|r| hex(&r.content_hash) == key)
+        .map(|r| r.json())
+}
+
+#[query(name = "getReceipt")]
+fn get_receipt(settlement_id: u64) -> Result<Receipt, String> {
+    let receipt = RECEIPT_MAP
+        .with(|r| r.borrow().get(&settlement_id))
+        .ok_or_else(|| "Receipt could not be found.".to_string())?;
+    if receipt.seller != caller() && receipt.buyer != caller() {
+        return Err("Only the buyer and the seller can see this receipt.".to_string());
+    }
+    Ok(receipt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    #[test]
+    fn the_largest_receipt_fits() {
+        let mut receipt = Receipt {
+            id: u64::MAX,
+            item_id: u64::MAX,
+            seller: principal(1),
+            buyer: principal(2),
+            price: u64::MAX,
+            commission: u128::MAX,
+            seller_proceeds: u128::MAX,
+            tax: Some(TaxBreakdown::largest()),
+            source: format!("second chance after {}", principal(3)),
+            settled_at: u64::MAX,
+            issued_at: u64::MAX,
+            content_hash: vec![],
+        };
+        receipt.content_hash = Sha256::digest(receipt.content_json().as_bytes()).to_vec();
+        assert_fits(&receipt);
+    }
+}
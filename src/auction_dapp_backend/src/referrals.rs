@@ -0,0 +1,206 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{
+    memory_manager::MemoryId, BoundedStorable, StableBTreeMap, StableCell, Storable,
+};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    admin, balances,
+    blocks::{self, FinancialOp},
+    clock::time,
+    settlement, Memory, StorablePrincipal, MEMORY_MANAGER,
+};
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct ReferralPolicy {
+    reward: u128, //cycles credited to the referrer, nothing is paid while it is zero
+}
+
+impl Storable for ReferralPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+struct Referral {
+    referrer: Principal,
+    registered_at: u64,
+    rewarded: Option<u128>, //set once the referred user completed their first purchase
+}
+
+impl Storable for Referral {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Referral {
+    const MAX_SIZE: u32 = 150;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType)]
+struct ReferralStats {
+    referrer: Option<Principal>, //who referred the caller
+    referred: u64,
+    converted: u64, //referred users who completed a purchase
+    rewards_earned: u128,
+}
+
+thread_local! {
+    static REFERRAL_POLICY: RefCell<StableCell<ReferralPolicy, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55))),
+        ReferralPolicy::default()).unwrap());
+
+    //keyed by the referred user
+    static REFERRAL_MAP: RefCell<StableBTreeMap<StorablePrincipal, Referral, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56)))));
+
+    //(referrer, referred), for the referrer's stats
+    static REFERRER_INDEX: RefCell<StableBTreeMap<(StorablePrincipal, StorablePrincipal), (), Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))));
+}
+
+/// Pays the referrer of a buyer whose purchase just completed, only for their first one.
+/// Rewards come out of the collected fees, while those cannot cover it the referral stays
+/// unrewarded and the buyer's next purchase tries again.
+pub fn purchase_completed(buyer: Principal) {
+    let Some(mut referral) = REFERRAL_MAP.with(|r| r.borrow().get(&buyer.into())) else {
+        return;
+    };
+    if referral.rewarded.is_some() {
+        return;
+    }
+    let reward = REFERRAL_POLICY.with(|c| c.borrow().get().reward);
+    if reward > blocks::treasury_balance(None) {
+        return;
+    }
+    if reward > 0 {
+        balances::credit(referral.referrer, reward);
+        blocks::append(
+            FinancialOp::Reward,
+            reward,
+            None,
+            Some(referral.referrer),
+            None,
+        );
+    }
+    referral.rewarded = Some(reward);
+    REFERRAL_MAP.with(|r| r.borrow_mut().insert(buyer.into(), referral));
+}
+
+//only new buyers can name a referrer, and only once
+#[update(name = "setReferrer")]
+fn set_referrer(referrer: Principal) -> Result<String, String> {
+    let user = caller();
+    if referrer == user || referrer == Principal::anonymous() {
+        return Err("You cannot refer yourself.".to_string());
+    }
+    if REFERRAL_MAP.with(|r| r.borrow().contains_key(&user.into())) {
+        return Err("You have already registered a referrer.".to_string());
+    }
+    if REFERRAL_MAP
+        .with(|r| r.borrow().get(&referrer.into()))
+        .is_some_and(|r| r.referrer == user)
+    {
+        return Err("You cannot be referred by someone you referred.".to_string());
+    }
+    if settlement::has_bought(user) {
+        return Err("Referrers can only be registered before the first purchase.".to_string());
+    }
+    let referral = Referral {
+        referrer,
+        registered_at: time(),
+        rewarded: None,
+    };
+    REFERRAL_MAP.with(|r| r.borrow_mut().insert(user.into(), referral));
+    REFERRER_INDEX.with(|idx| idx.borrow_mut().insert((referrer.into(), user.into()), ()));
+    Ok(format!("{} registered as your referrer", referrer))
+}
+
+#[update(name = "setReferralReward")]
+fn set_referral_reward(reward: u128) -> Result<String, String> {
+    admin::ensure_admin()?;
+    REFERRAL_POLICY.with(|c| c.borrow_mut().set(ReferralPolicy { reward }).unwrap());
+    Ok("Referral reward updated".to_string())
+}
+
+#[query(name = "getReferralStats")]
+fn get_referral_stats() -> ReferralStats {
+    let user = StorablePrincipal::from(caller());
+    let referred: Vec<Referral> = REFERRER_INDEX.with(|idx| {
+        idx.borrow()
+            .range((user, StorablePrincipal::default())..)
+            .take_while(|((referrer, _), _)| *referrer == user)
+            .filter_map(|((_, referred), _)| REFERRAL_MAP.with(|r| r.borrow().get(&referred)))
+            .collect()
+    });
+    ReferralStats {
+        referrer: REFERRAL_MAP.with(|r| r.borrow().get(&user).map(|r| r.referrer)),
+        referred: referred.len() as u64,
+        converted: referred.iter().filter(|r| r.rewarded.is_some()).count() as u64,
+        rewards_earned: referred.iter().filter_map(|r| r.rewarded).sum(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    fn refer(referrer: Principal, buyer: Principal, reward: u128) {
+        REFERRAL_POLICY.with(|c| c.borrow_mut().set(ReferralPolicy { reward }).unwrap());
+        let referral = Referral {
+            referrer,
+            registered_at: 0,
+            rewarded: None,
+        };
+        REFERRAL_MAP.with(|r| r.borrow_mut().insert(buyer.into(), referral));
+    }
+
+    #[test]
+    fn a_rewarded_referral_fits() {
+        assert_fits(&Referral {
+            referrer: principal(1),
+            registered_at: u64::MAX,
+            rewarded: Some(u128::MAX),
+        });
+    }
+
+    #[test]
+    fn rewards_wait_for_the_fees_to_cover_them() {
+        let (referrer, buyer) = (principal(1), principal(2));
+        refer(referrer, buyer, 100);
+        blocks::append(FinancialOp::Fee, 60, None, None, Some(1));
+        purchase_completed(buyer);
+        assert_eq!(balances::balance_of(referrer), 0);
+        assert_eq!(blocks::treasury_balance(None), 60);
+
+        blocks::append(FinancialOp::Fee, 40, None, None, Some(2));
+        purchase_completed(buyer);
+        assert_eq!(balances::balance_of(referrer), 100);
+        assert_eq!(blocks::treasury_balance(None), 0);
+
+        //paid once only
+        blocks::append(FinancialOp::Fee, 100, None, None, Some(3));
+        purchase_completed(buyer);
+        assert_eq!(balances::balance_of(referrer), 100);
+    }
+
+    #[test]
+    fn token_fees_do_not_fund_cycles_rewards() {
+        let (referrer, buyer) = (principal(1), principal(2));
+        refer(referrer, buyer, 100);
+        blocks::append_token(FinancialOp::Fee, principal(9), 1_000, None, None, Some(1));
+        purchase_completed(buyer);
+        assert_eq!(balances::balance_of(referrer), 0);
+    }
+}
@@ -0,0 +1,176 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    admin, bid_as, clock::time, guard, validation, BidBase, Memory, StorablePrincipal,
+    MEMORY_MANAGER,
+};
+
+const MAX_NAME_LENGTH: usize = 100;
+//what the name takes as UTF-8, all of it fits in an Intermediary
+const MAX_NAME_BYTES: usize = 150;
+
+#[derive(Deserialize, CandidType, Clone)]
+struct Intermediary {
+    name: String,
+    registered_at: u64,
+}
+
+impl Storable for Intermediary {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//an intermediary with a MAX_NAME_BYTES name takes 181 bytes
+impl BoundedStorable for Intermediary {
+    const MAX_SIZE: u32 = 200;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType)]
+struct RelayConsent {
+    intermediary: Principal,
+    name: String,
+    since: u64,
+}
+
+thread_local! {
+    //canisters allowed to relay bids, like wallet dapps
+    static INTERMEDIARIES: RefCell<StableBTreeMap<StorablePrincipal, Intermediary, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68)))));
+
+    //(beneficiary, intermediary) -> when the beneficiary gave consent
+    static CONSENTS: RefCell<StableBTreeMap<(StorablePrincipal, StorablePrincipal), u64, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69)))));
+}
+
+fn intermediary_of(p: Principal) -> Option<Intermediary> {
+    INTERMEDIARIES.with(|i| i.borrow().get(&p.into()))
+}
+
+#[update(name = "registerIntermediary")]
+fn register_intermediary(intermediary: Principal, name: String) -> Result<String, String> {
+    admin::ensure_admin()?;
+    let name =
+        validation::bounded_text("Intermediary name", &name, MAX_NAME_LENGTH, MAX_NAME_BYTES)?;
+    let registered_at = intermediary_of(intermediary).map_or(time(), |i| i.registered_at);
+    INTERMEDIARIES.with(|i| {
+        i.borrow_mut().insert(
+            intermediary.into(),
+            Intermediary {
+                name,
+                registered_at,
+            },
+        )
+    });
+    Ok(format!("Intermediary {} registered", intermediary))
+}
+
+//consents are kept, they apply again if the intermediary is registered again
+#[update(name = "removeIntermediary")]
+fn remove_intermediary(intermediary: Principal) -> Result<String, String> {
+    admin::ensure_admin()?;
+    INTERMEDIARIES
+        .with(|i| i.borrow_mut().remove(&intermediary.into()))
+        .ok_or_else(|| "Intermediary could not be found.".to_string())?;
+    Ok(format!("Intermediary {} removed", intermediary))
+}
+
+#[update(name = "setRelayConsent")]
+fn set_relay_consent(intermediary: Principal, consent: bool) -> Result<String, String> {
+    guard::check_write_headroom()?;
+    if consent && intermediary_of(intermediary).is_none() {
+        return Err("Intermediary could not be found.".to_string());
+    }
+    let key = (
+        StorablePrincipal::from(caller()),
+        StorablePrincipal::from(intermediary),
+    );
+    CONSENTS.with(|c| {
+        if consent {
+            c.borrow_mut().insert(key, time());
+        } else {
+            c.borrow_mut().remove(&key);
+        }
+    });
+    Ok(format!("Relay consent for {}: {}", intermediary, consent))
+}
+
+#[query(name = "getRelayConsents")]
+fn get_relay_consents() -> Vec<RelayConsent> {
+    let beneficiary = StorablePrincipal::from(caller());
+    let min = StorablePrincipal::default();
+    let consents: Vec<(Principal, u64)> = CONSENTS.with(|c| {
+        c.borrow()
+            .range((beneficiary, min)..)
+            .take_while(|((b, _), _)| *b == beneficiary)
+            .map(|((_, i), since)| (Principal::from(i), since))
+            .collect()
+    });
+    consents
+        .into_iter()
+        .filter_map(|(intermediary, since)| {
+            intermediary_of(intermediary).map(|i| RelayConsent {
+                intermediary,
+                name: i.name,
+                since,
+            })
+        })
+        .collect()
+}
+
+//the bid is the beneficiary's in every way, they have to have the allowance or deposit for it
+#[update(name = "placeRelayedBid")]
+async fn place_relayed_bid(
+    beneficiary: Principal,
+    key: u64,
+    bid: BidBase,
+) -> Result<String, String> {
+    let intermediary = caller();
+    if intermediary_of(intermediary).is_none() {
+        return Err("Only registered intermediaries can relay bids.".to_string());
+    }
+    let consent = (
+        StorablePrincipal::from(beneficiary),
+        StorablePrincipal::from(intermediary),
+    );
+    if !CONSENTS.with(|c| c.borrow().contains_key(&consent)) {
+        return Err("The beneficiary has not consented to this intermediary.".to_string());
+    }
+    bid_as(key, bid, beneficiary).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::principal;
+
+    #[test]
+    fn the_largest_intermediary_name_is_stored() {
+        //37 characters of 4 bytes and 2 of one make up the whole byte bound
+        let name = "\u{1F600}".repeat(37) + "ab";
+        let name =
+            validation::bounded_text("Intermediary name", &name, MAX_NAME_LENGTH, MAX_NAME_BYTES)
+                .unwrap();
+        let intermediary = Intermediary {
+            name: name.clone(),
+            registered_at: u64::MAX,
+        };
+        INTERMEDIARIES.with(|i| i.borrow_mut().insert(principal(5).into(), intermediary));
+        assert_eq!(intermediary_of(principal(5)).map(|i| i.name), Some(name));
+
+        let too_large = "\u{1F600}".repeat(38);
+        assert!(validation::bounded_text(
+            "Intermediary name",
+            &too_large,
+            MAX_NAME_LENGTH,
+            MAX_NAME_BYTES
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::{caller, update};
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap};
+use std::cell::RefCell;
+
+use crate::{
+    clock::time,
+    get_and_inc_current_id, runner_up, settlement, store_new_item,
+    winners::{self, WinnerPolicy},
+    Item, Memory, Status, ITEM_MAP, MEMORY_MANAGER,
+};
+
+const MAX_RELIST_ROUNDS: u64 = 10;
+
+thread_local! {
+    //item_id -> the item it was listed again as. an item is only relisted once, the link stays when the copy is deleted
+    static RELISTED_AS: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(108)))));
+}
+
+//lets an item that closed below its reserve go up again without the seller
+#[derive(Deserialize, CandidType, Clone, Copy, PartialEq)]
+pub struct RelistPolicy {
+    rounds: u64,                    //relists left
+    reserve_reduction_percent: u64, //the reserve is lowered by this much every round
+    round_duration: u64,            //in nanoseconds
+}
+
+#[cfg(test)]
+impl RelistPolicy {
+    /// The largest relist policy, for the size tests of the Item.
+    pub fn largest() -> Self {
+        RelistPolicy {
+            rounds: MAX_RELIST_ROUNDS,
+            reserve_reduction_percent: 100,
+            round_duration: u64::MAX,
+        }
+    }
+}
+
+pub fn validate(policy: Option<RelistPolicy>) -> Result<Option<RelistPolicy>, String> {
+    let Some(p) = policy else {
+        return Ok(None);
+    };
+    if p.rounds == 0 || p.rounds > MAX_RELIST_ROUNDS {
+        return Err(format!(
+            "Items can be relisted 1 to {} times.",
+            MAX_RELIST_ROUNDS
+        ));
+    }
+    if p.reserve_reduction_percent > 100 {
+        return Err("The reserve cannot be reduced by more than 100 percent.".to_string());
+    }
+    if p.round_duration == 0 {
+        return Err("Relisted auctions need a duration.".to_string());
+    }
+    Ok(Some(p))
+}
+
+/// Lists an unsold reserve auction again as a new item, if its owner asked for it. Returns the new id.
+pub fn relist_if_configured(item: &Item) -> Option<u64> {
+    let policy = item.relist_policy?;
+    let WinnerPolicy::HighestBidAboveReserve { reserve } = winners::policy_of(item) else {
+        return None;
+    };
+    if policy.rounds == 0 {
+        return None;
+    }
+
+    let reduction = reserve as u128 * policy.reserve_reduction_percent as u128 / 100;
+    let reserve = reserve - reduction as u64;
+    Some(relist(
+        item,
+        time() + policy.round_duration,
+        Some(WinnerPolicy::HighestBidAboveReserve { reserve }),
+        Some(RelistPolicy {
+            rounds: policy.rounds - 1,
+            ..policy
+        }),
+    ))
+}
+
+pub fn was_relisted(item_id: u64) -> bool {
+    RELISTED_AS.with(|r| r.borrow().contains_key(&item_id))
+}
+
+//links the relisted items that existed before the index, runs as a schema migration
+pub fn backfill() {
+    let links: Vec<(u64, u64)> = ITEM_MAP.with(|items| {
+        items
+            .borrow()
+            .iter()
+            .filter_map(|(id, item)| item.relisted_from.map(|from| (from, id)))
+            .collect()
+    });
+    for (from, id) in links {
+        RELISTED_AS.with(|r| r.borrow_mut().insert(from, id));
+    }
+}
+
+//a sale the buyer never paid for can go up again, as long as the runner-up is not taking it
+#[update(name = "relistDefaulted")]
+fn relist_defaulted(item_id: u64, result_date: u64) -> Result<u64, String> {
+    let settlement = settlement::find_settlement(item_id)?;
+    if settlement.seller != caller() {
+        return Err("Only the seller can relist this item.".to_string());
+    }
+    if settlement.defaulted_at.is_none() {
+        return Err("Only items the buyer did not pay for can be relisted.".to_string());
+    }
+    if runner_up::is_pending_or_taken(item_id) {
+        return Err("The item was offered to the runner-up.".to_string());
+    }
+    if was_relisted(item_id) {
+        return Err("This item has already been listed again.".to_string());
+    }
+    if result_date <= time() {
+        return Err("The new end date has to be in the future.".to_string());
+    }
+    let item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+    Ok(relist(
+        &item,
+        result_date,
+        item.winner_policy,
+        item.relist_policy,
+    ))
+}
+
+//the copy starts a fresh auction, bids and moderation state stay with the old item
+fn relist(
+    item: &Item,
+    result_date: u64,
+    winner_policy: Option<WinnerPolicy>,
+    relist_policy: Option<RelistPolicy>,
+) -> u64 {
+    let now = time();
+    let id = get_and_inc_current_id();
+    let relisted = Item {
+        item_owner: item.item_owner,
+        id,
+        description: item.description.clone(),
+        highest_bid: 0,
+        latest_update: now,
+        result_date,
+        bid_vector: vec![],
+        is_active: true,
+        accepts_offers: item.accepts_offers,
+        auto_accept_price: item.auto_accept_price,
+        is_hidden: None,
+        terms: item.terms.clone(),
+        category: item.category.clone(),
+        status: Some(Status::Active),
+        starts_at: None,
+        winner_policy,
+        relist_policy,
+        relisted_from: Some(item.id),
+        increment_bands: item.increment_bands.clone(),
+        payment_mode: item.payment_mode,
+        bid_bond: item.bid_bond,
+        payment_window: item.payment_window,
+        tax: item.tax.clone(),
+        minimum_bid: item.minimum_bid,
+        media: item.media.clone(),
+        certified: item.certified,
+        bid_archive: None,
+    };
+    store_new_item(relisted);
+    RELISTED_AS.with(|r| r.borrow_mut().insert(item.id, id));
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock,
+        test_support::{self, principal, HOUR},
+    };
+
+    #[test]
+    fn relisted_items_are_linked_to_their_copy() {
+        clock::set(1_000 * HOUR);
+        let item = test_support::item(1, principal(1));
+        assert!(!was_relisted(1));
+        let copy = relist(&item, time() + HOUR, None, None);
+        assert!(was_relisted(1));
+        assert!(!was_relisted(copy));
+        assert_eq!(test_support::stored(copy).relisted_from, Some(1));
+    }
+}
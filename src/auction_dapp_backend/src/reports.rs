@@ -0,0 +1,236 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap};
+
+use crate::{
+    admin, allowance_bids,
+    audit::{self, AuditEvent, Resolution},
+    clock::time,
+    close_timers::{self, EarlyClose},
+    cycle_bids, expiry, guard, indexer_sync,
+    validation::{self, ValidationError},
+    Memory, Status, StorablePrincipal, ITEM_MAP, MEMORY_MANAGER,
+};
+
+//items reported by this many different users are hidden until an admin reviews them
+const AUTO_HIDE_REPORT_THRESHOLD: u64 = 5;
+const MAX_DETAILS_LENGTH: usize = 500;
+
+#[derive(Deserialize, CandidType, Clone, Copy, PartialEq)]
+pub enum ReportReason {
+    Counterfeit,
+    Prohibited,
+    Spam,
+    Other,
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct Report {
+    item_id: u64,
+    reporter: Principal,
+    reason: ReportReason,
+    details: String,
+    reported_at: u64,
+}
+
+impl Storable for Report {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Report {
+    const MAX_SIZE: u32 = 2_500;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType)]
+struct ReportCount {
+    item_id: u64,
+    count: u64,
+    is_hidden: bool,
+}
+
+#[derive(CandidType)]
+struct ModerationCase {
+    item_id: u64,
+    item_owner: Principal,
+    description: String,
+    is_hidden: bool,
+    reports: Vec<Report>,
+}
+
+thread_local! {
+    //one report per user per item
+    static REPORT_MAP: RefCell<StableBTreeMap<(u64, StorablePrincipal), Report, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))));
+}
+
+pub fn item_reports(item_id: u64) -> Vec<Report> {
+    REPORT_MAP.with(|r| {
+        r.borrow()
+            .range(
+                (item_id, StorablePrincipal::default())
+                    ..(item_id + 1, StorablePrincipal::default()),
+            )
+            .map(|(_, v)| v)
+            .collect()
+    })
+}
+
+#[update(name = "flagItem")]
+fn flag_item(item_id: u64, reason: ReportReason, details: String) -> Result<String, String> {
+    guard::check_write_headroom()?;
+    let mut item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+
+    if item.item_owner == caller() {
+        return Err("You cannot flag your own item.".to_string());
+    }
+    let details = validation::sanitize(&details);
+    if details.chars().count() > MAX_DETAILS_LENGTH {
+        return Err(ValidationError::TooLong {
+            field: "Report details",
+            max: MAX_DETAILS_LENGTH,
+        }
+        .into());
+    }
+    let key = (item_id, StorablePrincipal::from(caller()));
+    if REPORT_MAP.with(|r| r.borrow().contains_key(&key)) {
+        return Err("You have already flagged this item.".to_string());
+    }
+
+    let report = Report {
+        item_id,
+        reporter: caller(),
+        reason,
+        details,
+        reported_at: time(),
+    };
+    REPORT_MAP.with(|r| r.borrow_mut().insert(key, report));
+
+    if item.is_hidden != Some(true)
+        && item_reports(item_id).len() as u64 >= AUTO_HIDE_REPORT_THRESHOLD
+    {
+        item.is_hidden = Some(true);
+        ITEM_MAP.with(|items| items.borrow_mut().insert(item_id, item));
+        indexer_sync::item_changed(item_id);
+    }
+    Ok("Thank you, the item has been reported.".to_string())
+}
+
+#[query(name = "getReportCounts")]
+fn get_report_counts() -> Result<Vec<ReportCount>, String> {
+    admin::ensure_admin()?;
+    let mut counts: BTreeMap<u64, u64> = BTreeMap::new();
+    REPORT_MAP.with(|r| {
+        for ((item_id, _), _) in r.borrow().iter() {
+            *counts.entry(item_id).or_default() += 1;
+        }
+    });
+
+    Ok(counts
+        .into_iter()
+        .map(|(item_id, count)| ReportCount {
+            item_id,
+            count,
+            is_hidden: ITEM_MAP
+                .with(|items| items.borrow().get(&item_id))
+                .is_some_and(|item| item.is_hidden == Some(true)),
+        })
+        .collect())
+}
+
+#[query(name = "getReports")]
+fn get_reports(item_id: u64) -> Result<Vec<Report>, String> {
+    admin::ensure_admin()?;
+    Ok(item_reports(item_id))
+}
+
+//every item with unresolved reports, most reported first
+#[query(name = "getModerationQueue")]
+fn get_moderation_queue() -> Result<Vec<ModerationCase>, String> {
+    admin::ensure_admin()?;
+    let mut item_ids: Vec<u64> =
+        REPORT_MAP.with(|r| r.borrow().iter().map(|((id, _), _)| id).collect());
+    item_ids.dedup();
+
+    let mut queue: Vec<ModerationCase> = item_ids
+        .into_iter()
+        .filter_map(|item_id| {
+            let item = ITEM_MAP.with(|items| items.borrow().get(&item_id))?;
+            Some(ModerationCase {
+                item_id,
+                item_owner: item.item_owner,
+                description: item.description,
+                is_hidden: item.is_hidden == Some(true),
+                reports: item_reports(item_id),
+            })
+        })
+        .collect();
+    queue.sort_by_key(|c| std::cmp::Reverse(c.reports.len()));
+    Ok(queue)
+}
+
+//approve and warn put the item back on the list, remove takes it down for good
+#[update(name = "resolveReport")]
+fn resolve_report(item_id: u64, resolution: Resolution) -> Result<String, String> {
+    admin::ensure_admin()?;
+    let mut item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+
+    let reports = item_reports(item_id);
+    if reports.is_empty() {
+        return Err("There are no open reports for this item.".to_string());
+    }
+
+    match resolution {
+        Resolution::Approve | Resolution::Warn => item.is_hidden = Some(false),
+        Resolution::Remove => {
+            item.set_status(Status::Removed)?;
+            cycle_bids::release_hold(item_id);
+            allowance_bids::release_hold(item_id);
+            item.is_hidden = Some(true);
+            close_timers::cancel_early(item_id, EarlyClose::Removed);
+            expiry::index_remove(&item);
+            expiry::schedule_remove(&item);
+        }
+    }
+    ITEM_MAP.with(|items| items.borrow_mut().insert(item_id, item));
+    indexer_sync::item_changed(item_id);
+    REPORT_MAP.with(|r| {
+        for report in reports.iter() {
+            r.borrow_mut().remove(&(item_id, report.reporter.into()));
+        }
+    });
+
+    audit::record(AuditEvent::ReportResolved {
+        item_id,
+        resolution,
+        report_count: reports.len() as u64,
+    });
+    Ok(format!("Reports for item {} resolved", item_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    #[test]
+    fn the_longest_report_fits() {
+        assert_fits(&Report {
+            item_id: u64::MAX,
+            reporter: principal(1),
+            reason: ReportReason::Counterfeit,
+            details: "\u{1F600}".repeat(MAX_DETAILS_LENGTH),
+            reported_at: u64::MAX,
+        });
+    }
+}
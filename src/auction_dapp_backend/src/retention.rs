@@ -0,0 +1,196 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::{
+    memory_manager::MemoryId, BoundedStorable, StableBTreeMap, StableCell, Storable,
+};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    time::Duration,
+};
+
+use crate::{
+    admin, bid_limits, bid_store, budget,
+    clock::time,
+    compaction::{self, DAY_NS},
+    expiry, indexer_sync, stats, Item, Memory, Status, ITEM_MAP, MEMORY_MANAGER,
+    SHORT_DESCRIPTION_LENGTH,
+};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+const MIN_ARCHIVE_AFTER: u64 = 90 * DAY_NS;
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct ArchivePolicy {
+    archive_after: Option<u64>, //nanoseconds after the auction closed, items are kept when not set
+}
+
+impl Storable for ArchivePolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//the three stages of a closed item: kept in full, summary only (see compaction.rs), archived
+#[derive(CandidType)]
+struct RetentionPolicy {
+    summary_after: Option<u64>,
+    archive_after: Option<u64>,
+}
+
+//all that is kept of an item once it left the item map
+#[derive(Deserialize, CandidType, Clone)]
+struct ArchivedItem {
+    id: u64,
+    item_owner: Principal,
+    short_description: String,
+    category: Option<String>,
+    highest_bid: u64,
+    result_date: u64,
+    status: Status,
+    bid_count: u64,
+    winner: Option<Principal>,
+    archived_at: u64,
+}
+
+impl Storable for ArchivedItem {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ArchivedItem {
+    const MAX_SIZE: u32 = 1_000;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl From<&Item> for ArchivedItem {
+    fn from(item: &Item) -> Self {
+        ArchivedItem {
+            id: item.id,
+            item_owner: item.item_owner,
+            short_description: item
+                .description
+                .chars()
+                .take(SHORT_DESCRIPTION_LENGTH)
+                .collect(),
+            category: item.category.clone(),
+            highest_bid: item.highest_bid,
+            result_date: item.result_date,
+            status: item.status(),
+            bid_count: item.bid_count(),
+            winner: compaction::winner_of(item),
+            archived_at: time(),
+        }
+    }
+}
+
+thread_local! {
+    static ARCHIVE_POLICY: RefCell<StableCell<ArchivePolicy, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(78))),
+        ArchivePolicy::default()).unwrap());
+
+    static ARCHIVED_ITEMS: RefCell<StableBTreeMap<u64, ArchivedItem, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(79)))));
+
+    //where a sweep that ran out of instructions continues
+    static CURSOR: Cell<u64> = const { Cell::new(0) };
+}
+
+fn archive_after() -> Option<u64> {
+    ARCHIVE_POLICY.with(|c| c.borrow().get().archive_after)
+}
+
+//the item's side records (offers, watchlists and the like) are left as they are, like on deleteItem
+fn archive_closed() {
+    let Some(archive_after) = archive_after() else {
+        return;
+    };
+    let closed_before = time().saturating_sub(archive_after);
+    let from = CURSOR.with(|c| c.replace(0));
+    let rest = budget::scan_items(from, |item| {
+        if item.result_date >= closed_before || !compaction::is_closed_for_good(&item) {
+            return;
+        }
+        ARCHIVED_ITEMS.with(|a| a.borrow_mut().insert(item.id, ArchivedItem::from(&item)));
+        expiry::index_remove(&item);
+        expiry::schedule_remove(&item);
+        ITEM_MAP.with(|m| m.borrow_mut().remove(&item.id));
+        bid_store::remove_all(item.id);
+        bid_limits::forget(item.id);
+        stats::item_deleted();
+        indexer_sync::item_removed(item.id);
+    });
+    if let Some(next) = rest {
+        CURSOR.with(|c| c.set(next));
+        budget::resume(archive_closed);
+    }
+}
+
+pub fn start_sweep_timer() {
+    ic_cdk_timers::set_timer_interval(SWEEP_INTERVAL, archive_closed);
+}
+
+#[update(name = "setRetentionPolicy")]
+fn set_retention_policy(
+    summary_after: Option<u64>,
+    archive_after: Option<u64>,
+) -> Result<String, String> {
+    admin::ensure_admin()?;
+    if archive_after.is_some_and(|a| a < MIN_ARCHIVE_AFTER) {
+        return Err(format!(
+            "Items can only be archived {} days after their auction closed.",
+            MIN_ARCHIVE_AFTER / DAY_NS
+        ));
+    }
+    if let (Some(s), Some(a)) = (summary_after, archive_after) {
+        if a <= s {
+            return Err("Items have to be archived after their bids are summarized.".to_string());
+        }
+    }
+    compaction::set_closed_for(summary_after)?;
+    ARCHIVE_POLICY.with(|c| c.borrow_mut().set(ArchivePolicy { archive_after }).unwrap());
+    Ok("Retention policy updated".to_string())
+}
+
+#[query(name = "getRetentionPolicy")]
+fn get_retention_policy() -> RetentionPolicy {
+    RetentionPolicy {
+        summary_after: compaction::closed_for(),
+        archive_after: archive_after(),
+    }
+}
+
+#[query(name = "getArchivedItem")]
+fn get_archived_item(id: u64) -> Option<ArchivedItem> {
+    ARCHIVED_ITEMS.with(|a| a.borrow().get(&id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    #[test]
+    fn the_largest_archived_item_fits() {
+        assert_fits(&ArchivedItem {
+            id: u64::MAX,
+            item_owner: principal(1),
+            short_description: "\u{1F600}".repeat(SHORT_DESCRIPTION_LENGTH),
+            category: Some("x".repeat(crate::MAX_CATEGORY_LENGTH)),
+            highest_bid: u64::MAX,
+            result_date: u64::MAX,
+            status: Status::Settled,
+            bid_count: u64::MAX,
+            winner: Some(principal(2)),
+            archived_at: u64::MAX,
+        });
+    }
+}
@@ -0,0 +1,150 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    bid_store,
+    clock::time,
+    defaults, escrow,
+    notifications::{self, NotificationKind},
+    relisting,
+    settlement::{self, Settlement, SettlementSource},
+    Memory, ITEM_MAP, MEMORY_MANAGER,
+};
+
+//how long the runner-up has to take the item at their bid
+const ACCEPTANCE_WINDOW_NS: u64 = 2 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct RunnerUpOffer {
+    item_id: u64,
+    bidder: Principal,
+    amount: u64, //the runner-up's own highest bid
+    offered_at: u64,
+    expires_at: u64,
+    accepted: bool,
+}
+
+impl Storable for RunnerUpOffer {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RunnerUpOffer {
+    const MAX_SIZE: u32 = 200;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    //at most one second chance per item
+    static RUNNER_UP_MAP: RefCell<StableBTreeMap<u64, RunnerUpOffer, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))));
+}
+
+//the seller can offer the item to the next highest bidder once the winner missed the payment window
+#[update(name = "offerToRunnerUp")]
+fn offer_to_runner_up(item_id: u64) -> Result<RunnerUpOffer, String> {
+    let settlement = settlement::find_settlement(item_id)?;
+    if settlement.seller != caller() {
+        return Err("Only the seller can make a second chance offer.".to_string());
+    }
+    if settlement.source != SettlementSource::Auction {
+        return Err("Second chance offers are only possible for auction sales.".to_string());
+    }
+    if !escrow::default_if_overdue(item_id) {
+        return Err("The winner can still pay for this item.".to_string());
+    }
+    if relisting::was_relisted(item_id) {
+        return Err("This item has already been listed again.".to_string());
+    }
+    if RUNNER_UP_MAP.with(|r| r.borrow().contains_key(&item_id)) {
+        return Err("A second chance offer was already made for this item.".to_string());
+    }
+
+    let item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+    let runner_up = bid_store::of(item.id)
+        .into_iter()
+        .filter(|b| b.bidder_principal != settlement.buyer)
+        .max_by_key(|b| (b.bid_amount, std::cmp::Reverse(b.seq)))
+        .ok_or_else(|| "Nobody else bid on this item.".to_string())?;
+
+    let now = time();
+    let offer = RunnerUpOffer {
+        item_id,
+        bidder: runner_up.bidder_principal,
+        amount: runner_up.bid_amount,
+        offered_at: now,
+        expires_at: now + ACCEPTANCE_WINDOW_NS,
+        accepted: false,
+    };
+    RUNNER_UP_MAP.with(|r| r.borrow_mut().insert(item_id, offer.clone()));
+    notifications::notify(
+        offer.bidder,
+        NotificationKind::RunnerUpOffer {
+            item_id,
+            amount: offer.amount,
+            expires_at: offer.expires_at,
+        },
+    );
+    Ok(offer)
+}
+
+#[update(name = "acceptRunnerUpOffer")]
+fn accept_runner_up_offer(item_id: u64) -> Result<Settlement, String> {
+    let mut offer = RUNNER_UP_MAP
+        .with(|r| r.borrow().get(&item_id))
+        .ok_or_else(|| "There is no second chance offer for this item.".to_string())?;
+
+    if offer.bidder != caller() {
+        return Err("This offer was not made to you.".to_string());
+    }
+    if offer.accepted {
+        return Err("You have already accepted this offer.".to_string());
+    }
+    if offer.expires_at <= time() {
+        return Err("This offer has expired.".to_string());
+    }
+    defaults::ensure_in_good_standing(offer.bidder)?;
+
+    let settlement = settlement::reassign(item_id, offer.bidder, offer.amount)?;
+    offer.accepted = true;
+    RUNNER_UP_MAP.with(|r| r.borrow_mut().insert(item_id, offer));
+    Ok(settlement)
+}
+
+//an offer that was accepted or can still be, either way the item is spoken for
+pub fn is_pending_or_taken(item_id: u64) -> bool {
+    RUNNER_UP_MAP
+        .with(|r| r.borrow().get(&item_id))
+        .is_some_and(|o| o.accepted || o.expires_at > time())
+}
+
+#[query(name = "getRunnerUpOffer")]
+fn get_runner_up_offer(item_id: u64) -> Option<RunnerUpOffer> {
+    RUNNER_UP_MAP.with(|r| r.borrow().get(&item_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    #[test]
+    fn an_offer_fits() {
+        assert_fits(&RunnerUpOffer {
+            item_id: u64::MAX,
+            bidder: principal(1),
+            amount: u64::MAX,
+            offered_at: u64::MAX,
+            expires_at: u64::MAX,
+            accepted: true,
+        });
+    }
+}
@@ -0,0 +1,447 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    allowance_bids, analytics, bonds,
+    clock::time,
+    close_timers::{self, EarlyClose},
+    cycle_bids, dashboard, defaults, escrow, expiry, fees, indexer_sync, price_history,
+    promo_codes, receipts,
+    subscriptions::{self, AuctionEvent},
+    tax::{self, TaxBreakdown},
+    terms::{self, TermsAcceptance},
+    Item, Memory, PaymentMode, Status, StorablePrincipal, ITEM_MAP, MEMORY_MANAGER,
+};
+
+const MAX_SOLD_PAGE_SIZE: u64 = 100;
+
+#[derive(Deserialize, CandidType, Clone, Copy, PartialEq)]
+pub enum SettlementSource {
+    Auction,
+    Offer { offer_id: u64 },
+    RunnerUp { previous_buyer: Principal }, //the winner did not pay in time
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct Settlement {
+    pub id: Option<u64>, //also the id of its receipt, see getReceipt
+    pub item_id: u64,
+    pub seller: Principal,
+    pub buyer: Principal,
+    pub price: u64,
+    pub settled_at: u64,
+    pub source: SettlementSource,
+    pub terms_acceptance: Option<TermsAcceptance>, //proof the buyer agreed to the item's terms
+    pub defaulted_at: Option<u64>,                 //set when the buyer missed the payment window
+    pub commission_bps: Option<u64>, //the seller's tier when the sale was settled, none before commissions
+    pub tax: Option<TaxBreakdown>,   //from the tax details the seller gave the item
+}
+
+#[derive(CandidType)]
+struct SoldItem {
+    item_id: u64,
+    settlement_id: Option<u64>, //also the receipt id
+    buyer: Principal,
+    price: u64,
+    commission: u128,
+    seller_proceeds: u128,
+    tax: Option<TaxBreakdown>,
+    source: SettlementSource,
+    settled_at: u64,
+}
+
+#[derive(CandidType)]
+struct SoldItemsPage {
+    items: Vec<SoldItem>,     //newest item first
+    next_cursor: Option<u64>, //pass it back to read older sales
+}
+
+impl Settlement {
+    /// Splits a payment into what the seller gets and the auction's commission.
+    pub fn split(&self, amount: u128) -> (u128, u128) {
+        let commission = amount * self.commission_bps.unwrap_or(0) as u128 / 10_000;
+        let commission = commission - promo_codes::discount_for(self.item_id, commission);
+        (amount - commission, commission)
+    }
+}
+
+impl Storable for Settlement {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Settlement {
+    const MAX_SIZE: u32 = 1_000;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    //one settlement per item, keyed by the item id
+    static SETTLEMENT_MAP: RefCell<StableBTreeMap<u64, Settlement, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))));
+
+    //(seller, item id) for every settled item, so a seller's sales can be read without a full scan
+    static SELLER_SALES: RefCell<StableBTreeMap<(StorablePrincipal, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(95)))));
+
+    //(buyer, item id) of everyone who won an item, kept when they default and it goes to someone else
+    static BUYER_PURCHASES: RefCell<StableBTreeMap<(StorablePrincipal, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(99)))));
+}
+
+/// Closes the item and records who bought it for how much.
+pub fn settle(
+    item_id: u64,
+    buyer: Principal,
+    price: u64,
+    source: SettlementSource,
+) -> Result<Settlement, String> {
+    let mut item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+
+    if SETTLEMENT_MAP.with(|s| s.borrow().contains_key(&item_id)) {
+        return Err("The selected item has already been settled.".to_string());
+    }
+
+    let settlement = Settlement {
+        id: Some(receipts::next_id()),
+        item_id,
+        seller: item.item_owner,
+        buyer,
+        price,
+        settled_at: time(),
+        source,
+        terms_acceptance: terms::acceptance_of(item_id, buyer),
+        defaulted_at: None,
+        commission_bps: Some(fees::commission_bps_for(item.item_owner)),
+        tax: item.tax.as_ref().map(|t| tax::breakdown(t, price)),
+    };
+
+    ensure_payable(&item, buyer)?;
+    let was_listed = item.is_active;
+    item.set_status(Status::Settled)?;
+    close_timers::cancel_early(item_id, EarlyClose::Sold);
+    expiry::index_remove(&item);
+    if was_listed {
+        analytics::listing_finished(&item);
+    }
+    record_sale(&item, &settlement);
+    let payment_mode = item.payment_mode;
+    ITEM_MAP.with(|items| items.borrow_mut().insert(item_id, item));
+    bonds::release_all(item_id, Some(buyer));
+    collect_payment(payment_mode, &settlement)?;
+    indexer_sync::item_changed(item_id);
+    subscriptions::publish(AuctionEvent::AuctionClosed {
+        item_id,
+        buyer: Some(buyer),
+        price: Some(price),
+    });
+    Ok(settlement)
+}
+
+//cycles items are paid for when bidding, the buyer's bid has to still be held
+fn ensure_payable(item: &Item, buyer: Principal) -> Result<(), String> {
+    if cycle_bids::is_cycles_item(item) && !cycle_bids::is_held_for(item.id, buyer) {
+        return Err("The buyer has no cycles held for this item.".to_string());
+    }
+    Ok(())
+}
+
+//the stats and indexes every sale goes into, and its receipt
+fn record_sale(item: &Item, settlement: &Settlement) {
+    let (item_id, buyer, price) = (settlement.item_id, settlement.buyer, settlement.price);
+    price_history::record(item.category.as_deref(), price, settlement.settled_at);
+    dashboard::item_sold(settlement.seller, price);
+    dashboard::bidder_joined(buyer, item_id);
+    analytics::sale_settled(item, buyer, price, settlement.settled_at);
+    analytics::category_sold(item, price);
+    SETTLEMENT_MAP.with(|s| s.borrow_mut().insert(item_id, settlement.clone()));
+    SELLER_SALES.with(|s| {
+        s.borrow_mut()
+            .insert((settlement.seller.into(), item_id), ())
+    });
+    BUYER_PURCHASES.with(|b| b.borrow_mut().insert((buyer.into(), item_id), ()));
+    receipts::issue(settlement.id.unwrap_or_default(), settlement);
+}
+
+//how the buyer pays depends on the item's payment mode
+fn collect_payment(mode: Option<PaymentMode>, settlement: &Settlement) -> Result<(), String> {
+    match mode {
+        Some(PaymentMode::CyclesAtBid) => cycle_bids::pay_seller(settlement)?,
+        Some(PaymentMode::Icrc2 { ledger }) => allowance_bids::collect(settlement.item_id, ledger),
+        Some(PaymentMode::Escrow) | None => escrow::open(settlement.item_id, settlement.price),
+    }
+    Ok(())
+}
+
+/// Hands an unpaid sale over to another buyer, who pays for it the way the item's payment mode asks.
+pub fn reassign(item_id: u64, buyer: Principal, price: u64) -> Result<Settlement, String> {
+    let defaulted = escrow::default_if_overdue(item_id);
+    let previous = find_settlement(item_id)?;
+    if !defaulted {
+        return Err("The selected item is not waiting for another buyer.".to_string());
+    }
+    let mut item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+    ensure_payable(&item, buyer)?;
+    item.set_status(Status::Settled)?;
+
+    let settlement = Settlement {
+        id: Some(receipts::next_id()),
+        item_id,
+        seller: previous.seller,
+        buyer,
+        price,
+        settled_at: time(),
+        source: SettlementSource::RunnerUp {
+            previous_buyer: previous.buyer,
+        },
+        terms_acceptance: terms::acceptance_of(item_id, buyer),
+        defaulted_at: None,
+        commission_bps: Some(fees::commission_bps_for(previous.seller)),
+        tax: item.tax.as_ref().map(|t| tax::breakdown(t, price)),
+    };
+    record_sale(&item, &settlement);
+    let payment_mode = item.payment_mode;
+    ITEM_MAP.with(|items| items.borrow_mut().insert(item_id, item));
+    collect_payment(payment_mode, &settlement)?;
+    indexer_sync::item_changed(item_id);
+    Ok(settlement)
+}
+
+/// Records that the buyer did not pay: their bond goes to the seller and the item is ended again,
+/// free to be offered to the runner-up or relisted.
+pub fn mark_defaulted(item_id: u64) {
+    let Some(mut settlement) = SETTLEMENT_MAP.with(|s| s.borrow().get(&item_id)) else {
+        return;
+    };
+    if settlement.defaulted_at.is_some() {
+        return;
+    }
+    settlement.defaulted_at = Some(time());
+    defaults::record(settlement.buyer);
+    dashboard::sale_defaulted(settlement.seller, settlement.price);
+    bonds::forfeit(item_id, settlement.buyer, settlement.seller);
+    if let Some(mut item) = ITEM_MAP.with(|items| items.borrow().get(&item_id)) {
+        analytics::sale_defaulted(&item, settlement.price, settlement.settled_at);
+        analytics::category_sale_defaulted(&item, settlement.price);
+        if item.set_status(Status::Ended).is_ok() {
+            ITEM_MAP.with(|items| items.borrow_mut().insert(item_id, item));
+        }
+    }
+    SETTLEMENT_MAP.with(|s| s.borrow_mut().insert(item_id, settlement));
+    indexer_sync::item_changed(item_id);
+}
+
+//what the seller sold since `since`, sales the buyer defaulted on do not count
+pub fn trailing_volume(seller: Principal, since: u64) -> u128 {
+    sales_of(seller)
+        .into_iter()
+        .filter_map(|item_id| SETTLEMENT_MAP.with(|s| s.borrow().get(&item_id)))
+        .filter(|v| v.settled_at >= since && v.defaulted_at.is_none())
+        .map(|v| v.price as u128)
+        .sum()
+}
+
+//the ids of the items the seller sold, oldest first
+fn sales_of(seller: Principal) -> Vec<u64> {
+    let seller = StorablePrincipal::from(seller);
+    SELLER_SALES.with(|s| {
+        s.borrow()
+            .range((seller, 0)..=(seller, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+//defaulted sales count as well, the buyer did win them
+pub fn has_bought(buyer: Principal) -> bool {
+    let buyer = StorablePrincipal::from(buyer);
+    BUYER_PURCHASES.with(|b| {
+        b.borrow()
+            .range((buyer, 0)..=(buyer, u64::MAX))
+            .next()
+            .is_some()
+    })
+}
+
+/// Every settlement whose buyer has not defaulted.
+pub fn completed_sales() -> Vec<Settlement> {
+    SETTLEMENT_MAP.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, v)| v)
+            .filter(|v| v.defaulted_at.is_none())
+            .collect()
+    })
+}
+
+/// Indexes the settlements recorded before sales were indexed by seller.
+pub fn backfill_seller_index() {
+    let sales: Vec<(Principal, u64)> =
+        SETTLEMENT_MAP.with(|s| s.borrow().iter().map(|(id, v)| (v.seller, id)).collect());
+    SELLER_SALES.with(|s| {
+        for (seller, item_id) in sales {
+            s.borrow_mut().insert((seller.into(), item_id), ());
+        }
+    });
+}
+
+/// Indexes the buyers of the settlements recorded before purchases were indexed by buyer.
+pub fn backfill_buyer_index() {
+    let purchases: Vec<(Principal, u64)> = SETTLEMENT_MAP.with(|s| {
+        s.borrow()
+            .iter()
+            .flat_map(|(id, v)| {
+                let previous = match v.source {
+                    SettlementSource::RunnerUp { previous_buyer } => Some((previous_buyer, id)),
+                    _ => None,
+                };
+                std::iter::once((v.buyer, id)).chain(previous)
+            })
+            .collect()
+    });
+    BUYER_PURCHASES.with(|b| {
+        for (buyer, item_id) in purchases {
+            b.borrow_mut().insert((buyer.into(), item_id), ());
+        }
+    });
+}
+
+pub fn find_settlement(item_id: u64) -> Result<Settlement, String> {
+    SETTLEMENT_MAP
+        .with(|s| s.borrow().get(&item_id))
+        .ok_or_else(|| "The selected item has not been sold.".to_string())
+}
+
+#[query(name = "getSettlement")]
+fn get_settlement(item_id: u64) -> Option<Settlement> {
+    SETTLEMENT_MAP.with(|s| s.borrow().get(&item_id))
+}
+
+//sales the buyer defaulted on are left out until another buyer takes the item over
+#[query(name = "getSoldItems")]
+fn get_sold_items(cursor: Option<u64>, limit: u64) -> SoldItemsPage {
+    let seller = StorablePrincipal::from(caller());
+    let limit = limit.clamp(1, MAX_SOLD_PAGE_SIZE) as usize;
+    let end = cursor.unwrap_or(u64::MAX);
+    let ids: Vec<u64> = SELLER_SALES.with(|s| {
+        s.borrow()
+            .range((seller, 0)..(seller, end))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    let mut items = vec![];
+    let mut next_cursor = None;
+    for item_id in ids.into_iter().rev() {
+        if items.len() == limit {
+            next_cursor = Some(item_id + 1);
+            break;
+        }
+        let Some(settlement) = SETTLEMENT_MAP.with(|s| s.borrow().get(&item_id)) else {
+            continue;
+        };
+        if settlement.defaulted_at.is_some() {
+            continue;
+        }
+        let (seller_proceeds, commission) = settlement.split(settlement.price as u128);
+        items.push(SoldItem {
+            item_id,
+            settlement_id: settlement.id,
+            buyer: settlement.buyer,
+            price: settlement.price,
+            commission,
+            seller_proceeds,
+            tax: settlement.tax,
+            source: settlement.source,
+            settled_at: settlement.settled_at,
+        });
+    }
+    SoldItemsPage { items, next_cursor }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock,
+        escrow::EscrowState,
+        test_support::{self, assert_fits, principal, HOUR},
+    };
+
+    //lists an item and moves to its end, settling earlier is audited under the caller, who only exists on-chain
+    fn ended_item(id: u64) {
+        test_support::list(test_support::item(id, principal(1)));
+        clock::set(test_support::stored(id).result_date);
+    }
+
+    #[test]
+    fn the_largest_settlement_fits() {
+        assert_fits(&Settlement {
+            id: Some(u64::MAX),
+            item_id: u64::MAX,
+            seller: principal(1),
+            buyer: principal(2),
+            price: u64::MAX,
+            settled_at: u64::MAX,
+            source: SettlementSource::RunnerUp {
+                previous_buyer: principal(3),
+            },
+            terms_acceptance: Some(TermsAcceptance::largest(principal(2))),
+            defaulted_at: Some(u64::MAX),
+            commission_bps: Some(u64::MAX),
+            tax: Some(TaxBreakdown::largest()),
+        });
+    }
+
+    #[test]
+    fn settling_sells_the_item_once_and_waits_for_payment() {
+        clock::set(1_000 * HOUR);
+        ended_item(1);
+        let sale = settle(1, principal(2), 40, SettlementSource::Auction).unwrap();
+        assert_eq!(
+            (sale.seller, sale.buyer, sale.price),
+            (principal(1), principal(2), 40)
+        );
+
+        let item = test_support::stored(1);
+        assert_eq!(item.status(), Status::Settled);
+        assert!(!item.is_active);
+        assert!(escrow::state_of(1) == Some(EscrowState::AwaitingPayment));
+        assert_eq!(sales_of(principal(1)), vec![1]);
+        assert!(settle(1, principal(3), 50, SettlementSource::Auction).is_err());
+        assert_eq!(find_settlement(1).unwrap().buyer, principal(2));
+    }
+
+    #[test]
+    fn cycles_items_only_sell_to_a_held_bid() {
+        clock::set(1_000 * HOUR);
+        let mut item = test_support::item(1, principal(1));
+        item.payment_mode = Some(PaymentMode::CyclesAtBid);
+        test_support::list(item);
+        assert!(settle(1, principal(2), 40, SettlementSource::Auction).is_err());
+        assert_eq!(test_support::stored(1).status(), Status::Active);
+        assert!(find_settlement(1).is_err());
+    }
+
+    #[test]
+    fn an_unpaid_sale_ends_the_item_again() {
+        clock::set(1_000 * HOUR);
+        ended_item(1);
+        settle(1, principal(2), 40, SettlementSource::Auction).unwrap();
+        assert_eq!(trailing_volume(principal(1), 0), 40);
+        assert!(!escrow::default_if_overdue(1));
+
+        clock::set(clock::time() + 30 * 24 * HOUR);
+        assert!(escrow::default_if_overdue(1));
+        assert!(find_settlement(1).unwrap().defaulted_at.is_some());
+        assert_eq!(test_support::stored(1).status(), Status::Ended);
+        assert_eq!(trailing_volume(principal(1), 0), 0);
+    }
+}
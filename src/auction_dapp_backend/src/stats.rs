@@ -0,0 +1,123 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::query;
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap, StableCell, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{Memory, StorablePrincipal, ITEM_MAP, MEMORY_MANAGER};
+
+const SELLER: u8 = 1;
+const BIDDER: u8 = 2;
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct Counters {
+    item_count: u64,
+    active_count: u64,
+    total_bids: u64,
+    unique_bidders: u64,
+    unique_sellers: u64,
+}
+
+impl Storable for Counters {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(CandidType)]
+struct Stats {
+    item_count: u64,
+    active_count: u64,
+    closed_count: u64,
+    total_bids: u64,
+    unique_bidders: u64,
+    unique_sellers: u64,
+}
+
+thread_local! {
+    static COUNTERS: RefCell<StableCell<Counters, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))),
+        Counters::default()).unwrap());
+
+    //SELLER and BIDDER flags of everyone who ever listed or bid, so each is only counted once
+    static PARTICIPANTS: RefCell<StableBTreeMap<StorablePrincipal, u8, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))));
+}
+
+fn update(f: impl FnOnce(&mut Counters)) {
+    COUNTERS.with(|c| {
+        let mut counters = c.borrow().get().clone();
+        f(&mut counters);
+        c.borrow_mut().set(counters).unwrap();
+    });
+}
+
+//returns true the first time a principal gets the role
+fn add_role(p: Principal, role: u8) -> bool {
+    PARTICIPANTS.with(|m| {
+        let roles = m.borrow().get(&p.into()).unwrap_or(0);
+        m.borrow_mut().insert(p.into(), roles | role);
+        roles & role == 0
+    })
+}
+
+pub fn item_listed(owner: Principal) {
+    let new_seller = add_role(owner, SELLER);
+    update(|c| {
+        c.item_count += 1;
+        c.unique_sellers += new_seller as u64;
+    });
+}
+
+pub fn item_deleted() {
+    update(|c| c.item_count = c.item_count.saturating_sub(1));
+}
+
+pub fn listing_opened() {
+    update(|c| c.active_count += 1);
+}
+
+pub fn listing_closed() {
+    update(|c| c.active_count = c.active_count.saturating_sub(1));
+}
+
+pub fn bid_placed(bidder: Principal) {
+    let new_bidder = add_role(bidder, BIDDER);
+    update(|c| {
+        c.total_bids += 1;
+        c.unique_bidders += new_bidder as u64;
+    });
+}
+
+//counts items listed before the counters existed, runs as a schema migration
+pub fn backfill() {
+    let mut counters = Counters::default();
+    ITEM_MAP.with(|items| {
+        for (_, item) in items.borrow().iter() {
+            counters.item_count += 1;
+            counters.active_count += item.is_active as u64;
+            counters.unique_sellers += add_role(item.item_owner, SELLER) as u64;
+            //runs before bid_store::move_out_of_items, the bids are still inside the items
+            for bid in item.bid_vector.iter() {
+                counters.total_bids += 1;
+                counters.unique_bidders += add_role(bid.bidder_principal, BIDDER) as u64;
+            }
+        }
+    });
+    COUNTERS.with(|c| c.borrow_mut().set(counters).unwrap());
+}
+
+#[query(name = "getStats")]
+fn get_stats() -> Stats {
+    let c = COUNTERS.with(|c| c.borrow().get().clone());
+    Stats {
+        item_count: c.item_count,
+        active_count: c.active_count,
+        closed_count: c.item_count.saturating_sub(c.active_count),
+        total_bids: c.total_bids,
+        unique_bidders: c.unique_bidders,
+        unique_sellers: c.unique_sellers,
+    }
+}
@@ -0,0 +1,492 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell, time::Duration};
+
+use crate::{admin, clock::time, guard, Memory, StorablePrincipal, ITEM_MAP, MEMORY_MANAGER};
+
+const DEFAULT_CALLBACK_METHOD: &str = "onAuctionEvent";
+const MAX_METHOD_NAME_LENGTH: usize = 64;
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+const RETRY_BASE_DELAY_NS: u64 = 10 * 1_000_000_000;
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(10);
+const DELIVERIES_PER_TICK: usize = 20;
+//pollers that fall further behind than this start over from the oldest kept event
+const MAX_LOGGED_EVENTS: u64 = 10_000;
+const MAX_POLL_SIZE: u64 = 100;
+const MAX_ITEM_SUBSCRIBERS: usize = 20;
+//item subscribers are dropped after this many notifications in a row could not be sent
+const MAX_NOTIFY_FAILURES: u32 = 5;
+
+#[derive(Deserialize, CandidType, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    ItemListed,
+    BidPlaced,
+    AuctionClosed,
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub enum AuctionEvent {
+    ItemListed {
+        item_id: u64,
+        owner: Principal,
+    },
+    BidPlaced {
+        item_id: u64,
+        bidder: Principal,
+        amount: u64,
+    },
+    AuctionClosed {
+        item_id: u64,
+        buyer: Option<Principal>,
+        price: Option<u64>,
+    },
+}
+
+impl AuctionEvent {
+    fn kind(&self) -> EventKind {
+        match self {
+            AuctionEvent::ItemListed { .. } => EventKind::ItemListed,
+            AuctionEvent::BidPlaced { .. } => EventKind::BidPlaced,
+            AuctionEvent::AuctionClosed { .. } => EventKind::AuctionClosed,
+        }
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct Subscription {
+    canister: Principal,
+    kinds: Vec<EventKind>,
+    method: String, //called with a single AuctionEvent argument
+    registered_at: u64,
+}
+
+//a canister told about every bid on one item, with one-way calls that are not retried
+#[derive(Deserialize, CandidType, Clone)]
+struct ItemSubscriber {
+    canister: Principal,
+    method: String,
+    registered_at: u64,
+    failures: u32, //in a row, reset by a notification that went out
+}
+
+impl Storable for ItemSubscriber {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ItemSubscriber {
+    const MAX_SIZE: u32 = 200;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+struct Delivery {
+    subscriber: Principal,
+    method: String,
+    event: AuctionEvent,
+    attempts: u32,
+    next_attempt_at: u64,
+    in_flight: bool,
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+struct EventRecord {
+    seq: u64,
+    event: AuctionEvent,
+    recorded_at: u64,
+}
+
+#[derive(CandidType)]
+struct EventPage {
+    events: Vec<EventRecord>,
+    last_seq: Option<u64>, //pass it back as `after_seq`, not set when there are no events at all
+}
+
+impl Storable for EventRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EventRecord {
+    const MAX_SIZE: u32 = 300;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Subscription {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Subscription {
+    const MAX_SIZE: u32 = 300;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Delivery {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Delivery {
+    const MAX_SIZE: u32 = 400;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static SUBSCRIPTION_MAP: RefCell<StableBTreeMap<StorablePrincipal, Subscription, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))));
+
+    //pending notifications, keyed by a sequence number so they go out in order
+    static OUTBOX: RefCell<StableBTreeMap<u64, Delivery, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))));
+
+    //every published event for frontends that poll, the oldest are dropped past MAX_LOGGED_EVENTS
+    static EVENT_LOG: RefCell<StableBTreeMap<u64, EventRecord, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63)))));
+
+    //(item id, canister) -> a subscriber to the item's bids
+    static ITEM_SUBSCRIBERS: RefCell<StableBTreeMap<(u64, StorablePrincipal), ItemSubscriber, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(84)))));
+}
+
+//canister ids are opaque principals, which end with 0x01
+pub fn is_canister(p: Principal) -> bool {
+    p.as_slice().last() == Some(&0x01)
+}
+
+fn log_event(event: &AuctionEvent) {
+    EVENT_LOG.with(|log| {
+        let seq = log.borrow().last_key_value().map_or(0, |(k, _)| k + 1);
+        let record = EventRecord {
+            seq,
+            event: event.clone(),
+            recorded_at: time(),
+        };
+        log.borrow_mut().insert(seq, record);
+        if seq >= MAX_LOGGED_EVENTS {
+            log.borrow_mut().remove(&(seq - MAX_LOGGED_EVENTS));
+        }
+    });
+}
+
+fn item_subscribers(item_id: u64) -> Vec<ItemSubscriber> {
+    ITEM_SUBSCRIBERS.with(|s| {
+        s.borrow()
+            .range((item_id, StorablePrincipal::default())..)
+            .take_while(|((id, _), _)| *id == item_id)
+            .map(|(_, v)| v)
+            .collect()
+    })
+}
+
+//a failed notify only tells that the call could not be sent, what the subscriber does with it is not known
+fn notify_item_subscribers(item_id: u64, event: &AuctionEvent) {
+    for mut sub in item_subscribers(item_id) {
+        let sent = ic_cdk::notify(sub.canister, &sub.method, (event.clone(),)).is_ok();
+        let key = (item_id, StorablePrincipal::from(sub.canister));
+        match sent {
+            true if sub.failures == 0 => {}
+            true => {
+                sub.failures = 0;
+                ITEM_SUBSCRIBERS.with(|s| s.borrow_mut().insert(key, sub));
+            }
+            false if sub.failures + 1 >= MAX_NOTIFY_FAILURES => {
+                ITEM_SUBSCRIBERS.with(|s| s.borrow_mut().remove(&key));
+            }
+            false => {
+                sub.failures += 1;
+                ITEM_SUBSCRIBERS.with(|s| s.borrow_mut().insert(key, sub));
+            }
+        }
+    }
+}
+
+/// Logs the event for pollers and queues it for every canister subscribed to its kind.
+pub fn publish(event: AuctionEvent) {
+    log_event(&event);
+    if let AuctionEvent::BidPlaced { item_id, .. } = event {
+        notify_item_subscribers(item_id, &event);
+    }
+    let kind = event.kind();
+    let subscribers: Vec<Subscription> = SUBSCRIPTION_MAP.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, v)| v)
+            .filter(|v| v.kinds.contains(&kind))
+            .collect()
+    });
+    let now = time();
+    OUTBOX.with(|outbox| {
+        for sub in subscribers {
+            let seq = outbox.borrow().last_key_value().map_or(0, |(k, _)| k + 1);
+            let delivery = Delivery {
+                subscriber: sub.canister,
+                method: sub.method,
+                event: event.clone(),
+                attempts: 0,
+                next_attempt_at: now,
+                in_flight: false,
+            };
+            outbox.borrow_mut().insert(seq, delivery);
+        }
+    });
+}
+
+pub fn start_delivery_timer() {
+    ic_cdk_timers::set_timer_interval(DELIVERY_INTERVAL, deliver_due);
+}
+
+fn deliver_due() {
+    let now = time();
+    let due: Vec<(u64, Delivery)> = OUTBOX.with(|outbox| {
+        outbox
+            .borrow()
+            .iter()
+            .filter(|(_, d)| !d.in_flight && d.next_attempt_at <= now)
+            .take(DELIVERIES_PER_TICK)
+            .collect()
+    });
+
+    for (seq, mut delivery) in due {
+        delivery.in_flight = true;
+        OUTBOX.with(|outbox| outbox.borrow_mut().insert(seq, delivery.clone()));
+        ic_cdk::spawn(async move {
+            let result: Result<(), _> = ic_cdk::call(
+                delivery.subscriber,
+                &delivery.method,
+                (delivery.event.clone(),),
+            )
+            .await;
+            finish_delivery(seq, delivery, result.is_ok());
+        });
+    }
+}
+
+//successful and exhausted deliveries leave the outbox, failed ones back off exponentially
+fn finish_delivery(seq: u64, mut delivery: Delivery, succeeded: bool) {
+    delivery.attempts += 1;
+    if succeeded || delivery.attempts >= MAX_DELIVERY_ATTEMPTS {
+        OUTBOX.with(|outbox| outbox.borrow_mut().remove(&seq));
+        return;
+    }
+    delivery.in_flight = false;
+    delivery.next_attempt_at = time() + RETRY_BASE_DELAY_NS * 2u64.pow(delivery.attempts);
+    OUTBOX.with(|outbox| outbox.borrow_mut().insert(seq, delivery));
+}
+
+//in-flight flags don't survive an upgrade, calls interrupted by one are retried
+pub fn reset_in_flight() {
+    let stuck: Vec<(u64, Delivery)> = OUTBOX.with(|outbox| {
+        outbox
+            .borrow()
+            .iter()
+            .filter(|(_, d)| d.in_flight)
+            .collect()
+    });
+    for (seq, mut delivery) in stuck {
+        delivery.in_flight = false;
+        OUTBOX.with(|outbox| outbox.borrow_mut().insert(seq, delivery));
+    }
+}
+
+#[update(name = "subscribe")]
+fn subscribe(kinds: Vec<EventKind>, method: Option<String>) -> Result<String, String> {
+    guard::check_write_headroom()?;
+    if !is_canister(caller()) {
+        return Err("Only canisters can subscribe to auction events.".to_string());
+    }
+    if kinds.is_empty() {
+        return Err("Subscribe to at least one event kind.".to_string());
+    }
+    let method = validate_method(method)?;
+
+    let subscription = Subscription {
+        canister: caller(),
+        kinds,
+        method,
+        registered_at: time(),
+    };
+    SUBSCRIPTION_MAP.with(|s| s.borrow_mut().insert(caller().into(), subscription));
+    Ok(format!("{} subscribed to auction events", caller()))
+}
+
+#[update(name = "unsubscribe")]
+fn unsubscribe() -> Result<String, String> {
+    match SUBSCRIPTION_MAP.with(|s| s.borrow_mut().remove(&caller().into())) {
+        Some(_) => Ok(format!("{} unsubscribed from auction events", caller())),
+        None => Err("You are not subscribed to auction events.".to_string()),
+    }
+}
+
+fn validate_method(method: Option<String>) -> Result<String, String> {
+    let method = method.unwrap_or_else(|| DEFAULT_CALLBACK_METHOD.to_string());
+    if method.is_empty() || method.len() > MAX_METHOD_NAME_LENGTH {
+        return Err(format!(
+            "Callback method names must be 1 to {} bytes long.",
+            MAX_METHOD_NAME_LENGTH
+        ));
+    }
+    Ok(method)
+}
+
+//a canister subscribes itself, an item owner can also subscribe a canister of theirs to their item
+#[update(name = "subscribeToItem")]
+fn subscribe_to_item(
+    item_id: u64,
+    canister: Option<Principal>,
+    method: Option<String>,
+) -> Result<String, String> {
+    guard::check_write_headroom()?;
+    let item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+    let canister = match canister {
+        Some(c) if item.item_owner != caller() => {
+            return Err(format!(
+                "Only the item owner can subscribe {} to this item.",
+                c
+            ))
+        }
+        Some(c) => c,
+        None => caller(),
+    };
+    if !is_canister(canister) {
+        return Err("Only canisters can subscribe to an item's bids.".to_string());
+    }
+    let method = validate_method(method)?;
+    let key = (item_id, StorablePrincipal::from(canister));
+    let exists = ITEM_SUBSCRIBERS.with(|s| s.borrow().contains_key(&key));
+    if !exists && item_subscribers(item_id).len() >= MAX_ITEM_SUBSCRIBERS {
+        return Err(format!(
+            "An item can have at most {} subscribers.",
+            MAX_ITEM_SUBSCRIBERS
+        ));
+    }
+    let subscriber = ItemSubscriber {
+        canister,
+        method,
+        registered_at: time(),
+        failures: 0,
+    };
+    ITEM_SUBSCRIBERS.with(|s| s.borrow_mut().insert(key, subscriber));
+    Ok(format!(
+        "{} subscribed to bids on item {}",
+        canister, item_id
+    ))
+}
+
+//the subscribed canister or the item's owner can end a subscription
+#[update(name = "unsubscribeFromItem")]
+fn unsubscribe_from_item(item_id: u64, canister: Option<Principal>) -> Result<String, String> {
+    let canister = canister.unwrap_or_else(caller);
+    let is_owner = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .is_some_and(|item| item.item_owner == caller());
+    if canister != caller() && !is_owner {
+        return Err("You cannot unsubscribe this canister.".to_string());
+    }
+    match ITEM_SUBSCRIBERS.with(|s| s.borrow_mut().remove(&(item_id, canister.into()))) {
+        Some(_) => Ok(format!(
+            "{} unsubscribed from bids on item {}",
+            canister, item_id
+        )),
+        None => Err(format!("{} is not subscribed to this item.", canister)),
+    }
+}
+
+#[query(name = "getItemSubscribers")]
+fn get_item_subscribers(item_id: u64) -> Result<Vec<ItemSubscriber>, String> {
+    let is_owner = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .is_some_and(|item| item.item_owner == caller());
+    if !is_owner && !admin::is_admin(caller()) {
+        return Err("Only the item owner can see its subscribers.".to_string());
+    }
+    Ok(item_subscribers(item_id))
+}
+
+#[query(name = "getSubscription")]
+fn get_subscription() -> Option<Subscription> {
+    SUBSCRIPTION_MAP.with(|s| s.borrow().get(&caller().into()))
+}
+
+//events after `after_seq` in order, leave it unset to start with the oldest kept event
+#[query(name = "pollEvents")]
+fn poll_events(after_seq: Option<u64>, limit: u64) -> EventPage {
+    let limit = limit.clamp(1, MAX_POLL_SIZE) as usize;
+    let from = after_seq.map_or(0, |s| s.saturating_add(1));
+    let events: Vec<EventRecord> = EVENT_LOG.with(|log| {
+        log.borrow()
+            .range(from..)
+            .take(limit)
+            .map(|(_, v)| v)
+            .collect()
+    });
+    let last_seq = events.last().map(|e| e.seq).or(after_seq);
+    EventPage { events, last_seq }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    fn largest_event() -> AuctionEvent {
+        AuctionEvent::AuctionClosed {
+            item_id: u64::MAX,
+            buyer: Some(principal(2)),
+            price: Some(u64::MAX),
+        }
+    }
+
+    #[test]
+    fn the_largest_subscription_records_fit() {
+        let method = "x".repeat(MAX_METHOD_NAME_LENGTH);
+        assert_fits(&Subscription {
+            canister: principal(1),
+            kinds: vec![
+                EventKind::ItemListed,
+                EventKind::BidPlaced,
+                EventKind::AuctionClosed,
+            ],
+            method: method.clone(),
+            registered_at: u64::MAX,
+        });
+        assert_fits(&ItemSubscriber {
+            canister: principal(1),
+            method: method.clone(),
+            registered_at: u64::MAX,
+            failures: u32::MAX,
+        });
+        assert_fits(&Delivery {
+            subscriber: principal(1),
+            method,
+            event: largest_event(),
+            attempts: u32::MAX,
+            next_attempt_at: u64::MAX,
+            in_flight: true,
+        });
+        assert_fits(&EventRecord {
+            seq: u64::MAX,
+            event: largest_event(),
+            recorded_at: u64::MAX,
+        });
+    }
+}
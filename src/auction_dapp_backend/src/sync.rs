@@ -0,0 +1,74 @@
+use candid::CandidType;
+use ic_cdk::{caller, query};
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap};
+use std::cell::RefCell;
+
+use crate::{clock::time, is_visible_to, ItemSummary, Memory, ITEM_MAP, MEMORY_MANAGER};
+
+const MAX_PAGE_SIZE: u64 = 200;
+
+#[derive(CandidType)]
+struct ModifiedItem {
+    seq: u64,
+    item_id: u64,
+    modified_at: u64,
+    summary: Option<ItemSummary>, //not set once the item was removed or hidden from the caller
+}
+
+#[derive(CandidType)]
+struct ModifiedItemsPage {
+    items: Vec<ModifiedItem>,
+    next_seq: Option<u64>, //pass it back as `from_seq` to read the next page
+}
+
+thread_local! {
+    //seq -> (item_id, modified_at), only the latest modification of each item is kept
+    static MODIFICATION_LOG: RefCell<StableBTreeMap<u64, (u64, u64), Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61)))));
+
+    //item_id -> seq of its entry in the log
+    static LAST_MODIFIED: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62)))));
+}
+
+/// Moves the item to the end of the modification log, called from the indexer_sync hooks.
+pub fn touch(item_id: u64) {
+    let seq = MODIFICATION_LOG.with(|log| log.borrow().last_key_value().map_or(0, |(k, _)| k + 1));
+    if let Some(previous) = LAST_MODIFIED.with(|m| m.borrow_mut().insert(item_id, seq)) {
+        MODIFICATION_LOG.with(|log| log.borrow_mut().remove(&previous));
+    }
+    MODIFICATION_LOG.with(|log| log.borrow_mut().insert(seq, (item_id, time())));
+}
+
+//items listed before the log existed are logged once, in id order
+pub fn backfill() {
+    let ids: Vec<u64> = ITEM_MAP.with(|items| items.borrow().iter().map(|(k, _)| k).collect());
+    for id in ids {
+        touch(id);
+    }
+}
+
+//oldest modification first, a client that saw up to seq n syncs from n + 1, or from 0 for everything
+#[query(name = "getItemsModifiedSince")]
+fn get_items_modified_since(from_seq: u64, limit: u64) -> ModifiedItemsPage {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let mut entries: Vec<(u64, (u64, u64))> =
+        MODIFICATION_LOG.with(|log| log.borrow().range(from_seq..).take(limit + 1).collect());
+    let next_seq = if entries.len() > limit {
+        entries.pop().map(|(seq, _)| seq)
+    } else {
+        None
+    };
+    let viewer = caller();
+    let items = entries
+        .into_iter()
+        .map(|(seq, (item_id, modified_at))| ModifiedItem {
+            seq,
+            item_id,
+            modified_at,
+            summary: ITEM_MAP
+                .with(|items| items.borrow().get(&item_id))
+                .filter(|item| is_visible_to(item, viewer))
+                .map(|item| ItemSummary::from(&item)),
+        })
+        .collect();
+    ModifiedItemsPage { items, next_seq }
+}
@@ -0,0 +1,90 @@
+use candid::{CandidType, Deserialize};
+
+use crate::{http::escape_json, validation};
+
+const MAX_JURISDICTION_LENGTH: usize = 16;
+const MAX_VAT_BPS: u64 = 10_000;
+
+//what the seller declares for their own bookkeeping, the auction does not collect tax
+#[derive(Deserialize, CandidType, Clone, PartialEq)]
+pub struct TaxInfo {
+    jurisdiction: String, //e.g. "DE" or "US-CA"
+    vat_bps: u64,
+    tax_inclusive: bool, //whether the winning price already contains the tax
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct TaxBreakdown {
+    jurisdiction: String,
+    vat_bps: u64,
+    net: u64,
+    tax: u64,
+    gross: u64, //above the price for tax exclusive listings, the seller invoices the difference
+}
+
+impl TaxBreakdown {
+    pub fn json(&self) -> String {
+        format!(
+            "{{\"jurisdiction\":\"{}\",\"vat_bps\":{},\"net\":{},\"tax\":{},\"gross\":{}}}",
+            escape_json(&self.jurisdiction),
+            self.vat_bps,
+            self.net,
+            self.tax,
+            self.gross
+        )
+    }
+
+    /// The largest breakdown valid tax details give, for the size tests of the records embedding one.
+    #[cfg(test)]
+    pub fn largest() -> Self {
+        TaxBreakdown {
+            jurisdiction: "\u{1F600}".repeat(MAX_JURISDICTION_LENGTH),
+            vat_bps: MAX_VAT_BPS,
+            net: u64::MAX,
+            tax: u64::MAX,
+            gross: u64::MAX,
+        }
+    }
+}
+
+#[cfg(test)]
+impl TaxInfo {
+    /// The largest tax details a seller can give, for the size tests of the Item.
+    pub fn largest() -> Self {
+        TaxInfo {
+            jurisdiction: "\u{1F600}".repeat(MAX_JURISDICTION_LENGTH),
+            vat_bps: MAX_VAT_BPS,
+            tax_inclusive: true,
+        }
+    }
+}
+
+pub fn validate(info: Option<TaxInfo>) -> Result<Option<TaxInfo>, String> {
+    let Some(mut info) = info else {
+        return Ok(None);
+    };
+    info.jurisdiction =
+        validation::text("Jurisdiction", &info.jurisdiction, MAX_JURISDICTION_LENGTH)?
+            .to_uppercase();
+    if info.vat_bps > MAX_VAT_BPS {
+        return Err("The VAT rate cannot be above 100%.".to_string());
+    }
+    Ok(Some(info))
+}
+
+pub fn breakdown(info: &TaxInfo, price: u64) -> TaxBreakdown {
+    let (price, bps) = (price as u128, info.vat_bps as u128);
+    let (net, tax) = if info.tax_inclusive {
+        let net = price * 10_000 / (10_000 + bps);
+        (net, price - net)
+    } else {
+        (price, price * bps / 10_000)
+    };
+    TaxBreakdown {
+        jurisdiction: info.jurisdiction.clone(),
+        vat_bps: info.vat_bps,
+        net: net as u64,
+        tax: tax as u64,
+        gross: (net + tax) as u64,
+    }
+}
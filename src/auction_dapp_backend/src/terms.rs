@@ -0,0 +1,111 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{clock::time, Item, Memory, StorablePrincipal, ITEM_MAP, MEMORY_MANAGER};
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct TermsAcceptance {
+    item_id: u64,
+    bidder: Principal,
+    terms_hash: Vec<u8>, //sha256 of the terms text the bidder agreed to
+    accepted_at: u64,
+}
+
+impl Storable for TermsAcceptance {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TermsAcceptance {
+    const MAX_SIZE: u32 = 200;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[cfg(test)]
+impl TermsAcceptance {
+    /// The largest acceptance `bidder` can make, for the size tests of the records embedding one.
+    pub fn largest(bidder: Principal) -> Self {
+        TermsAcceptance {
+            item_id: u64::MAX,
+            bidder,
+            terms_hash: hash_terms(""),
+            accepted_at: u64::MAX,
+        }
+    }
+}
+
+thread_local! {
+    static ACCEPTANCE_MAP: RefCell<StableBTreeMap<(u64, StorablePrincipal), TermsAcceptance, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))));
+}
+
+pub fn hash_terms(terms: &str) -> Vec<u8> {
+    Sha256::digest(terms.as_bytes()).to_vec()
+}
+
+pub fn acceptance_of(item_id: u64, bidder: Principal) -> Option<TermsAcceptance> {
+    ACCEPTANCE_MAP.with(|a| a.borrow().get(&(item_id, bidder.into())))
+}
+
+/// Bidders must have accepted the current terms of an item, if it has any, before bidding.
+pub fn ensure_accepted(item: &Item, bidder: Principal) -> Result<(), String> {
+    let Some(terms) = item.terms.as_ref() else {
+        return Ok(());
+    };
+    match acceptance_of(item.id, bidder) {
+        Some(a) if a.terms_hash == hash_terms(terms) => Ok(()),
+        Some(_) => Err("The terms of sale have changed, please accept them again.".to_string()),
+        None => Err("Please accept the terms of sale before bidding.".to_string()),
+    }
+}
+
+//the hash proves which version of the terms was shown to the bidder
+#[update(name = "acceptTerms")]
+fn accept_terms(item_id: u64, terms_hash: Vec<u8>) -> Result<String, String> {
+    let item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+    let terms = item
+        .terms
+        .as_ref()
+        .ok_or_else(|| "This item has no terms of sale.".to_string())?;
+
+    if terms_hash != hash_terms(terms) {
+        return Err("The terms you accepted do not match the current terms of sale.".to_string());
+    }
+
+    let acceptance = TermsAcceptance {
+        item_id,
+        bidder: caller(),
+        terms_hash,
+        accepted_at: time(),
+    };
+    ACCEPTANCE_MAP.with(|a| {
+        a.borrow_mut()
+            .insert((item_id, caller().into()), acceptance)
+    });
+    Ok("Terms of sale accepted".to_string())
+}
+
+#[query(name = "getTermsAcceptance")]
+fn get_terms_acceptance(item_id: u64, bidder: Principal) -> Option<TermsAcceptance> {
+    acceptance_of(item_id, bidder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    #[test]
+    fn an_acceptance_fits() {
+        assert_fits(&TermsAcceptance::largest(principal(1)));
+    }
+}
@@ -0,0 +1,79 @@
+//fixtures for the unit tests, every test runs on its own thread and so starts from empty stable memory
+use candid::Principal;
+use ic_stable_structures::BoundedStorable;
+
+use crate::{clock, expiry, Bid, Item, Status, ITEM_MAP};
+
+pub const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+pub fn principal(n: u8) -> Principal {
+    Principal::from_slice(&[n; 29])
+}
+
+/// An active escrow item of `owner` that ends in a day.
+pub fn item(id: u64, owner: Principal) -> Item {
+    Item {
+        item_owner: owner,
+        id,
+        description: format!("Item {}", id),
+        highest_bid: 0,
+        latest_update: clock::time(),
+        result_date: clock::time() + 24 * HOUR,
+        bid_vector: vec![],
+        is_active: true,
+        accepts_offers: None,
+        auto_accept_price: None,
+        is_hidden: None,
+        terms: None,
+        category: None,
+        status: Some(Status::Active),
+        starts_at: None,
+        winner_policy: None,
+        relist_policy: None,
+        relisted_from: None,
+        increment_bands: None,
+        payment_mode: None,
+        bid_bond: None,
+        payment_window: None,
+        tax: None,
+        minimum_bid: None,
+        media: None,
+        certified: None,
+        bid_archive: None,
+    }
+}
+
+/// Stores the item and indexes it like a new listing, which also sets its close timer.
+pub fn list(item: Item) {
+    expiry::index_insert(&item);
+    ITEM_MAP.with(|items| items.borrow_mut().insert(item.id, item));
+}
+
+pub fn stored(id: u64) -> Item {
+    ITEM_MAP.with(|items| items.borrow().get(&id)).unwrap()
+}
+
+pub fn bid(item_id: u64, bidder: Principal, amount: u64, seq: u64) -> Bid {
+    Bid {
+        item_id,
+        bidder_principal: bidder,
+        bid_date: 0,
+        bid_amount: amount,
+        memo: None,
+        seq: Some(seq),
+        placed_at: Some(clock::time()),
+    }
+}
+
+/// Checks that `value` fits in its type's stored size and reads back as the same bytes.
+pub fn assert_fits<T: BoundedStorable>(value: &T) {
+    let bytes = value.to_bytes().into_owned();
+    assert!(
+        bytes.len() <= T::MAX_SIZE as usize,
+        "{} bytes do not fit in {}",
+        bytes.len(),
+        T::MAX_SIZE
+    );
+    let read = T::from_bytes(bytes.clone().into());
+    assert_eq!(read.to_bytes().as_ref(), bytes.as_slice());
+}
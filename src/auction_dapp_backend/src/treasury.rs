@@ -0,0 +1,451 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_cdk::{
+    api::management_canister::main::{deposit_cycles, CanisterIdRecord},
+    caller, query, update,
+};
+use ic_stable_structures::{
+    memory_manager::MemoryId, BoundedStorable, StableBTreeMap, StableCell, Storable,
+};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    admin,
+    blocks::{self, FinancialOp},
+    clock::time,
+    icrc::{self, Account, TransferArg},
+    ledgers, validation, Memory, MEMORY_MANAGER,
+};
+
+const DEFAULT_TIMELOCK_NS: u64 = 2 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_TIMELOCK_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(Deserialize, CandidType, Clone)]
+struct TreasuryPolicy {
+    large_withdrawal: Option<u128>, //withdrawals taking the last timelock's immediate ones to this many cycles are timelocked, all of them when not set. token withdrawals always are
+    timelock: u64,
+}
+
+impl Default for TreasuryPolicy {
+    fn default() -> Self {
+        TreasuryPolicy {
+            large_withdrawal: None,
+            timelock: DEFAULT_TIMELOCK_NS,
+        }
+    }
+}
+
+impl TreasuryPolicy {
+    //`recent` is what went out without a timelock within the last one, so splitting a withdrawal up does not skip it
+    fn is_large(&self, amount: u128, recent: u128) -> bool {
+        self.large_withdrawal
+            .is_none_or(|t| recent.saturating_add(amount) >= t)
+    }
+
+    //a looser policy would let a compromised admin skip the timelock, so it waits out the current one
+    fn is_looser_than(&self, other: &TreasuryPolicy) -> bool {
+        let threshold = |p: &TreasuryPolicy| p.large_withdrawal.unwrap_or(0);
+        threshold(self) > threshold(other) || self.timelock < other.timelock
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+struct PendingPolicy {
+    policy: TreasuryPolicy,
+    effective_at: u64,
+}
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct TreasuryConfig {
+    policy: TreasuryPolicy,
+    pending: Option<PendingPolicy>,
+}
+
+impl Storable for TreasuryConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone, PartialEq)]
+enum TreasuryWithdrawalState {
+    Queued,
+    Executing, //the cycles are on their way, the amount is no longer available
+    Executed { at: u64 },
+    Cancelled { by: Principal, at: u64 },
+    Failed { reason: String },
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+struct TreasuryWithdrawal {
+    id: u64,
+    to: Principal, //a canister the cycles are deposited into, or the owner of the account the tokens go to
+    amount: u128,
+    ledger: Option<Principal>, //not set for cycles
+    proposed_by: Principal,
+    proposed_at: u64,
+    executable_at: u64,
+    state: TreasuryWithdrawalState,
+}
+
+impl Storable for TreasuryWithdrawal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//a failed token withdrawal with the longest kept reason takes 378 bytes
+impl BoundedStorable for TreasuryWithdrawal {
+    const MAX_SIZE: u32 = 400;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType)]
+struct TokenTreasury {
+    ledger: Principal,
+    balance: u128,
+    available: u128,
+}
+
+#[derive(CandidType)]
+struct TreasuryOverview {
+    balance: u128,   //in cycles
+    available: u128, //the balance minus queued and executing withdrawals
+    tokens: Vec<TokenTreasury>,
+    policy: TreasuryPolicy,
+    pending_policy: Option<PendingPolicy>,
+    withdrawals: Vec<TreasuryWithdrawal>,
+}
+
+thread_local! {
+    static TREASURY_CONFIG: RefCell<StableCell<TreasuryConfig, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(74))),
+        TreasuryConfig::default()).unwrap());
+
+    static TREASURY_WITHDRAWALS: RefCell<StableBTreeMap<u64, TreasuryWithdrawal, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(75)))));
+}
+
+//a pending policy applies once its timelock has passed
+fn load_config() -> (TreasuryConfig, bool) {
+    let mut config = TREASURY_CONFIG.with(|c| c.borrow().get().clone());
+    let due = config.pending.take_if(|p| p.effective_at <= time());
+    let applied = due.is_some();
+    if let Some(pending) = due {
+        config.policy = pending.policy;
+    }
+    (config, applied)
+}
+
+fn current_config() -> TreasuryConfig {
+    let (config, applied) = load_config();
+    if applied {
+        TREASURY_CONFIG.with(|c| c.borrow_mut().set(config.clone()).unwrap());
+    }
+    config
+}
+
+fn save(withdrawal: &TreasuryWithdrawal) {
+    TREASURY_WITHDRAWALS.with(|w| w.borrow_mut().insert(withdrawal.id, withdrawal.clone()));
+}
+
+fn find(id: u64) -> Result<TreasuryWithdrawal, String> {
+    TREASURY_WITHDRAWALS
+        .with(|w| w.borrow().get(&id))
+        .ok_or_else(|| "Treasury withdrawal could not be found.".to_string())
+}
+
+fn unit(ledger: Option<Principal>) -> String {
+    ledger.map_or("cycles".to_string(), |l| format!("tokens of {}", l))
+}
+
+fn available(ledger: Option<Principal>) -> u128 {
+    let reserved: u128 = TREASURY_WITHDRAWALS.with(|w| {
+        w.borrow()
+            .iter()
+            .filter(|(_, w)| w.ledger == ledger)
+            .filter(|(_, w)| {
+                matches!(
+                    w.state,
+                    TreasuryWithdrawalState::Queued | TreasuryWithdrawalState::Executing
+                )
+            })
+            .map(|(_, w)| w.amount)
+            .sum()
+    });
+    blocks::treasury_balance(ledger).saturating_sub(reserved)
+}
+
+//cycles withdrawals that were not timelocked, proposed since `since` and not failed or cancelled
+fn immediate_since(since: u64) -> u128 {
+    TREASURY_WITHDRAWALS.with(|w| {
+        w.borrow()
+            .iter()
+            .map(|(_, w)| w)
+            .filter(|w| w.ledger.is_none())
+            .filter(|w| w.proposed_at >= since && w.executable_at == w.proposed_at)
+            .filter(|w| {
+                matches!(
+                    w.state,
+                    TreasuryWithdrawalState::Executing | TreasuryWithdrawalState::Executed { .. }
+                )
+            })
+            .map(|w| w.amount)
+            .sum()
+    })
+}
+
+async fn send_cycles(withdrawal: &TreasuryWithdrawal) -> Result<(), String> {
+    let target = CanisterIdRecord {
+        canister_id: withdrawal.to,
+    };
+    deposit_cycles(target, withdrawal.amount)
+        .await
+        .map_err(|(_, msg)| format!("The cycles could not be deposited: {}", msg))
+}
+
+//the ledger fee comes out of the amount, so the treasury's account goes down by exactly the amount
+async fn send_tokens(withdrawal: &TreasuryWithdrawal, ledger: Principal) -> Result<(), String> {
+    let fee = icrc::fee(ledger).await?;
+    if withdrawal.amount <= fee {
+        return Err(format!(
+            "The amount does not cover the ledger fee of {}.",
+            fee
+        ));
+    }
+    let args = TransferArg {
+        from_subaccount: None,
+        to: Account {
+            owner: withdrawal.to,
+            subaccount: None,
+        },
+        amount: Nat::from(withdrawal.amount) - fee.clone(),
+        fee: Some(fee),
+        memo: Some([b"treasury".as_slice(), &withdrawal.id.to_be_bytes()].concat()),
+        created_at_time: Some(time()),
+    };
+    icrc::transfer(ledger, args).await.map(|_| ())
+}
+
+async fn execute(mut withdrawal: TreasuryWithdrawal) -> Result<String, String> {
+    withdrawal.state = TreasuryWithdrawalState::Executing;
+    save(&withdrawal);
+    let sent = match withdrawal.ledger {
+        None => send_cycles(&withdrawal).await,
+        Some(ledger) => send_tokens(&withdrawal, ledger).await,
+    };
+    match sent {
+        Ok(()) => {
+            let to = Some(withdrawal.to);
+            match withdrawal.ledger {
+                None => blocks::append(
+                    FinancialOp::TreasuryWithdrawal,
+                    withdrawal.amount,
+                    None,
+                    to,
+                    None,
+                ),
+                Some(ledger) => blocks::append_token(
+                    FinancialOp::TreasuryWithdrawal,
+                    ledger,
+                    withdrawal.amount,
+                    None,
+                    to,
+                    None,
+                ),
+            };
+            withdrawal.state = TreasuryWithdrawalState::Executed { at: time() };
+            save(&withdrawal);
+            Ok(format!(
+                "{} {} sent to {}",
+                withdrawal.amount,
+                unit(withdrawal.ledger),
+                withdrawal.to
+            ))
+        }
+        Err(reason) => {
+            withdrawal.state = TreasuryWithdrawalState::Failed {
+                reason: validation::reason(reason.clone()),
+            };
+            save(&withdrawal);
+            Err(reason)
+        }
+    }
+}
+
+#[update(name = "setTreasuryPolicy")]
+fn set_treasury_policy(large_withdrawal: Option<u128>, timelock: u64) -> Result<String, String> {
+    admin::ensure_admin()?;
+    if timelock > MAX_TIMELOCK_NS {
+        return Err("The timelock can be at most 30 days.".to_string());
+    }
+    let policy = TreasuryPolicy {
+        large_withdrawal,
+        timelock,
+    };
+    let mut config = current_config();
+    let message = if policy.is_looser_than(&config.policy) {
+        let effective_at = time() + config.policy.timelock;
+        config.pending = Some(PendingPolicy {
+            policy,
+            effective_at,
+        });
+        format!("Treasury policy will change at {}", effective_at)
+    } else {
+        config.policy = policy;
+        config.pending = None;
+        "Treasury policy updated".to_string()
+    };
+    TREASURY_CONFIG.with(|c| c.borrow_mut().set(config).unwrap());
+    Ok(message)
+}
+
+//small cycles withdrawals go out right away, large ones, those over the rolling limit and every token
+//withdrawal wait out the timelock and can be cancelled until then
+#[update(name = "withdrawTreasury")]
+async fn withdraw_treasury(
+    to: Principal,
+    amount: u128,
+    ledger: Option<Principal>,
+) -> Result<String, String> {
+    admin::ensure_admin()?;
+    if amount == 0 {
+        return Err("The amount has to be above zero.".to_string());
+    }
+    if let Some(ledger) = ledger {
+        ledgers::ensure_supported(ledger)?;
+    }
+    let available = available(ledger);
+    if amount > available {
+        return Err(format!(
+            "Only {} {} are available in the treasury.",
+            available,
+            unit(ledger)
+        ));
+    }
+    let policy = current_config().policy;
+    let now = time();
+    let recent = immediate_since(now.saturating_sub(policy.timelock));
+    //the threshold is in cycles, it says nothing about how much a token amount is worth
+    let large = ledger.is_some() || policy.is_large(amount, recent);
+    let withdrawal = TreasuryWithdrawal {
+        id: TREASURY_WITHDRAWALS.with(|w| w.borrow().last_key_value().map_or(0, |(k, _)| k + 1)),
+        to,
+        amount,
+        ledger,
+        proposed_by: caller(),
+        proposed_at: now,
+        executable_at: if large { now + policy.timelock } else { now },
+        state: TreasuryWithdrawalState::Queued,
+    };
+    save(&withdrawal);
+    if large {
+        return Ok(format!(
+            "Treasury withdrawal {} is queued until {}",
+            withdrawal.id, withdrawal.executable_at
+        ));
+    }
+    execute(withdrawal).await
+}
+
+#[update(name = "executeTreasuryWithdrawal")]
+async fn execute_treasury_withdrawal(id: u64) -> Result<String, String> {
+    admin::ensure_admin()?;
+    let withdrawal = find(id)?;
+    if withdrawal.state != TreasuryWithdrawalState::Queued {
+        return Err("Only queued withdrawals can be executed.".to_string());
+    }
+    if withdrawal.executable_at > time() {
+        return Err(format!(
+            "This withdrawal is timelocked until {}.",
+            withdrawal.executable_at
+        ));
+    }
+    execute(withdrawal).await
+}
+
+//any admin can cancel, so one compromised key cannot drain the treasury unnoticed
+#[update(name = "cancelTreasuryWithdrawal")]
+fn cancel_treasury_withdrawal(id: u64) -> Result<String, String> {
+    admin::ensure_admin()?;
+    let mut withdrawal = find(id)?;
+    if withdrawal.state != TreasuryWithdrawalState::Queued {
+        return Err("Only queued withdrawals can be cancelled.".to_string());
+    }
+    withdrawal.state = TreasuryWithdrawalState::Cancelled {
+        by: caller(),
+        at: time(),
+    };
+    save(&withdrawal);
+    Ok(format!("Treasury withdrawal {} cancelled", id))
+}
+
+#[query(name = "getTreasury")]
+fn get_treasury() -> Result<TreasuryOverview, String> {
+    admin::ensure_admin()?;
+    let (config, _) = load_config();
+    Ok(TreasuryOverview {
+        balance: blocks::treasury_balance(None),
+        available: available(None),
+        tokens: blocks::token_treasuries()
+            .into_iter()
+            .map(|(ledger, balance)| TokenTreasury {
+                ledger,
+                balance,
+                available: available(Some(ledger)),
+            })
+            .collect(),
+        policy: config.policy,
+        pending_policy: config.pending,
+        withdrawals: TREASURY_WITHDRAWALS.with(|w| w.borrow().iter().map(|(_, v)| v).collect()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    fn withdrawal(id: u64, ledger: Option<Principal>, amount: u128) -> TreasuryWithdrawal {
+        TreasuryWithdrawal {
+            id,
+            to: principal(2),
+            amount,
+            ledger,
+            proposed_by: principal(1),
+            proposed_at: time(),
+            executable_at: time(),
+            state: TreasuryWithdrawalState::Executing,
+        }
+    }
+
+    #[test]
+    fn the_largest_failed_withdrawal_fits() {
+        let mut withdrawal = withdrawal(u64::MAX, Some(principal(9)), u128::MAX);
+        withdrawal.state = TreasuryWithdrawalState::Failed {
+            reason: validation::reason("x".repeat(10_000)),
+        };
+        assert_fits(&withdrawal);
+    }
+
+    #[test]
+    fn each_ledger_reserves_its_own_treasury() {
+        let ledger = principal(9);
+        blocks::append(FinancialOp::Fee, 1_000, None, None, Some(1));
+        blocks::append_token(FinancialOp::Fee, ledger, 500, None, None, Some(2));
+        save(&withdrawal(0, Some(ledger), 200));
+        assert_eq!(available(None), 1_000);
+        assert_eq!(available(Some(ledger)), 300);
+        //a token withdrawal does not count towards the cycles that went out without a timelock
+        assert_eq!(immediate_since(0), 0);
+        save(&withdrawal(1, None, 400));
+        assert_eq!(available(None), 600);
+        assert_eq!(immediate_since(0), 400);
+    }
+}
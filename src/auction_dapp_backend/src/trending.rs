@@ -0,0 +1,150 @@
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell, time::Duration};
+
+use crate::{clock::time, is_visible_to, Item, ItemSummary, Memory, ITEM_MAP, MEMORY_MANAGER};
+
+//a score halves every 6 hours without new activity
+const HALF_LIFE_NS: f64 = 6.0 * 60.0 * 60.0 * 1_000_000_000.0;
+//scores below this are dropped by the cleanup timer
+const MIN_TRACKED_SCORE: f64 = 0.05;
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_TRENDING_LIMIT: usize = 50;
+
+pub enum TrendEvent {
+    View,
+    Watch,
+    Bid,
+}
+
+impl TrendEvent {
+    fn weight(&self) -> f64 {
+        match self {
+            TrendEvent::View => 1.0,
+            TrendEvent::Watch => 3.0,
+            TrendEvent::Bid => 5.0,
+        }
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone, Copy)]
+struct TrendScore {
+    score: f64,
+    updated_at: u64,
+}
+
+impl TrendScore {
+    fn decayed(&self, now: u64) -> f64 {
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        self.score * 0.5f64.powf(elapsed / HALF_LIFE_NS)
+    }
+}
+
+impl Storable for TrendScore {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TrendScore {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static TREND_MAP: RefCell<StableBTreeMap<u64, TrendScore, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))));
+}
+
+/// Adds the event's weight on top of the item's decayed score.
+pub fn record(item_id: u64, event: TrendEvent) {
+    let now = time();
+    TREND_MAP.with(|t| {
+        let score = t.borrow().get(&item_id).map_or(0.0, |s| s.decayed(now));
+        t.borrow_mut().insert(
+            item_id,
+            TrendScore {
+                score: score + event.weight(),
+                updated_at: now,
+            },
+        );
+    });
+}
+
+pub fn start_cleanup_timer() {
+    ic_cdk_timers::set_timer_interval(CLEANUP_INTERVAL, cleanup);
+}
+
+fn cleanup() {
+    let now = time();
+    let stale: Vec<u64> = TREND_MAP.with(|t| {
+        t.borrow()
+            .iter()
+            .filter(|(id, s)| {
+                s.decayed(now) < MIN_TRACKED_SCORE
+                    || !ITEM_MAP
+                        .with(|items| items.borrow().get(id))
+                        .is_some_and(|i| i.is_active)
+            })
+            .map(|(id, _)| id)
+            .collect()
+    });
+    TREND_MAP.with(|t| {
+        for id in stale {
+            t.borrow_mut().remove(&id);
+        }
+    });
+}
+
+/// Active items with the highest current score, best first.
+pub fn top(limit: usize) -> Vec<Item> {
+    let now = time();
+    let mut scored: Vec<(f64, u64)> = TREND_MAP.with(|t| {
+        t.borrow()
+            .iter()
+            .map(|(id, s)| (s.decayed(now), id))
+            .collect()
+    });
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    scored
+        .into_iter()
+        .filter_map(|(_, id)| ITEM_MAP.with(|items| items.borrow().get(&id)))
+        .filter(|item| item.is_active && is_visible_to(item, caller()))
+        .take(limit)
+        .collect()
+}
+
+//views can't be counted from queries, so the frontend reports them
+#[update(name = "recordView")]
+fn record_view(item_id: u64) {
+    if ITEM_MAP.with(|items| items.borrow().contains_key(&item_id)) {
+        record(item_id, TrendEvent::View);
+    }
+}
+
+#[query(name = "getTrendingItems")]
+fn get_trending_items(limit: u64) -> Vec<ItemSummary> {
+    top((limit as usize).min(MAX_TRENDING_LIMIT))
+        .iter()
+        .map(ItemSummary::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::assert_fits;
+
+    #[test]
+    fn a_score_fits() {
+        assert_fits(&TrendScore {
+            score: f64::MAX,
+            updated_at: u64::MAX,
+        });
+    }
+}
@@ -0,0 +1,189 @@
+use std::fmt;
+
+use crate::nfc;
+
+pub const MAX_DESCRIPTION_LENGTH: usize = 1_000;
+pub const MAX_TERMS_LENGTH: usize = 1_000;
+//what the two take as UTF-8, together with the rest of a listing they fit in an Item
+pub const MAX_DESCRIPTION_BYTES: usize = 2_800;
+pub const MAX_TERMS_BYTES: usize = 2_800;
+//what is kept of an error another canister returned, once it is stored in a bounded record
+pub const MAX_REASON_BYTES: usize = 128;
+
+#[derive(Debug, PartialEq)]
+pub enum ValidationError {
+    Empty {
+        field: &'static str,
+    },
+    TooLong {
+        field: &'static str,
+        max: usize,
+    },
+    TooLarge {
+        field: &'static str,
+        max_bytes: usize,
+    }, //within the characters, but not the bytes it is stored in
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::Empty { field } => write!(f, "{} cannot be empty.", field),
+            ValidationError::TooLong { field, max } => {
+                write!(f, "{} cannot be longer than {} characters.", field, max)
+            }
+            ValidationError::TooLarge { field, max_bytes } => write!(
+                f,
+                "{} cannot take more than {} bytes, some characters take up to 4.",
+                field, max_bytes
+            ),
+        }
+    }
+}
+
+//endpoints still return text errors, so validation failures can be passed on with `?`
+impl From<ValidationError> for String {
+    fn from(e: ValidationError) -> Self {
+        e.to_string()
+    }
+}
+
+//invisible characters that can be used to make text render differently from what it contains
+fn is_invisible(c: char) -> bool {
+    matches!(c, '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}')
+}
+
+/// Normalizes the text to NFC and its line endings, strips control and invisible characters and trims the result.
+pub fn sanitize(value: &str) -> String {
+    nfc::normalize(value)
+        .replace("\r\n", "\n")
+        .chars()
+        .filter(|&c| c == '\n' || c == '\t' || !(c.is_control() || is_invisible(c)))
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// Sanitizes a required text field and checks its length in characters.
+pub fn text(field: &'static str, value: &str, max: usize) -> Result<String, ValidationError> {
+    let value = sanitize(value);
+    if value.is_empty() {
+        return Err(ValidationError::Empty { field });
+    }
+    if value.chars().count() > max {
+        return Err(ValidationError::TooLong { field, max });
+    }
+    Ok(value)
+}
+
+/// Like `text`, for a field stored in a bounded record, which also has to fit in `max_bytes` once encoded as UTF-8.
+pub fn bounded_text(
+    field: &'static str,
+    value: &str,
+    max: usize,
+    max_bytes: usize,
+) -> Result<String, ValidationError> {
+    let value = text(field, value, max)?;
+    if value.len() > max_bytes {
+        return Err(ValidationError::TooLarge { field, max_bytes });
+    }
+    Ok(value)
+}
+
+/// Cuts an error message another canister returned down to MAX_REASON_BYTES, at a character boundary.
+pub fn reason(mut value: String) -> String {
+    if value.len() > MAX_REASON_BYTES {
+        let end = (0..=MAX_REASON_BYTES)
+            .rev()
+            .find(|&i| value.is_char_boundary(i))
+            .unwrap_or(0);
+        value.truncate(end);
+    }
+    value
+}
+
+//an optional field left blank is treated as not set
+pub fn optional_text(
+    field: &'static str,
+    value: Option<String>,
+    max: usize,
+) -> Result<Option<String>, ValidationError> {
+    match value.map(|v| sanitize(&v)) {
+        Some(v) if v.is_empty() => Ok(None),
+        Some(v) => text(field, &v, max).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Like `optional_text`, for a field stored in a bounded record.
+pub fn optional_bounded_text(
+    field: &'static str,
+    value: Option<String>,
+    max: usize,
+    max_bytes: usize,
+) -> Result<Option<String>, ValidationError> {
+    match value.map(|v| sanitize(&v)) {
+        Some(v) if v.is_empty() => Ok(None),
+        Some(v) => bounded_text(field, &v, max, max_bytes).map(Some),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reasons_are_cut_at_a_character_boundary() {
+        assert_eq!(reason("short".to_string()), "short");
+        let long = "a".repeat(MAX_REASON_BYTES - 1) + "\u{e9}\u{e9}";
+        let cut = reason(long);
+        assert_eq!(cut.len(), MAX_REASON_BYTES - 1);
+        assert!(cut.chars().all(|c| c == 'a'));
+    }
+
+    #[test]
+    fn text_that_renders_the_same_is_stored_the_same() {
+        assert_eq!(sanitize(" Cafe\u{301}\r\n"), sanitize("Caf\u{e9}"));
+        assert_eq!(
+            text("Description", "Cafe\u{301}", 4),
+            Ok("Caf\u{e9}".to_string())
+        );
+    }
+
+    #[test]
+    fn listing_text_is_bounded_in_bytes() {
+        let description = "\u{1F600}".repeat(MAX_DESCRIPTION_LENGTH);
+        assert_eq!(
+            bounded_text(
+                "Description",
+                &description,
+                MAX_DESCRIPTION_LENGTH,
+                MAX_DESCRIPTION_BYTES
+            ),
+            Err(ValidationError::TooLarge {
+                field: "Description",
+                max_bytes: MAX_DESCRIPTION_BYTES
+            })
+        );
+        assert_eq!(
+            optional_bounded_text(
+                "Terms",
+                Some(" \n".to_string()),
+                MAX_TERMS_LENGTH,
+                MAX_TERMS_BYTES
+            ),
+            Ok(None)
+        );
+        let terms = "x".repeat(MAX_TERMS_LENGTH);
+        assert_eq!(
+            optional_bounded_text(
+                "Terms",
+                Some(terms.clone()),
+                MAX_TERMS_LENGTH,
+                MAX_TERMS_BYTES
+            ),
+            Ok(Some(terms))
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use candid::Principal;
+use ic_cdk::{caller, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap};
+use std::cell::RefCell;
+
+use crate::{
+    guard,
+    trending::{self, TrendEvent},
+    ItemSummary, Memory, StorablePrincipal, ITEM_MAP, MEMORY_MANAGER,
+};
+
+const MAX_WATCHLIST_SIZE: usize = 500;
+
+thread_local! {
+    //(watcher, item_id), so a user's whole watchlist is one range
+    static WATCH_MAP: RefCell<StableBTreeMap<(StorablePrincipal, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))));
+
+    //the same entries as (item_id, watcher), so an item's watchers are one range
+    static ITEM_WATCHERS: RefCell<StableBTreeMap<(u64, StorablePrincipal), (), Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(100)))));
+}
+
+pub fn watched_ids(p: Principal) -> Vec<u64> {
+    let key = StorablePrincipal::from(p);
+    WATCH_MAP.with(|w| {
+        w.borrow()
+            .range((key, 0)..=(key, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+pub fn watchers_of(item_id: u64) -> Vec<Principal> {
+    ITEM_WATCHERS.with(|w| {
+        w.borrow()
+            .range((item_id, StorablePrincipal::default())..)
+            .take_while(|((id, _), _)| *id == item_id)
+            .map(|((_, watcher), _)| watcher.into())
+            .collect()
+    })
+}
+
+//watches added before the item index existed, runs as a schema migration
+pub fn backfill_item_index() {
+    WATCH_MAP.with(|w| {
+        for ((watcher, item_id), _) in w.borrow().iter() {
+            ITEM_WATCHERS.with(|i| i.borrow_mut().insert((item_id, watcher), ()));
+        }
+    });
+}
+
+pub fn is_watching(p: Principal, item_id: u64) -> bool {
+    WATCH_MAP.with(|w| w.borrow().contains_key(&(p.into(), item_id)))
+}
+
+#[update(name = "watchItem")]
+fn watch_item(item_id: u64) -> Result<String, String> {
+    guard::check_optional_write()?;
+    if !ITEM_MAP.with(|items| items.borrow().contains_key(&item_id)) {
+        return Err("Item could not be found.".to_string());
+    }
+    if is_watching(caller(), item_id) {
+        return Err("You are already watching this item.".to_string());
+    }
+    if watched_ids(caller()).len() >= MAX_WATCHLIST_SIZE {
+        return Err(format!(
+            "You cannot watch more than {} items.",
+            MAX_WATCHLIST_SIZE
+        ));
+    }
+
+    WATCH_MAP.with(|w| w.borrow_mut().insert((caller().into(), item_id), ()));
+    ITEM_WATCHERS.with(|w| w.borrow_mut().insert((item_id, caller().into()), ()));
+    trending::record(item_id, TrendEvent::Watch);
+    Ok(format!("Item {} added to your watchlist", item_id))
+}
+
+#[update(name = "unwatchItem")]
+fn unwatch_item(item_id: u64) -> Result<String, String> {
+    match WATCH_MAP.with(|w| w.borrow_mut().remove(&(caller().into(), item_id))) {
+        Some(_) => {
+            ITEM_WATCHERS.with(|w| w.borrow_mut().remove(&(item_id, caller().into())));
+            Ok(format!("Item {} removed from your watchlist", item_id))
+        }
+        None => Err("You are not watching this item.".to_string()),
+    }
+}
+
+#[query(name = "getWatchlist")]
+fn get_watchlist() -> Vec<ItemSummary> {
+    watched_ids(caller())
+        .into_iter()
+        .filter_map(|id| ITEM_MAP.with(|items| items.borrow().get(&id)))
+        .map(|item| ItemSummary::from(&item))
+        .collect()
+}
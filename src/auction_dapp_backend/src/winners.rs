@@ -0,0 +1,188 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::query;
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    bid_store,
+    clock::time,
+    notifications::{self, NotificationKind},
+    Bid, Item, Memory, ITEM_MAP, MEMORY_MANAGER,
+};
+
+//how the winner of an item is picked when its auction closes
+#[derive(Deserialize, CandidType, Clone, Copy, PartialEq)]
+pub enum WinnerPolicy {
+    HighestBid, //ties go to the latest bid
+    HighestBidAboveReserve { reserve: u64 },
+    EarliestAtTopPrice, //a bid can match the top one, ties go to the earliest bid
+}
+
+pub enum Decision {
+    Winner(Bid),
+    ReserveNotMet,
+    NoBids,
+}
+
+pub fn policy_of(item: &Item) -> WinnerPolicy {
+    item.winner_policy.unwrap_or(WinnerPolicy::HighestBid)
+}
+
+/// Applies the item's winner policy to its bids.
+pub fn decide(item: &Item) -> Decision {
+    //bids without a sequence number predate those with one, so None orders first
+    let order = |b: &Bid| (b.bid_amount, b.seq);
+    let bids = bid_store::of(item.id).into_iter();
+    let top = match policy_of(item) {
+        WinnerPolicy::HighestBid | WinnerPolicy::HighestBidAboveReserve { .. } => {
+            bids.max_by_key(order)
+        }
+        WinnerPolicy::EarliestAtTopPrice => {
+            bids.max_by_key(|b| (b.bid_amount, std::cmp::Reverse(b.seq)))
+        }
+    };
+    match (top, policy_of(item)) {
+        (None, _) => Decision::NoBids,
+        (Some(bid), WinnerPolicy::HighestBidAboveReserve { reserve })
+            if bid.bid_amount < reserve =>
+        {
+            Decision::ReserveNotMet
+        }
+        (Some(bid), _) => Decision::Winner(bid),
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+pub struct WinnerRecord {
+    principal: Principal,
+    amount: u64,
+    decided_at: u64,
+    policy: Option<WinnerPolicy>, //the policy the winner was picked by
+}
+
+impl Storable for WinnerRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//a record with a policy takes 119 bytes, the rest is headroom
+impl BoundedStorable for WinnerRecord {
+    const MAX_SIZE: u32 = 200;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static WINNER_MAP: RefCell<StableBTreeMap<u64, WinnerRecord, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))));
+}
+
+/// Records the winner of a closed auction and lets them know in their inbox.
+pub fn record(item_id: u64, principal: Principal, amount: u64, policy: WinnerPolicy) {
+    let winner = WinnerRecord {
+        principal,
+        amount,
+        decided_at: time(),
+        policy: Some(policy),
+    };
+    WINNER_MAP.with(|w| w.borrow_mut().insert(item_id, winner));
+    notifications::notify(principal, NotificationKind::AuctionWon { item_id, amount });
+    if let Some(item) = ITEM_MAP.with(|items| items.borrow().get(&item_id)) {
+        notifications::notify(
+            item.item_owner,
+            NotificationKind::ItemSold { item_id, amount },
+        );
+    }
+}
+
+#[query(name = "getWinner")]
+fn get_winner(item_id: u64) -> Option<WinnerRecord> {
+    WINNER_MAP.with(|w| w.borrow().get(&item_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        accept_bid, clock, exposure,
+        test_support::{self, principal, HOUR},
+        ApiError, BidBase, BidReceipt,
+    };
+
+    #[test]
+    fn the_highest_bid_wins() {
+        let item = test_support::item(1, principal(1));
+        assert!(matches!(decide(&item), Decision::NoBids));
+        bid_store::push(test_support::bid(1, principal(2), 10, 0));
+        bid_store::push(test_support::bid(1, principal(3), 30, 1));
+        bid_store::push(test_support::bid(1, principal(4), 20, 2));
+        let Decision::Winner(bid) = decide(&item) else {
+            panic!("the auction had bids");
+        };
+        assert_eq!((bid.bidder_principal, bid.bid_amount), (principal(3), 30));
+    }
+
+    #[test]
+    fn a_reserve_above_the_top_bid_sells_nothing() {
+        let mut item = test_support::item(1, principal(1));
+        item.winner_policy = Some(WinnerPolicy::HighestBidAboveReserve { reserve: 50 });
+        bid_store::push(test_support::bid(1, principal(2), 49, 0));
+        assert!(matches!(decide(&item), Decision::ReserveNotMet));
+        bid_store::push(test_support::bid(1, principal(3), 50, 1));
+        assert!(matches!(decide(&item), Decision::Winner(b) if b.bidder_principal == principal(3)));
+    }
+
+    fn offer(item_id: u64, amount: u64, bidder: Principal) -> Result<BidReceipt, ApiError> {
+        let bid = BidBase {
+            bid_amount: amount,
+            bid_date: 0,
+            memo: None,
+        };
+        accept_bid(item_id, bid, bidder)
+    }
+
+    #[test]
+    fn matching_bids_rank_behind_the_earliest_one() {
+        clock::set(1_000 * HOUR);
+        let mut item = test_support::item(1, principal(1));
+        item.winner_policy = Some(WinnerPolicy::EarliestAtTopPrice);
+        test_support::list(item);
+        test_support::list(test_support::item(2, principal(1)));
+
+        assert!(offer(1, 50, principal(2)).is_ok());
+        assert!(offer(1, 50, principal(3)).is_ok());
+        assert!(
+            offer(1, 50, principal(2)).is_err(),
+            "the leader cannot match their own bid"
+        );
+        let Decision::Winner(bid) = decide(&test_support::stored(1)) else {
+            panic!("the auction had bids");
+        };
+        assert_eq!(bid.bidder_principal, principal(2));
+        assert_eq!(exposure::leader(1), Some(principal(2)));
+        assert!(offer(1, 51, principal(3)).is_ok());
+        assert_eq!(exposure::leader(1), Some(principal(3)));
+
+        //other items still need a higher bid
+        assert!(offer(2, 50, principal(2)).is_ok());
+        assert!(offer(2, 50, principal(3)).is_err());
+    }
+
+    #[test]
+    fn largest_record_fits() {
+        let record = WinnerRecord {
+            principal: Principal::from_slice(&[0xff; 29]),
+            amount: u64::MAX,
+            decided_at: u64::MAX,
+            policy: Some(WinnerPolicy::HighestBidAboveReserve { reserve: u64::MAX }),
+        };
+        let bytes = record.to_bytes();
+        assert!(bytes.len() <= WinnerRecord::MAX_SIZE as usize);
+        let decoded = WinnerRecord::from_bytes(bytes);
+        assert_eq!(decoded.principal, record.principal);
+        assert!(decoded.policy == record.policy);
+    }
+}
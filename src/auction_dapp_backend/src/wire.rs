@@ -0,0 +1,78 @@
+use candid::{CandidType, Principal};
+
+use crate::{
+    compaction::BidArchive, cycle_bids::PaymentMode, increments::IncrementBand, media::Media,
+    names, relisting::RelistPolicy, tax::TaxInfo, winners::WinnerPolicy, Bid, Item, Status,
+};
+
+//what endpoints return for an item, kept apart from the stored Item so the two can change on their own.
+//the first fields are the ones every frontend was built against, everything added after them is opt
+//so a frontend built before a field existed still decodes the record, and drops what it does not know
+#[derive(CandidType)]
+pub struct ItemView {
+    item_owner: Principal,
+    id: u64,
+    description: String,
+    highest_bid: u64,
+    latest_update: u64,
+    result_date: u64,
+    bid_vector: Vec<Bid>, //only filled where the endpoint says so, getBids pages through them otherwise
+    is_active: bool,
+    accepts_offers: Option<bool>,
+    auto_accept_price: Option<u64>,
+    is_hidden: Option<bool>,
+    terms: Option<String>,
+    category: Option<String>,
+    status: Option<Status>,
+    starts_at: Option<u64>,
+    winner_policy: Option<WinnerPolicy>,
+    relist_policy: Option<RelistPolicy>,
+    relisted_from: Option<u64>,
+    increment_bands: Option<Vec<IncrementBand>>,
+    payment_mode: Option<PaymentMode>,
+    bid_bond: Option<u64>,
+    payment_window: Option<u64>,
+    tax: Option<TaxInfo>,
+    minimum_bid: Option<u64>,
+    media: Option<Vec<Media>>,
+    certified: Option<bool>,
+    bid_archive: Option<BidArchive>,
+    bid_count: Option<u64>,     //including the bids compaction dropped
+    owner_name: Option<String>, //the owner's display name
+}
+
+impl From<Item> for ItemView {
+    fn from(item: Item) -> Self {
+        ItemView {
+            bid_count: Some(item.bid_count()),
+            owner_name: names::of(item.item_owner),
+            status: Some(item.status()),
+            item_owner: item.item_owner,
+            id: item.id,
+            description: item.description,
+            highest_bid: item.highest_bid,
+            latest_update: item.latest_update,
+            result_date: item.result_date,
+            bid_vector: item.bid_vector,
+            is_active: item.is_active,
+            accepts_offers: item.accepts_offers,
+            auto_accept_price: item.auto_accept_price,
+            is_hidden: item.is_hidden,
+            terms: item.terms,
+            category: item.category,
+            starts_at: item.starts_at,
+            winner_policy: item.winner_policy,
+            relist_policy: item.relist_policy,
+            relisted_from: item.relisted_from,
+            increment_bands: item.increment_bands,
+            payment_mode: item.payment_mode,
+            bid_bond: item.bid_bond,
+            payment_window: item.payment_window,
+            tax: item.tax,
+            minimum_bid: item.minimum_bid,
+            media: item.media,
+            certified: item.certified,
+            bid_archive: item.bid_archive,
+        }
+    }
+}
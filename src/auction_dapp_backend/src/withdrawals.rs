@@ -0,0 +1,253 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_cdk::{
+    api::management_canister::main::{deposit_cycles, CanisterIdRecord},
+    caller, query, update,
+};
+use ic_stable_structures::{memory_manager::MemoryId, BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    balances,
+    blocks::{self, FinancialOp},
+    clock::time,
+    icrc::{self, Account, TransferArg, TransferError},
+    ledgers, validation, Memory, MEMORY_MANAGER,
+};
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum WithdrawalState {
+    Pending,
+    Completed { block_index: Nat },
+    Failed { reason: String }, //the amount was credited back
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Withdrawal {
+    id: u64,
+    owner: Principal,
+    ledger: Principal,
+    amount: u128, //debited from the balance, the ledger fee is taken out of it
+    to: Account,
+    created_at_time: u64, //sent with every attempt, so the ledger drops retried duplicates
+    state: WithdrawalState,
+    fee: Option<Nat>, //the ledger fee of the first attempt, retries reuse it so they match it exactly
+}
+
+impl Storable for Withdrawal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//a failed withdrawal to a subaccount, with the longest kept reason, takes 421 bytes
+impl BoundedStorable for Withdrawal {
+    const MAX_SIZE: u32 = 500;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType)]
+enum WithdrawError {
+    UnsupportedLedger,
+    InsufficientBalance { balance: u128 },
+    AmountBelowFee { fee: Nat },
+    LedgerUnavailable { reason: String },
+    TransferFailed { reason: String },
+    Unresolved { reason: String }, //a retry could not tell whether the first attempt went through, it stays pending
+    NotRetriable,
+}
+
+thread_local! {
+    static WITHDRAWAL_MAP: RefCell<StableBTreeMap<u64, Withdrawal, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))));
+}
+
+fn save(withdrawal: &Withdrawal) {
+    WITHDRAWAL_MAP.with(|w| w.borrow_mut().insert(withdrawal.id, withdrawal.clone()));
+}
+
+//the balance is debited before the transfer and only credited back on a definite failure
+#[update(name = "withdraw")]
+async fn withdraw(ledger: Principal, amount: u128, to: Account) -> Result<Nat, WithdrawError> {
+    ledgers::ensure_supported(ledger).map_err(|_| WithdrawError::UnsupportedLedger)?;
+    let owner = caller();
+    let fee = icrc::fee(ledger)
+        .await
+        .map_err(|reason| WithdrawError::LedgerUnavailable { reason })?;
+    if fee >= amount {
+        return Err(WithdrawError::AmountBelowFee { fee });
+    }
+    balances::debit_token(owner, ledger, amount)
+        .map_err(|balance| WithdrawError::InsufficientBalance { balance })?;
+
+    let id = WITHDRAWAL_MAP.with(|w| w.borrow().last_key_value().map_or(0, |(k, _)| k + 1));
+    let withdrawal = Withdrawal {
+        id,
+        owner,
+        ledger,
+        amount,
+        to,
+        created_at_time: time(),
+        state: WithdrawalState::Pending,
+        fee: Some(fee.clone()),
+    };
+    save(&withdrawal);
+    send(withdrawal, fee, false).await
+}
+
+async fn send(mut withdrawal: Withdrawal, fee: Nat, retry: bool) -> Result<Nat, WithdrawError> {
+    let args = TransferArg {
+        from_subaccount: None,
+        to: withdrawal.to.clone(),
+        amount: Nat::from(withdrawal.amount) - fee.clone(),
+        fee: Some(fee),
+        memo: Some(withdrawal.id.to_be_bytes().to_vec()),
+        created_at_time: Some(withdrawal.created_at_time),
+    };
+    let result = match icrc::transfer_raw(withdrawal.ledger, args).await {
+        Err(reason) => {
+            //the transfer may or may not have happened, it stays pending until retried
+            return Err(WithdrawError::LedgerUnavailable { reason });
+        }
+        Ok(result) => result,
+    };
+    let outcome = match result {
+        Ok(block_index)
+        | Err(TransferError::Duplicate {
+            duplicate_of: block_index,
+        }) => {
+            blocks::append_token(
+                FinancialOp::Withdrawal,
+                withdrawal.ledger,
+                withdrawal.amount,
+                Some(withdrawal.owner),
+                Some(withdrawal.to.owner),
+                None,
+            );
+            withdrawal.state = WithdrawalState::Completed {
+                block_index: block_index.clone(),
+            };
+            Ok(block_index)
+        }
+        //the ledger no longer deduplicates against the first attempt, which may have landed,
+        //so crediting back could pay the withdrawal twice
+        Err(e @ (TransferError::TooOld | TransferError::CreatedInFuture { .. })) if retry => {
+            return Err(WithdrawError::Unresolved {
+                reason: format!("{:?}", e),
+            });
+        }
+        Err(e) => {
+            let reason = format!("{:?}", e);
+            balances::credit_token(withdrawal.owner, withdrawal.ledger, withdrawal.amount);
+            withdrawal.state = WithdrawalState::Failed {
+                reason: validation::reason(reason.clone()),
+            };
+            Err(WithdrawError::TransferFailed { reason })
+        }
+    };
+    save(&withdrawal);
+    outcome
+}
+
+//resends a pending withdrawal with the same arguments, a transfer that already went through is not repeated
+#[update(name = "retryWithdrawal")]
+async fn retry_withdrawal(id: u64) -> Result<Nat, WithdrawError> {
+    let withdrawal = WITHDRAWAL_MAP
+        .with(|w| w.borrow().get(&id))
+        .filter(|w| w.owner == caller() && matches!(w.state, WithdrawalState::Pending))
+        .ok_or(WithdrawError::NotRetriable)?;
+    //withdrawals made before the fee was stored can only use the current one
+    let fee = match withdrawal.fee.clone() {
+        Some(fee) => fee,
+        None => icrc::fee(withdrawal.ledger)
+            .await
+            .map_err(|reason| WithdrawError::LedgerUnavailable { reason })?,
+    };
+    send(withdrawal, fee, true).await
+}
+
+//the balance is debited before the deposit and credited back if the deposit fails
+#[update(name = "withdrawCycles")]
+async fn withdraw_cycles(canister: Principal, amount: u128) -> Result<String, String> {
+    if amount == 0 {
+        return Err("The amount has to be above zero.".to_string());
+    }
+    let owner = caller();
+    balances::debit(owner, amount).map_err(|balance| {
+        format!(
+            "Your balance of {} cycles does not cover the withdrawal.",
+            balance
+        )
+    })?;
+    let target = CanisterIdRecord {
+        canister_id: canister,
+    };
+    match deposit_cycles(target, amount).await {
+        Ok(()) => {
+            blocks::append(
+                FinancialOp::Withdrawal,
+                amount,
+                Some(owner),
+                Some(canister),
+                None,
+            );
+            Ok(format!("{} cycles sent to {}", amount, canister))
+        }
+        Err((_, msg)) => {
+            balances::credit(owner, amount);
+            Err(format!("The cycles could not be deposited: {}", msg))
+        }
+    }
+}
+
+pub fn pending_amount(owner: Principal, ledger: Principal) -> u128 {
+    WITHDRAWAL_MAP.with(|w| {
+        w.borrow()
+            .iter()
+            .map(|(_, v)| v)
+            .filter(|v| v.owner == owner && v.ledger == ledger)
+            .filter(|v| matches!(v.state, WithdrawalState::Pending))
+            .map(|v| v.amount)
+            .sum()
+    })
+}
+
+#[query(name = "getWithdrawals")]
+fn get_withdrawals() -> Vec<Withdrawal> {
+    let owner = caller();
+    WITHDRAWAL_MAP.with(|w| {
+        w.borrow()
+            .iter()
+            .map(|(_, v)| v)
+            .filter(|v| v.owner == owner)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_fits, principal};
+
+    #[test]
+    fn the_largest_failed_withdrawal_fits() {
+        let withdrawal = Withdrawal {
+            id: u64::MAX,
+            owner: principal(1),
+            ledger: principal(9),
+            amount: u128::MAX,
+            to: Account {
+                owner: principal(2),
+                subaccount: Some(vec![0xff; 32]),
+            },
+            created_at_time: u64::MAX,
+            state: WithdrawalState::Failed {
+                reason: validation::reason("x".repeat(10_000)),
+            },
+            fee: Some(Nat::from(u128::MAX)),
+        };
+        assert_fits(&withdrawal);
+    }
+}
@@ -0,0 +1,208 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal, Reserved};
+use ic_cdk::{api::call::call_with_payment128, query, update};
+use ic_stable_structures::{memory_manager::MemoryId, StableCell, Storable};
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap, time::Duration};
+
+use crate::{admin, allowance_bids, ledgers, validation, Memory, ITEM_MAP, MEMORY_MANAGER};
+
+//the exchange rate canister on the NNS subnet, each request has to carry cycles
+const XRC_CANISTER: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";
+const XRC_FEE: u128 = 1_000_000_000;
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30 * 60);
+const MAX_REFERENCE_CURRENCIES: usize = 5;
+//cycles are pegged to the XDR, a trillion cycles are one XDR
+const CYCLES_PER_XDR: f64 = 1_000_000_000_000.0;
+
+#[derive(CandidType, Deserialize, Clone)]
+enum AssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Asset {
+    symbol: String,
+    class: AssetClass,
+}
+
+#[derive(CandidType)]
+struct GetExchangeRateRequest {
+    base_asset: Asset,
+    quote_asset: Asset,
+    timestamp: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ExchangeRateMetadata {
+    decimals: u32,
+}
+
+//only the fields the auction uses, candid skips the rest
+#[derive(CandidType, Deserialize)]
+struct ExchangeRate {
+    timestamp: u64, //in seconds
+    rate: u64,
+    metadata: ExchangeRateMetadata,
+}
+
+#[derive(Clone, Copy)]
+struct CachedRate {
+    rate: f64,
+    timestamp: u64,
+}
+
+#[derive(CandidType)]
+struct ConvertedPrice {
+    currency: String,
+    amount: f64,
+    rate_timestamp: u64, //when the rate was observed, in seconds
+}
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct ReferenceCurrencies {
+    symbols: Vec<String>,
+}
+
+impl Storable for ReferenceCurrencies {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static REFERENCE_CURRENCIES: RefCell<StableCell<ReferenceCurrencies, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47))),
+        ReferenceCurrencies::default()).unwrap());
+
+    //(base, quote) -> rate, filled by the refresh timer and lost on upgrade
+    static RATE_CACHE: RefCell<BTreeMap<(String, String), CachedRate>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+fn reference_currencies() -> Vec<String> {
+    REFERENCE_CURRENCIES.with(|c| c.borrow().get().symbols.clone())
+}
+
+//ledger symbols like ckBTC are quoted under the asset they wrap
+fn asset_symbol(ledger_symbol: &str) -> String {
+    ledger_symbol
+        .strip_prefix("ck")
+        .unwrap_or(ledger_symbol)
+        .to_uppercase()
+}
+
+pub fn start_refresh_timer() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(refresh()));
+    ic_cdk_timers::set_timer_interval(REFRESH_INTERVAL, || ic_cdk::spawn(refresh()));
+}
+
+async fn refresh() {
+    let quotes = reference_currencies();
+    if quotes.is_empty() {
+        return;
+    }
+    let mut bases = vec![Asset {
+        symbol: "XDR".to_string(),
+        class: AssetClass::FiatCurrency,
+    }];
+    for info in ledgers::supported() {
+        bases.push(Asset {
+            symbol: asset_symbol(&info.symbol),
+            class: AssetClass::Cryptocurrency,
+        });
+    }
+    for base in bases {
+        for quote in &quotes {
+            let quote = Asset {
+                symbol: quote.clone(),
+                class: AssetClass::FiatCurrency,
+            };
+            if let Some(rate) = fetch(base.clone(), quote.clone()).await {
+                RATE_CACHE.with(|c| {
+                    c.borrow_mut()
+                        .insert((base.symbol.clone(), quote.symbol), rate)
+                });
+            }
+        }
+    }
+}
+
+async fn fetch(base_asset: Asset, quote_asset: Asset) -> Option<CachedRate> {
+    let xrc = Principal::from_text(XRC_CANISTER).unwrap();
+    let request = GetExchangeRateRequest {
+        base_asset,
+        quote_asset,
+        timestamp: None,
+    };
+    let (result,): (Result<ExchangeRate, Reserved>,) =
+        call_with_payment128(xrc, "get_exchange_rate", (request,), XRC_FEE)
+            .await
+            .ok()?;
+    let rate = result.ok()?;
+    Some(CachedRate {
+        rate: rate.rate as f64 / 10f64.powi(rate.metadata.decimals as i32),
+        timestamp: rate.timestamp,
+    })
+}
+
+#[update(name = "setReferenceCurrencies")]
+fn set_reference_currencies(symbols: Vec<String>) -> Result<String, String> {
+    admin::ensure_admin()?;
+    if symbols.len() > MAX_REFERENCE_CURRENCIES {
+        return Err(format!(
+            "At most {} reference currencies can be configured.",
+            MAX_REFERENCE_CURRENCIES
+        ));
+    }
+    let symbols = symbols
+        .iter()
+        .map(|s| validation::text("Currency", s, 8).map(|s| s.to_uppercase()))
+        .collect::<Result<Vec<_>, _>>()?;
+    REFERENCE_CURRENCIES.with(|c| c.borrow_mut().set(ReferenceCurrencies { symbols }).unwrap());
+    ic_cdk::spawn(refresh());
+    Ok("Reference currencies updated".to_string())
+}
+
+#[query(name = "getReferenceCurrencies")]
+fn get_reference_currencies() -> Vec<String> {
+    reference_currencies()
+}
+
+//currencies without a cached rate yet are left out
+#[query(name = "getConvertedPrice")]
+fn get_converted_price(item_id: u64) -> Result<Vec<ConvertedPrice>, String> {
+    let item = ITEM_MAP
+        .with(|items| items.borrow().get(&item_id))
+        .ok_or_else(|| "Item could not be found.".to_string())?;
+    let (base, units) = match allowance_bids::ledger_of(&item) {
+        None => ("XDR".to_string(), item.highest_bid as f64 / CYCLES_PER_XDR),
+        Some(ledger) => {
+            let symbol = ledgers::supported()
+                .into_iter()
+                .find(|l| l.ledger == ledger)
+                .map(|l| asset_symbol(&l.symbol))
+                .ok_or_else(|| "The item's ledger is no longer supported.".to_string())?;
+            let decimals = ledgers::decimals(ledger)
+                .ok_or_else(|| "The decimals of the item's ledger are not known.".to_string())?;
+            (
+                symbol,
+                item.highest_bid as f64 / 10f64.powi(decimals as i32),
+            )
+        }
+    };
+    Ok(reference_currencies()
+        .into_iter()
+        .filter_map(|currency| {
+            let cached =
+                RATE_CACHE.with(|c| c.borrow().get(&(base.clone(), currency.clone())).copied())?;
+            Some(ConvertedPrice {
+                amount: units * cached.rate,
+                rate_timestamp: cached.timestamp,
+                currency,
+            })
+        })
+        .collect())
+}
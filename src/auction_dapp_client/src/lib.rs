@@ -0,0 +1,127 @@
+//! Typed access to the auction canister for other Rust canisters.
+//!
+//! The types mirror auction_dapp_backend.did. Records only carry the fields a caller is likely to
+//! need, candid drops the ones left out when decoding, so they keep working as the auction adds more.
+
+use candid::{CandidType, Deserialize, Principal};
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct BidBase {
+    pub bid_amount: u64,
+    pub bid_date: u64, //a unix timestamp, the auction records its own time next to it
+    pub memo: Option<String>,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct Bid {
+    pub item_id: u64,
+    pub bidder_principal: Principal,
+    pub bid_date: u64,
+    pub bid_amount: u64,
+    pub memo: Option<String>, //only returned to the item owner and the bidder
+    pub seq: Option<u64>,
+    pub placed_at: Option<u64>,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct Item {
+    pub item_owner: Principal,
+    pub id: u64,
+    pub description: String,
+    pub highest_bid: u64,
+    pub latest_update: u64,
+    pub result_date: u64,
+    pub is_active: bool,
+    pub category: Option<String>,
+    pub starts_at: Option<u64>,
+    pub minimum_bid: Option<u64>,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct BidSummary {
+    pub count: u64,
+    pub highest: u64,
+    pub latest_at: Option<u64>,
+    pub own_best: Option<u64>, //the calling canister's highest bid
+    pub archived: Option<bool>,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct ItemWithBidSummary {
+    pub item: Item,
+    pub bid_summary: BidSummary,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct BidPage {
+    pub bids: Vec<Bid>,
+    pub next_index: Option<u64>,
+    pub archived: Option<bool>,
+}
+
+#[cfg(feature = "calls")]
+pub use calls::AuctionClient;
+
+#[cfg(feature = "calls")]
+mod calls {
+    use candid::{utils::ArgumentEncoder, CandidType, Deserialize, Principal};
+
+    use crate::{BidBase, BidPage, ItemWithBidSummary};
+
+    /// Calls one auction canister. Bids and reads are made as the canister holding the client.
+    #[derive(Clone, Copy, Debug)]
+    pub struct AuctionClient {
+        pub canister_id: Principal,
+    }
+
+    impl AuctionClient {
+        pub fn new(canister_id: Principal) -> Self {
+            AuctionClient { canister_id }
+        }
+
+        //rejections and the auction's own errors both come back as the message to show
+        async fn call<A: ArgumentEncoder, R: CandidType + for<'de> Deserialize<'de>>(
+            &self,
+            method: &str,
+            args: A,
+        ) -> Result<R, String> {
+            ic_cdk::call::<A, (R,)>(self.canister_id, method, args)
+                .await
+                .map(|(reply,)| reply)
+                .map_err(|(code, message)| {
+                    format!("The auction rejected {} ({:?}): {}", method, code, message)
+                })
+        }
+
+        /// Bids `bid_amount` on the item, returning the auction's confirmation.
+        pub async fn bid(
+            &self,
+            item_id: u64,
+            bid_amount: u64,
+            memo: Option<String>,
+        ) -> Result<String, String> {
+            let bid = BidBase {
+                bid_amount,
+                bid_date: ic_cdk::api::time() / 1_000_000_000,
+                memo,
+            };
+            self.call::<_, Result<String, String>>("bidForAnItem", (item_id, bid))
+                .await?
+        }
+
+        /// The item with a summary of its bids, none when it does not exist or is hidden.
+        pub async fn get_item(&self, item_id: u64) -> Result<Option<ItemWithBidSummary>, String> {
+            self.call("getItem", (item_id,)).await
+        }
+
+        /// A page of the item's bids, oldest first.
+        pub async fn get_bids(
+            &self,
+            item_id: u64,
+            from_index: u64,
+            limit: u64,
+        ) -> Result<Option<BidPage>, String> {
+            self.call("getBids", (item_id, from_index, limit)).await
+        }
+    }
+}